@@ -1,122 +1,248 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::parser::{Statement, StatementKind, Operand, MemoryOffset};
-use crate::lexer::ModifierKind;
-use crate::symbols::SymbolTable;
+use crate::parser::{Expr, Statement, StatementKind, Operand, MemoryOffset};
+use crate::lexer::{ModifierKind, Operator};
+use crate::program::{Relocation, RelocationKind};
+use crate::source::FileId;
+use crate::section::{self, SectionStack, SECTION_DIRECTIVES};
+use crate::symbols::{self, SymbolTable, SYMBOL_DIRECTIVES};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct AssemblerError {
+    pub file: FileId,
     pub line: usize,
     pub message: String,
 }
 
 impl AssemblerError {
-    fn new(line: usize, message: String) -> Self {
-        Self { line, message }
+    fn new(statement: &Statement, message: String) -> Self {
+        Self { file: statement.file, line: statement.line, message }
     }
 }
 
+#[derive(Default)]
 pub struct DebugInfo {
     pub address_to_source: HashMap<u32, SourceMapping>,
+    // Every statement with what it assembled to, in the order of the source
+    pub statements: Vec<AssembledStatement>,
 }
 
+impl DebugInfo {
+    /// Adds the statements of another file, as the linker does.
+    pub fn extend(&mut self, other: DebugInfo) {
+        self.address_to_source.extend(other.address_to_source);
+        self.statements.extend(other.statements);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct SourceMapping {
     pub raw_text: String,
+    pub file: FileId,
     pub line: usize,
     pub section: String,
 }
 
+/// One statement, where it was placed and the bytes it assembled to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembledStatement {
+    pub address: u32,
+    pub bytes: Vec<u8>, // none in sections without contents, like .bss
+    pub label: bool,
+    pub absolute: bool, // defines a constant, and has no address
+    pub source: SourceMapping,
+}
+
 pub struct Assembler {
-    pub text_bin: Vec<u8>,
-    pub data_bin: Vec<u8>,
+    sections: HashMap<String, Vec<u8>>,
+    relocatable: bool,
+    relocations: Vec<Relocation>,
     pub debug_info: DebugInfo,
-    pub text_base: u32,
-    pub data_base: u32,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Assembler {
-    pub fn new(text_base: u32, data_base: u32) -> Self {
+    pub fn new() -> Self {
         Self {
-            text_bin: Vec::new(),
-            data_bin: Vec::new(),
-            debug_info: DebugInfo { address_to_source: HashMap::new() },
-            text_base,
-            data_base,
+            sections: HashMap::new(),
+            relocatable: false,
+            relocations: Vec::new(),
+            debug_info: DebugInfo::default(),
         }
     }
 
+    /// An assembler for a relocatable object, with its sections at 0: the
+    /// addresses of symbols it does not define, and of labels where the
+    /// placement of their section matters, are left to a linker as relocations.
+    pub fn relocatable() -> Self {
+        Self { relocatable: true, ..Self::new() }
+    }
+
+    /// The relocations of a relocatable object, in the order of the statements.
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
+    }
+
+    /// The bytes assembled into a section, from where the symbol table placed
+    /// it. Sections without contents, like `.bss`, have none.
+    pub fn section(&self, name: &str) -> &[u8] {
+        self.sections.get(name).map_or(&[], Vec::as_slice)
+    }
+
     pub fn assemble(&mut self, statements: &[Statement], sym_table: &SymbolTable) -> Result<(), Vec<AssemblerError>> {
-        let mut current_pc = self.text_base;
-        let mut data_pc = self.data_base;
-        let mut current_section = ".text";
+        // Sections are placed by the symbol table; ones it has not seen are at 0
+        let mut sections = sym_table.sections().clone();
+        let mut stack = SectionStack::default();
         let mut errors = Vec::new();
+        // The offset each auipc with %pcrel_hi or %got_pcrel_hi adds, by its address
+        let mut pcrel_hi = HashMap::new();
+        // The auipcs whose %pcrel_hi or %got_pcrel_hi was left to the linker, by section and address
+        let mut relocated_hi = HashSet::new();
+        // The labels of auipcs that could not be encoded, whose %pcrel_lo has nothing to add to their error
+        let mut failed_hi = HashSet::new();
+        let spelled = symbols::source_spelling(statements);
+
+        for (index, stmt) in statements.iter().enumerate() {
+            if let StatementKind::Directive(name, ops) = &stmt.kind
+                && SECTION_DIRECTIVES.contains(&name.as_str())
+            {
+                // No bytes to emit for section directives
+                if let Err(msg) = stack.switch(name, ops, &mut sections) {
+                    errors.push(AssemblerError::new(stmt, msg));
+                }
+                continue;
+            }
 
-        for stmt in statements {
-            let addr = if current_section == ".text" { current_pc } else { data_pc };
+            let (base, nobits) = sections.get(stack.current()).map_or((0, false), |section| (section.base, section.nobits));
+            let bytes = self.sections.entry(stack.current().to_string()).or_default();
+            let start = bytes.len();
+            // only a statement that adds nothing comes after a section that ends at 2^32
+            let addr = base.wrapping_add(start as u32);
+            let context = &Context { symbols: sym_table, section: stack.current(), pc: addr, pcrel_hi: &pcrel_hi };
 
-            self.debug_info.address_to_source.insert(addr, SourceMapping {
+            let mapping = SourceMapping {
+                file: stmt.file,
                 line: stmt.line,
-                raw_text: stmt.to_string(),
-                section: current_section.to_string(),
-            });
+                raw_text: spelled[index].to_string(),
+                section: stack.current().to_string(),
+            };
+            let absolute = matches!(&stmt.kind, StatementKind::Directive(name, _) if name == ".equ" || name == ".set");
+            if !absolute {
+                self.debug_info.address_to_source.insert(addr, mapping.clone());
+            }
 
-            match &stmt.kind {
-                StatementKind::Instruction(name, ops) => {
-                    match encode_instruction(name, ops, sym_table, current_pc) {
-                        Ok(bytes) => {
-                            self.text_bin.extend_from_slice(&bytes.to_le_bytes());
-                            current_pc += 4;
-                        }
+            // Operands left to the linker are encoded as 0, or as the offset 0 for branches
+            let relocated = match &stmt.kind {
+                StatementKind::Instruction(name, ops) | StatementKind::Directive(name, ops) if self.relocatable => {
+                    match relocate(name, ops, context, &relocated_hi) {
+                        Ok(relocated) => relocated,
                         Err(msg) => {
-                            errors.push(AssemblerError::new(stmt.line, msg));
+                            if name == "auipc" {
+                                failed_hi.extend(labels_before(&statements[..index]));
+                            }
+                            errors.push(AssemblerError::new(stmt, msg));
+                            continue;
                         }
                     }
                 }
-                StatementKind::Directive(name, ops) => {
-                    if name == ".text" || name == ".data" {
-                        current_section = name.as_str();
-                        continue; // No bytes to emit for section directives
+                _ => None,
+            };
+            if let Some((_, relocations)) = &relocated {
+                for (offset, kind, symbol, addend) in relocations {
+                    if matches!(kind, RelocationKind::PcrelHi20 | RelocationKind::GotHi20) {
+                        relocated_hi.insert((stack.current().to_string(), addr));
                     }
+                    self.relocations.push(Relocation {
+                        section: stack.current().to_string(),
+                        offset: start as u32 + offset,
+                        kind: *kind,
+                        symbol: symbol.clone(),
+                        addend: *addend,
+                    });
+                }
+            }
+            let relocated_ops = relocated.as_ref().map(|(ops, _)| ops.as_slice());
 
-                    if name == ".align" {
-                        if let Some(Operand::Immediate(pow)) = ops.get(0) {
-                            let alignment = 2u32.pow(*pow as u32);
-                            let padding = (alignment - (addr % alignment)) % alignment;
-                            let padding_bytes = vec![0u8; padding as usize];
-
-                            if current_section == ".text" {
-                                self.text_bin.extend_from_slice(&padding_bytes);
-                                current_pc += padding;
-                            } else {
-                                self.data_bin.extend_from_slice(&padding_bytes);
-                                data_pc += padding;
+            match &stmt.kind {
+                StatementKind::Instruction(name, ops) => {
+                    let ops = relocated_ops.unwrap_or(ops);
+                    let offset = match (name.as_str(), ops) {
+                        ("auipc", [_, op]) => pcrel_hi_offset(op, context),
+                        _ => None,
+                    };
+                    match encode_instruction(name, ops, context) {
+                        Ok(instruction) => {
+                            if let Some(offset) = offset {
+                                pcrel_hi.insert(addr, offset);
+                            }
+                            bytes.extend_from_slice(&instruction.to_le_bytes());
+                        }
+                        Err(msg) => {
+                            if name == "auipc" {
+                                failed_hi.extend(labels_before(&statements[..index]));
+                            }
+                            if !pcrel_lo_label(ops).is_some_and(|label| failed_hi.contains(label)) {
+                                errors.push(AssemblerError::new(stmt, msg));
                             }
-                            continue;
-                        } else {
-                            errors.push(AssemblerError::new(stmt.line, "Directive .align requires an immediate value".to_string()));
-                            continue;
                         }
                     }
-
-                    match emit_data_bytes(name, ops) {
-                        Ok(bytes) => {
-                            if current_section == ".text" {
-                                // TODO doubt: this seems to let me put data in the text section with some directives.. this is ok??
-                                // current assemblers allows it (i.e. GNU AS), but maybe I should launch a warning or be more restrictive
-                                self.text_bin.extend_from_slice(&bytes);
-                                current_pc += bytes.len() as u32;
-                            } else {
-                                self.data_bin.extend_from_slice(&bytes);
-                                data_pc += bytes.len() as u32;
+                }
+                StatementKind::Directive(name, _) if SYMBOL_DIRECTIVES.contains(&name.as_str()) => {
+                    // Handled when building the symbol table
+                }
+                StatementKind::Directive(name, ops) if name == ".align" => {
+                    if let Some(Ok(pow)) = ops.first().map(|op| resolve_any_immediate(op, context)) {
+                        match section::alignment(pow) {
+                            Ok(alignment) => {
+                                let padding = (alignment - (addr % alignment)) % alignment;
+                                bytes.resize(bytes.len() + padding as usize, 0);
                             }
+                            Err(msg) => errors.push(AssemblerError::new(stmt, msg)),
                         }
+                    } else {
+                        errors.push(AssemblerError::new(stmt, "Directive .align requires an immediate value".to_string()));
+                    }
+                }
+                StatementKind::Directive(name, ops) => {
+                    match emit_data_bytes(name, relocated_ops.unwrap_or(ops), context) {
+                        // TODO doubt: this seems to let me put data in the text section with some directives.. this is ok??
+                        // current assemblers allows it (i.e. GNU AS), but maybe I should launch a warning or be more restrictive
+                        Ok(data) => bytes.extend_from_slice(&data),
                         Err(msg) => {
-                            errors.push(AssemblerError::new(stmt.line, msg));
+                            errors.push(AssemblerError::new(stmt, msg));
                         }
                     }
                 }
                 _ => {}
             }
+
+            self.debug_info.statements.push(AssembledStatement {
+                address: addr,
+                bytes: if nobits { Vec::new() } else { bytes[start..].to_vec() },
+                label: matches!(stmt.kind, StatementKind::Label(_)),
+                absolute,
+                source: mapping,
+            });
+        }
+
+        // The global offset table, in the section the symbol table gave it
+        if !sym_table.got_entries().is_empty() {
+            let got = self.sections.entry(".got".to_string()).or_default();
+            for (name, _) in sym_table.got_entries() {
+                // an unknown symbol is reported by the instruction using it
+                let address = sym_table.get_address(name).unwrap_or(0);
+                got.extend_from_slice(&address.to_le_bytes());
+            }
+        }
+
+        // Zero-initialized sections only reserve their space
+        for section in sections.iter().filter(|section| section.nobits) {
+            self.sections.remove(&section.name);
         }
 
         if !errors.is_empty() {
@@ -124,11 +250,18 @@ impl Assembler {
         }
         Ok(())
     }
+}
 
-
+// What operands are resolved against: the symbols, the section and address
+// of the statement, and the offsets added by the auipcs a %pcrel_lo can name
+struct Context<'a> {
+    symbols: &'a SymbolTable,
+    section: &'a str,
+    pc: u32,
+    pcrel_hi: &'a HashMap<u32, i32>,
 }
 
-fn encode_instruction(name: &str, ops: &[Operand], sym_table: &SymbolTable, current_pc: u32) -> Result<u32, String> {
+fn encode_instruction(name: &str, ops: &[Operand], context: &Context) -> Result<u32, String> {
     match name {
         // R-type | Opcode: 0x33 | Format: funct7, rs2, rs1, funct3, rd, opcode
         "add"   => encode_r_type(0x33, 0x0, 0x00, ops),
@@ -142,48 +275,68 @@ fn encode_instruction(name: &str, ops: &[Operand], sym_table: &SymbolTable, curr
         "or"    => encode_r_type(0x33, 0x6, 0x00, ops),
         "and"   => encode_r_type(0x33, 0x7, 0x00, ops),
 
+        // RV32M | Opcode: 0x33 | funct7: 0x01
+        "mul"    => encode_r_type(0x33, 0x0, 0x01, ops),
+        "mulh"   => encode_r_type(0x33, 0x1, 0x01, ops),
+        "mulhsu" => encode_r_type(0x33, 0x2, 0x01, ops),
+        "mulhu"  => encode_r_type(0x33, 0x3, 0x01, ops),
+        "div"    => encode_r_type(0x33, 0x4, 0x01, ops),
+        "divu"   => encode_r_type(0x33, 0x5, 0x01, ops),
+        "rem"    => encode_r_type(0x33, 0x6, 0x01, ops),
+        "remu"   => encode_r_type(0x33, 0x7, 0x01, ops),
+
         // I-type | Opcode: 0x13 for ALU, 0x03 for Loads, 0x67 for jalr
-        "addi"  => encode_i_type(0x13, 0x0, ops, sym_table),
-        "slti"  => encode_i_type(0x13, 0x2, ops, sym_table),
-        "sltiu" => encode_i_type(0x13, 0x3, ops, sym_table),
-        "xori"  => encode_i_type(0x13, 0x4, ops, sym_table),
-        "ori"   => encode_i_type(0x13, 0x6, ops, sym_table),
-        "andi"  => encode_i_type(0x13, 0x7, ops, sym_table),
-        "slli"  => encode_i_shift(0x13, 0x1, 0x00, ops), // Special: uses shift amount
-        "srli"  => encode_i_shift(0x13, 0x5, 0x00, ops),
-        "srai"  => encode_i_shift(0x13, 0x5, 0x20, ops),
-
-        "lb"    => encode_i_type(0x03, 0x0, ops, sym_table),
-        "lh"    => encode_i_type(0x03, 0x1, ops, sym_table),
-        "lw"    => encode_i_type(0x03, 0x2, ops, sym_table),
-        "lbu"   => encode_i_type(0x03, 0x4, ops, sym_table),
-        "lhu"   => encode_i_type(0x03, 0x5, ops, sym_table),
-
-        "jalr"  => encode_i_type(0x67, 0x0, ops, sym_table),
+        "addi"  => encode_i_type(0x13, 0x0, ops, context),
+        "slti"  => encode_i_type(0x13, 0x2, ops, context),
+        "sltiu" => encode_i_type(0x13, 0x3, ops, context),
+        "xori"  => encode_i_type(0x13, 0x4, ops, context),
+        "ori"   => encode_i_type(0x13, 0x6, ops, context),
+        "andi"  => encode_i_type(0x13, 0x7, ops, context),
+        "slli"  => encode_i_shift(0x13, 0x1, 0x00, ops, context), // Special: uses shift amount
+        "srli"  => encode_i_shift(0x13, 0x5, 0x00, ops, context),
+        "srai"  => encode_i_shift(0x13, 0x5, 0x20, ops, context),
+
+        "lb"    => encode_i_type(0x03, 0x0, ops, context),
+        "lh"    => encode_i_type(0x03, 0x1, ops, context),
+        "lw"    => encode_i_type(0x03, 0x2, ops, context),
+        "lbu"   => encode_i_type(0x03, 0x4, ops, context),
+        "lhu"   => encode_i_type(0x03, 0x5, ops, context),
+
+        "jalr"  => encode_i_type(0x67, 0x0, ops, context),
 
         // S-type | Opcode: 0x23
-        "sb"    => encode_s_type(0x23, 0x0, ops, sym_table),
-        "sh"    => encode_s_type(0x23, 0x1, ops, sym_table),
-        "sw"    => encode_s_type(0x23, 0x2, ops, sym_table),
+        "sb"    => encode_s_type(0x23, 0x0, ops, context),
+        "sh"    => encode_s_type(0x23, 0x1, ops, context),
+        "sw"    => encode_s_type(0x23, 0x2, ops, context),
 
         // B-type | Opcode: 0x63
-        "beq"   => encode_b_type(0x63, 0x0, ops, sym_table, current_pc),
-        "bne"   => encode_b_type(0x63, 0x1, ops, sym_table, current_pc),
-        "blt"   => encode_b_type(0x63, 0x4, ops, sym_table, current_pc),
-        "bge"   => encode_b_type(0x63, 0x5, ops, sym_table, current_pc),
-        "bltu"  => encode_b_type(0x63, 0x6, ops, sym_table, current_pc),
-        "bgeu"  => encode_b_type(0x63, 0x7, ops, sym_table, current_pc),
+        "beq"   => encode_b_type(0x63, 0x0, ops, context),
+        "bne"   => encode_b_type(0x63, 0x1, ops, context),
+        "blt"   => encode_b_type(0x63, 0x4, ops, context),
+        "bge"   => encode_b_type(0x63, 0x5, ops, context),
+        "bltu"  => encode_b_type(0x63, 0x6, ops, context),
+        "bgeu"  => encode_b_type(0x63, 0x7, ops, context),
 
         // U-type | Opcode: 0x37 lui, 0x17 auipc
-        "lui"   => encode_u_type(0x37, ops, sym_table),
-        "auipc" => encode_u_type(0x17, ops, sym_table),
+        "lui"   => encode_u_type(0x37, ops, context),
+        "auipc" => encode_u_type(0x17, ops, context),
 
         // J-type | Opcode: 0x6F
-        "jal"   => encode_j_type(0x6F, ops, sym_table, current_pc),
+        "jal"   => encode_j_type(0x6F, ops, context),
+
+        // CSR access | Opcode: 0x73 | funct3 bit 2 selects the immediate form
+        "csrrw"  => encode_csr_type(0x1, ops),
+        "csrrs"  => encode_csr_type(0x2, ops),
+        "csrrc"  => encode_csr_type(0x3, ops),
+        "csrrwi" => encode_csr_type(0x5, ops),
+        "csrrsi" => encode_csr_type(0x6, ops),
+        "csrrci" => encode_csr_type(0x7, ops),
 
         // System and Miscellaneous
         "ecall"  => Ok(0x00000073),
         "ebreak" => Ok(0x00100073),
+        "mret"   => Ok(0x30200073),
+        "wfi"    => Ok(0x10500073),
         "fence"  => Ok(0x0000000F), // TODO Simplified for this example
 
         _ => Err(format!("Unsupported instruction '{}'", name)),
@@ -198,7 +351,7 @@ fn encode_r_type(opcode: u8, funct3: u8, funct7: u8, ops: &[Operand]) -> Result<
     }
 }
 
-fn encode_i_type(opcode: u8, funct3: u8, ops: &[Operand], sym_table: &SymbolTable) -> Result<u32, String> {
+fn encode_i_type(opcode: u8, funct3: u8, ops: &[Operand], context: &Context) -> Result<u32, String> {
     let (rd, rs1, base_op) = match (opcode, ops) {
         // load: rd, offset(rs1)
         (0x03, [Operand::Register(rd), mem @ Operand::Memory { reg, .. }]) => {
@@ -212,9 +365,9 @@ fn encode_i_type(opcode: u8, funct3: u8, ops: &[Operand], sym_table: &SymbolTabl
         _ => return Err("Invalid operands for I-type instruction".to_string()),
     };
 
-    let imm_val = resolve_any_immediate(base_op, sym_table)?;
+    let imm_val = resolve_any_immediate(base_op, context)?;
 
-    if imm_val < -2048 || imm_val > 2047 {
+    if !(-2048..=2047).contains(&imm_val) {
         return Err(format!("Immediate value {} out of range for 12-bit field", imm_val));
     }
 
@@ -226,13 +379,41 @@ fn encode_i_type(opcode: u8, funct3: u8, ops: &[Operand], sym_table: &SymbolTabl
     Ok(instruction)
 }
 
+fn encode_csr_type(funct3: u8, ops: &[Operand]) -> Result<u32, String> {
+    let [Operand::Register(rd), csr_op, source] = ops else {
+        return Err("Invalid operands for CSR instruction: expected rd, csr, rs1/uimm".to_string());
+    };
+    let csr = match csr_op {
+        Operand::Csr(address) => *address as u32,
+        Operand::Immediate(address) if (0..=0xFFF).contains(address) => *address as u32,
+        _ => return Err(format!("Invalid CSR '{}': expected a CSR name or a 12-bit address", csr_op)),
+    };
+    // the immediate forms encode a 5-bit zero-extended value in the rs1 field
+    let source = match (funct3 & 0x4 != 0, source) {
+        (false, Operand::Register(rs1)) => *rs1 as u32,
+        (true, Operand::Immediate(uimm)) if (0..=31).contains(uimm) => *uimm as u32,
+        (true, Operand::Immediate(uimm)) => return Err(format!("Immediate value {} out of range for 5-bit field", uimm)),
+        (false, _) => return Err("Invalid operands for CSR instruction: expected rd, csr, rs1".to_string()),
+        (true, _) => return Err("Invalid operands for CSR instruction: expected rd, csr, uimm".to_string()),
+    };
+
+    let instruction = (csr << 20)             |
+                      (source << 15)          |
+                      ((funct3 as u32) << 12) |
+                      ((*rd as u32) << 7)     |
+                      0x73;
+    Ok(instruction)
+}
+
 fn encode_i_shift(
     opcode: u8,
     funct3: u8,
     funct7: u8,
-    ops: &[Operand]
+    ops: &[Operand],
+    context: &Context,
 ) -> Result<u32, String> {
-    if let [Operand::Register(rd), Operand::Register(rs1), Operand::Immediate(shamt)] = ops {
+    if let [Operand::Register(rd), Operand::Register(rs1), shamt @ (Operand::Immediate(_) | Operand::Label(_) | Operand::Expression(_))] = ops {
+        let shamt = &resolve_any_immediate(shamt, context)?;
         if *shamt < 0 || *shamt > 31 {
             return Err(format!("Shift amount {} out of range (0-31)", shamt));
         }
@@ -254,14 +435,14 @@ fn encode_s_type(
     opcode: u8,
     funct3: u8,
     ops: &[Operand],
-    sym_table: &SymbolTable,
+    context: &Context,
 ) -> Result<u32, String> {
     // Note: The usual order in RISC-V is sw rs2, offset(rs1)
     if let [Operand::Register(rs2), Operand::Memory { offset, reg }] = ops {
         // Resolve the immediate (can be label or number)
-        let imm_val = resolve_memory_offset(offset, sym_table)?;
+        let imm_val = resolve_memory_offset(offset, context)?;
 
-        if imm_val < -2048 || imm_val > 2047 {
+        if !(-2048..=2047).contains(&imm_val) {
             return Err(format!("Immediate value {} out of range for 12-bit field", imm_val));
         }
 
@@ -282,58 +463,104 @@ fn encode_s_type(
     }
 }
 
-fn resolve_memory_offset(offset: &MemoryOffset, sym_table: &SymbolTable) -> Result<i32, String> {
+fn resolve_memory_offset(offset: &MemoryOffset, context: &Context) -> Result<i32, String> {
     match offset {
         MemoryOffset::Immediate(val) => Ok(*val),
-        MemoryOffset::Label(name) => sym_table.get_address(name)
+        MemoryOffset::Label(name) => context.symbols.get_address(name)
             .map(|addr| addr as i32)
             .ok_or_else(|| format!("Unknown label '{}'", name)),
-        MemoryOffset::Modifier(kind, name) => resolve_modifier(kind, name, sym_table),
+        MemoryOffset::Modifier(kind, name) => resolve_modifier(kind, name, context),
+        MemoryOffset::Expression(expr) => evaluate(expr, context),
     }
 }
 
-fn resolve_any_immediate(op: &Operand, sym_table: &SymbolTable) -> Result<i32, String> {
+fn resolve_any_immediate(op: &Operand, context: &Context) -> Result<i32, String> {
     match op {
         // For example in addi x1, x2, 10
         Operand::Immediate(val) => Ok(*val),
 
         // For example in addi x1, x2, symbol
-        Operand::Label(name) => sym_table.get_address(name)
+        Operand::Label(name) => context.symbols.get_address(name)
             .map(|addr| addr as i32)
             .ok_or_else(|| format!("Unknown label '{}'", name)),
 
         // For example in lw x1, 4(x2) o lw x1, symbol(x2)
-        Operand::Memory { offset, .. } => resolve_memory_offset(offset, sym_table),
+        Operand::Memory { offset, .. } => resolve_memory_offset(offset, context),
 
         // For example in addi x1, x2, %hi(symbol)
-        Operand::Modifier(kind, name) => resolve_modifier(kind, name, sym_table),
+        Operand::Modifier(kind, name) => resolve_modifier(kind, name, context),
+
+        // For example in addi x1, x2, %lo(symbol+4) or .word end-start
+        Operand::Expression(expr) => evaluate(expr, context),
 
         _ => Err("This operand do not contain a numeric value or a label".to_string()),
     }
 }
 
-fn resolve_modifier(kind: &ModifierKind, name: &str, sym_table: &SymbolTable) -> Result<i32, String> {
-    let addr = sym_table.get_address(name)
+fn resolve_modifier(kind: &ModifierKind, name: &str, context: &Context) -> Result<i32, String> {
+    let addr = context.symbols.get_address(name)
         .ok_or_else(|| format!("Unknown label '{}'", name))?;
 
     match kind {
-        ModifierKind::Hi => {
-            // %hi(addr) = (addr + 0x800) >> 12
-            // 0x800 is the offset to make the address positive and sign-extend it to 32 bits
-            Ok(((addr as i64 + 0x800) >> 12) as i32)
+        // %hi(addr) = (addr + 0x800) >> 12, so that adding the sign-extended %lo(addr) gives addr
+        ModifierKind::Hi | ModifierKind::Lo => Ok(kind.apply(addr as i32)),
+        ModifierKind::PcrelHi => Ok(kind.apply(addr.wrapping_sub(context.pc) as i32)),
+        ModifierKind::GotPcrelHi => {
+            let entry = context.symbols.got_address(name)
+                .ok_or_else(|| format!("Symbol '{}' has no GOT entry: %got_pcrel_hi is only allowed as an instruction operand", name))?;
+            Ok(kind.apply(entry.wrapping_sub(context.pc) as i32))
         }
-        ModifierKind::Lo => {
-            Ok(((addr << 20) as i32) >> 20)
+        // The label is on the auipc, whose offset the lower bits complete
+        ModifierKind::PcrelLo => context.pcrel_hi.get(&addr)
+            .map(|&offset| kind.apply(offset))
+            .ok_or_else(|| format!("%pcrel_lo({}) must name an earlier auipc with %pcrel_hi or %got_pcrel_hi", name)),
+    }
+}
+
+// The offset from the statement to the target of a %pcrel_hi or %got_pcrel_hi
+// operand, or None if the operand is neither or the target is unknown
+fn pcrel_hi_offset(op: &Operand, context: &Context) -> Option<i32> {
+    let target = match op {
+        Operand::Modifier(ModifierKind::PcrelHi, name) => context.symbols.get_address(name)?,
+        Operand::Modifier(ModifierKind::GotPcrelHi, name) => context.symbols.got_address(name)?,
+        Operand::Expression(Expr::Modifier(ModifierKind::PcrelHi, expr)) => evaluate(expr, context).ok()? as u32,
+        _ => return None,
+    };
+    Some(target.wrapping_sub(context.pc) as i32)
+}
+
+// The label of the auipc a %pcrel_lo operand names
+fn pcrel_lo_label(ops: &[Operand]) -> Option<&str> {
+    ops.iter().find_map(|op| match reference(op) {
+        Some((Some(ModifierKind::PcrelLo), label, _)) => Some(label),
+        _ => None,
+    })
+}
+
+// The labels right before a statement, which are at its address
+fn labels_before(statements: &[Statement]) -> impl Iterator<Item = &str> {
+    statements.iter().rev().map_while(|statement| match &statement.kind {
+        StatementKind::Label(name) => Some(name.as_str()),
+        _ => None,
+    })
+}
+
+fn evaluate(expr: &Expr, context: &Context) -> Result<i32, String> {
+    match expr {
+        // As in 'auipc a0, %pcrel_hi(table+8)'; the other PC-relative modifiers take a symbol
+        Expr::Modifier(ModifierKind::PcrelHi, target) => {
+            let target = evaluate(target, context)?;
+            Ok(ModifierKind::PcrelHi.apply(target.wrapping_sub(context.pc as i32)))
         }
+        _ => expr.evaluate(&|name| context.symbols.get_address(name)),
     }
 }
 
-fn encode_b_type(opcode: u8, funct3: u8, ops: &[Operand], sym_table: &SymbolTable, current_pc: u32) -> Result<u32, String> {
-    if let [Operand::Register(rs1), Operand::Register(rs2), Operand::Label(label)] = ops {
-        let label_addr = sym_table.get_address(label)
-                .ok_or_else(|| format!("Unknown label '{}'", label))?;
-        let offset = (label_addr as i32) - (current_pc as i32);
-        if offset < -4096 || offset > 4094 {
+fn encode_b_type(opcode: u8, funct3: u8, ops: &[Operand], context: &Context) -> Result<u32, String> {
+    if let [Operand::Register(rs1), Operand::Register(rs2), target @ (Operand::Label(_) | Operand::Expression(_))] = ops {
+        let label_addr = resolve_any_immediate(target, context)?;
+        let offset = label_addr - (context.pc as i32);
+        if !(-4096..=4094).contains(&offset) {
             return Err(format!("Branch target offset {} out of range", offset));
         }
         if offset % 2 != 0 {
@@ -364,10 +591,10 @@ fn encode_b_type(opcode: u8, funct3: u8, ops: &[Operand], sym_table: &SymbolTabl
 fn encode_u_type(
     opcode: u8,
     ops: &[Operand],
-    sym_table: &SymbolTable,
+    context: &Context,
 ) -> Result<u32, String> {
     if let [Operand::Register(rd), imm_op] = ops {
-        let val = resolve_any_immediate(imm_op, sym_table)?;
+        let val = resolve_any_immediate(imm_op, context)?;
         // TODO review this because i'm not completely sure how the U-immediate is represented in the instruction encoding
         let imm_u32 = val as u32;
         Ok((imm_u32 << 12) | ((*rd as u32) << 7) | (opcode as u32))
@@ -379,14 +606,13 @@ fn encode_u_type(
 fn encode_j_type(
     opcode: u8,
     ops: &[Operand],
-    sym_table: &SymbolTable,
-    current_pc: u32
+    context: &Context,
 ) -> Result<u32, String> {
     if let [Operand::Register(rd), imm_op] = ops {
-        let val = resolve_any_immediate(imm_op, sym_table)?;
-        let offset = (val as i32) - (current_pc as i32);
+        let val = resolve_any_immediate(imm_op, context)?;
+        let offset = val - (context.pc as i32);
 
-        if offset < -1048576 || offset > 1048574 {
+        if !(-1048576..=1048574).contains(&offset) {
             return Err(format!("Jump target offset {} out of range", offset));
         }
 
@@ -407,32 +633,155 @@ fn encode_j_type(
     }
 }
 
-fn emit_data_bytes(name: &str, ops: &[Operand]) -> Result<Vec<u8>, String> {
+// A relocation of a statement: its offset from the start of the statement,
+// its kind, the symbol and the addend
+type PendingRelocation = (u32, RelocationKind, String, i32);
+
+// The operands of a statement to encode, and the relocations completing them
+type Relocated = (Vec<Operand>, Vec<PendingRelocation>);
+
+// In a relocatable object, the operands of a statement with the ones whose
+// value the linker completes set to 0, and the relocations that do so. None
+// if the statement has no such operands.
+fn relocate(name: &str, ops: &[Operand], context: &Context, relocated_hi: &HashSet<(String, u32)>)
+    -> Result<Option<Relocated>, String>
+{
+    let is_data = matches!(name, ".byte" | ".half" | ".word");
+    let is_jump = matches!(name, "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" | "jal");
+    if name.starts_with('.') && !is_data {
+        return Ok(None);
+    }
+    let mut relocated = ops.to_vec();
+    let mut relocations = Vec::new();
+    for (index, op) in ops.iter().enumerate() {
+        let kind = match reference(op) {
+            Some((None, symbol, _)) if is_data && is_relocated(symbol, false, context) => match name {
+                ".word" => Some(RelocationKind::Abs32),
+                _ => return Err(format!("The address of '{}' does not fit in {} in a relocatable object; use .word", symbol, name)),
+            },
+            Some((None, symbol, _)) if is_jump && is_relocated(symbol, true, context) => match name {
+                "jal" => Some(RelocationKind::Jal),
+                _ => Some(RelocationKind::Branch),
+            },
+            Some((Some(ModifierKind::PcrelHi), symbol, _)) if name == "auipc" && is_relocated(symbol, true, context) => {
+                Some(RelocationKind::PcrelHi20)
+            }
+            Some((Some(ModifierKind::GotPcrelHi), symbol, _)) if name == "auipc" && is_relocated(symbol, false, context) => {
+                Some(RelocationKind::GotHi20)
+            }
+            Some((Some(ModifierKind::Hi), symbol, _)) if name == "lui" && is_relocated(symbol, false, context) => {
+                Some(RelocationKind::Hi20)
+            }
+            Some((Some(ModifierKind::Lo), symbol, _)) if is_relocated(symbol, false, context) => match name {
+                "sb" | "sh" | "sw" => Some(RelocationKind::Lo12S),
+                _ => Some(RelocationKind::Lo12I),
+            },
+            // The label is on the auipc, whose relocation the lower bits complete
+            Some((Some(ModifierKind::PcrelLo), label, _)) => {
+                let auipc = context.symbols.section_of(label).zip(context.symbols.get_address(label));
+                match auipc.filter(|&(section, address)| relocated_hi.contains(&(section.to_string(), address))) {
+                    Some(_) if matches!(name, "sb" | "sh" | "sw") => Some(RelocationKind::PcrelLo12S),
+                    Some(_) => Some(RelocationKind::PcrelLo12I),
+                    None => None,
+                }
+            }
+            _ => None,
+        };
+        let Some(kind) = kind else {
+            // A %pcrel_hi of a label of this section, and the %pcrel_lo naming
+            // its auipc, are the same wherever the section is placed
+            let pc_relative = is_jump || matches!(reference(op), Some((Some(ModifierKind::PcrelHi | ModifierKind::PcrelLo), ..)));
+            // The difference of two labels of a section is the same wherever it is placed
+            let symbols: Vec<&str> = op.symbols().into_iter().filter(|symbol| is_relocated(symbol, pc_relative, context)).collect();
+            let sections: HashSet<Option<&str>> = symbols.iter().map(|symbol| context.symbols.section_of(symbol)).collect();
+            let fixed = symbols.len() != 1 && sections.len() <= 1 && !sections.contains(&None);
+            if let Some(symbol) = symbols.first().filter(|_| !fixed) {
+                return Err(format!("The address of '{}' is not known in a relocatable object: use %hi/%lo, %pcrel_hi/%pcrel_lo or .word", symbol));
+            }
+            continue;
+        };
+        let Some((_, symbol, addend)) = reference(op) else { unreachable!() };
+        relocated[index] = match (kind, op) {
+            // an offset of 0 from the branch itself
+            (RelocationKind::Branch, _) => Operand::Expression(Expr::Number(context.pc as i32)),
+            (RelocationKind::Jal, _) => Operand::Immediate(context.pc as i32),
+            (_, Operand::Memory { reg, .. }) => Operand::Memory { offset: MemoryOffset::Immediate(0), reg: *reg },
+            _ => Operand::Immediate(0),
+        };
+        let addend = if matches!(kind, RelocationKind::PcrelLo12I | RelocationKind::PcrelLo12S) { 0 } else { addend };
+        let offset = if is_data { 4 * index as u32 } else { 0 };
+        relocations.push((offset, kind, symbol.to_string(), addend));
+    }
+    Ok((!relocations.is_empty()).then_some((relocated, relocations)))
+}
+
+// Whether the linker must complete a use of a symbol: a symbol of another
+// object, or a label, unless the use is relative to the statement and the
+// label is in the same section
+fn is_relocated(symbol: &str, pc_relative: bool, context: &Context) -> bool {
+    let known = context.symbols.get_address(symbol).is_some() && !context.symbols.is_absolute(symbol);
+    known && !(pc_relative && context.symbols.section_of(symbol) == Some(context.section))
+}
+
+// The symbol an operand refers to, through which modifier, and the constant
+// added to it, as in '%hi(table+8)'
+fn reference(op: &Operand) -> Option<(Option<ModifierKind>, &str, i32)> {
+    match op {
+        Operand::Label(name) | Operand::Memory { offset: MemoryOffset::Label(name), .. } => Some((None, name, 0)),
+        Operand::Modifier(kind, name) | Operand::Memory { offset: MemoryOffset::Modifier(kind, name), .. } => {
+            Some((Some(kind.clone()), name, 0))
+        }
+        Operand::Expression(expr) | Operand::Memory { offset: MemoryOffset::Expression(expr), .. } => match expr {
+            Expr::Modifier(kind, target) => symbol_offset(target).map(|(name, addend)| (Some(kind.clone()), name, addend)),
+            _ => symbol_offset(expr).map(|(name, addend)| (None, name, addend)),
+        },
+        _ => None,
+    }
+}
+
+fn symbol_offset(expr: &Expr) -> Option<(&str, i32)> {
+    let constant = |expr: &Expr| expr.evaluate(&|_| None).ok();
+    match expr {
+        Expr::Symbol(name) => Some((name, 0)),
+        Expr::Binary(Operator::Plus, lhs, rhs) => match (&**lhs, &**rhs) {
+            (Expr::Symbol(name), other) | (other, Expr::Symbol(name)) => Some((name, constant(other)?)),
+            _ => None,
+        },
+        Expr::Binary(Operator::Minus, lhs, rhs) => match &**lhs {
+            Expr::Symbol(name) => Some((name, constant(rhs)?.wrapping_neg())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The value of a .byte/.half/.word operand: a number, a label or an expression like end-start
+fn data_value(name: &str, op: &Operand, context: &Context) -> Result<i32, String> {
+    match op {
+        Operand::Immediate(_) | Operand::Label(_) | Operand::Modifier(..) | Operand::Expression(_) => {
+            resolve_any_immediate(op, context)
+        }
+        _ => Err(format!("Invalid operand for {}: expected immediate or expression", name)),
+    }
+}
+
+fn emit_data_bytes(name: &str, ops: &[Operand], context: &Context) -> Result<Vec<u8>, String> {
     let mut bytes = Vec::new();
     // TODO doubt: should warn the user if the operand does not fit the target size? i.e. "Value {} out of range for .byte"
     match name {
         ".byte" => {
             for op in ops {
-                match op {
-                    Operand::Immediate(val) => bytes.push(*val as u8),
-                    _ => return Err("Invalid operand for .byte: expected immediate".to_string()),
-                }
+                bytes.push(data_value(name, op, context)? as u8);
             }
         }
         ".half" => {
             for op in ops {
-                match op {
-                    Operand::Immediate(val) => bytes.extend_from_slice(&(*val as u16).to_le_bytes()),
-                    _ => return Err("Invalid operand for .half: expected immediate".to_string()),
-                }
+                bytes.extend_from_slice(&(data_value(name, op, context)? as u16).to_le_bytes());
             }
         }
         ".word" => {
             for op in ops {
-                match op {
-                    Operand::Immediate(val) => bytes.extend_from_slice(&(*val as u32).to_le_bytes()),
-                    _ => return Err("Invalid operand for .word: expected immediate".to_string()),
-                }
+                bytes.extend_from_slice(&(data_value(name, op, context)? as u32).to_le_bytes());
             }
         }
         ".ascii" | ".asciz" | ".string" => {
@@ -450,11 +799,12 @@ fn emit_data_bytes(name: &str, ops: &[Operand]) -> Result<Vec<u8>, String> {
             }
         }
         ".space" => {
-            if let Some(Operand::Immediate(val)) = ops.get(0) {
-                if *val < 0 {
+            if let Some(op) = ops.first() {
+                let val = resolve_any_immediate(op, context)?;
+                if val < 0 {
                     return Err(".space requires a positive value".to_string());
                 }
-                bytes.resize(bytes.len() + *val as usize, 0);
+                bytes.resize(bytes.len() + val as usize, 0);
             } else {
                 return Err(".space requires an immediate value".to_string());
             }
@@ -471,7 +821,7 @@ mod tests {
 
     #[test]
     fn test_assemble_simple_program() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -481,24 +831,27 @@ mod tests {
                     Operand::Register(3),
                 ]),
                 line: 1,
+                file: 0,
             },
             Statement {
                 kind: StatementKind::Directive(".data".to_string(), vec![]),
                 line: 2,
+                file: 0,
             },
             Statement {
                 kind: StatementKind::Directive(".word".to_string(), vec![
                     Operand::Immediate(42),
                 ]),
                 line: 3,
+                file: 0,
             },
         ];
         assembler.assemble(&statements, &sym_table).expect("Assembly should succeed");
-        assert_eq!(assembler.text_bin.len(), 4);
-        assert_eq!(assembler.data_bin.len(), 4);
+        assert_eq!(assembler.section(".text").len(), 4);
+        assert_eq!(assembler.section(".data").len(), 4);
 
         assert_eq!(
-            assembler.text_bin,
+            assembler.section(".text"),
             vec![
                 0b10110011, // Byte 0: rd[0] + opcode
                 0b00000000, // Byte 1: rs1[0] + funct3 + rd[4:1]
@@ -506,21 +859,37 @@ mod tests {
                 0b00000000, // Byte 3: funct7 + rs2[0]
             ]
         );
-        assert_eq!(assembler.data_bin, vec![0x2A, 0x00, 0x00, 0x00]); // .word 42
+        assert_eq!(assembler.section(".data"), vec![0x2A, 0x00, 0x00, 0x00]); // .word 42
+    }
+
+    #[test]
+    fn test_align_out_of_range() {
+        let mut assembler = Assembler::new();
+        let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
+        let statements: Vec<Statement> = [40, -1].into_iter().enumerate().map(|(i, power)| Statement {
+            kind: StatementKind::Directive(".align".to_string(), vec![Operand::Immediate(power)]),
+            line: i + 1,
+            file: 0,
+        }).collect();
+
+        let errors = assembler.assemble(&statements, &sym_table).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|error| error.message == "Directive .align value out of range"));
     }
 
     #[test]
     fn test_unsupported_instruction() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
-                kind: StatementKind::Instruction("mul".to_string(), vec![
+                kind: StatementKind::Instruction("fmul".to_string(), vec![
                     Operand::Register(1),
                     Operand::Register(2),
                     Operand::Register(3),
                 ]),
                 line: 5,
+                file: 0,
             },
         ];
 
@@ -529,12 +898,38 @@ mod tests {
         let errors = result.unwrap_err();
         assert_eq!(errors.len(), 1);
         assert_eq!(errors[0].line, 5);
-        assert!(errors[0].message.contains("Unsupported instruction 'mul'"));
+        assert!(errors[0].message.contains("Unsupported instruction 'fmul'"));
+    }
+
+    #[test]
+    fn test_encoding_of_m_extension_instructions() {
+        let mut assembler = Assembler::new();
+        let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
+        let mnemonics = ["mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu"];
+        let statements: Vec<Statement> = mnemonics.iter().enumerate().map(|(i, name)| Statement {
+            kind: StatementKind::Instruction(name.to_string(), vec![
+                Operand::Register(10),
+                Operand::Register(11),
+                Operand::Register(12),
+            ]),
+            line: i + 1,
+            file: 0,
+        }).collect();
+
+        assembler.assemble(&statements, &sym_table).expect("Assembly should succeed");
+        assert_eq!(assembler.section(".text").len(), 4 * mnemonics.len());
+
+        // funct7=0000001 | rs2=01100 | rs1=01011 | funct3 | rd=01010 | opcode=0110011
+        // mul a0, a1, a2 => 0x02C58533, funct3 increments by one per mnemonic (bits 14:12)
+        for (funct3, chunk) in assembler.section(".text").chunks(4).enumerate() {
+            let word = u32::from_le_bytes(chunk.try_into().unwrap());
+            assert_eq!(word, 0x02C58533 | ((funct3 as u32) << 12), "Mismatch for {}", mnemonics[funct3]);
+        }
     }
 
     #[test]
     fn test_invalid_r_type_operands() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -544,6 +939,7 @@ mod tests {
                     Operand::Immediate(5), // Should be a register
                 ]),
                 line: 10,
+                file: 0,
             },
         ];
 
@@ -557,7 +953,7 @@ mod tests {
 
     #[test]
     fn test_invalid_i_type_operands() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -567,6 +963,7 @@ mod tests {
                     Operand::Register(3), // Should be immediate
                 ]),
                 line: 15,
+                file: 0,
             },
         ];
 
@@ -580,7 +977,7 @@ mod tests {
 
     #[test]
     fn test_invalid_s_type_operands() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -589,6 +986,7 @@ mod tests {
                     Operand::Register(2), // Should be memory operand
                 ]),
                 line: 20,
+                file: 0,
             },
         ];
 
@@ -602,7 +1000,7 @@ mod tests {
 
     #[test]
     fn test_invalid_b_type_operands() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -612,6 +1010,7 @@ mod tests {
                     Operand::Immediate(100),
                 ]),
                 line: 25,
+                file: 0,
             },
         ];
 
@@ -625,7 +1024,7 @@ mod tests {
 
     #[test]
     fn test_invalid_j_type_operands() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -633,6 +1032,7 @@ mod tests {
                     Operand::Immediate(100), // Missing destination register
                 ]),
                 line: 30,
+                file: 0,
             },
         ];
 
@@ -646,7 +1046,7 @@ mod tests {
 
     #[test]
     fn test_unsupported_directive() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -654,6 +1054,7 @@ mod tests {
                     Operand::Immediate(42),
                 ]),
                 line: 35,
+                file: 0,
             },
         ];
 
@@ -667,7 +1068,7 @@ mod tests {
 
     #[test]
     fn test_invalid_directive_operands() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -675,6 +1076,7 @@ mod tests {
                     Operand::Register(1), // Should be immediate
                 ]),
                 line: 40,
+                file: 0,
             },
         ];
 
@@ -688,16 +1090,17 @@ mod tests {
 
     #[test]
     fn test_multiple_errors() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
-                kind: StatementKind::Instruction("mul".to_string(), vec![
+                kind: StatementKind::Instruction("fmul".to_string(), vec![
                     Operand::Register(1),
                     Operand::Register(2),
                     Operand::Register(3),
                 ]),
                 line: 1,
+                file: 0,
             },
             Statement {
                 kind: StatementKind::Instruction("add".to_string(), vec![
@@ -706,42 +1109,45 @@ mod tests {
                     Operand::Register(3),
                 ]),
                 line: 2,
+                file: 0,
             },
             Statement {
-                kind: StatementKind::Instruction("div".to_string(), vec![
+                kind: StatementKind::Instruction("fdiv".to_string(), vec![
                     Operand::Register(4),
                     Operand::Register(5),
                     Operand::Register(6),
                 ]),
                 line: 3,
+                file: 0,
             },
             Statement {
                 kind: StatementKind::Directive(".float".to_string(), vec![
                     Operand::Immediate(42),
                 ]),
                 line: 4,
+                file: 0,
             },
         ];
 
         let result = assembler.assemble(&statements, &sym_table);
         assert!(result.is_err());
         let errors = result.unwrap_err();
-        // Should collect all 3 errors (mul, div, .float), but not the valid add
+        // Should collect all 3 errors (fmul, fdiv, .float), but not the valid add
         assert_eq!(errors.len(), 3);
         assert_eq!(errors[0].line, 1);
-        assert!(errors[0].message.contains("Unsupported instruction 'mul'"));
+        assert!(errors[0].message.contains("Unsupported instruction 'fmul'"));
         assert_eq!(errors[1].line, 3);
-        assert!(errors[1].message.contains("Unsupported instruction 'div'"));
+        assert!(errors[1].message.contains("Unsupported instruction 'fdiv'"));
         assert_eq!(errors[2].line, 4);
         assert!(errors[2].message.contains("Unsupported directive '.float'"));
 
         // Verify that the valid instruction was assembled
-        assert_eq!(assembler.text_bin.len(), 4);
+        assert_eq!(assembler.section(".text").len(), 4);
     }
 
     #[test]
     fn test_modifier_assembly() {
-        let mut assembler = Assembler::new(0, 0);
+        let mut assembler = Assembler::new();
         let mut sym_table = SymbolTable::new(0, 0);
 
         // my_label at 0x12800 (bit 11 is 1)
@@ -754,6 +1160,7 @@ mod tests {
                     Operand::Modifier(ModifierKind::Hi, "my_label".to_string()),
                 ]),
                 line: 1,
+                file: 0,
             },
             Statement {
                 kind: StatementKind::Instruction("addi".to_string(), vec![
@@ -762,6 +1169,7 @@ mod tests {
                     Operand::Modifier(ModifierKind::Lo, "my_label".to_string()),
                 ]),
                 line: 2,
+                file: 0,
             },
             Statement {
                 kind: StatementKind::Instruction("lw".to_string(), vec![
@@ -772,6 +1180,7 @@ mod tests {
                     },
                 ]),
                 line: 3,
+                file: 0,
             },
         ];
 
@@ -779,21 +1188,21 @@ mod tests {
 
         // LUI x1, %hi(0x12800) -> %hi = (0x12800 + 0x800) >> 12 = 0x13
         // Result: 0x000130B7
-        assert_eq!(u32::from_le_bytes(assembler.text_bin[0..4].try_into().unwrap()), 0x000130B7);
+        assert_eq!(u32::from_le_bytes(assembler.section(".text")[0..4].try_into().unwrap()), 0x000130B7);
 
         // ADDI x1, x1, %lo(0x12800) -> %lo = 0x12800 & 0xFFF = 0x800 (signed -2048)
         // Result: 0x80008093
-        assert_eq!(u32::from_le_bytes(assembler.text_bin[4..8].try_into().unwrap()), 0x80008093);
+        assert_eq!(u32::from_le_bytes(assembler.section(".text")[4..8].try_into().unwrap()), 0x80008093);
 
         // LW x2, %lo(0x12800)(x1) -> %lo = 0x800
         // I-type: imm[11:0]=0x800, rs1=1, funct3=010, rd=2, opcode=0000011
         // 0x80000000 | 0x8000 | 0x2000 | 0x100 | 0x03
-        assert_eq!(u32::from_le_bytes(assembler.text_bin[8..12].try_into().unwrap()), 0x8000A103);
+        assert_eq!(u32::from_le_bytes(assembler.section(".text")[8..12].try_into().unwrap()), 0x8000A103);
     }
 
     #[test]
     fn test_assemble_i_type_instruction() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -803,12 +1212,13 @@ mod tests {
                     Operand::Immediate(8),
                 ]),
                 line: 1,
+                file: 0,
             },
         ];
 
         let result = assembler.assemble(&statements, &sym_table);
         assert!(result.is_ok());
-        let instructions = assembler.text_bin;
+        let instructions = assembler.section(".text");
         assert_eq!(instructions.len(), 4);
         assert_eq!(
             instructions[0..4],
@@ -826,7 +1236,7 @@ mod tests {
 
     #[test]
     fn test_assemble_i_type_instruction_with_negative_immediate() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -836,12 +1246,13 @@ mod tests {
                     Operand::Immediate(-8),
                 ]),
                 line: 1,
+                file: 0,
             },
         ];
 
         let result = assembler.assemble(&statements, &sym_table);
         assert!(result.is_ok());
-        let instructions = assembler.text_bin;
+        let instructions = assembler.section(".text");
         assert_eq!(instructions.len(), 4);
         assert_eq!(
             instructions[0..4],
@@ -859,7 +1270,7 @@ mod tests {
 
     #[test]
     fn test_s_instruction_with_unknown_label() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -871,6 +1282,7 @@ mod tests {
                     },
                 ]),
                 line: 1,
+                file: 0,
             },
         ];
 
@@ -884,7 +1296,7 @@ mod tests {
 
     #[test]
     fn test_encoding_of_i_shift_instruction() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -894,12 +1306,13 @@ mod tests {
                     Operand::Immediate(4),
                 ]),
                 line: 1,
+                file: 0,
             },
         ];
 
         let result = assembler.assemble(&statements, &sym_table);
         assert!(result.is_ok());
-        let instructions = assembler.text_bin;
+        let instructions = assembler.section(".text");
         assert_eq!(instructions.len(), 4);
         // srai x10, x11, 4
         // opcode=0x13, rd=10, funct3=0x5, rs1=11, shamt=4, funct7=0x20
@@ -916,7 +1329,7 @@ mod tests {
 
     #[test]
     fn test_encoding_of_b_type_instruction() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let mut sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         sym_table.add_label("target".to_string(), config::TEXT_BASE + 0x10).unwrap();
         let statements = vec![
@@ -927,12 +1340,13 @@ mod tests {
                     Operand::Label("target".to_string()),
                 ]),
                 line: 1,
+                file: 0,
             },
         ];
 
         let result = assembler.assemble(&statements, &sym_table);
         assert!(result.is_ok());
-        let instructions = assembler.text_bin;
+        let instructions = assembler.section(".text");
         assert_eq!(instructions.len(), 4);
         // beq x1, x2, target (offset = target - current_pc = 0x0040_0010 - 0x0040_0000 = 16)
         // opcode=0x63, funct3=0x0, rs1=1, rs2=2, imm=16
@@ -949,7 +1363,7 @@ mod tests {
 
     #[test]
     fn test_encoding_of_u_type_instruction() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let statements = vec![
             Statement {
@@ -958,12 +1372,13 @@ mod tests {
                     Operand::Immediate(0xF1),
                 ]),
                 line: 1,
+                file: 0,
             },
         ];
 
         let result = assembler.assemble(&statements, &sym_table);
         assert!(result.is_ok());
-        let instructions = assembler.text_bin;
+        let instructions = assembler.section(".text");
         assert_eq!(instructions.len(), 4);
         // lui x5, 0x12345
         // opcode=0x37, rd=5, imm=0xF1
@@ -980,7 +1395,7 @@ mod tests {
 
     #[test]
     fn test_extended_directives() {
-        let mut assembler = Assembler::new(config::TEXT_BASE, config::DATA_BASE);
+        let mut assembler = Assembler::new();
         let mut sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         let source = r#"
             .data
@@ -998,12 +1413,91 @@ mod tests {
 
         assembler.assemble(&statements, &sym_table).expect("Assembly should succeed");
 
-        assert_eq!(assembler.data_bin.len(), 24);
-        assert_eq!(assembler.data_bin[0..3], [1, 2, 3]);
-        assert_eq!(assembler.data_bin[3..7], [0x34, 0x12, 0x78, 0x56]);
-        assert_eq!(assembler.data_bin[7..11], [0xEF, 0xBE, 0xAD, 0xDE]);
-        assert_eq!(assembler.data_bin[11..18], *b"RISC-V\0");
-        assert_eq!(assembler.data_bin[18..20], [0, 0]);
-        assert_eq!(assembler.data_bin[20..24], [42, 0, 0, 0]);
+        assert_eq!(assembler.section(".data").len(), 24);
+        assert_eq!(assembler.section(".data")[0..3], [1, 2, 3]);
+        assert_eq!(assembler.section(".data")[3..7], [0x34, 0x12, 0x78, 0x56]);
+        assert_eq!(assembler.section(".data")[7..11], [0xEF, 0xBE, 0xAD, 0xDE]);
+        assert_eq!(assembler.section(".data")[11..18], *b"RISC-V\0");
+        assert_eq!(assembler.section(".data")[18..20], [0, 0]);
+        assert_eq!(assembler.section(".data")[20..24], [42, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_expression_operands() {
+        let mut assembler = Assembler::new();
+        let mut sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
+        let source = r#"
+            .text
+            top: lui a0, %hi(end+4)
+            addi a0, a0, %lo(end+4)
+            slli a1, a1, end-start
+            beq a0, a1, top+4
+            .data
+            start: .word end-start, start+8
+            .byte 'A', (end-start)*2
+            middle: .space (middle-start)/2 # sizes can only use labels defined above
+            end:
+        "#;
+        let tokens = crate::lexer::tokenize(source).unwrap();
+        let statements = crate::parser::Parser::new(tokens).parse().unwrap();
+        sym_table.build(&statements).unwrap();
+        assert_eq!(sym_table.get_address("end"), Some(config::DATA_BASE + 15));
+
+        assembler.assemble(&statements, &sym_table).expect("Assembly should succeed");
+        let end = config::DATA_BASE + 15 + 4;
+        let words: Vec<u32> = assembler.section(".text").chunks(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect();
+        assert_eq!(words[0] >> 12, (end + 0x800) >> 12);
+        assert_eq!(words[1] >> 20, end & 0xFFF);
+        assert_eq!((words[2] >> 20) & 0x1F, 15);
+        assert_eq!(words[3], 0xFEB50CE3); // beq a0, a1, -8
+        assert_eq!(assembler.section(".data")[0..4], 15u32.to_le_bytes());
+        assert_eq!(assembler.section(".data")[4..8], (config::DATA_BASE + 8).to_le_bytes());
+        assert_eq!(assembler.section(".data")[8..10], [b'A', 30]);
+        assert_eq!(assembler.section(".data").len(), 15);
+    }
+
+    #[test]
+    fn test_encoding_of_csr_instructions() {
+        let mut assembler = Assembler::new();
+        let mut sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
+        let source = "
+            csrrw a0, mstatus, a1
+            csrr t0, mtvec
+            csrc mepc, s1
+            csrrwi a0, mscratch, 31
+            csrci 0x344, 1
+            rdcycle a0
+            rdinstreth a1
+            csrw mtvec, t0
+            mret
+        ";
+        let tokens = crate::lexer::tokenize(source).unwrap();
+        let statements = crate::pseudo::expand(crate::parser::Parser::new(tokens).parse().unwrap()).unwrap();
+        sym_table.build(&statements).unwrap();
+        assembler.assemble(&statements, &sym_table).expect("Assembly should succeed");
+
+        let words: Vec<u32> = assembler.section(".text").chunks(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(words, vec![
+            0x30059573, 0x305022F3, 0x3414B073, 0x340FD573,
+            0x3440F073, 0xC0002573, 0xC82025F3, 0x30529073,
+            0x30200073,
+        ]);
+    }
+
+    #[test]
+    fn test_invalid_csr_operands() {
+        let cases = [
+            vec![Operand::Register(1), Operand::Immediate(0x1000), Operand::Register(2)],
+            vec![Operand::Register(1), Operand::Register(3), Operand::Register(2)],
+            vec![Operand::Register(1), Operand::Csr(0x300), Operand::Immediate(2)],
+        ];
+        let context = Context { symbols: &SymbolTable::new(0, 0), section: ".text", pc: 0, pcrel_hi: &HashMap::new() };
+        for ops in cases {
+            assert!(encode_instruction("csrrw", &ops, &context).is_err(), "{:?}", ops);
+        }
+        let ops = [Operand::Register(1), Operand::Csr(0x300), Operand::Immediate(32)];
+        assert!(encode_instruction("csrrwi", &ops, &context).is_err());
     }
 }
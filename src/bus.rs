@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::memory::MemoryFault;
+
+/// A memory-mapped peripheral. Offsets are relative to the address the
+/// device is attached at, and accesses are 1, 2 or 4 bytes wide.
+pub trait Device {
+    /// Reads `size` bytes at `offset`, zero extended. None faults the access.
+    fn read(&mut self, offset: u32, size: u32) -> Option<u32>;
+
+    /// Writes the low `size` bytes of `value` at `offset`. None faults the access.
+    fn write(&mut self, offset: u32, size: u32, value: u32) -> Option<()>;
+
+    /// Called once per processor step.
+    fn tick(&mut self) {}
+
+    /// The `mip` bits (`csr::MSI`, `csr::MTI`, `csr::MEI`) the device is raising.
+    fn interrupts(&self) -> u32 {
+        0
+    }
+}
+
+// Lets the processor keep a handle on a device it has attached, like the CLINT
+impl<D: Device + ?Sized> Device for Rc<RefCell<D>> {
+    fn read(&mut self, offset: u32, size: u32) -> Option<u32> {
+        self.borrow_mut().read(offset, size)
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: u32) -> Option<()> {
+        self.borrow_mut().write(offset, size, value)
+    }
+
+    fn tick(&mut self) {
+        self.borrow_mut().tick()
+    }
+
+    fn interrupts(&self) -> u32 {
+        self.borrow().interrupts()
+    }
+}
+
+/// Where a device is attached on the bus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceRange {
+    pub name: String,
+    pub base: u32,
+    pub size: u32,
+}
+
+impl DeviceRange {
+    pub fn contains(&self, address: u32) -> bool {
+        address.wrapping_sub(self.base) < self.size
+    }
+}
+
+/// Address ranges claimed by devices. Loads and stores are offered to the
+/// bus before RAM, so a device shadows any memory mapped at its range.
+#[derive(Default)]
+pub struct Bus {
+    devices: Vec<(DeviceRange, Box<dyn Device>)>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn attach(&mut self, name: &str, base: u32, size: u32, device: Box<dyn Device>) -> Result<(), String> {
+        let range = DeviceRange { name: name.to_string(), base, size };
+        let end = base as u64 + size as u64;
+        if size == 0 || end > 1 << 32 {
+            return Err(format!("Device '{}' at 0x{:08x} has an invalid size 0x{:x}", name, base, size));
+        }
+        if let Some((other, _)) = self.devices.iter()
+            .find(|(r, _)| (r.base as u64) < end && (base as u64) < r.base as u64 + r.size as u64)
+        {
+            return Err(format!("Device '{}' at 0x{:08x} overlaps '{}' at 0x{:08x}", name, base, other.name, other.base));
+        }
+        self.devices.push((range, device));
+        Ok(())
+    }
+
+    /// Removes the device attached at `base`.
+    pub fn detach(&mut self, base: u32) -> Option<Box<dyn Device>> {
+        let index = self.devices.iter().position(|(range, _)| range.base == base)?;
+        Some(self.devices.remove(index).1)
+    }
+
+    pub fn ranges(&self) -> impl Iterator<Item = &DeviceRange> {
+        self.devices.iter().map(|(range, _)| range)
+    }
+
+    pub fn range_at(&self, address: u32) -> Option<&DeviceRange> {
+        self.ranges().find(|range| range.contains(address))
+    }
+
+    /// Reads from the device at `address`; None if no device claims it.
+    pub fn read(&mut self, address: u32, size: u32) -> Option<Result<u32, MemoryFault>> {
+        let (range, device) = self.devices.iter_mut().find(|(range, _)| range.contains(address))?;
+        Some(device.read(address - range.base, size).ok_or(MemoryFault::OutOfBounds { address }))
+    }
+
+    /// Writes to the device at `address`; None if no device claims it.
+    pub fn write(&mut self, address: u32, size: u32, value: u32) -> Option<Result<(), MemoryFault>> {
+        let (range, device) = self.devices.iter_mut().find(|(range, _)| range.contains(address))?;
+        Some(device.write(address - range.base, size, value).ok_or(MemoryFault::OutOfBounds { address }))
+    }
+
+    pub fn tick(&mut self) {
+        for (_, device) in &mut self.devices {
+            device.tick();
+        }
+    }
+
+    /// The `mip` bits raised by any device.
+    pub fn interrupts(&self) -> u32 {
+        self.devices.iter().fold(0, |bits, (_, device)| bits | device.interrupts())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A single 32-bit register that raises an interrupt while it is non-zero
+    #[derive(Default)]
+    struct Latch {
+        value: u32,
+        ticks: u32,
+    }
+
+    impl Device for Latch {
+        fn read(&mut self, offset: u32, _size: u32) -> Option<u32> {
+            (offset == 0).then_some(self.value)
+        }
+
+        fn write(&mut self, offset: u32, _size: u32, value: u32) -> Option<()> {
+            (offset == 0).then(|| self.value = value)
+        }
+
+        fn tick(&mut self) {
+            self.ticks += 1;
+        }
+
+        fn interrupts(&self) -> u32 {
+            if self.value != 0 { crate::csr::MEI } else { 0 }
+        }
+    }
+
+    #[test]
+    fn test_accesses_are_dispatched_by_range() {
+        let latch = Rc::new(RefCell::new(Latch::default()));
+        let mut bus = Bus::new();
+        bus.attach("latch", 0x1000_0000, 0x100, Box::new(latch.clone())).unwrap();
+
+        assert_eq!(bus.write(0x1000_0000, 4, 7), Some(Ok(())));
+        assert_eq!(bus.read(0x1000_0000, 4), Some(Ok(7)));
+        assert_eq!(bus.read(0x1000_0004, 4), Some(Err(MemoryFault::OutOfBounds { address: 0x1000_0004 })));
+        assert_eq!(bus.read(0x1000_0100, 4), None);
+        assert_eq!(bus.interrupts(), crate::csr::MEI);
+
+        bus.tick();
+        assert_eq!(latch.borrow().ticks, 1);
+        assert_eq!(bus.range_at(0x1000_00FF).unwrap().name, "latch");
+    }
+
+    #[test]
+    fn test_overlapping_devices_are_rejected() {
+        let mut bus = Bus::new();
+        bus.attach("a", 0x1000, 0x100, Box::new(Latch::default())).unwrap();
+        assert!(bus.attach("b", 0x10FF, 0x10, Box::new(Latch::default())).is_err());
+        assert!(bus.attach("c", 0x0F00, 0x101, Box::new(Latch::default())).is_err());
+        assert!(bus.attach("d", 0xFFFF_FF00, 0x200, Box::new(Latch::default())).is_err());
+        assert!(bus.attach("e", 0x1100, 0x10, Box::new(Latch::default())).is_ok());
+        assert!(bus.detach(0x1000).is_some());
+        assert!(bus.attach("b", 0x1080, 0x80, Box::new(Latch::default())).is_ok());
+    }
+}
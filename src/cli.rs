@@ -0,0 +1,781 @@
+use crate::assembler::DebugInfo;
+use crate::config;
+use crate::disassembler::Disassembler;
+use crate::elf;
+use crate::export::{self, OutputFormat};
+use crate::listing;
+use crate::memory_map::{self, MemoryMap, Region};
+use crate::pipeline;
+use crate::processor::{Processor, StepError};
+use crate::program::{Permissions, Program, Segment};
+use crate::source::SourceFiles;
+use crate::syscalls::{Console, LinuxSyscalls, PollingStdConsole, RarsSyscalls};
+use crate::uart::{self, Uart};
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+usage: ruscv [memory map options] [program.s | program.elf]
+       ruscv run [options] <program.s... | program.elf>
+       ruscv asm [-o <output>] [--format <format>] <program.s>...
+       ruscv disasm [options] <program.elf | program.bin>
+
+Several source files are assembled separately and linked in the order given.
+
+options:
+  --limit <n>          stop after n instructions (default 100000000, 0 for no limit)
+  --regs               print the registers when the program stops
+  --mem <addr>:<len>   print len bytes from addr, a number or a symbol (repeatable)
+  --linux <dir>        Linux system calls instead of RARS ones, with files in dir
+  -I <dir>             also look for .include files in dir (repeatable, run and asm)
+
+memory map options (run, asm and the editor):
+  --memory-map <file>  regions and stack from a TOML file
+  --region <name>:<origin>:<length>[:<permissions>]
+                       place sections in this region instead of the map's (repeatable)
+  --stack <top>:<size> the stack grows down from top
+
+asm options:
+  -o, --output <file>  output file (default: the input with the format's extension)
+  --format <format>    elf (default), bin, ihex, srec, readmemh, or obj for an
+                       ELF relocatable object of a single file, left to be linked
+  --listing <file>     also write a listing of addresses, bytes and source lines
+  --symbol-map <file>  also write the sections and symbols by address
+
+disasm options:
+  --base <addr>        load address of a raw binary (default 0x10000)
+  --numeric            x0-x31 instead of ABI register names
+  --no-aliases         no pseudo-instructions";
+
+// Exit codes for outcomes other than the program's own exit, following sysexits.h
+pub const EXIT_USAGE: i32 = 64;
+pub const EXIT_ASSEMBLY_ERROR: i32 = 65;
+pub const EXIT_NO_INPUT: i32 = 66;
+pub const EXIT_FAULT: i32 = 70;
+pub const EXIT_CANNOT_CREATE: i32 = 73;
+pub const EXIT_CONFIG: i32 = 78;
+// Like timeout(1)
+pub const EXIT_LIMIT: i32 = 124;
+
+pub const DEFAULT_LIMIT: u64 = 100_000_000;
+
+#[derive(Debug, PartialEq)]
+pub struct RunOptions {
+    pub paths: Vec<String>,
+    pub include_paths: Vec<String>,
+    pub limit: u64,  // 0 for no limit
+    pub print_registers: bool,
+    pub memory_ranges: Vec<(String, u32)>,  // address or symbol, length
+    pub linux: Option<String>,  // the sandbox directory for Linux system calls
+    pub map: MapOptions,
+}
+
+impl RunOptions {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut paths = Vec::new();
+        let mut include_paths = Vec::new();
+        let mut limit = DEFAULT_LIMIT;
+        let mut print_registers = false;
+        let mut memory_ranges = Vec::new();
+        let mut linux = None;
+        let mut map = MapOptions::default();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--limit" => {
+                    let value = args.next().ok_or("--limit needs a number of instructions")?;
+                    limit = parse_number(value).ok_or(format!("Invalid instruction limit '{}'", value))? as u64;
+                }
+                "--regs" => print_registers = true,
+                "--mem" => {
+                    let value = args.next().ok_or("--mem needs a range like 0x110000:64")?;
+                    let (address, length) = value.rsplit_once(':')
+                        .ok_or(format!("Invalid memory range '{}', expected <addr>:<len>", value))?;
+                    let length = parse_number(length).ok_or(format!("Invalid length in memory range '{}'", value))?;
+                    if parse_number(address).is_some_and(|start| start as u64 + length as u64 > 1 << 32) {
+                        return Err(format!("Memory range '{}' goes past the end of the address space", value));
+                    }
+                    memory_ranges.push((address.to_string(), length));
+                }
+                "--linux" => linux = Some(args.next().ok_or("--linux needs a sandbox directory")?.clone()),
+                "-I" => include_paths.push(args.next().ok_or("-I needs a directory")?.clone()),
+                flag if map.parse_flag(flag, &mut args)? => {}
+                flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+                file => paths.push(file.to_string()),
+            }
+        }
+
+        if paths.is_empty() {
+            return Err("No program given".to_string());
+        }
+        Ok(Self { paths, include_paths, limit, print_registers, memory_ranges, linux, map })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AsmOptions {
+    pub inputs: Vec<String>,
+    pub include_paths: Vec<String>,
+    pub output: Option<String>,
+    pub format: OutputFormat,
+    pub relocatable: bool, // --format obj: an unlinked object of one file
+    pub listing: Option<String>,
+    pub symbol_map: Option<String>,
+    pub map: MapOptions,
+}
+
+impl AsmOptions {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut inputs = Vec::new();
+        let mut include_paths = Vec::new();
+        let mut output = None;
+        let mut format = OutputFormat::Elf;
+        let mut relocatable = false;
+        let mut listing = None;
+        let mut symbol_map = None;
+        let mut map = MapOptions::default();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" | "--output" => output = Some(args.next().ok_or("-o needs an output file")?.clone()),
+                "--format" => {
+                    let name = args.next().ok_or("--format needs a format name")?;
+                    relocatable = name == "obj";
+                    if !relocatable {
+                        format = OutputFormat::from_name(name).ok_or(format!("Unknown output format '{}'", name))?;
+                    }
+                }
+                "--listing" => listing = Some(args.next().ok_or("--listing needs an output file")?.clone()),
+                "--symbol-map" => symbol_map = Some(args.next().ok_or("--symbol-map needs an output file")?.clone()),
+                "-I" => include_paths.push(args.next().ok_or("-I needs a directory")?.clone()),
+                flag if map.parse_flag(flag, &mut args)? => {}
+                flag if flag.starts_with('-') => return Err(format!("Unknown option '{}'", flag)),
+                file => inputs.push(file.to_string()),
+            }
+        }
+
+        if inputs.is_empty() {
+            return Err("No input file given".to_string());
+        }
+        if relocatable && inputs.len() > 1 {
+            return Err("--format obj assembles a single file".to_string());
+        }
+        if relocatable && (listing.is_some() || symbol_map.is_some()) {
+            return Err("--listing and --symbol-map need a linked program, not --format obj".to_string());
+        }
+        Ok(Self { inputs, include_paths, output, format, relocatable, listing, symbol_map, map })
+    }
+}
+
+/// The options that change the memory map.
+#[derive(Debug, Default, PartialEq)]
+pub struct MapOptions {
+    pub file: Option<String>,
+    pub regions: Vec<Region>,       // replace the regions of the map when given
+    pub stack: Option<(u32, u32)>,  // top, size
+}
+
+impl MapOptions {
+    // Takes a memory map option and its value; false if `flag` is not one
+    fn parse_flag(&mut self, flag: &str, args: &mut std::slice::Iter<String>) -> Result<bool, String> {
+        match flag {
+            "--memory-map" => self.file = Some(args.next().ok_or("--memory-map needs a file")?.clone()),
+            "--region" => {
+                let value = args.next().ok_or("--region needs a region like rom:0x0:256K:rx")?;
+                self.regions.push(memory_map::parse_region(value)?);
+            }
+            "--stack" => {
+                let value = args.next().ok_or("--stack needs a stack like 0x80010000:4096")?;
+                let stack = value.split_once(':').and_then(|(top, size)| Some((parse_number(top)?, parse_number(size)?)));
+                self.stack = Some(stack.ok_or(format!("Invalid stack '{}', expected <top>:<size>", value))?);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// The map of the file, or the default one, with the regions and stack
+    /// given as options.
+    pub fn memory_map(&self) -> Result<MemoryMap, String> {
+        let mut map = match &self.file {
+            Some(path) => MemoryMap::load(path)?,
+            None => MemoryMap::default(),
+        };
+        if !self.regions.is_empty() {
+            map.regions = self.regions.clone();
+        }
+        if let Some((top, size)) = self.stack {
+            map.stack_top = top;
+            map.stack_size = size;
+        }
+        map.validate()?;
+        Ok(map)
+    }
+}
+
+/// The arguments of the editor: the file to open, if any, and the memory map options.
+pub fn tui_options(args: &[String]) -> Result<(Option<String>, MemoryMap), String> {
+    let mut file = None;
+    let mut map = MapOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            flag if map.parse_flag(flag, &mut args)? => {}
+            flag if flag.starts_with("--") => return Err(format!("Unknown option '{}'", flag)),
+            path if file.is_none() => file = Some(path.to_string()),
+            extra => return Err(format!("Unexpected argument '{}'", extra)),
+        }
+    }
+    Ok((file, map.memory_map()?))
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DisasmOptions {
+    pub input: String,
+    pub base: u32,
+    pub abi_names: bool,
+    pub pseudo_instructions: bool,
+}
+
+impl DisasmOptions {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut input = None;
+        let mut base = config::TEXT_BASE;
+        let mut abi_names = true;
+        let mut pseudo_instructions = true;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--base" => {
+                    let value = args.next().ok_or("--base needs an address")?;
+                    base = parse_number(value).ok_or(format!("Invalid base address '{}'", value))?;
+                }
+                "--numeric" => abi_names = false,
+                "--no-aliases" => pseudo_instructions = false,
+                flag if flag.starts_with("--") => return Err(format!("Unknown option '{}'", flag)),
+                file if input.is_none() => input = Some(file.to_string()),
+                extra => return Err(format!("Unexpected argument '{}'", extra)),
+            }
+        }
+
+        let input = input.ok_or("No input file given")?;
+        Ok(Self { input, base, abi_names, pseudo_instructions })
+    }
+}
+
+/// Why a headless run stopped.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Exited(i32),
+    Breakpoint,
+    Fault(StepError),
+    EndOfInput,  // the program waits for input after stdin was closed
+    LimitReached,
+}
+
+impl Outcome {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Exited(code) => *code,
+            Outcome::Breakpoint => 0,
+            Outcome::Fault(_) | Outcome::EndOfInput => EXIT_FAULT,
+            Outcome::LimitReached => EXIT_LIMIT,
+        }
+    }
+}
+
+/// Steps the processor until the program stops or `limit` instructions have
+/// run (0 for no limit). While the program waits for input that has not
+/// arrived on `console` yet, the run sleeps instead of counting steps.
+pub fn execute(processor: &mut Processor, limit: u64, console: &dyn Console) -> Outcome {
+    let mut steps = 0;
+    while limit == 0 || steps < limit {
+        match processor.step() {
+            Ok(()) => steps += 1,
+            Err(StepError::Exit(code)) => return Outcome::Exited(code),
+            Err(StepError::Ebreak) => return Outcome::Breakpoint,
+            Err(StepError::WaitingForInput) if console.input_closed() => return Outcome::EndOfInput,
+            Err(StepError::WaitingForInput) => std::thread::sleep(Duration::from_millis(1)),
+            Err(e) => return Outcome::Fault(e),
+        }
+    }
+    Outcome::LimitReached
+}
+
+/// `ruscv run`: assembles or loads a program, runs it with its console and
+/// UART on stdin/stdout, and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let options = match RunOptions::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("ruscv: {}\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    let map = match options.map.memory_map() {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("ruscv: {}", e);
+            return EXIT_CONFIG;
+        }
+    };
+    let program = match load_program(&options.paths, &options.include_paths, &map) {
+        Ok(loaded) => loaded.program,
+        Err((code, message)) => {
+            eprintln!("ruscv: {}", message.trim_end());
+            return code;
+        }
+    };
+
+    if let Some(sandbox) = options.linux.as_deref().filter(|sandbox| !Path::new(sandbox).is_dir()) {
+        eprintln!("ruscv: {} is not a directory", sandbox);
+        return EXIT_NO_INPUT;
+    }
+
+    let console = Rc::new(RefCell::new(PollingStdConsole::new()));
+    let mut processor = Processor::with_memory_map(&map);
+    if let Err(e) = processor.load(&program) {
+        eprintln!("ruscv: {}", e);
+        return EXIT_FAULT;
+    }
+    match &options.linux {
+        Some(sandbox) => processor.set_syscall_handler(Box::new(LinuxSyscalls::new(console.clone(), sandbox))),
+        None => processor.set_syscall_handler(Box::new(RarsSyscalls::new(console.clone()))),
+    }
+    if let Err(e) = processor.attach_device("uart", uart::BASE, uart::SIZE, Box::new(Uart::new(console.clone()))) {
+        eprintln!("ruscv: {}", e);
+        return EXIT_FAULT;
+    }
+
+    let outcome = execute(&mut processor, options.limit, &console);
+    match &outcome {
+        Outcome::Fault(e) => eprintln!("ruscv: halted at pc 0x{:08x}: {:?}", processor.pc(), e),
+        Outcome::EndOfInput => eprintln!("ruscv: halted at pc 0x{:08x}: end of input", processor.pc()),
+        Outcome::LimitReached => eprintln!("ruscv: stopped after {} instructions at pc 0x{:08x}", options.limit, processor.pc()),
+        Outcome::Exited(_) | Outcome::Breakpoint => {}
+    }
+
+    if options.print_registers {
+        print!("{}", format_registers(&processor));
+    }
+    for (address, length) in &options.memory_ranges {
+        match resolve_address(&processor, address) {
+            Some(address) => print!("{}", format_memory(&processor, address, *length)),
+            None => eprintln!("ruscv: unknown address or symbol '{}'", address),
+        }
+    }
+
+    outcome.exit_code()
+}
+
+/// `ruscv asm`: assembles a program and writes it in one of the export
+/// formats. Returns the process exit code.
+pub fn asm(args: &[String]) -> i32 {
+    let options = match AsmOptions::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("ruscv: {}\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    if options.relocatable {
+        return asm_object(&options);
+    }
+    let map = match options.map.memory_map() {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("ruscv: {}", e);
+            return EXIT_CONFIG;
+        }
+    };
+    let Loaded { program, assembly } = match load_program(&options.inputs, &options.include_paths, &map) {
+        Ok(loaded) => loaded,
+        Err((code, message)) => {
+            eprintln!("ruscv: {}", message.trim_end());
+            return code;
+        }
+    };
+
+    let output = match &options.output {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(&options.inputs[0]).with_extension(options.format.extension()),
+    };
+    let mut files = match export::save(&program, options.format, &output) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("ruscv: cannot write {}: {}", output.display(), e);
+            return EXIT_CANNOT_CREATE;
+        }
+    };
+
+    let mut reports = Vec::new();
+    if let Some(path) = &options.listing {
+        let Some((debug_info, sources)) = &assembly else {
+            eprintln!("ruscv: {} is an ELF executable; only assembled programs have a listing", options.inputs[0]);
+            return EXIT_USAGE;
+        };
+        reports.push((path, listing::listing(debug_info, sources)));
+    }
+    if let Some(path) = &options.symbol_map {
+        reports.push((path, listing::symbol_map(&program)));
+    }
+    for (path, text) in reports {
+        if let Err(e) = std::fs::write(path, text) {
+            eprintln!("ruscv: cannot write {}: {}", path, e);
+            return EXIT_CANNOT_CREATE;
+        }
+        files.push(PathBuf::from(path));
+    }
+
+    for file in files {
+        eprintln!("ruscv: wrote {}", file.display());
+    }
+    0
+}
+
+// Writes the relocatable object of a single source file; the memory map is
+// the linker's concern
+fn asm_object(options: &AsmOptions) -> i32 {
+    let path = &options.inputs[0];
+    let mut sources = SourceFiles::new(options.include_paths.iter().map(PathBuf::from).collect());
+    let object = std::fs::read_to_string(path)
+        .map_err(|e| (EXIT_NO_INPUT, format!("Cannot read {}: {}", path, e)))
+        .and_then(|source| {
+            let file = sources.add(&source, Some(Path::new(path)));
+            pipeline::assemble_object(&mut sources, file).map_err(|e| (EXIT_ASSEMBLY_ERROR, e))
+        });
+    let object = match object {
+        Ok(object) => object,
+        Err((code, message)) => {
+            eprintln!("ruscv: {}", message.trim_end());
+            return code;
+        }
+    };
+    let output = options.output.as_ref().map_or_else(|| Path::new(path).with_extension("o"), PathBuf::from);
+    if let Err(e) = elf::save_relocatable(&object, &output) {
+        eprintln!("ruscv: cannot write {}: {}", output.display(), e);
+        return EXIT_CANNOT_CREATE;
+    }
+    eprintln!("ruscv: wrote {}", output.display());
+    0
+}
+
+/// `ruscv disasm`: prints a listing of an ELF executable or a raw binary.
+/// Returns the process exit code.
+pub fn disasm(args: &[String]) -> i32 {
+    let result = DisasmOptions::parse(args)
+        .map_err(|e| (EXIT_USAGE, format!("{}\n{}", e, USAGE)))
+        .and_then(|options| disassemble_file(&options));
+    match result {
+        Ok(listing) => {
+            print!("{}", listing);
+            0
+        }
+        Err((code, message)) => {
+            eprintln!("ruscv: {}", message);
+            code
+        }
+    }
+}
+
+fn disassemble_file(options: &DisasmOptions) -> Result<String, (i32, String)> {
+    let bytes = std::fs::read(&options.input).map_err(|e| (EXIT_NO_INPUT, format!("Cannot read {}: {}", options.input, e)))?;
+    let program = if bytes.starts_with(b"\x7fELF") {
+        elf::parse(&bytes).map_err(|e| (EXIT_NO_INPUT, format!("{}: {}", options.input, e)))?
+    } else {
+        let segment = Segment {
+            name: "binary".to_string(),
+            vaddr: options.base,
+            mem_size: bytes.len() as u32,
+            data: bytes,
+            permissions: Permissions::RX,
+        };
+        Program { entry: options.base, segments: vec![segment], symbols: Vec::new() }
+    };
+
+    let mut disassembler = Disassembler::new();
+    disassembler.set_abi_names(options.abi_names);
+    disassembler.set_pseudo_instructions(options.pseudo_instructions);
+    disassembler.add_program_symbols(&program.symbols);
+    Ok(disassembler.disassemble_program(&program))
+}
+
+// A program read or assembled for run and asm. Assembled programs keep what
+// their listing is made from.
+struct Loaded {
+    program: Program,
+    assembly: Option<(DebugInfo, SourceFiles)>,
+}
+
+// ELF executables are recognized by their header, anything else is assembled.
+// Several files must all be sources, which are linked together.
+fn load_program(paths: &[String], include_paths: &[String], map: &MemoryMap) -> Result<Loaded, (i32, String)> {
+    let mut sources = SourceFiles::new(include_paths.iter().map(PathBuf::from).collect());
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        let bytes = std::fs::read(path).map_err(|e| (EXIT_NO_INPUT, format!("Cannot read {}: {}", path, e)))?;
+        if bytes.starts_with(b"\x7fELF") {
+            if paths.len() > 1 {
+                return Err((EXIT_NO_INPUT, format!("{} is an ELF executable; only source files can be linked", path)));
+            }
+            let program = elf::parse(&bytes).map_err(|e| (EXIT_NO_INPUT, format!("{}: {}", path, e)))?;
+            return Ok(Loaded { program, assembly: None });
+        }
+        let source = String::from_utf8(bytes).map_err(|_| (EXIT_NO_INPUT, format!("{} is not a text file", path)))?;
+        files.push(sources.add(&source, Some(Path::new(path))));
+    }
+    let (program, debug_info) = pipeline::assemble_with_debug_info(&mut sources, &files, map).map_err(|e| (EXIT_ASSEMBLY_ERROR, e))?;
+    Ok(Loaded { program, assembly: Some((debug_info, sources)) })
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => text.replace('_', "").parse().ok(),
+    }
+}
+
+fn resolve_address(processor: &Processor, text: &str) -> Option<u32> {
+    parse_number(text).or_else(|| {
+        processor.symbols().iter().find(|symbol| symbol.name == text).map(|symbol| symbol.address)
+    })
+}
+
+pub fn format_registers(processor: &Processor) -> String {
+    let mut out = format!("pc  0x{:08x}\n", processor.pc());
+    for (i, row) in processor.registers().chunks(4).enumerate() {
+        let cells: Vec<String> = row.iter().enumerate()
+            .map(|(j, value)| format!("x{:<2} 0x{:08x}", i * 4 + j, value))
+            .collect();
+        out.push_str(&cells.join("  "));
+        out.push('\n');
+    }
+    out
+}
+
+// 16 bytes per line, like hexdump -C; unmapped bytes are shown as --. The
+// range stops at the end of the address space.
+pub fn format_memory(processor: &Processor, address: u32, length: u32) -> String {
+    let length = (length as u64).min((1 << 32) - address as u64) as u32;
+    let mut out = String::new();
+    for line_start in (0..length).step_by(16) {
+        let line_address = address.wrapping_add(line_start);
+        let bytes: Vec<Option<u8>> = (line_start..length.min(line_start.saturating_add(16)))
+            .map(|offset| processor.memory().peek_byte(address.wrapping_add(offset)).ok())
+            .collect();
+        let hex: Vec<String> = bytes.iter()
+            .map(|byte| byte.map_or("--".to_string(), |b| format!("{:02x}", b)))
+            .collect();
+        let text: String = bytes.iter()
+            .map(|byte| match byte {
+                Some(b) if b.is_ascii_graphic() || *b == b' ' => *b as char,
+                _ => '.',
+            })
+            .collect();
+        out.push_str(&format!("0x{:08x}: {:<47}  |{}|\n", line_address, hex.join(" "), text));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::BufferedConsole;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn run_source(source: &str, limit: u64, input: &str) -> (Outcome, Processor, String) {
+        let console = Rc::new(RefCell::new(BufferedConsole::new()));
+        console.borrow_mut().push_input(input);
+        console.borrow_mut().close_input();
+        let mut processor = Processor::new(config::TEXT_BASE, config::DATA_BASE, config::STACK_BASE, config::STACK_SIZE);
+        processor.load(&pipeline::assemble(source).unwrap()).unwrap();
+        processor.set_syscall_handler(Box::new(RarsSyscalls::new(console.clone())));
+        processor.attach_device("uart", uart::BASE, uart::SIZE, Box::new(Uart::new(console.clone()))).unwrap();
+        let outcome = execute(&mut processor, limit, &console);
+        let output = console.borrow().output_text();
+        (outcome, processor, output)
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = RunOptions::parse(&args("--regs prog.s --limit 0x100 --mem msg:16 --mem 0x110000:4")).unwrap();
+        assert_eq!(options, RunOptions {
+            paths: vec!["prog.s".to_string()],
+            include_paths: Vec::new(),
+            limit: 256,
+            print_registers: true,
+            memory_ranges: vec![("msg".to_string(), 16), ("0x110000".to_string(), 4)],
+            linux: None,
+            map: MapOptions::default(),
+        });
+        assert_eq!(RunOptions::parse(&args("prog.s")).unwrap().limit, DEFAULT_LIMIT);
+        assert!(RunOptions::parse(&args("--regs")).is_err());
+        assert_eq!(RunOptions::parse(&args("a.s -I inc b.s")).unwrap().paths, ["a.s", "b.s"]);
+        assert!(RunOptions::parse(&args("a.s --mem 16")).is_err());
+        assert!(RunOptions::parse(&args("a.s --verbose")).is_err());
+    }
+
+    #[test]
+    fn test_memory_map_options() {
+        let options = AsmOptions::parse(&args("--region rom:0:64K --region ram:0x80000000:12K:rw --stack 0x80004000:4096 a.s")).unwrap();
+        let map = options.map.memory_map().unwrap();
+        assert_eq!((map.text_base(), map.data_base(), map.stack_top), (0, 0x8000_0000, 0x8000_4000));
+
+        // a program placed in the regions runs with its stack at the top of RAM
+        let mut sources = SourceFiles::default();
+        let file = sources.add("la a0, value\nlw a0, 0(a0)\nsw a0, -4(sp)\nli a7, 93\necall\n.data\nvalue: .word 7\n", None);
+        let program = pipeline::assemble_sources(&mut sources, &[file], &map).unwrap();
+        assert_eq!((program.entry, program.segments[1].vaddr), (0, 0x8000_0000));
+        let console = Rc::new(RefCell::new(BufferedConsole::new()));
+        let mut processor = Processor::with_memory_map(&map);
+        processor.load(&program).unwrap();
+        processor.set_syscall_handler(Box::new(RarsSyscalls::new(console.clone())));
+        assert_eq!(execute(&mut processor, 100, &console), Outcome::Exited(7));
+
+        let file = sources.add(".data\n.space 0x3001\n", None);
+        let error = pipeline::assemble_sources(&mut sources, &[file], &map).unwrap_err();
+        assert_eq!(error, "Memory map error: .data exceeds the 12 KiB region 'ram'");
+        let options = AsmOptions::parse(&args("--region rom:0:64K --region ram:0x8000:1M a.s")).unwrap();
+        assert_eq!(options.map.memory_map().unwrap_err(), "Regions 'rom' and 'ram' overlap");
+        assert!(RunOptions::parse(&args("--stack 0x1000 a.s")).is_err());
+    }
+
+    #[test]
+    fn test_outcomes_and_exit_codes() {
+        let (outcome, _, output) = run_source("li a0, 65\nli a7, 11\necall\nli a0, 7\nli a7, 93\necall\n", 0, "");
+        assert_eq!((outcome.exit_code(), output.as_str()), (7, "A"));
+
+        let (outcome, p, _) = run_source("li a0, 3\nebreak\n", 0, "");
+        assert_eq!(outcome, Outcome::Breakpoint);
+        assert_eq!((outcome.exit_code(), p.read_register(10)), (0, 3));
+
+        let (outcome, _, _) = run_source("lw a0, 0(zero)\n", 0, "");
+        assert!(matches!(outcome, Outcome::Fault(StepError::MemoryFault(_))));
+        assert_eq!(outcome.exit_code(), EXIT_FAULT);
+
+        let (outcome, _, _) = run_source("loop: j loop\n", 1000, "");
+        assert_eq!(outcome.exit_code(), EXIT_LIMIT);
+
+        let (outcome, _, _) = run_source("li a7, 12\necall\n", 0, "");
+        assert_eq!(outcome, Outcome::EndOfInput);
+    }
+
+    #[test]
+    fn test_run_elf_with_linux_syscalls() {
+        let dir = std::env::temp_dir().join(format!("ruscv-linux-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let program = pipeline::assemble(".data\npath: .string \"out.txt\"\nmsg: .string \"hi\"\n.text\n\
+            li a0, -100\nla a1, path\nli a2, 0x241\nli a3, 0644\nli a7, 56\necall\n\
+            la a1, msg\nli a2, 2\nli a7, 64\necall\n\
+            li a0, 5\nli a7, 93\necall\n").unwrap();
+        let elf = dir.join("prog.elf");
+        elf::save_executable(&program, &elf).unwrap();
+
+        // RARS has no openat, so only the Linux calls write the file
+        assert_eq!(run(&args(&format!("--linux {} {}", dir.display(), elf.display()))), 5);
+        assert_eq!(std::fs::read_to_string(dir.join("out.txt")).unwrap(), "hi");
+        assert_eq!(run(&args(&format!("--linux {} {}", dir.join("none").display(), elf.display()))), EXIT_NO_INPUT);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_uart_uses_the_same_console() {
+        let (outcome, _, output) = run_source("
+                lui t0, 0x10000
+                lbu t1, 0(t0)
+                sb t1, 0(t0)
+                li a0, 0
+                li a7, 93
+                ecall
+        ", 0, "z");
+        assert_eq!(outcome, Outcome::Exited(0));
+        assert_eq!(output, "z");
+    }
+
+    #[test]
+    fn test_format_memory_and_registers() {
+        let (_, p, _) = run_source(".data\nmsg: .asciz \"Hi\"\n.text\nli a0, 1\nebreak\n", 0, "");
+        assert_eq!(resolve_address(&p, "msg"), Some(config::DATA_BASE));
+        assert_eq!(
+            format_memory(&p, config::DATA_BASE, 3),
+            format!("0x{:08x}: {:<47}  |Hi.|\n", config::DATA_BASE, "48 69 00"),
+        );
+        assert!(format_memory(&p, 0, 2).contains("-- --"));
+        assert_eq!(format_memory(&p, 0xFFFF_FFF8, 64), format!("0xfffffff8: {:<47}  |........|\n", ["--"; 8].join(" ")));
+        assert!(RunOptions::parse(&args("a.s --mem 0xFFFFFFF0:32")).is_err());
+        assert!(format_registers(&p).contains("x10 0x00000001"));
+    }
+
+    #[test]
+    fn test_assembly_errors_have_their_own_exit_code() {
+        let path = std::env::temp_dir().join(format!("ruscv-cli-{}.s", std::process::id()));
+        std::fs::write(&path, "addi a0, zero\n").unwrap();
+        assert_eq!(run(&args(&path.display().to_string())), EXIT_ASSEMBLY_ERROR);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(run(&args("/nonexistent/prog.s")), EXIT_NO_INPUT);
+        assert_eq!(run(&args("")), EXIT_USAGE);
+    }
+
+    #[test]
+    fn test_asm_writes_each_format() {
+        let dir = std::env::temp_dir().join(format!("ruscv-asm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("prog.s");
+        std::fs::write(&source, ".data\nmsg: .byte 1\n.text\nli a0, 1\n").unwrap();
+        let source = source.display().to_string();
+
+        assert_eq!(asm(&args(&format!("{} --format readmemh", source))), 0);
+        assert!(std::fs::read_to_string(dir.join("prog.mem")).unwrap().contains("@00004000\n00100513\n"));
+
+        let output = dir.join("out.hex").display().to_string();
+        assert_eq!(asm(&args(&format!("{} -o {} --format ihex", source, output))), 0);
+        assert!(std::fs::read_to_string(&output).unwrap().ends_with(":00000001FF\n"));
+
+        assert_eq!(asm(&args(&format!("{} -o {} --format bin", source, dir.join("out").display()))), 0);
+        assert_eq!(std::fs::read(dir.join("out.data.bin")).unwrap(), [1]);
+        assert_eq!(std::fs::read(dir.join("out.text.bin")).unwrap().len(), 4);
+
+        assert_eq!(asm(&args(&source)), 0);
+        assert!(elf::load_file(dir.join("prog.elf")).is_ok());
+
+        assert_eq!(asm(&args(&format!("{} --format obj", source))), 0);
+        let object = std::fs::read(dir.join("prog.o")).unwrap();
+        assert_eq!(object[16..18], [1, 0]); // ET_REL
+        assert_eq!(asm(&args(&format!("{} {} --format obj", source, source))), EXIT_USAGE);
+
+        assert_eq!(asm(&args(&format!("{} --format coff", source))), EXIT_USAGE);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_disassemble_raw_binary_and_elf() {
+        let dir = std::env::temp_dir().join(format!("ruscv-disasm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let binary = dir.join("prog.bin");
+        std::fs::write(&binary, [0x13, 0x05, 0x50, 0x00, 0x67, 0x80, 0x00, 0x00]).unwrap();
+
+        let options = DisasmOptions::parse(&args(&format!("--base 0x80000000 --numeric {}", binary.display()))).unwrap();
+        let listing = disassemble_file(&options).unwrap();
+        assert!(listing.contains("  80000000:  00500513  li x10, 5\n"), "{}", listing);
+        assert!(listing.contains("  80000004:  00008067  ret\n"));
+
+        let elf = dir.join("prog.elf");
+        elf::save_executable(&pipeline::assemble("main: nop\nj main\n").unwrap(), &elf).unwrap();
+        let options = DisasmOptions::parse(&args(&format!("--no-aliases {}", elf.display()))).unwrap();
+        let listing = disassemble_file(&options).unwrap();
+        assert!(listing.contains("00010000 <main>:\n  00010000:  00000013  addi zero, zero, 0\n"), "{}", listing);
+        assert!(listing.contains("jal zero, 0x10000 <main>"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use crate::bus::Device;
+use crate::csr;
+
+/// Core-local interruptor, laid out like the SiFive CLINT used by QEMU's
+/// `virt` machine and most RISC-V firmware.
+pub const BASE: u32 = 0x0200_0000;
+pub const SIZE: u32 = 0x1_0000;
+
+// Register offsets from BASE
+pub const MSIP: u32 = 0x0000;
+pub const MTIMECMP: u32 = 0x4000;
+pub const MTIME: u32 = 0xBFF8;
+
+#[derive(Debug, Clone)]
+pub struct Clint {
+    msip: bool,
+    mtimecmp: u64,
+    mtime: u64,
+    instructions_per_tick: u32,
+    instructions: u32,  // since the last tick
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self {
+            msip: false,
+            mtimecmp: u64::MAX,  // no timer interrupt until the program sets one
+            mtime: 0,
+            instructions_per_tick: 1,
+            instructions: 0,
+        }
+    }
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `mtime` advance once every `instructions` steps instead of every step.
+    pub fn set_instructions_per_tick(&mut self, instructions: u32) {
+        self.instructions_per_tick = instructions.max(1);
+        self.instructions = 0;
+    }
+
+    /// Clears the registers but keeps the clock ratio.
+    pub fn reset(&mut self) {
+        *self = Self { instructions_per_tick: self.instructions_per_tick, ..Self::default() };
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn timer_pending(&self) -> bool {
+        self.mtime >= self.mtimecmp
+    }
+
+    pub fn software_pending(&self) -> bool {
+        self.msip
+    }
+
+    /// Moves `mtime` forward to the next timer event, as if the hart slept
+    /// until then. Returns false if there is none to wait for.
+    pub fn skip_to_timer(&mut self) -> bool {
+        if self.mtimecmp == u64::MAX {
+            return false;
+        }
+        self.mtime = self.mtime.max(self.mtimecmp);
+        self.instructions = 0;
+        true
+    }
+
+    // The register containing `offset`, with its start offset and width
+    fn register(&self, offset: u32) -> Option<(u64, u32, u32)> {
+        match offset {
+            MSIP..=0x3 => Some((self.msip as u64, MSIP, 4)),
+            MTIMECMP..=0x4007 => Some((self.mtimecmp, MTIMECMP, 8)),
+            MTIME..=0xBFFF => Some((self.mtime, MTIME, 8)),
+            _ => None,
+        }
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u32, size: u32) -> Option<u32> {
+        let (value, start, width) = self.register(offset)?;
+        let shift = (offset - start) * 8;
+        if offset - start + size > width {
+            return None;
+        }
+        Some((value >> shift) as u32 & mask(size))
+    }
+
+    // Partial writes replace only the bytes written, e.g. one half of the
+    // 64-bit mtimecmp
+    fn write(&mut self, offset: u32, size: u32, value: u32) -> Option<()> {
+        let (old, start, width) = self.register(offset)?;
+        let shift = (offset - start) * 8;
+        if offset - start + size > width {
+            return None;
+        }
+        let field = (mask(size) as u64) << shift;
+        let new = (old & !field) | ((value as u64) << shift & field);
+        match start {
+            MSIP => self.msip = new & 1 != 0,
+            MTIMECMP => self.mtimecmp = new,
+            _ => self.mtime = new,
+        }
+        Some(())
+    }
+
+    fn tick(&mut self) {
+        self.instructions += 1;
+        if self.instructions >= self.instructions_per_tick {
+            self.instructions = 0;
+            self.mtime = self.mtime.wrapping_add(1);
+        }
+    }
+
+    fn interrupts(&self) -> u32 {
+        let timer = if self.timer_pending() { csr::MTI } else { 0 };
+        let software = if self.software_pending() { csr::MSI } else { 0 };
+        timer | software
+    }
+}
+
+fn mask(size: u32) -> u32 {
+    if size >= 4 { u32::MAX } else { (1 << (size * 8)) - 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mtime_advances_at_the_configured_ratio() {
+        let mut clint = Clint::new();
+        clint.set_instructions_per_tick(3);
+        for _ in 0..7 {
+            clint.tick();
+        }
+        assert_eq!(clint.mtime(), 2);
+        assert_eq!(clint.read(MTIME, 4), Some(2));
+    }
+
+    #[test]
+    fn test_mtimecmp_is_written_in_halves() {
+        let mut clint = Clint::new();
+        assert!(!clint.timer_pending());
+        clint.write(MTIMECMP + 4, 4, 0).unwrap();
+        clint.write(MTIMECMP, 4, 5).unwrap();
+        assert_eq!(clint.read(MTIMECMP, 4), Some(5));
+        assert_eq!(clint.read(MTIMECMP + 4, 4), Some(0));
+
+        clint.write(MTIME, 4, 5).unwrap();
+        assert!(clint.timer_pending());
+    }
+
+    #[test]
+    fn test_msip_and_reserved_offsets() {
+        let mut clint = Clint::new();
+        clint.write(MSIP, 4, 0xFFFF_FFFF).unwrap();
+        assert!(clint.software_pending());
+        assert_eq!(clint.read(MSIP, 4), Some(1));
+        assert_eq!(clint.read(0x10, 4), None);
+        assert_eq!(clint.write(MTIME + 6, 4, 0), None);  // straddles the end of mtime
+    }
+
+    #[test]
+    fn test_skip_to_timer() {
+        let mut clint = Clint::new();
+        assert!(!clint.skip_to_timer());
+        clint.write(MTIMECMP, 4, 100).unwrap();
+        clint.write(MTIMECMP + 4, 4, 0).unwrap();
+        assert!(clint.skip_to_timer());
+        assert_eq!(clint.mtime(), 100);
+        assert!(clint.timer_pending());
+    }
+}
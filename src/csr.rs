@@ -0,0 +1,299 @@
+use std::fmt;
+
+// Machine information registers (read-only)
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+
+// Machine trap setup and handling
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+
+// Machine counters (writable)
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+pub const MCYCLEH: u16 = 0xB80;
+pub const MINSTRETH: u16 = 0xB82;
+
+// Unprivileged counters (read-only shadows)
+pub const CYCLE: u16 = 0xC00;
+pub const TIME: u16 = 0xC01;
+pub const INSTRET: u16 = 0xC02;
+pub const CYCLEH: u16 = 0xC80;
+pub const TIMEH: u16 = 0xC81;
+pub const INSTRETH: u16 = 0xC82;
+
+const NAMES: &[(&str, u16)] = &[
+    ("mvendorid", MVENDORID), ("marchid", MARCHID), ("mimpid", MIMPID), ("mhartid", MHARTID),
+    ("mstatus", MSTATUS), ("misa", MISA), ("mie", MIE), ("mtvec", MTVEC),
+    ("mscratch", MSCRATCH), ("mepc", MEPC), ("mcause", MCAUSE), ("mtval", MTVAL), ("mip", MIP),
+    ("mcycle", MCYCLE), ("minstret", MINSTRET), ("mcycleh", MCYCLEH), ("minstreth", MINSTRETH),
+    ("cycle", CYCLE), ("time", TIME), ("instret", INSTRET),
+    ("cycleh", CYCLEH), ("timeh", TIMEH), ("instreth", INSTRETH),
+];
+
+// mstatus fields
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+const MSTATUS_MPP: u32 = 0b11 << 11;  // hardwired to machine mode, the only one implemented
+
+// mie/mip fields: software, timer and external interrupts
+pub const MSI: u32 = 1 << 3;
+pub const MTI: u32 = 1 << 7;
+pub const MEI: u32 = 1 << 11;
+
+// mcause: the top bit marks interrupts, the rest is the exception code
+pub const INTERRUPT: u32 = 1 << 31;
+pub const CAUSE_MISALIGNED_FETCH: u32 = 0;
+pub const CAUSE_FETCH_ACCESS: u32 = 1;
+pub const CAUSE_ILLEGAL_INSTRUCTION: u32 = 2;
+pub const CAUSE_BREAKPOINT: u32 = 3;
+pub const CAUSE_MISALIGNED_LOAD: u32 = 4;
+pub const CAUSE_LOAD_ACCESS: u32 = 5;
+pub const CAUSE_MISALIGNED_STORE: u32 = 6;
+pub const CAUSE_STORE_ACCESS: u32 = 7;
+pub const CAUSE_MACHINE_ECALL: u32 = 11;
+pub const CAUSE_SOFTWARE_INTERRUPT: u32 = INTERRUPT | 3;
+pub const CAUSE_TIMER_INTERRUPT: u32 = INTERRUPT | 7;
+pub const CAUSE_EXTERNAL_INTERRUPT: u32 = INTERRUPT | 11;
+
+// MXL = 32 bits, extensions I and M
+const MISA_VALUE: u32 = (1 << 30) | (1 << 8) | (1 << 12);
+
+pub fn address_of(name: &str) -> Option<u16> {
+    NAMES.iter().find(|(n, _)| *n == name).map(|&(_, address)| address)
+}
+
+pub fn name_of(address: u16) -> Option<&'static str> {
+    NAMES.iter().find(|(_, a)| *a == address).map(|&(name, _)| name)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CsrError {
+    Unknown(u16),
+    ReadOnly(u16),
+}
+
+impl fmt::Display for CsrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CsrError::Unknown(address) => write!(f, "Unknown CSR 0x{:03x}", address),
+            CsrError::ReadOnly(address) => write!(f, "CSR 0x{:03x} is read-only", address),
+        }
+    }
+}
+
+/// Control and status registers of a single machine-mode hart. Fields are
+/// WARL: writes to bits that are not implemented are ignored, so a read
+/// always returns a legal value.
+#[derive(Debug, Default, Clone)]
+pub struct CsrFile {
+    mstatus: u32,
+    mie: u32,
+    mip: u32,
+    mtvec: u32,
+    mscratch: u32,
+    mepc: u32,
+    mcause: u32,
+    mtval: u32,
+    cycle: u64,
+    instret: u64,
+    time: u64,
+}
+
+impl CsrFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&self, address: u16) -> Result<u32, CsrError> {
+        Ok(match address {
+            MVENDORID | MARCHID | MIMPID | MHARTID => 0,
+            MSTATUS => self.mstatus | MSTATUS_MPP,
+            MISA => MISA_VALUE,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip,
+            MCYCLE | CYCLE => self.cycle as u32,
+            MCYCLEH | CYCLEH => (self.cycle >> 32) as u32,
+            TIME => self.time as u32,
+            TIMEH => (self.time >> 32) as u32,
+            MINSTRET | INSTRET => self.instret as u32,
+            MINSTRETH | INSTRETH => (self.instret >> 32) as u32,
+            _ => return Err(CsrError::Unknown(address)),
+        })
+    }
+
+    pub fn write(&mut self, address: u16, value: u32) -> Result<(), CsrError> {
+        // the top two address bits set mark a read-only CSR
+        if address >> 10 == 0b11 {
+            return Err(if name_of(address).is_some() { CsrError::ReadOnly(address) } else { CsrError::Unknown(address) });
+        }
+        match address {
+            MSTATUS => self.mstatus = value & (MSTATUS_MIE | MSTATUS_MPIE),
+            MISA => {}  // the extensions cannot be switched off
+            MIE => self.mie = value & (MSI | MTI | MEI),
+            MIP => {}   // pending bits are driven by the interrupt sources
+            MTVEC => {
+                // only the direct (0) and vectored (1) modes exist
+                let mode = if value & 0b11 == 1 { 1 } else { 0 };
+                self.mtvec = (value & !0b11) | mode;
+            }
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value & !0b11,  // instructions are word aligned
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            MCYCLE => self.cycle = (self.cycle & !0xFFFF_FFFF) | value as u64,
+            MCYCLEH => self.cycle = (self.cycle & 0xFFFF_FFFF) | (value as u64) << 32,
+            MINSTRET => self.instret = (self.instret & !0xFFFF_FFFF) | value as u64,
+            MINSTRETH => self.instret = (self.instret & 0xFFFF_FFFF) | (value as u64) << 32,
+            _ => return Err(CsrError::Unknown(address)),
+        }
+        Ok(())
+    }
+
+    /// Whether the program has installed a trap handler.
+    pub fn has_trap_handler(&self) -> bool {
+        self.mtvec & !0b11 != 0
+    }
+
+    /// Records a trap taken at `pc` and returns the address of its handler.
+    /// Interrupts are disabled until the handler returns with `mret`.
+    pub fn enter_trap(&mut self, pc: u32, cause: u32, tval: u32) -> u32 {
+        self.mepc = pc;
+        self.mcause = cause;
+        self.mtval = tval;
+        let mpie = if self.mstatus & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+        self.mstatus = (self.mstatus & !(MSTATUS_MIE | MSTATUS_MPIE)) | mpie;
+
+        let base = self.mtvec & !0b11;
+        // vectored mode only applies to interrupts; exceptions always use the base
+        if self.mtvec & 0b11 == 1 && cause & INTERRUPT != 0 {
+            base.wrapping_add(4 * (cause & !INTERRUPT))
+        } else {
+            base
+        }
+    }
+
+    /// Restores the interrupt enable saved by `enter_trap` and returns `mepc`.
+    pub fn return_from_trap(&mut self) -> u32 {
+        let mie = if self.mstatus & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+        self.mstatus = (self.mstatus & !MSTATUS_MIE) | mie | MSTATUS_MPIE;
+        self.mepc
+    }
+
+    /// Interrupts that are both pending and enabled in `mie`.
+    pub fn enabled_interrupts(&self) -> u32 {
+        self.mip & self.mie
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.mstatus & MSTATUS_MIE != 0
+    }
+
+    /// Raises or clears a bit of `mip` on behalf of an interrupt source.
+    pub fn set_pending(&mut self, interrupt: u32, pending: bool) {
+        if pending {
+            self.mip |= interrupt;
+        } else {
+            self.mip &= !interrupt;
+        }
+    }
+
+    /// Mirrors the platform timer into the `time` CSR.
+    pub fn set_time(&mut self, time: u64) {
+        self.time = time;
+    }
+
+    /// Counts a clock cycle.
+    pub fn tick(&mut self) {
+        self.cycle = self.cycle.wrapping_add(1);
+    }
+
+    /// Counts a retired instruction.
+    pub fn retire(&mut self) {
+        self.instret = self.instret.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_round_trip() {
+        assert_eq!(address_of("mtvec"), Some(MTVEC));
+        assert_eq!(name_of(INSTRETH), Some("instreth"));
+        assert_eq!(address_of("sstatus"), None);
+    }
+
+    #[test]
+    fn test_warl_fields_keep_legal_values() {
+        let mut csrs = CsrFile::new();
+        csrs.write(MSTATUS, 0xFFFF_FFFF).unwrap();
+        assert_eq!(csrs.read(MSTATUS), Ok(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP));
+
+        csrs.write(MTVEC, 0x1000_0003).unwrap();
+        assert_eq!(csrs.read(MTVEC), Ok(0x1000_0000));
+        csrs.write(MTVEC, 0x1000_0001).unwrap();
+        assert_eq!(csrs.read(MTVEC), Ok(0x1000_0001));
+
+        csrs.write(MEPC, 0x1003).unwrap();
+        assert_eq!(csrs.read(MEPC), Ok(0x1000));
+
+        csrs.write(MISA, 0).unwrap();
+        assert_eq!(csrs.read(MISA), Ok(MISA_VALUE));
+    }
+
+    #[test]
+    fn test_read_only_and_unknown_csrs() {
+        let mut csrs = CsrFile::new();
+        assert_eq!(csrs.write(CYCLE, 1), Err(CsrError::ReadOnly(CYCLE)));
+        assert_eq!(csrs.write(MHARTID, 1), Err(CsrError::ReadOnly(MHARTID)));
+        assert_eq!(csrs.read(0x7C0), Err(CsrError::Unknown(0x7C0)));
+        assert_eq!(csrs.write(0x7C0, 1), Err(CsrError::Unknown(0x7C0)));
+        assert_eq!(csrs.write(0xCFF, 1), Err(CsrError::Unknown(0xCFF)));
+    }
+
+    #[test]
+    fn test_counters_are_64_bit() {
+        let mut csrs = CsrFile::new();
+        csrs.write(MCYCLE, 0xFFFF_FFFF).unwrap();
+        csrs.tick();
+        assert_eq!(csrs.read(CYCLE), Ok(0));
+        assert_eq!(csrs.read(CYCLEH), Ok(1));
+        csrs.retire();
+        assert_eq!(csrs.read(INSTRET), Ok(1));
+    }
+
+    #[test]
+    fn test_trap_entry_and_return() {
+        let mut csrs = CsrFile::new();
+        csrs.write(MTVEC, 0x8000_0001).unwrap();
+        csrs.write(MSTATUS, MSTATUS_MIE).unwrap();
+
+        assert_eq!(csrs.enter_trap(0x1004, CAUSE_ILLEGAL_INSTRUCTION, 0xFFFF_FFFF), 0x8000_0000);
+        assert_eq!(csrs.read(MEPC), Ok(0x1004));
+        assert_eq!(csrs.read(MCAUSE), Ok(CAUSE_ILLEGAL_INSTRUCTION));
+        assert_eq!(csrs.read(MTVAL), Ok(0xFFFF_FFFF));
+        assert_eq!(csrs.read(MSTATUS), Ok(MSTATUS_MPIE | MSTATUS_MPP));
+
+        assert_eq!(csrs.return_from_trap(), 0x1004);
+        assert_eq!(csrs.read(MSTATUS), Ok(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP));
+
+        // vectored interrupts jump to base + 4 * cause
+        assert_eq!(csrs.enter_trap(0x1008, CAUSE_TIMER_INTERRUPT, 0), 0x8000_001C);
+    }
+}
@@ -0,0 +1,106 @@
+use std::ops::Range;
+
+use crate::assembler::AssemblerError;
+use crate::lexer::LexError;
+use crate::macros::MacroError;
+use crate::parser::ParseError;
+use crate::source::{FileId, SourceFiles};
+
+/// The stage of assembly a diagnostic comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Lexer,
+    Macros,
+    Parser,
+    Pseudo,
+    Symbols,
+    Assembler,
+}
+
+/// An error found in a source file. Each stage goes on with the next line
+/// after an error, so that one run reports every line that has one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: FileId,
+    pub line: usize, // 0 for problems with the file as a whole
+    pub columns: Option<Range<usize>>, // counted from 1, from the lexer and parser
+    pub stage: Stage,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(stage: Stage, file: FileId, line: usize, message: impl Into<String>) -> Self {
+        Self { file, line, columns: None, stage, message: message.into() }
+    }
+
+    pub fn with_columns(self, columns: Range<usize>) -> Self {
+        Self { columns: Some(columns), ..self }
+    }
+
+    /// The diagnostic for display, prefixed with where it is: "prog.s:3:5:
+    /// message", "Line 3, column 5: message", or the file name alone for a
+    /// diagnostic without a line.
+    pub fn render(&self, sources: &SourceFiles) -> String {
+        let stage = match self.stage {
+            Stage::Pseudo => "Pseudo-instruction error: ",
+            Stage::Symbols => "Symbol error: ",
+            _ => "",
+        };
+        let message = format!("{}{}", stage, self.message);
+        match (self.line, sources.name(self.file), &self.columns) {
+            (0, "", _) => message,
+            (0, name, _) => format!("{}: {}", name, message),
+            (line, "", Some(columns)) => format!("Line {}, column {}: {}", line, columns.start, message),
+            (line, name, Some(columns)) => format!("{}:{}:{}: {}", name, line, columns.start, message),
+            (line, _, None) => sources.error(self.file, line, message),
+        }
+    }
+}
+
+impl From<LexError> for Diagnostic {
+    fn from(error: LexError) -> Self {
+        let columns = error.column..error.column + error.width();
+        Self::error(Stage::Lexer, error.file, error.line, error.to_string()).with_columns(columns)
+    }
+}
+
+impl From<MacroError> for Diagnostic {
+    fn from(error: MacroError) -> Self {
+        Self::error(Stage::Macros, error.file, error.line, error.message)
+    }
+}
+
+impl From<ParseError> for Diagnostic {
+    fn from(error: ParseError) -> Self {
+        Self::error(Stage::Parser, error.file, error.line, error.message).with_columns(error.columns)
+    }
+}
+
+impl From<AssemblerError> for Diagnostic {
+    fn from(error: AssemblerError) -> Self {
+        Self::error(Stage::Assembler, error.file, error.line, error.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_render() {
+        let mut sources = SourceFiles::default();
+        let buffer = sources.add("nop\n", None);
+        let file = sources.add("nop\n", Some(Path::new("prog.s")));
+
+        let error = Diagnostic::error(Stage::Symbols, buffer, 3, "Unknown label 'x'");
+        assert_eq!(error.render(&sources), "Line 3: Symbol error: Unknown label 'x'");
+        let error = Diagnostic::error(Stage::Lexer, file, 3, "Unterminated string literal").with_columns(5..6);
+        assert_eq!(error.render(&sources), "prog.s:3:5: Unterminated string literal");
+        // a lexer error covers the text it is about
+        let error = Diagnostic::from(LexError::new(3, 5, crate::lexer::LexErrorKind::InvalidNumber("0xZZ".to_string())));
+        assert_eq!(error.columns, Some(5..9));
+        let error = Diagnostic::error(Stage::Symbols, file, 0, "Section '.got' was already used with other flags");
+        assert_eq!(error.render(&sources), "prog.s: Symbol error: Section '.got' was already used with other flags");
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::csr;
+use crate::processor::{Instruction, Processor};
+use crate::program::{Program, ProgramSymbol};
+use crate::symbols::SymbolTable;
+
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// Turns machine code back into assembly in GNU syntax. Like `objdump`,
+/// registers use their ABI names and common pseudo-instructions are
+/// recognized unless turned off, and branch and jump targets are annotated
+/// with the label at the target address.
+pub struct Disassembler {
+    abi_names: bool,
+    pseudo_instructions: bool,
+    labels: HashMap<u32, String>,
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Self { abi_names: true, pseudo_instructions: true, labels: HashMap::new() }
+    }
+}
+
+impl Disassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `a0`, `sp`, ... instead of `x10`, `x2`, ...
+    pub fn set_abi_names(&mut self, enabled: bool) {
+        self.abi_names = enabled;
+    }
+
+    /// Show e.g. `addi a0, zero, 1` as `li a0, 1`.
+    pub fn set_pseudo_instructions(&mut self, enabled: bool) {
+        self.pseudo_instructions = enabled;
+    }
+
+    pub fn add_labels(&mut self, symbols: &SymbolTable) {
+        for (name, address) in symbols.iter().filter(|(name, _)| !symbols.is_absolute(name)) {
+            self.add_label(name, address);
+        }
+    }
+
+    pub fn add_program_symbols(&mut self, symbols: &[ProgramSymbol]) {
+        // constants are not locations, so they do not label anything
+        for symbol in symbols.iter().filter(|symbol| !symbol.absolute) {
+            self.add_label(&symbol.name, symbol.address);
+        }
+    }
+
+    // With several labels at one address, the alphabetically first is kept so the output is stable
+    fn add_label(&mut self, name: &str, address: u32) {
+        if name.is_empty() {
+            return;
+        }
+        let label = self.labels.entry(address).or_insert_with(|| name.to_string());
+        if name < label.as_str() {
+            *label = name.to_string();
+        }
+    }
+
+    pub fn label_at(&self, address: u32) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    /// Disassembles the word at `address`; words that do not decode are shown as `.word`.
+    pub fn disassemble(&self, word: u32, address: u32) -> String {
+        match Processor::decode(word) {
+            Ok(instruction) => self.format(&instruction, address),
+            Err(_) => format!(".word 0x{:08x}", word),
+        }
+    }
+
+    /// A listing of the executable segments of `program`, with a heading at
+    /// every label: address, machine word and instruction on each line.
+    pub fn disassemble_program(&self, program: &Program) -> String {
+        let mut out = String::new();
+        for segment in program.segments.iter().filter(|segment| segment.permissions.execute) {
+            let _ = writeln!(out, "Disassembly of {}:", segment.name);
+            for (i, bytes) in segment.data.chunks(4).enumerate() {
+                let address = segment.vaddr.wrapping_add(i as u32 * 4);
+                if let Some(label) = self.label_at(address) {
+                    let _ = writeln!(out, "\n{:08x} <{}>:", address, label);
+                }
+                let mut padded = [0u8; 4];
+                padded[..bytes.len()].copy_from_slice(bytes);
+                let word = u32::from_le_bytes(padded);
+                let _ = writeln!(out, "  {:08x}:  {:08x}  {}", address, word, self.disassemble(word, address));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn reg(&self, index: usize) -> String {
+        if self.abi_names { ABI_NAMES[index].to_string() } else { format!("x{}", index) }
+    }
+
+    fn target(&self, address: u32, offset: i32) -> String {
+        let target = address.wrapping_add(offset as u32);
+        match self.label_at(target) {
+            Some(label) => format!("0x{:x} <{}>", target, label),
+            None => format!("0x{:x}", target),
+        }
+    }
+
+    fn format(&self, instruction: &Instruction, address: u32) -> String {
+        if self.pseudo_instructions
+            && let Some(text) = self.format_pseudo(instruction, address)
+        {
+            return text;
+        }
+
+        let r = |index: &usize| self.reg(*index);
+        let csr = |address: &u16| csr_name(*address);
+        let rrr = |name: &str, rd, rs1, rs2| format!("{} {}, {}, {}", name, r(rd), r(rs1), r(rs2));
+        let rri = |name: &str, rd, rs1, imm: &i32| format!("{} {}, {}, {}", name, r(rd), r(rs1), imm);
+        let mem = |name: &str, reg, base, imm: &i32| format!("{} {}, {}({})", name, r(reg), imm, r(base));
+        let branch = |name: &str, rs1, rs2, imm: &i32| format!("{} {}, {}, {}", name, r(rs1), r(rs2), self.target(address, *imm));
+
+        use Instruction::*;
+        match instruction {
+            Add { rd, rs1, rs2 } => rrr("add", rd, rs1, rs2),
+            Sub { rd, rs1, rs2 } => rrr("sub", rd, rs1, rs2),
+            And { rd, rs1, rs2 } => rrr("and", rd, rs1, rs2),
+            Or { rd, rs1, rs2 } => rrr("or", rd, rs1, rs2),
+            Xor { rd, rs1, rs2 } => rrr("xor", rd, rs1, rs2),
+            Sll { rd, rs1, rs2 } => rrr("sll", rd, rs1, rs2),
+            Srl { rd, rs1, rs2 } => rrr("srl", rd, rs1, rs2),
+            Sra { rd, rs1, rs2 } => rrr("sra", rd, rs1, rs2),
+            Slt { rd, rs1, rs2 } => rrr("slt", rd, rs1, rs2),
+            Sltu { rd, rs1, rs2 } => rrr("sltu", rd, rs1, rs2),
+            Mul { rd, rs1, rs2 } => rrr("mul", rd, rs1, rs2),
+            Mulh { rd, rs1, rs2 } => rrr("mulh", rd, rs1, rs2),
+            Mulhsu { rd, rs1, rs2 } => rrr("mulhsu", rd, rs1, rs2),
+            Mulhu { rd, rs1, rs2 } => rrr("mulhu", rd, rs1, rs2),
+            Div { rd, rs1, rs2 } => rrr("div", rd, rs1, rs2),
+            Divu { rd, rs1, rs2 } => rrr("divu", rd, rs1, rs2),
+            Rem { rd, rs1, rs2 } => rrr("rem", rd, rs1, rs2),
+            Remu { rd, rs1, rs2 } => rrr("remu", rd, rs1, rs2),
+
+            Addi { rd, rs1, imm } => rri("addi", rd, rs1, imm),
+            Andi { rd, rs1, imm } => rri("andi", rd, rs1, imm),
+            Ori { rd, rs1, imm } => rri("ori", rd, rs1, imm),
+            Xori { rd, rs1, imm } => rri("xori", rd, rs1, imm),
+            Slti { rd, rs1, imm } => rri("slti", rd, rs1, imm),
+            Sltiu { rd, rs1, imm } => rri("sltiu", rd, rs1, imm),
+            Slli { rd, rs1, shamt } => rri("slli", rd, rs1, &(*shamt as i32)),
+            Srli { rd, rs1, shamt } => rri("srli", rd, rs1, &(*shamt as i32)),
+            Srai { rd, rs1, shamt } => rri("srai", rd, rs1, &(*shamt as i32)),
+
+            Lb { rd, rs1, imm } => mem("lb", rd, rs1, imm),
+            Lh { rd, rs1, imm } => mem("lh", rd, rs1, imm),
+            Lw { rd, rs1, imm } => mem("lw", rd, rs1, imm),
+            Lbu { rd, rs1, imm } => mem("lbu", rd, rs1, imm),
+            Lhu { rd, rs1, imm } => mem("lhu", rd, rs1, imm),
+            Sb { rs1, rs2, imm } => mem("sb", rs2, rs1, imm),
+            Sh { rs1, rs2, imm } => mem("sh", rs2, rs1, imm),
+            Sw { rs1, rs2, imm } => mem("sw", rs2, rs1, imm),
+
+            Beq { rs1, rs2, imm } => branch("beq", rs1, rs2, imm),
+            Bne { rs1, rs2, imm } => branch("bne", rs1, rs2, imm),
+            Blt { rs1, rs2, imm } => branch("blt", rs1, rs2, imm),
+            Bge { rs1, rs2, imm } => branch("bge", rs1, rs2, imm),
+            Bltu { rs1, rs2, imm } => branch("bltu", rs1, rs2, imm),
+            Bgeu { rs1, rs2, imm } => branch("bgeu", rs1, rs2, imm),
+
+            // the upper immediate is shown as the 20-bit field, as it is written
+            Lui { rd, imm } => format!("lui {}, 0x{:x}", r(rd), (*imm as u32) >> 12),
+            Auipc { rd, imm } => format!("auipc {}, 0x{:x}", r(rd), (*imm as u32) >> 12),
+
+            Jal { rd, imm } => format!("jal {}, {}", r(rd), self.target(address, *imm)),
+            Jalr { rd, rs1, imm } => mem("jalr", rd, rs1, imm),
+
+            Ecall => "ecall".to_string(),
+            Ebreak => "ebreak".to_string(),
+            Mret => "mret".to_string(),
+            Wfi => "wfi".to_string(),
+
+            Csrrw { rd, rs1, csr: c } => format!("csrrw {}, {}, {}", r(rd), csr(c), r(rs1)),
+            Csrrs { rd, rs1, csr: c } => format!("csrrs {}, {}, {}", r(rd), csr(c), r(rs1)),
+            Csrrc { rd, rs1, csr: c } => format!("csrrc {}, {}, {}", r(rd), csr(c), r(rs1)),
+            Csrrwi { rd, uimm, csr: c } => format!("csrrwi {}, {}, {}", r(rd), csr(c), uimm),
+            Csrrsi { rd, uimm, csr: c } => format!("csrrsi {}, {}, {}", r(rd), csr(c), uimm),
+            Csrrci { rd, uimm, csr: c } => format!("csrrci {}, {}, {}", r(rd), csr(c), uimm),
+        }
+    }
+
+    // The aliases objdump prints for the base instructions
+    fn format_pseudo(&self, instruction: &Instruction, address: u32) -> Option<String> {
+        let r = |index: &usize| self.reg(*index);
+        let csr = |address: &u16| csr_name(*address);
+
+        use Instruction::*;
+        let text = match instruction {
+            Addi { rd: 0, rs1: 0, imm: 0 } => "nop".to_string(),
+            Addi { rd, rs1: 0, imm } => format!("li {}, {}", r(rd), imm),
+            Addi { rd, rs1, imm: 0 } => format!("mv {}, {}", r(rd), r(rs1)),
+            Xori { rd, rs1, imm: -1 } => format!("not {}, {}", r(rd), r(rs1)),
+            Sub { rd, rs1: 0, rs2 } => format!("neg {}, {}", r(rd), r(rs2)),
+            Sltiu { rd, rs1, imm: 1 } => format!("seqz {}, {}", r(rd), r(rs1)),
+            Sltu { rd, rs1: 0, rs2 } => format!("snez {}, {}", r(rd), r(rs2)),
+            Beq { rs1, rs2: 0, imm } => format!("beqz {}, {}", r(rs1), self.target(address, *imm)),
+            Bne { rs1, rs2: 0, imm } => format!("bnez {}, {}", r(rs1), self.target(address, *imm)),
+            Jal { rd: 0, imm } => format!("j {}", self.target(address, *imm)),
+            Jal { rd: 1, imm } => format!("jal {}", self.target(address, *imm)),
+            Jalr { rd: 0, rs1: 1, imm: 0 } => "ret".to_string(),
+            Jalr { rd: 0, rs1, imm: 0 } => format!("jr {}", r(rs1)),
+            Jalr { rd: 1, rs1, imm: 0 } => format!("jalr {}", r(rs1)),
+            Csrrs { rd, rs1: 0, csr: c } => format!("csrr {}, {}", r(rd), csr(c)),
+            Csrrw { rd: 0, rs1, csr: c } => format!("csrw {}, {}", csr(c), r(rs1)),
+            _ => return None,
+        };
+        Some(text)
+    }
+}
+
+fn csr_name(address: u16) -> String {
+    match csr::name_of(address) {
+        Some(name) => name.to_string(),
+        None => format!("0x{:03x}", address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::pipeline;
+
+    #[test]
+    fn test_base_instructions_in_gnu_syntax() {
+        let mut d = Disassembler::new();
+        d.set_pseudo_instructions(false);
+        assert_eq!(d.disassemble(0x003100B3, 0), "add ra, sp, gp");
+        assert_eq!(d.disassemble(0xFFF10093, 0), "addi ra, sp, -1");
+        assert_eq!(d.disassemble(0xFE312E23, 0), "sw gp, -4(sp)");
+        assert_eq!(d.disassemble(0x123452B7, 0), "lui t0, 0x12345");
+        assert_eq!(d.disassemble(0x40515093, 0), "srai ra, sp, 5");
+        assert_eq!(d.disassemble(0x004100E7, 0), "jalr ra, 4(sp)");
+        assert_eq!(d.disassemble(0x30059573, 0), "csrrw a0, mstatus, a1");
+        assert_eq!(d.disassemble(0x340FD573, 0), "csrrwi a0, mscratch, 31");
+        assert_eq!(d.disassemble(0xFE208EE3, 0x100), "beq ra, sp, 0xfc");
+        assert_eq!(d.disassemble(0x00000013, 0), "addi zero, zero, 0");
+        assert_eq!(d.disassemble(0xFFFFFFFF, 0), ".word 0xffffffff");
+
+        d.set_abi_names(false);
+        assert_eq!(d.disassemble(0x003100B3, 0), "add x1, x2, x3");
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        let d = Disassembler::new();
+        assert_eq!(d.disassemble(0x00000013, 0), "nop");
+        assert_eq!(d.disassemble(0x00500513, 0), "li a0, 5");
+        assert_eq!(d.disassemble(0x00058513, 0), "mv a0, a1");
+        assert_eq!(d.disassemble(0x00008067, 0), "ret");
+        assert_eq!(d.disassemble(0x0000006F, 0x40), "j 0x40");
+        assert_eq!(d.disassemble(0x300025F3, 0), "csrr a1, mstatus");
+    }
+
+    #[test]
+    fn test_round_trip_with_labels() {
+        let source = "
+            main:
+                li a0, 3
+            loop:
+                addi a0, a0, -1
+                bnez a0, loop
+                jal done
+                j loop
+            done:
+                ret
+        ";
+        let program = pipeline::assemble(source).unwrap();
+        let mut d = Disassembler::new();
+        d.add_program_symbols(&program.symbols);
+
+        let listing = d.disassemble_program(&program);
+        let lines: Vec<&str> = listing.lines().filter(|line| line.starts_with("  ")).map(|line| line.rsplit("  ").next().unwrap()).collect();
+        assert_eq!(lines, [
+            "li a0, 3",
+            "addi a0, a0, -1",
+            "bnez a0, 0x10004 <loop>",
+            "jal 0x10014 <done>",
+            "j 0x10004 <loop>",
+            "ret",
+        ]);
+        assert!(listing.contains(&format!("\n{:08x} <main>:\n", config::TEXT_BASE)));
+    }
+}
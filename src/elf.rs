@@ -0,0 +1,769 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::program::{
+    Permissions, Program, ProgramSymbol, Relocatable, RelocationKind, Segment, SymbolBinding, SymbolSection, SymbolType,
+};
+
+pub const EM_RISCV: u16 = 243;
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const EV_CURRENT: u8 = 1;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u32 = 0x1;
+const SHF_ALLOC: u32 = 0x2;
+const SHF_EXECINSTR: u32 = 0x4;
+const SHF_INFO_LINK: u32 = 0x40;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const PAGE_SIZE: usize = 0x1000;
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+const EHDR_SIZE: usize = 52;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+const RELA_SIZE: usize = 12;
+
+#[derive(Debug, PartialEq)]
+pub enum ElfError {
+    Io(String),
+    NotElf,
+    UnsupportedClass(u8),
+    UnsupportedEncoding(u8),
+    UnsupportedType(u16),
+    WrongMachine(u16),
+    Truncated { offset: usize },
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Io(e) => write!(f, "Cannot read file: {}", e),
+            ElfError::NotElf => write!(f, "Not an ELF file"),
+            ElfError::UnsupportedClass(c) => write!(f, "Unsupported ELF class {} (only ELF32 is supported)", c),
+            ElfError::UnsupportedEncoding(e) => write!(f, "Unsupported data encoding {} (only little endian is supported)", e),
+            ElfError::UnsupportedType(t) => write!(f, "Unsupported ELF type {} (only executables are supported)", t),
+            ElfError::WrongMachine(m) => write!(f, "Not a RISC-V executable (e_machine = {})", m),
+            ElfError::Truncated { offset } => write!(f, "File truncated at offset 0x{:x}", offset),
+        }
+    }
+}
+
+// Bounds-checked little endian field access
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn slice(&self, offset: usize, len: usize) -> Result<&[u8], ElfError> {
+        offset.checked_add(len)
+            .and_then(|end| self.bytes.get(offset..end))
+            .ok_or(ElfError::Truncated { offset })
+    }
+
+    fn u8(&self, offset: usize) -> Result<u8, ElfError> {
+        Ok(self.slice(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, ElfError> {
+        Ok(u16::from_le_bytes(self.slice(offset, 2)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, ElfError> {
+        Ok(u32::from_le_bytes(self.slice(offset, 4)?.try_into().unwrap()))
+    }
+
+    fn c_string(&self, offset: usize) -> Result<String, ElfError> {
+        let rest = self.bytes.get(offset..).ok_or(ElfError::Truncated { offset })?;
+        let end = rest.iter().position(|&b| b == 0).ok_or(ElfError::Truncated { offset })?;
+        Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+    }
+}
+
+pub fn load_file(path: impl AsRef<Path>) -> Result<Program, ElfError> {
+    let bytes = std::fs::read(path).map_err(|e| ElfError::Io(e.to_string()))?;
+    parse(&bytes)
+}
+
+/// Parses an ELF32 little endian RISC-V executable into its loadable segments,
+/// entry point and `.symtab` symbols.
+pub fn parse(bytes: &[u8]) -> Result<Program, ElfError> {
+    let r = Reader { bytes };
+
+    if r.slice(0, 4).ok() != Some(b"\x7fELF".as_slice()) {
+        return Err(ElfError::NotElf);
+    }
+    let class = r.u8(4)?;
+    if class != ELFCLASS32 {
+        return Err(ElfError::UnsupportedClass(class));
+    }
+    let encoding = r.u8(5)?;
+    if encoding != ELFDATA2LSB {
+        return Err(ElfError::UnsupportedEncoding(encoding));
+    }
+    let elf_type = r.u16(16)?;
+    if elf_type != ET_EXEC {
+        return Err(ElfError::UnsupportedType(elf_type));
+    }
+    let machine = r.u16(18)?;
+    if machine != EM_RISCV {
+        return Err(ElfError::WrongMachine(machine));
+    }
+
+    let entry = r.u32(24)?;
+    let phoff = r.u32(28)? as usize;
+    let shoff = r.u32(32)? as usize;
+    let phentsize = r.u16(42)? as usize;
+    let phnum = r.u16(44)? as usize;
+    let shentsize = r.u16(46)? as usize;
+    let shnum = r.u16(48)? as usize;
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        if r.u32(ph)? != PT_LOAD {
+            continue;
+        }
+        let offset = r.u32(ph + 4)? as usize;
+        let vaddr = r.u32(ph + 8)?;
+        let file_size = r.u32(ph + 16)? as usize;
+        let mem_size = r.u32(ph + 20)?;
+        let flags = r.u32(ph + 24)?;
+
+        let permissions = Permissions {
+            read: flags & PF_R != 0,
+            write: flags & PF_W != 0,
+            execute: flags & PF_X != 0,
+        };
+        let name = if permissions.execute {
+            "text"
+        } else if permissions.write {
+            "data"
+        } else {
+            "rodata"
+        };
+
+        segments.push(Segment {
+            name: name.to_string(),
+            vaddr,
+            data: r.slice(offset, file_size)?.to_vec(),
+            mem_size,
+            permissions,
+        });
+    }
+
+    let mut symbols = Vec::new();
+    for i in 0..shnum {
+        let sh = shoff + i * shentsize;
+        if r.u32(sh + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let offset = r.u32(sh + 16)? as usize;
+        let size = r.u32(sh + 20)? as usize;
+        let entsize = (r.u32(sh + 36)? as usize).max(16);
+        // sh_link names the string table holding the symbol names
+        let strtab = shoff + r.u32(sh + 24)? as usize * shentsize;
+        let strtab_offset = r.u32(strtab + 16)? as usize;
+
+        for sym in (offset..offset + size).step_by(entsize).skip(1) { // entry 0 is reserved
+            let name = r.u32(sym)? as usize;
+            let value = r.u32(sym + 4)?;
+            let sym_size = r.u32(sym + 8)?;
+            let info = r.u8(sym + 12)?;
+            let shndx = r.u16(sym + 14)?;
+
+            // Only named, defined NOTYPE/OBJECT/FUNC symbols are useful for display
+            let symbol_type = match info & 0xF {
+                STT_NOTYPE => SymbolType::NoType,
+                STT_OBJECT => SymbolType::Object,
+                STT_FUNC => SymbolType::Function,
+                _ => continue,
+            };
+            if name == 0 || shndx == 0 {
+                continue;
+            }
+            symbols.push(ProgramSymbol {
+                name: r.c_string(strtab_offset + name)?,
+                address: value,
+                size: sym_size,
+                binding: match info >> 4 {
+                    STB_GLOBAL | STB_WEAK => SymbolBinding::Global,
+                    _ => SymbolBinding::Local,
+                },
+                symbol_type,
+                absolute: shndx == SHN_ABS,
+            });
+        }
+    }
+    symbols.sort_by_key(|symbol| symbol.address);
+
+    Ok(Program { entry, segments, symbols })
+}
+
+// Collects names for a string table section; offset 0 is the empty name
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        Self { bytes: vec![0] }
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(name.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    sh_type: u32,
+    flags: u32,
+    addr: u32,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    align: u32,
+    entsize: u32,
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn pad_to_word(out: &mut Vec<u8>) {
+    while !out.len().is_multiple_of(4) {
+        out.push(0);
+    }
+}
+
+fn flag_bits(flags: &[(bool, u32)]) -> u32 {
+    flags.iter().filter(|(set, _)| *set).fold(0, |bits, (_, bit)| bits | bit)
+}
+
+pub fn save_executable(program: &Program, path: impl AsRef<Path>) -> std::io::Result<()> {
+    std::fs::write(path, write_executable(program))
+}
+
+/// Serializes a program as an ELF32 RISC-V executable: one `PT_LOAD` program
+/// header per segment, a section per segment (`.text`, `.data`, `.bss`, ...)
+/// plus `.bss` for zero-filled tails of others, and `.symtab`/`.strtab`/`.shstrtab`.
+pub fn write_executable(program: &Program) -> Vec<u8> {
+    const PHDR_SIZE: usize = 32;
+
+    let segments: Vec<&Segment> = program.segments.iter().filter(|s| s.mem_size > 0 || !s.data.is_empty()).collect();
+    let mut out = vec![0u8; EHDR_SIZE + segments.len() * PHDR_SIZE];
+
+    let mut shstrtab = StringTable::new();
+    let mut sections = vec![SectionHeader { name: 0, sh_type: 0, flags: 0, addr: 0, offset: 0, size: 0, link: 0, info: 0, align: 0, entsize: 0 }];
+    let mut program_headers = Vec::new();
+
+    for segment in &segments {
+        // Loaders map whole pages, so the file offset must be congruent to the address
+        while out.len() % PAGE_SIZE != segment.vaddr as usize % PAGE_SIZE {
+            out.push(0);
+        }
+        let offset = out.len();
+        out.extend_from_slice(&segment.data);
+
+        let p = segment.permissions;
+        program_headers.push((offset, segment, flag_bits(&[(p.read, PF_R), (p.write, PF_W), (p.execute, PF_X)])));
+
+        let flags = SHF_ALLOC | flag_bits(&[(p.write, SHF_WRITE), (p.execute, SHF_EXECINSTR)]);
+        let align = if p.execute { 4 } else { 1 };
+        if !segment.data.is_empty() {
+            sections.push(SectionHeader {
+                name: shstrtab.add(&format!(".{}", segment.name)),
+                sh_type: SHT_PROGBITS, flags, addr: segment.vaddr, offset, size: segment.data.len(),
+                link: 0, info: 0, align, entsize: 0,
+            });
+        }
+        let bss_size = (segment.mem_size as usize).saturating_sub(segment.data.len());
+        if bss_size > 0 {
+            sections.push(SectionHeader {
+                // a segment with no contents is a section of its own, like .bss
+                name: shstrtab.add(if segment.data.is_empty() { format!(".{}", segment.name) } else { ".bss".to_string() }.as_str()),
+                sh_type: SHT_NOBITS, flags, addr: segment.vaddr + segment.data.len() as u32,
+                offset: offset + segment.data.len(), size: bss_size, link: 0, info: 0, align: 1, entsize: 0,
+            });
+        }
+    }
+
+    // Local symbols must come before global ones; sh_info is the index of the first global
+    let mut strtab = StringTable::new();
+    let mut symbols = program.symbols.clone();
+    symbols.sort_by(|a, b| {
+        (a.binding == SymbolBinding::Global).cmp(&(b.binding == SymbolBinding::Global))
+            .then_with(|| a.address.cmp(&b.address))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    let locals = symbols.iter().take_while(|symbol| symbol.binding == SymbolBinding::Local).count();
+    let mut symtab = vec![0u8; SYM_SIZE];
+    for symbol in &symbols {
+        // a label just past the end of a section, like 'main_end:', still belongs to it
+        let shndx = sections.iter()
+            .position(|s| s.sh_type != 0 && (s.addr as u64..s.addr as u64 + s.size as u64).contains(&(symbol.address as u64)))
+            .or_else(|| sections.iter().position(|s| s.sh_type != 0 && symbol.address as u64 == s.addr as u64 + s.size as u64))
+            .filter(|_| !symbol.absolute)
+            .map_or(SHN_ABS, |index| index as u16);
+        let binding = match symbol.binding {
+            SymbolBinding::Local => STB_LOCAL,
+            SymbolBinding::Global => STB_GLOBAL,
+        };
+        let symbol_type = match symbol.symbol_type {
+            SymbolType::NoType => STT_NOTYPE,
+            SymbolType::Object => STT_OBJECT,
+            SymbolType::Function => STT_FUNC,
+        };
+        push_symbol(&mut symtab, strtab.add(&symbol.name), symbol.address, symbol.size, binding << 4 | symbol_type, shndx);
+    }
+
+    let symtab_index = sections.len() as u32;
+    let symtab_name = shstrtab.add(".symtab");
+    let strtab_name = shstrtab.add(".strtab");
+    let shstrtab_name = shstrtab.add(".shstrtab");
+
+    pad_to_word(&mut out);
+    sections.push(SectionHeader {
+        name: symtab_name, sh_type: SHT_SYMTAB, flags: 0, addr: 0, offset: out.len(), size: symtab.len(),
+        link: symtab_index + 1, info: locals as u32 + 1, align: 4, entsize: SYM_SIZE as u32,
+    });
+    out.extend_from_slice(&symtab);
+    sections.push(SectionHeader {
+        name: strtab_name, sh_type: SHT_STRTAB, flags: 0, addr: 0, offset: out.len(), size: strtab.bytes.len(),
+        link: 0, info: 0, align: 1, entsize: 0,
+    });
+    out.extend_from_slice(&strtab.bytes);
+    let shstrtab_index = sections.len() as u16;
+    sections.push(SectionHeader {
+        name: shstrtab_name, sh_type: SHT_STRTAB, flags: 0, addr: 0, offset: out.len(), size: shstrtab.bytes.len(),
+        link: 0, info: 0, align: 1, entsize: 0,
+    });
+    out.extend_from_slice(&shstrtab.bytes);
+
+    let shoff = push_section_headers(&mut out, &sections);
+
+    let mut phdrs = Vec::new();
+    for (offset, segment, flags) in program_headers {
+        push_u32(&mut phdrs, PT_LOAD);
+        push_u32(&mut phdrs, offset as u32);
+        push_u32(&mut phdrs, segment.vaddr);                // p_vaddr
+        push_u32(&mut phdrs, segment.vaddr);                // p_paddr
+        push_u32(&mut phdrs, segment.data.len() as u32);    // p_filesz
+        push_u32(&mut phdrs, segment.mem_size.max(segment.data.len() as u32));
+        push_u32(&mut phdrs, flags);
+        push_u32(&mut phdrs, PAGE_SIZE as u32);
+    }
+    out[EHDR_SIZE..EHDR_SIZE + phdrs.len()].copy_from_slice(&phdrs);
+
+    let ehdr = elf_header(ET_EXEC, program.entry, (PHDR_SIZE, segments.len()), shoff, sections.len(), shstrtab_index);
+    out[..EHDR_SIZE].copy_from_slice(&ehdr);
+
+    out
+}
+
+pub fn save_relocatable(object: &Relocatable, path: impl AsRef<Path>) -> std::io::Result<()> {
+    std::fs::write(path, write_relocatable(object))
+}
+
+/// Serializes an object as an ELF32 RISC-V relocatable file: a section per
+/// section of the object, a `.rela` section with the relocations of each one
+/// that has some, and `.symtab`/`.strtab`/`.shstrtab`. Symbols the object does
+/// not define are global and undefined, for the linker to find elsewhere.
+pub fn write_relocatable(object: &Relocatable) -> Vec<u8> {
+    let mut out = vec![0u8; EHDR_SIZE];
+    let mut shstrtab = StringTable::new();
+    let mut sections = vec![SectionHeader { name: 0, sh_type: 0, flags: 0, addr: 0, offset: 0, size: 0, link: 0, info: 0, align: 0, entsize: 0 }];
+
+    for section in &object.sections {
+        while !out.len().is_multiple_of(section.alignment.max(1) as usize) {
+            out.push(0);
+        }
+        let p = section.permissions;
+        sections.push(SectionHeader {
+            name: shstrtab.add(&section.name),
+            sh_type: if section.nobits { SHT_NOBITS } else { SHT_PROGBITS },
+            flags: SHF_ALLOC | flag_bits(&[(p.write, SHF_WRITE), (p.execute, SHF_EXECINSTR)]),
+            addr: 0, offset: out.len(), size: section.size as usize, link: 0, info: 0, align: section.alignment.max(1), entsize: 0,
+        });
+        out.extend_from_slice(&section.data);
+    }
+    let section_index = |name: &str| object.sections.iter().position(|section| section.name == name).map(|index| index as u16 + 1);
+
+    // Local symbols must come before global ones; sh_info is the index of the first global
+    let mut symbols: Vec<_> = object.symbols.iter().collect();
+    symbols.sort_by_key(|symbol| symbol.binding == SymbolBinding::Global);
+    let locals = symbols.iter().take_while(|symbol| symbol.binding == SymbolBinding::Local).count();
+    let mut strtab = StringTable::new();
+    let mut symtab = vec![0u8; SYM_SIZE];
+    for symbol in &symbols {
+        let shndx = match &symbol.section {
+            SymbolSection::Section(name) => section_index(name).unwrap_or(SHN_UNDEF),
+            SymbolSection::Absolute => SHN_ABS,
+            SymbolSection::Undefined => SHN_UNDEF,
+        };
+        let binding = match symbol.binding {
+            SymbolBinding::Local => STB_LOCAL,
+            SymbolBinding::Global => STB_GLOBAL,
+        };
+        let symbol_type = match symbol.symbol_type {
+            SymbolType::NoType => STT_NOTYPE,
+            SymbolType::Object => STT_OBJECT,
+            SymbolType::Function => STT_FUNC,
+        };
+        push_symbol(&mut symtab, strtab.add(&symbol.name), symbol.value, symbol.size, binding << 4 | symbol_type, shndx);
+    }
+    let symbol_index = |name: &str| symbols.iter().position(|symbol| symbol.name == name).map_or(0, |index| index as u32 + 1);
+
+    let targets: Vec<&str> = object.sections.iter()
+        .map(|section| section.name.as_str())
+        .filter(|&name| object.relocations.iter().any(|relocation| relocation.section == name))
+        .collect();
+    let symtab_index = (sections.len() + targets.len()) as u32;
+    for target in targets {
+        let mut rela = Vec::new();
+        for relocation in object.relocations.iter().filter(|relocation| relocation.section == target) {
+            push_u32(&mut rela, relocation.offset);
+            push_u32(&mut rela, symbol_index(&relocation.symbol) << 8 | relocation_type(relocation.kind));
+            push_u32(&mut rela, relocation.addend as u32);
+        }
+        pad_to_word(&mut out);
+        sections.push(SectionHeader {
+            name: shstrtab.add(&format!(".rela{}", target)), sh_type: SHT_RELA, flags: SHF_INFO_LINK, addr: 0,
+            offset: out.len(), size: rela.len(), link: symtab_index, info: section_index(target).unwrap_or(0) as u32,
+            align: 4, entsize: RELA_SIZE as u32,
+        });
+        out.extend_from_slice(&rela);
+    }
+
+    let symtab_name = shstrtab.add(".symtab");
+    let strtab_name = shstrtab.add(".strtab");
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    pad_to_word(&mut out);
+    sections.push(SectionHeader {
+        name: symtab_name, sh_type: SHT_SYMTAB, flags: 0, addr: 0, offset: out.len(), size: symtab.len(),
+        link: symtab_index + 1, info: locals as u32 + 1, align: 4, entsize: SYM_SIZE as u32,
+    });
+    out.extend_from_slice(&symtab);
+    sections.push(SectionHeader {
+        name: strtab_name, sh_type: SHT_STRTAB, flags: 0, addr: 0, offset: out.len(), size: strtab.bytes.len(),
+        link: 0, info: 0, align: 1, entsize: 0,
+    });
+    out.extend_from_slice(&strtab.bytes);
+    let shstrtab_index = sections.len() as u16;
+    sections.push(SectionHeader {
+        name: shstrtab_name, sh_type: SHT_STRTAB, flags: 0, addr: 0, offset: out.len(), size: shstrtab.bytes.len(),
+        link: 0, info: 0, align: 1, entsize: 0,
+    });
+    out.extend_from_slice(&shstrtab.bytes);
+
+    let shoff = push_section_headers(&mut out, &sections);
+    let ehdr = elf_header(ET_REL, 0, (0, 0), shoff, sections.len(), shstrtab_index);
+    out[..EHDR_SIZE].copy_from_slice(&ehdr);
+
+    out
+}
+
+// The numbers the RISC-V psABI gives the relocations
+fn relocation_type(kind: RelocationKind) -> u32 {
+    match kind {
+        RelocationKind::Abs32 => 1,
+        RelocationKind::Branch => 16,
+        RelocationKind::Jal => 17,
+        RelocationKind::GotHi20 => 20,
+        RelocationKind::PcrelHi20 => 23,
+        RelocationKind::PcrelLo12I => 24,
+        RelocationKind::PcrelLo12S => 25,
+        RelocationKind::Hi20 => 26,
+        RelocationKind::Lo12I => 27,
+        RelocationKind::Lo12S => 28,
+    }
+}
+
+fn push_symbol(out: &mut Vec<u8>, name: u32, value: u32, size: u32, info: u8, shndx: u16) {
+    push_u32(out, name);
+    push_u32(out, value);
+    push_u32(out, size);
+    out.push(info);
+    out.push(0);  // STV_DEFAULT
+    push_u16(out, shndx);
+}
+
+// Appends the section header table, word aligned, and returns its offset
+fn push_section_headers(out: &mut Vec<u8>, sections: &[SectionHeader]) -> usize {
+    pad_to_word(out);
+    let shoff = out.len();
+    for section in sections {
+        push_u32(out, section.name);
+        push_u32(out, section.sh_type);
+        push_u32(out, section.flags);
+        push_u32(out, section.addr);
+        push_u32(out, section.offset as u32);
+        push_u32(out, section.size as u32);
+        push_u32(out, section.link);
+        push_u32(out, section.info);
+        push_u32(out, section.align);
+        push_u32(out, section.entsize);
+    }
+    shoff
+}
+
+// The program headers, of the given size and count, follow the ELF header
+fn elf_header(elf_type: u16, entry: u32, (phentsize, phnum): (usize, usize), shoff: usize, shnum: usize, shstrndx: u16) -> Vec<u8> {
+    let mut ehdr = Vec::with_capacity(EHDR_SIZE);
+    ehdr.extend_from_slice(b"\x7fELF");
+    ehdr.extend_from_slice(&[ELFCLASS32, ELFDATA2LSB, EV_CURRENT, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    push_u16(&mut ehdr, elf_type);
+    push_u16(&mut ehdr, EM_RISCV);
+    push_u32(&mut ehdr, EV_CURRENT as u32);
+    push_u32(&mut ehdr, entry);
+    push_u32(&mut ehdr, if phnum > 0 { EHDR_SIZE as u32 } else { 0 }); // e_phoff
+    push_u32(&mut ehdr, shoff as u32);
+    push_u32(&mut ehdr, 0);                                   // e_flags: no RVC, soft-float ABI
+    push_u16(&mut ehdr, EHDR_SIZE as u16);
+    push_u16(&mut ehdr, phentsize as u16);
+    push_u16(&mut ehdr, phnum as u16);
+    push_u16(&mut ehdr, SHDR_SIZE as u16);
+    push_u16(&mut ehdr, shnum as u16);
+    push_u16(&mut ehdr, shstrndx);
+    ehdr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a minimal executable: one RX segment, one RW segment with .bss,
+    // and a symbol table with a single `main` symbol.
+    fn sample_elf() -> Vec<u8> {
+        let mut elf = vec![0u8; 52];
+        elf[0..4].copy_from_slice(b"\x7fELF");
+        elf[4] = ELFCLASS32;
+        elf[5] = ELFDATA2LSB;
+        elf[6] = 1;
+        elf[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        elf[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        elf[24..28].copy_from_slice(&0x1_0004u32.to_le_bytes());  // e_entry
+        elf[28..32].copy_from_slice(&52u32.to_le_bytes());        // e_phoff
+        elf[42..44].copy_from_slice(&32u16.to_le_bytes());        // e_phentsize
+        elf[44..46].copy_from_slice(&2u16.to_le_bytes());         // e_phnum
+        elf[46..48].copy_from_slice(&40u16.to_le_bytes());        // e_shentsize
+
+        let text = [0x13, 0, 0, 0, 0x73, 0, 0x10, 0]; // nop; ebreak
+        let data = [1, 2, 3, 4];
+        let text_offset = 52 + 2 * 32;
+        let data_offset = text_offset + text.len();
+
+        let phdr = |offset: usize, vaddr: u32, filesz: usize, memsz: u32, flags: u32| {
+            let mut ph = vec![0u8; 32];
+            ph[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            ph[4..8].copy_from_slice(&(offset as u32).to_le_bytes());
+            ph[8..12].copy_from_slice(&vaddr.to_le_bytes());
+            ph[16..20].copy_from_slice(&(filesz as u32).to_le_bytes());
+            ph[20..24].copy_from_slice(&memsz.to_le_bytes());
+            ph[24..28].copy_from_slice(&flags.to_le_bytes());
+            ph
+        };
+        elf.extend(phdr(text_offset, 0x1_0000, text.len(), text.len() as u32, PF_R | PF_X));
+        elf.extend(phdr(data_offset, 0x2_0000, data.len(), 16, PF_R | PF_W));
+        elf.extend_from_slice(&text);
+        elf.extend_from_slice(&data);
+
+        // symbol table: null symbol + main
+        let symtab_offset = elf.len();
+        elf.extend_from_slice(&[0; 16]);
+        let mut main = [0u8; 16];
+        main[0..4].copy_from_slice(&1u32.to_le_bytes());
+        main[4..8].copy_from_slice(&0x1_0004u32.to_le_bytes());
+        main[12] = 0x12; // GLOBAL FUNC
+        main[14..16].copy_from_slice(&1u16.to_le_bytes());
+        elf.extend_from_slice(&main);
+        let strtab_offset = elf.len();
+        elf.extend_from_slice(b"\0main\0");
+
+        // section headers: null, .symtab, .strtab
+        let shoff = elf.len();
+        elf.extend_from_slice(&[0; 40]);
+        let shdr = |sh_type: u32, offset: usize, size: usize, link: u32, entsize: u32| {
+            let mut sh = vec![0u8; 40];
+            sh[4..8].copy_from_slice(&sh_type.to_le_bytes());
+            sh[16..20].copy_from_slice(&(offset as u32).to_le_bytes());
+            sh[20..24].copy_from_slice(&(size as u32).to_le_bytes());
+            sh[24..28].copy_from_slice(&link.to_le_bytes());
+            sh[36..40].copy_from_slice(&entsize.to_le_bytes());
+            sh
+        };
+        elf.extend(shdr(SHT_SYMTAB, symtab_offset, 32, 2, 16));
+        elf.extend(shdr(3, strtab_offset, 6, 0, 0));
+        elf[32..36].copy_from_slice(&(shoff as u32).to_le_bytes());
+        elf[48..50].copy_from_slice(&3u16.to_le_bytes());
+        elf
+    }
+
+    #[test]
+    fn test_parse_segments_and_entry() {
+        let program = parse(&sample_elf()).unwrap();
+        assert_eq!(program.entry, 0x1_0004);
+        assert_eq!(program.segments.len(), 2);
+
+        let text = &program.segments[0];
+        assert_eq!(text.vaddr, 0x1_0000);
+        assert_eq!(text.permissions, Permissions::RX);
+        assert_eq!(text.data, vec![0x13, 0, 0, 0, 0x73, 0, 0x10, 0]);
+
+        let data = &program.segments[1];
+        assert_eq!(data.permissions, Permissions::RW);
+        assert_eq!(data.data, vec![1, 2, 3, 4]);
+        assert_eq!(data.mem_size, 16);
+    }
+
+    #[test]
+    fn test_parse_symbols() {
+        let program = parse(&sample_elf()).unwrap();
+        assert_eq!(program.symbols, vec![ProgramSymbol {
+            name: "main".to_string(),
+            address: 0x1_0004,
+            binding: SymbolBinding::Global,
+            symbol_type: SymbolType::Function,
+            ..Default::default()
+        }]);
+    }
+
+    #[test]
+    fn test_rejects_other_machines_and_classes() {
+        let mut elf = sample_elf();
+        elf[18..20].copy_from_slice(&62u16.to_le_bytes()); // x86-64
+        assert_eq!(parse(&elf), Err(ElfError::WrongMachine(62)));
+
+        let mut elf = sample_elf();
+        elf[4] = 2;
+        assert_eq!(parse(&elf), Err(ElfError::UnsupportedClass(2)));
+
+        assert_eq!(parse(b"#!/bin/sh"), Err(ElfError::NotElf));
+    }
+
+    #[test]
+    fn test_truncated_segment() {
+        let elf = sample_elf();
+        assert!(matches!(parse(&elf[..120]), Err(ElfError::Truncated { .. })));
+    }
+
+    #[test]
+    fn test_loaded_program_runs_from_entry_with_zeroed_bss() {
+        use crate::processor::{Processor, StepError};
+
+        let mut p = Processor::new(0, 0, 0x7FFF_FFF0, 1024);
+        p.load(&parse(&sample_elf()).unwrap()).unwrap();
+        assert_eq!(p.pc(), 0x1_0004);
+        assert_eq!(p.read_memory_byte(0x2_0003), Ok(4));
+        assert_eq!(p.read_memory_byte(0x2_000F), Ok(0)); // .bss
+        assert_eq!(p.symbol_at(0x1_0004), Some("main"));
+        assert_eq!(p.step(), Err(StepError::Ebreak));
+    }
+
+    #[test]
+    fn test_written_executable_round_trips() {
+        let mut program = Program::from_sections(0x1_0000, &[0x13, 0, 0, 0, 0x73, 0, 0x10, 0], 0x1_1000, &[1, 2, 3]);
+        program.segments[1].mem_size = 64;
+        program.symbols = vec![
+            ProgramSymbol {
+                name: "main".to_string(),
+                address: 0x1_0000,
+                size: 8,
+                binding: SymbolBinding::Global,
+                symbol_type: SymbolType::Function,
+                absolute: false,
+            },
+            // a constant whose value happens to fall in .text stays absolute
+            ProgramSymbol { name: "LIMIT".to_string(), address: 0x1_0004, absolute: true, ..Default::default() },
+            ProgramSymbol { name: "buffer".to_string(), address: 0x1_1010, symbol_type: SymbolType::Object, ..Default::default() },
+        ];
+
+        let bytes = write_executable(&program);
+        let parsed = parse(&bytes).unwrap();
+        assert_eq!(parsed.entry, program.entry);
+        assert_eq!(parsed.segments, program.segments);
+        assert_eq!(parsed.symbols, program.symbols);
+
+        // segment file offsets are congruent to their addresses modulo the page size
+        let data_offset = u32::from_le_bytes(bytes[52 + 32 + 4..52 + 32 + 8].try_into().unwrap());
+        assert_eq!(data_offset % 0x1000, 0);
+    }
+
+    #[test]
+    fn test_written_relocatable() {
+        use crate::program::{ObjectSection, ObjectSymbol, Relocation};
+
+        let object = Relocatable {
+            sections: vec![ObjectSection {
+                name: ".text".to_string(), data: vec![0x97, 0, 0, 0, 0x67, 0x80, 0, 0], size: 8, alignment: 4,
+                permissions: Permissions::RX, nobits: false,
+            }],
+            symbols: vec![
+                ObjectSymbol {
+                    name: "helper".to_string(), value: 0, size: 0, binding: SymbolBinding::Global,
+                    symbol_type: SymbolType::NoType, section: SymbolSection::Undefined,
+                },
+                ObjectSymbol {
+                    name: "main".to_string(), value: 0, size: 8, binding: SymbolBinding::Local,
+                    symbol_type: SymbolType::Function, section: SymbolSection::Section(".text".to_string()),
+                },
+            ],
+            relocations: vec![Relocation {
+                section: ".text".to_string(), offset: 0, kind: RelocationKind::PcrelHi20, symbol: "helper".to_string(), addend: 0,
+            }],
+        };
+        let bytes = write_relocatable(&object);
+        let r = Reader { bytes: &bytes };
+        assert_eq!((r.u16(16).unwrap(), r.u16(18).unwrap(), r.u16(44).unwrap()), (ET_REL, EM_RISCV, 0));
+
+        // null, .text, .rela.text, .symtab, .strtab, .shstrtab
+        let shoff = r.u32(32).unwrap() as usize;
+        let shstrtab = shoff + r.u16(50).unwrap() as usize * SHDR_SIZE;
+        let names: Vec<String> = (0..r.u16(48).unwrap() as usize)
+            .map(|i| r.c_string(r.u32(shstrtab + 16).unwrap() as usize + r.u32(shoff + i * SHDR_SIZE).unwrap() as usize).unwrap())
+            .collect();
+        assert_eq!(names, ["", ".text", ".rela.text", ".symtab", ".strtab", ".shstrtab"]);
+        let rela = shoff + 2 * SHDR_SIZE;
+        assert_eq!((r.u32(rela + 4).unwrap(), r.u32(rela + 24).unwrap(), r.u32(rela + 28).unwrap()), (SHT_RELA, 3, 1));
+
+        // the local 'main' comes first, so 'helper' is symbol 2
+        let entry = r.u32(rela + 16).unwrap() as usize;
+        assert_eq!(r.u32(entry + 4).unwrap(), 2 << 8 | 23);
+        let symtab = shoff + 3 * SHDR_SIZE;
+        assert_eq!(r.u32(symtab + 28).unwrap(), 2); // the first global
+        let helper = r.u32(symtab + 16).unwrap() as usize + 2 * SYM_SIZE;
+        assert_eq!((r.u8(helper + 12).unwrap(), r.u16(helper + 14).unwrap()), (STB_GLOBAL << 4, SHN_UNDEF));
+    }
+
+    #[test]
+    fn test_written_executable_skips_empty_segments() {
+        let program = Program::from_sections(0x1_0000, &[0x13, 0, 0, 0], 0x1_1000, &[]);
+        let parsed = parse(&write_executable(&program)).unwrap();
+        assert_eq!(parsed.segments.len(), 1);
+        assert_eq!(parsed.segments[0].name, "text");
+    }
+}
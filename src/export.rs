@@ -0,0 +1,227 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::elf;
+use crate::program::{Program, Segment};
+
+/// File formats the assembled program can be written in. Every format keeps
+/// the segment addresses (`text_base`, `data_base`) except the raw binaries,
+/// which are one file per section.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Binary,
+    IntelHex,
+    SRecord,
+    Readmemh,
+    Elf,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bin" | "binary" => Some(OutputFormat::Binary),
+            "ihex" | "hex" => Some(OutputFormat::IntelHex),
+            "srec" => Some(OutputFormat::SRecord),
+            "readmemh" | "vmem" => Some(OutputFormat::Readmemh),
+            "elf" => Some(OutputFormat::Elf),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Binary => "bin",
+            OutputFormat::IntelHex => "hex",
+            OutputFormat::SRecord => "srec",
+            OutputFormat::Readmemh => "mem",
+            OutputFormat::Elf => "elf",
+        }
+    }
+}
+
+/// Writes `program` to `path` and returns the files written. Raw binaries
+/// are written next to `path` as `<stem>.<section>.bin`.
+pub fn save(program: &Program, format: OutputFormat, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let files = match format {
+        OutputFormat::Binary => binaries(program).into_iter()
+            .map(|(name, bytes)| (path.with_extension(format!("{}.bin", name)), bytes))
+            .collect(),
+        OutputFormat::IntelHex => vec![(path.to_path_buf(), intel_hex(program).into_bytes())],
+        OutputFormat::SRecord => vec![(path.to_path_buf(), srecord(program).into_bytes())],
+        OutputFormat::Readmemh => vec![(path.to_path_buf(), readmemh(program).into_bytes())],
+        OutputFormat::Elf => vec![(path.to_path_buf(), elf::write_executable(program))],
+    };
+    for (file, bytes) in &files {
+        std::fs::write(file, bytes)?;
+    }
+    Ok(files.into_iter().map(|(file, _)| file).collect())
+}
+
+// Zero-filled tails like .bss have no bytes in the file formats
+fn initialized(program: &Program) -> impl Iterator<Item = &Segment> {
+    program.segments.iter().filter(|segment| !segment.data.is_empty())
+}
+
+/// The initialized bytes of each segment, by segment name.
+pub fn binaries(program: &Program) -> Vec<(String, Vec<u8>)> {
+    initialized(program).map(|segment| (segment.name.clone(), segment.data.clone())).collect()
+}
+
+/// Intel HEX with 16-byte data records, extended linear address records for
+/// the upper 16 address bits and a start linear address record for the entry.
+pub fn intel_hex(program: &Program) -> String {
+    fn record(out: &mut String, kind: u8, address: u16, data: &[u8]) {
+        let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+        bytes.extend_from_slice(data);
+        let checksum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg();
+        bytes.push(checksum);
+        out.push(':');
+        for byte in bytes {
+            let _ = write!(out, "{:02X}", byte);
+        }
+        out.push('\n');
+    }
+
+    let mut out = String::new();
+    let mut upper = None;
+    for segment in initialized(program) {
+        for (i, chunk) in segment.data.chunks(16).enumerate() {
+            let address = segment.vaddr.wrapping_add(i as u32 * 16);
+            // a record cannot cross a 64 KiB boundary, so split it there
+            let split = chunk.len().min((0x1_0000 - (address & 0xFFFF)) as usize);
+            for (address, part) in [(address, &chunk[..split]), (address.wrapping_add(split as u32), &chunk[split..])] {
+                if part.is_empty() {
+                    continue;
+                }
+                if upper != Some(address >> 16) {
+                    upper = Some(address >> 16);
+                    record(&mut out, 0x04, 0, &((address >> 16) as u16).to_be_bytes());
+                }
+                record(&mut out, 0x00, address as u16, part);
+            }
+        }
+    }
+    record(&mut out, 0x05, 0, &program.entry.to_be_bytes());
+    record(&mut out, 0x01, 0, &[]);
+    out
+}
+
+/// Motorola S-record with 32-bit addresses: an S0 header, S3 data records,
+/// an S5 record count and an S7 record holding the entry point.
+pub fn srecord(program: &Program) -> String {
+    fn record(out: &mut String, kind: char, address: &[u8], data: &[u8]) {
+        let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+        bytes.extend_from_slice(address);
+        bytes.extend_from_slice(data);
+        let checksum = !bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(checksum);
+        out.push('S');
+        out.push(kind);
+        for byte in bytes {
+            let _ = write!(out, "{:02X}", byte);
+        }
+        out.push('\n');
+    }
+
+    let mut out = String::new();
+    record(&mut out, '0', &[0, 0], b"ruscv");
+    let mut count = 0u32;
+    for segment in initialized(program) {
+        for (i, chunk) in segment.data.chunks(16).enumerate() {
+            let address = segment.vaddr.wrapping_add(i as u32 * 16);
+            record(&mut out, '3', &address.to_be_bytes(), chunk);
+            count += 1;
+        }
+    }
+    // S5 only holds 16 bits; larger counts are left out, as the record is optional
+    if count <= 0xFFFF {
+        record(&mut out, '5', &(count as u16).to_be_bytes(), &[]);
+    }
+    record(&mut out, '7', &program.entry.to_be_bytes(), &[]);
+    out
+}
+
+/// Verilog `$readmemh` input for a memory of 32-bit words: an `@` word
+/// address (byte address / 4) per segment, then one little-endian word per
+/// line, padded with zeros to a whole word.
+pub fn readmemh(program: &Program) -> String {
+    let mut out = String::new();
+    for segment in initialized(program) {
+        let _ = writeln!(out, "// {} at 0x{:08x}", segment.name, segment.vaddr);
+        let _ = writeln!(out, "@{:08x}", segment.vaddr / 4);
+        // a segment that is not word aligned starts with padding bytes
+        let padding = (segment.vaddr % 4) as usize;
+        let mut bytes = vec![0u8; padding];
+        bytes.extend_from_slice(&segment.data);
+        for word in bytes.chunks(4) {
+            let mut padded = [0u8; 4];
+            padded[..word.len()].copy_from_slice(word);
+            let _ = writeln!(out, "{:08x}", u32::from_le_bytes(padded));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program() -> Program {
+        Program::from_sections(0x10000, &[0x13, 0x05, 0x10, 0x00, 0x73, 0x00, 0x10, 0x00], 0x110000, b"Hi\0")
+    }
+
+    #[test]
+    fn test_intel_hex_records() {
+        let hex = intel_hex(&program());
+        let lines: Vec<&str> = hex.lines().collect();
+        assert_eq!(lines, [
+            ":020000040001F9",
+            ":0800000013051000730010004D",
+            ":020000040011E9",
+            ":030000004869004C",
+            ":0400000500010000F6",
+            ":00000001FF",
+        ]);
+
+        // records are split where they would cross a 64 KiB boundary
+        let program = Program::from_sections(0xFFF8, &[0u8; 16], 0x20000, &[]);
+        let hex = intel_hex(&program);
+        assert!(hex.contains(":08FFF800"));
+        assert!(hex.contains(":020000040001F9\n:08000000"));
+    }
+
+    #[test]
+    fn test_srecord_records() {
+        let srec = srecord(&program());
+        let lines: Vec<&str> = srec.lines().collect();
+        assert_eq!(lines, [
+            "S00800007275736376C4",
+            "S30D00010000130510007300100046",
+            "S3080011000048690035",
+            "S5030002FA",
+            "S70500010000F9",
+        ]);
+    }
+
+    #[test]
+    fn test_readmemh_words() {
+        let mem = readmemh(&program());
+        assert_eq!(mem, "\
+// text at 0x00010000
+@00004000
+00100513
+00100073
+// data at 0x00110000
+@00044000
+00006948
+");
+    }
+
+    #[test]
+    fn test_binaries_skip_empty_sections() {
+        let program = Program::from_sections(0x10000, &[1, 2, 3, 4], 0x110000, &[]);
+        assert_eq!(binaries(&program), [("text".to_string(), vec![1, 2, 3, 4])]);
+        assert_eq!(OutputFormat::from_name("vmem"), Some(OutputFormat::Readmemh));
+        assert_eq!(OutputFormat::from_name("coff"), None);
+    }
+}
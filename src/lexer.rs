@@ -1,5 +1,7 @@
 use std::fmt;
 
+use crate::source::FileId;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum LexErrorKind {
     Expected(char),
@@ -17,6 +19,7 @@ pub enum LexErrorKind {
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct LexError {
+    pub file: FileId,
     pub line: usize,
     pub column: usize,
     pub kind: LexErrorKind,
@@ -24,7 +27,18 @@ pub struct LexError {
 
 impl LexError {
     pub fn new(line: usize, column: usize, kind: LexErrorKind) -> Self {
-        Self { line, column, kind }
+        Self { file: 0, line, column, kind }
+    }
+
+    /// The columns the offending text takes from `column`, as for the whole of an invalid number.
+    pub fn width(&self) -> usize {
+        match &self.kind {
+            LexErrorKind::InvalidNumber(text) | LexErrorKind::EmptyNumberPrefix(text)
+            | LexErrorKind::InvalidRegister(text) | LexErrorKind::NumericOverflow(text) => text.chars().count(),
+            LexErrorKind::UnknownModifier(name) => name.chars().count() + 1, // and the '%'
+            LexErrorKind::UnknownEscapeSequence(_) => 2,
+            _ => 1,
+        }
     }
 }
 
@@ -46,17 +60,92 @@ impl fmt::Display for LexError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SpannedToken {
     pub token: Token,
     pub line: usize,
     pub column: usize,
+    pub width: usize, // in columns, 0 for the end of the file
+    pub file: FileId,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum ModifierKind {
     Hi,
     Lo,
+    PcrelHi,    // upper bits of the offset from the auipc to a symbol
+    PcrelLo,    // lower bits of that offset, given the label of the auipc
+    GotPcrelHi, // upper bits of the offset from the auipc to the symbol's GOT entry
+}
+
+impl ModifierKind {
+    /// The part of `value` the modifier selects. `%hi` is rounded up when
+    /// bit 11 is set, so that adding the sign-extended `%lo` gives `value` back.
+    /// For the PC-relative modifiers `value` is the offset from the `auipc`.
+    pub fn apply(&self, value: i32) -> i32 {
+        match self {
+            ModifierKind::Hi | ModifierKind::PcrelHi | ModifierKind::GotPcrelHi => ((value as u32 as i64 + 0x800) >> 12) as i32,
+            ModifierKind::Lo | ModifierKind::PcrelLo => (value << 20) >> 20,
+        }
+    }
+
+    /// Whether the value depends on the address of the instruction, so only
+    /// the assembler can work it out.
+    pub fn is_pc_relative(&self) -> bool {
+        !matches!(self, ModifierKind::Hi | ModifierKind::Lo)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operator {
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    ShiftLeft,
+    ShiftRight,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
+    Not,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    LogicalAnd,
+    LogicalOr,
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Operator::Plus => "+",
+            Operator::Minus => "-",
+            Operator::Star => "*",
+            Operator::Slash => "/",
+            Operator::Percent => "%",
+            Operator::ShiftLeft => "<<",
+            Operator::ShiftRight => ">>",
+            Operator::Ampersand => "&",
+            Operator::Pipe => "|",
+            Operator::Caret => "^",
+            Operator::Tilde => "~",
+            Operator::Not => "!",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::Less => "<",
+            Operator::LessEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterEqual => ">=",
+            Operator::LogicalAnd => "&&",
+            Operator::LogicalOr => "||",
+        };
+        write!(f, "{}", symbol)
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -66,7 +155,11 @@ pub enum Token {
     Immediate(i32),
     StringLiteral(String),
     Label(String),
-    Modifier(ModifierKind, String),
+    Modifier(ModifierKind), // followed by the parenthesized expression it applies to
+    Operator(Operator),
+    Attribute(String), // '@function' in '.type main, @function'
+    MacroText(String), // a word with macro argument references, like '\\reg' or 'loop\\@'
+    Equals,
     Colon,
     Directive(String),
     Comma,
@@ -76,11 +169,67 @@ pub enum Token {
     Eof,
 }
 
-pub fn tokenize(source: &str) -> Result<Vec<SpannedToken>, LexError> {
+// Tokens print as source text, so that macro arguments can be pasted into words
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Instruction(s) | Token::Label(s) | Token::Directive(s) | Token::MacroText(s) => write!(f, "{}", s),
+            Token::Register(n) => write!(f, "x{}", n),
+            Token::Immediate(n) => write!(f, "{}", n),
+            Token::StringLiteral(s) => {
+                let escaped = s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\t', "\\t");
+                write!(f, "\"{}\"", escaped)
+            }
+            Token::Modifier(ModifierKind::Hi) => write!(f, "%hi"),
+            Token::Modifier(ModifierKind::Lo) => write!(f, "%lo"),
+            Token::Modifier(ModifierKind::PcrelHi) => write!(f, "%pcrel_hi"),
+            Token::Modifier(ModifierKind::PcrelLo) => write!(f, "%pcrel_lo"),
+            Token::Modifier(ModifierKind::GotPcrelHi) => write!(f, "%got_pcrel_hi"),
+            Token::Operator(op) => write!(f, "{}", op),
+            Token::Attribute(s) => write!(f, "@{}", s),
+            Token::Equals => write!(f, "="),
+            Token::Colon => write!(f, ":"),
+            Token::Comma => write!(f, ","),
+            Token::LParenthesis => write!(f, "("),
+            Token::RParenthesis => write!(f, ")"),
+            Token::Newline => writeln!(f),
+            Token::Eof => Ok(()),
+        }
+    }
+}
+
+/// Splits source text into tokens. A line with an error is left out and
+/// lexing goes on with the next one, so that every such line is reported.
+pub fn tokenize(source: &str) -> Result<Vec<SpannedToken>, Vec<LexError>> {
+    tokenize_file(source, 0)
+}
+
+/// Like `tokenize`, for one of several source files: the tokens and any
+/// error carry `file`.
+pub fn tokenize_file(source: &str, file: FileId) -> Result<Vec<SpannedToken>, Vec<LexError>> {
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    for (index, text) in source.split_inclusive('\n').enumerate() {
+        match tokenize_line(text, index + 1, file) {
+            Ok(line) => tokens.extend(line),
+            Err(e) => errors.push(LexError { file, ..e }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let line = source.matches('\n').count() + 1;
+    let column = source.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+    tokens.push(SpannedToken { token: Token::Eof, line, column, width: 0, file });
+    Ok(tokens)
+}
+
+// Tokens never span lines, so each is lexed on its own
+fn tokenize_line(text: &str, line: usize, file: FileId) -> Result<Vec<SpannedToken>, LexError> {
     let mut tokens = Vec::new();
-    let mut line = 1;
     let mut column = 1;
-    let mut chars = source.chars().peekable();
+    let mut chars = text.chars().peekable();
 
     while let Some(char) = chars.next() {
         match char {
@@ -93,9 +242,9 @@ pub fn tokenize(source: &str) -> Result<Vec<SpannedToken>, LexError> {
                     token: Token::Newline,
                     line,
                     column,
+                    width: 1,
+                    file,
                 });
-                line += 1;
-                column = 1;
             }
             '#' => {
                 column += 1;
@@ -113,6 +262,8 @@ pub fn tokenize(source: &str) -> Result<Vec<SpannedToken>, LexError> {
                     token: Token::Colon,
                     line,
                     column,
+                    width: 1,
+                    file,
                 });
                 column += 1;
             }
@@ -121,6 +272,8 @@ pub fn tokenize(source: &str) -> Result<Vec<SpannedToken>, LexError> {
                     token: Token::Comma,
                     line,
                     column,
+                    width: 1,
+                    file,
                 });
                 column += 1;
             }
@@ -129,6 +282,8 @@ pub fn tokenize(source: &str) -> Result<Vec<SpannedToken>, LexError> {
                     token: Token::LParenthesis,
                     line,
                     column,
+                    width: 1,
+                    file,
                 });
                 column += 1;
             }
@@ -137,36 +292,105 @@ pub fn tokenize(source: &str) -> Result<Vec<SpannedToken>, LexError> {
                     token: Token::RParenthesis,
                     line,
                     column,
+                    width: 1,
+                    file,
                 });
                 column += 1;
             }
             '.' => {
                 let start_column = column;
                 let token = read_directive(&mut chars, line, &mut column, start_column)?;
-                tokens.push(token);
+                tokens.push(SpannedToken { token, line, column: start_column, width: column - start_column, file });
             }
             '"' => {
                 let start_column = column;
-                let token = read_string_literal(&mut chars, line, &mut column, start_column)?;
-                tokens.push(token);
+                let token = read_string_literal(&mut chars, line, &mut column)?;
+                tokens.push(SpannedToken { token, line, column: start_column, width: column - start_column, file });
             }
-            '%' => {
+            // A '%' right after a value is the remainder operator, as in 'N%size'
+            '%' if !follows_value(&tokens) && chars.peek().is_some_and(|c| c.is_alphabetic()) => {
                 let start_column = column;
                 let token = read_modifier(&mut chars, line, &mut column, start_column)?;
-                tokens.push(token);
+                tokens.push(SpannedToken { token, line, column: start_column, width: column - start_column, file });
+            }
+            // A '-' right after a value is a subtraction, elsewhere it starts a negative number or negates
+            '-' if follows_value(&tokens) || !chars.peek().is_some_and(|c| c.is_ascii_digit()) => {
+                tokens.push(SpannedToken { token: Token::Operator(Operator::Minus), line, column, width: 1, file });
+                column += 1;
+            }
+            '+' | '*' | '/' | '%' | '&' | '|' | '^' | '~' | '!' | '<' | '>' | '=' => {
+                let start_column = column;
+                column += 1;
+                let next = chars.peek().copied();
+                let (token, pair) = match (char, next) {
+                    ('&', Some('&')) => (Token::Operator(Operator::LogicalAnd), true),
+                    ('|', Some('|')) => (Token::Operator(Operator::LogicalOr), true),
+                    ('<', Some('<')) => (Token::Operator(Operator::ShiftLeft), true),
+                    ('>', Some('>')) => (Token::Operator(Operator::ShiftRight), true),
+                    ('<', Some('=')) => (Token::Operator(Operator::LessEqual), true),
+                    ('>', Some('=')) => (Token::Operator(Operator::GreaterEqual), true),
+                    ('=', Some('=')) => (Token::Operator(Operator::Equal), true),
+                    ('!', Some('=')) => (Token::Operator(Operator::NotEqual), true),
+                    ('+', _) => (Token::Operator(Operator::Plus), false),
+                    ('*', _) => (Token::Operator(Operator::Star), false),
+                    ('/', _) => (Token::Operator(Operator::Slash), false),
+                    ('%', _) => (Token::Operator(Operator::Percent), false),
+                    ('&', _) => (Token::Operator(Operator::Ampersand), false),
+                    ('|', _) => (Token::Operator(Operator::Pipe), false),
+                    ('^', _) => (Token::Operator(Operator::Caret), false),
+                    ('~', _) => (Token::Operator(Operator::Tilde), false),
+                    ('!', _) => (Token::Operator(Operator::Not), false),
+                    ('<', _) => (Token::Operator(Operator::Less), false),
+                    ('>', _) => (Token::Operator(Operator::Greater), false),
+                    _ => (Token::Equals, false),
+                };
+                if pair {
+                    chars.next();
+                    column += 1;
+                }
+                tokens.push(SpannedToken { token, line, column: start_column, width: column - start_column, file });
+            }
+            '\\' => {
+                let start_column = column;
+                column += 1;
+                let text = consume_macro_text(&mut chars, line, &mut column, "\\".to_string())?;
+                tokens.push(SpannedToken { token: Token::MacroText(text), line, column: start_column, width: column - start_column, file });
+            }
+            '@' if chars.peek().is_some_and(|c| c.is_alphabetic()) => {
+                let start_column = column;
+                let first = chars.next().unwrap();
+                column += 1;
+                let name = consume_identifier(&mut chars, &mut column, first);
+                tokens.push(SpannedToken { token: Token::Attribute(name.to_lowercase()), line, column: start_column, width: column - start_column, file });
+            }
+            '\'' => {
+                let start_column = column;
+                let token = read_char_literal(&mut chars, line, &mut column)?;
+                tokens.push(SpannedToken { token, line, column: start_column, width: column - start_column, file });
+            }
+            '0'..='9' if let Some(name) = read_local_label(&mut chars, &mut column, char) => {
+                let start_column = column - name.len();
+                tokens.push(SpannedToken { token: Token::Label(name), line, column: start_column, width: column - start_column, file });
             }
             '0'..='9' | '-' => {
                 let start_column = column;
                 let token = read_number(&mut chars, line, &mut column, start_column, char)?;
-                tokens.push(token);
+                tokens.push(SpannedToken { token, line, column: start_column, width: column - start_column, file });
             }
             'A'..='Z' | 'a'..='z' | '_' => {
                 let start_column = column;
                 let identifier = consume_identifier(&mut chars, &mut column, char);
+                if chars.peek() == Some(&'\\') {
+                    let text = consume_macro_text(&mut chars, line, &mut column, identifier)?;
+                    tokens.push(SpannedToken { token: Token::MacroText(text), line, column: start_column, width: column - start_column, file });
+                    continue;
+                }
                 tokens.push(SpannedToken {
                     token: classify_identifier(&identifier, line, start_column)?,
                     line,
                     column: start_column,
+                    width: column - start_column,
+                    file,
                 });
             }
             _ => {
@@ -175,15 +399,17 @@ pub fn tokenize(source: &str) -> Result<Vec<SpannedToken>, LexError> {
         }
     }
 
-    tokens.push(SpannedToken {
-        token: Token::Eof,
-        line,
-        column,
-    });
-
     Ok(tokens)
 }
 
+// Whether the last token ends a value, so that a following '-' subtracts from it
+fn follows_value(tokens: &[SpannedToken]) -> bool {
+    matches!(
+        tokens.last().map(|t| &t.token),
+        Some(Token::Immediate(_) | Token::Label(_) | Token::MacroText(_) | Token::RParenthesis)
+    )
+}
+
 fn expect_char(
     chars: &mut std::iter::Peekable<std::str::Chars>,
     line: usize,
@@ -220,21 +446,67 @@ fn consume_identifier(
     identifier
 }
 
+// Reads the rest of a word made of identifier characters and the macro
+// references '\\name', '\\@' and '\\()'. `text` is what was read so far,
+// ending with the backslash when called right after one.
+fn consume_macro_text(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    line: usize,
+    column: &mut usize,
+    mut text: String,
+) -> Result<String, LexError> {
+    loop {
+        if text.ends_with('\\') {
+            match chars.peek() {
+                Some(&'@') => {
+                    chars.next();
+                    *column += 1;
+                    text.push('@');
+                }
+                Some(&'(') => {
+                    chars.next();
+                    *column += 1;
+                    expect_char(chars, line, column, ')')?;
+                    text.push_str("()");
+                }
+                Some(&c) if c.is_alphabetic() || c == '_' => {}
+                Some(&c) => return Err(LexError::new(line, *column, LexErrorKind::UnexpectedChar(c))),
+                None => return Err(LexError::new(line, *column, LexErrorKind::UnexpectedEof)),
+            }
+        }
+        match chars.peek() {
+            Some(&c) if c.is_alphanumeric() || c == '_' || c == '\\' => {
+                text.push(c);
+                chars.next();
+                *column += 1;
+            }
+            _ => return Ok(text),
+        }
+    }
+}
+
 fn read_directive(
     chars: &mut std::iter::Peekable<std::str::Chars>,
     line: usize,
     column: &mut usize,
     start_column: usize,
-) -> Result<SpannedToken, LexError> {
-    let directive = consume_identifier(chars, column, '.');
+) -> Result<Token, LexError> {
+    let mut directive = consume_identifier(chars, column, '.');
+    // Section names go on with more dotted parts, as in '.text.startup'
+    while directive.len() > 1 && chars.peek() == Some(&'.') {
+        let mut lookahead = chars.clone();
+        lookahead.next();
+        if !lookahead.next().is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            break;
+        }
+        chars.next();
+        let part = consume_identifier(chars, column, '.');
+        directive.push_str(&part);
+    }
     if directive.len() == 1 {
         return Err(LexError::new(line, start_column, LexErrorKind::EmptyDirective));
     }
-    Ok(SpannedToken {
-        token: Token::Directive(directive.to_lowercase()),
-        line,
-        column: start_column,
-    })
+    Ok(Token::Directive(directive.to_lowercase()))
 }
 
 fn skip_whitespace(
@@ -256,44 +528,35 @@ fn read_modifier(
     line: usize,
     column: &mut usize,
     start_column: usize,
-) -> Result<SpannedToken, LexError> {
+) -> Result<Token, LexError> {
     let first = chars.next().ok_or(LexError::new(line, *column, LexErrorKind::UnexpectedEof))?;
     *column += 1;
     let kind_str = consume_identifier(chars, column, first);
     let kind = match kind_str.as_str() {
         "hi" => ModifierKind::Hi,
         "lo" => ModifierKind::Lo,
+        "pcrel_hi" => ModifierKind::PcrelHi,
+        "pcrel_lo" => ModifierKind::PcrelLo,
+        "got_pcrel_hi" => ModifierKind::GotPcrelHi,
         other => return Err(LexError::new(line, start_column, LexErrorKind::UnknownModifier(other.to_string()))),
     };
 
+    // The expression in parentheses is lexed as usual tokens; only check it is there
     skip_whitespace(chars, column);
-    expect_char(chars, line, column, '(')?;
-    skip_whitespace(chars, column);
-
-    let first = chars.next().ok_or(LexError::new(line, *column, LexErrorKind::UnexpectedEof))?;
-    if !first.is_alphabetic() && first != '_' {
-        return Err(LexError::new(line, *column, LexErrorKind::UnexpectedChar(first)));
+    match chars.peek() {
+        Some('(') => {}
+        Some(_) => return Err(LexError::new(line, *column, LexErrorKind::Expected('('))),
+        None => return Err(LexError::new(line, *column, LexErrorKind::UnexpectedEof)),
     }
 
-    let symbol = consume_identifier(chars, column, first);
-
-    skip_whitespace(chars, column);
-    expect_char(chars, line, column, ')')?;
-    skip_whitespace(chars, column);
-
-    Ok(SpannedToken {
-        token: Token::Modifier(kind, symbol),
-        line,
-        column: start_column,
-    })
+    Ok(Token::Modifier(kind))
 }
 
 fn read_string_literal(
     chars: &mut std::iter::Peekable<std::str::Chars>,
     line: usize,
     column: &mut usize,
-    start_column: usize,
-) -> Result<SpannedToken, LexError> {
+) -> Result<Token, LexError> {
     let mut string_literal = String::new();
     let mut unterminated = true;
     *column += 1;
@@ -311,7 +574,7 @@ fn read_string_literal(
                     't' => string_literal.push('\t'),
                     '\\' => string_literal.push('\\'),
                     '"' => string_literal.push('"'),
-                    _ => return Err(LexError::new(line, *column, LexErrorKind::UnknownEscapeSequence(escaped_char))),
+                    _ => return Err(LexError::new(line, *column - 2, LexErrorKind::UnknownEscapeSequence(escaped_char))),
                 }
             } else {
                 return Err(LexError::new(line, *column, LexErrorKind::UnterminatedString));
@@ -323,11 +586,62 @@ fn read_string_literal(
     if unterminated {
         return Err(LexError::new(line, *column, LexErrorKind::UnterminatedString));
     }
-    Ok(SpannedToken {
-        token: Token::StringLiteral(string_literal),
-        line,
-        column: start_column,
-    })
+    Ok(Token::StringLiteral(string_literal))
+}
+
+fn read_char_literal(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    line: usize,
+    column: &mut usize,
+) -> Result<Token, LexError> {
+    *column += 1;
+    let value = match chars.next() {
+        Some('\\') => {
+            *column += 1;
+            match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('\'') => '\'',
+                Some('"') => '"',
+                Some(other) => return Err(LexError::new(line, *column - 1, LexErrorKind::UnknownEscapeSequence(other))),
+                None => return Err(LexError::new(line, *column, LexErrorKind::UnexpectedEof)),
+            }
+        }
+        Some('\n') | None => return Err(LexError::new(line, *column, LexErrorKind::Expected('\''))),
+        Some(c) => c,
+    };
+    *column += 1;
+    expect_char(chars, line, column, '\'')?;
+
+    Ok(Token::Immediate(value as i32))
+}
+
+// Reads a numeric local label: the '1' of a '1:' definition, or a '1b' or
+// '1f' reference to the nearest definition before or after it. Anything
+// else, like '0b101', is left to be read as a number.
+fn read_local_label(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    column: &mut usize,
+    first_char: char,
+) -> Option<String> {
+    let mut name = first_char.to_string();
+    let mut ahead = chars.clone();
+    while let Some(c) = ahead.next_if(char::is_ascii_digit) {
+        name.push(c);
+    }
+    match ahead.next() {
+        Some(':') => {}
+        Some(direction @ ('b' | 'f')) if !ahead.next().is_some_and(|c| c.is_alphanumeric() || c == '_') => name.push(direction),
+        _ => return None,
+    }
+    for _ in 1..name.len() {
+        chars.next();
+    }
+    *column += name.len();
+    Some(name)
 }
 
 fn read_number(
@@ -336,14 +650,14 @@ fn read_number(
     column: &mut usize,
     start_column: usize,
     first_char: char,
-) -> Result<SpannedToken, LexError> {
+) -> Result<Token, LexError> {
     let is_negative = first_char == '-';
     let mut radix = 10;
     let mut number_str = String::new();
 
     if is_negative {
         match chars.peek() {
-            Some(&c) if c.is_digit(10) => {
+            Some(&c) if c.is_ascii_digit() => {
                 // continue to parse
             }
             _ => return Err(LexError::new(line, start_column, LexErrorKind::UnexpectedChar('-'))),
@@ -381,7 +695,7 @@ fn read_number(
                     Some(&c) if c.is_digit(radix) || (radix == 16 && c.is_ascii_hexdigit()) => {}
                     _ => {
                         let prefix_str = if is_negative { format!("-0{}", prefix) } else { format!("0{}", prefix) };
-                        return Err(LexError::new(line, *column, LexErrorKind::EmptyNumberPrefix(prefix_str)));
+                        return Err(LexError::new(line, start_column, LexErrorKind::EmptyNumberPrefix(prefix_str)));
                     }
                 }
             }
@@ -401,31 +715,31 @@ fn read_number(
     }
 
     // Check for trailing invalid characters
-    if let Some(&next) = chars.peek() {
-        if next.is_alphanumeric() || next == '_' {
-            while let Some(&next) = chars.peek() {
-                if next.is_alphanumeric() || next == '_' {
-                    number_str.push(next);
-                    chars.next();
-                    *column += 1;
-                } else {
-                    break;
-                }
-            }
-            let mut full_str = String::new();
-            if is_negative {
-                full_str.push('-');
-            }
-            if let Some(p) = prefix_char {
-                full_str.push('0');
-                full_str.push(p);
-                let digits = if !is_negative { &number_str[1..] } else { &number_str };
-                full_str.push_str(digits);
+    if let Some(&next) = chars.peek()
+        && (next.is_alphanumeric() || next == '_')
+    {
+        while let Some(&next) = chars.peek() {
+            if next.is_alphanumeric() || next == '_' {
+                number_str.push(next);
+                chars.next();
+                *column += 1;
             } else {
-                full_str.push_str(&number_str);
+                break;
             }
-            return Err(LexError::new(line, start_column, LexErrorKind::InvalidNumber(full_str)));
         }
+        let mut full_str = String::new();
+        if is_negative {
+            full_str.push('-');
+        }
+        if let Some(p) = prefix_char {
+            full_str.push('0');
+            full_str.push(p);
+            let digits = if !is_negative { &number_str[1..] } else { &number_str };
+            full_str.push_str(digits);
+        } else {
+            full_str.push_str(&number_str);
+        }
+        return Err(LexError::new(line, start_column, LexErrorKind::InvalidNumber(full_str)));
     }
 
     let val = if is_negative {
@@ -440,24 +754,20 @@ fn read_number(
         }
     };
 
-    Ok(SpannedToken {
-        token: Token::Immediate(val),
-        line,
-        column: start_column,
-    })
+    Ok(Token::Immediate(val))
 }
 
 fn classify_identifier(ident: &str, line: usize, column: usize) -> Result<Token, LexError> {
     let lower_ident = ident.to_lowercase();
 
     // Check if it looks like a register (x0-x31)
-    if lower_ident.starts_with('x') && lower_ident.len() > 1 && lower_ident[1..].chars().all(|c| c.is_ascii_digit()) {
-        if let Ok(num) = lower_ident[1..].parse::<u8>() {
-            if num <= 31 {
-                return Ok(Token::Register(num));
-            } else {
-                return Err(LexError::new(line, column, LexErrorKind::InvalidRegister(ident.to_string())));
-            }
+    if lower_ident.starts_with('x') && lower_ident.len() > 1 && lower_ident[1..].chars().all(|c| c.is_ascii_digit())
+        && let Ok(num) = lower_ident[1..].parse::<u8>()
+    {
+        if num <= 31 {
+            return Ok(Token::Register(num));
+        } else {
+            return Err(LexError::new(line, column, LexErrorKind::InvalidRegister(ident.to_string())));
         }
     }
 
@@ -478,14 +788,18 @@ fn classify_identifier(ident: &str, line: usize, column: usize) -> Result<Token,
 fn is_instruction(ident: &str) -> bool {
     matches!(ident,
         "add" | "sub" | "and" | "or" | "xor" | "sll" | "srl" | "sra" | "slt" | "sltu" |
+        "mul" | "mulh" | "mulhsu" | "mulhu" | "div" | "divu" | "rem" | "remu" |
         "addi" | "andi" | "ori" | "xori" | "slli" | "srli" | "srai" | "slti" | "sltiu" |
         "lw" | "sw" | "lb" | "lh" | "lbu" | "lhu" | "sb" | "sh" |
         "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" |
-        "jal" | "jalr" | "lui" | "auipc" | "ecall" | "ebreak" |
+        "jal" | "jalr" | "lui" | "auipc" | "ecall" | "ebreak" | "mret" | "wfi" |
+        "csrrw" | "csrrs" | "csrrc" | "csrrwi" | "csrrsi" | "csrrci" |
         // Pseudoinstructions
-        "la" | "nop" | "li" | "mv" | "not" | "neg" | "seqz" | "snez" | "sltz" | "sgtz" |
+        "la" | "lla" | "lga" | "nop" | "li" | "mv" | "not" | "neg" | "seqz" | "snez" | "sltz" | "sgtz" |
         "beqz" | "bnez" | "blez" | "bgez" | "bltz" | "bgtz" | "bgt" | "ble" | "bgtu" | "bleu" |
-        "j" | "jr" | "ret" |  "call" | "tail"
+        "j" | "jr" | "ret" |  "call" | "tail" |
+        "csrr" | "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" |
+        "rdcycle" | "rdcycleh" | "rdtime" | "rdtimeh" | "rdinstret" | "rdinstreth"
     )
 }
 
@@ -638,15 +952,15 @@ mod tests {
     fn test_lex_errors() {
         // Unexpected character
         let res = tokenize("add x1, x2, @");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 13, LexErrorKind::UnexpectedChar('@')));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 13, LexErrorKind::UnexpectedChar('@'))]);
 
         // Unterminated string
         let res = tokenize(".string \"Hello");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 15, LexErrorKind::UnterminatedString));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 15, LexErrorKind::UnterminatedString)]);
 
         // Unknown escape sequence
         let res = tokenize(".string \"Hello\\z\"");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 17, LexErrorKind::UnknownEscapeSequence('z')));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 15, LexErrorKind::UnknownEscapeSequence('z'))]);
     }
 
     #[test]
@@ -662,13 +976,13 @@ mod tests {
     #[test]
     fn test_invalid_register() {
         let res = tokenize("add x32, x1, x2");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 5, LexErrorKind::InvalidRegister("x32".to_string())));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 5, LexErrorKind::InvalidRegister("x32".to_string()))]);
     }
 
     #[test]
     fn test_empty_directive() {
         let res = tokenize(". ");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 1, LexErrorKind::EmptyDirective));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 1, LexErrorKind::EmptyDirective)]);
     }
 
     #[test]
@@ -680,11 +994,11 @@ mod tests {
 
         // Empty prefix
         let res = tokenize("addi a0, a0, 0x");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 16, LexErrorKind::EmptyNumberPrefix("0x".to_string())));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 14, LexErrorKind::EmptyNumberPrefix("0x".to_string()))]);
 
         // Negative empty prefix
         let res = tokenize("addi a0, a0, -0b");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 17, LexErrorKind::EmptyNumberPrefix("-0b".to_string())));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 14, LexErrorKind::EmptyNumberPrefix("-0b".to_string()))]);
 
         // Binary
         let res = tokenize("0b1010");
@@ -696,12 +1010,12 @@ mod tests {
     fn test_numeric_overflow() {
         // Now we allow up to u32::MAX for positive literals (interpreted as bit patterns)
         let res = tokenize("4294967296"); // u32::MAX + 1
-        assert_eq!(res.unwrap_err(), LexError::new(1, 1, LexErrorKind::NumericOverflow("4294967296".to_string())));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 1, LexErrorKind::NumericOverflow("4294967296".to_string()))]);
 
         // Negative numbers are still restricted to i32 range
         let res = tokenize("-2147483649"); // i32::MIN - 1
         // number_str in error kind does not include the '-' sign
-        assert_eq!(res.unwrap_err(), LexError::new(1, 1, LexErrorKind::NumericOverflow("2147483649".to_string())));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 1, LexErrorKind::NumericOverflow("2147483649".to_string()))]);
 
         // Verify hex bit pattern support (0xDEADBEEF)
         let res = tokenize("0xDEADBEEF");
@@ -713,51 +1027,69 @@ mod tests {
     fn test_hi_lo_modifier() {
         let res = tokenize("%hi(label)");
         let tokens = res.expect("Should tokenize successfully");
-        assert_eq!(tokens[0].token, Token::Modifier(ModifierKind::Hi, "label".to_string()));
+        assert_eq!(tokens[0].token, Token::Modifier(ModifierKind::Hi));
+        assert_eq!(tokens[1].token, Token::LParenthesis);
+        assert_eq!(tokens[2].token, Token::Label("label".to_string()));
+        assert_eq!(tokens[3].token, Token::RParenthesis);
 
         let res = tokenize("%lo(label)");
         let tokens = res.expect("Should tokenize successfully");
-        assert_eq!(tokens[0].token, Token::Modifier(ModifierKind::Lo, "label".to_string()));
+        assert_eq!(tokens[0].token, Token::Modifier(ModifierKind::Lo));
     }
 
     #[test]
     fn test_modifier_expected_paren() {
         let res = tokenize("%hi label");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 5, LexErrorKind::Expected('(')));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 5, LexErrorKind::Expected('('))]);
     }
 
     #[test]
     fn test_unknown_modifier() {
         let res = tokenize("%foo(label)");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 1, LexErrorKind::UnknownModifier("foo".to_string())));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 1, LexErrorKind::UnknownModifier("foo".to_string()))]);
     }
 
     #[test]
     fn test_invalid_number() {
         // Decimal with letters
         let res = tokenize("123xyz");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 1, LexErrorKind::InvalidNumber("123xyz".to_string())));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 1, LexErrorKind::InvalidNumber("123xyz".to_string()))]);
 
         // Binary with invalid digits
         let res = tokenize("0b10102");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 1, LexErrorKind::InvalidNumber("0b10102".to_string())));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 1, LexErrorKind::InvalidNumber("0b10102".to_string()))]);
 
         // Hex with invalid letters
         let res = tokenize("0xDEADG");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 1, LexErrorKind::InvalidNumber("0xDEADG".to_string())));
+        assert_eq!(res.unwrap_err(), [LexError::new(1, 1, LexErrorKind::InvalidNumber("0xDEADG".to_string()))]);
+    }
+
+    #[test]
+    fn test_modifier_takes_expression() {
+        let tokens = tokenize("%hi(sym+4)").expect("Should tokenize successfully");
+        assert_eq!(tokens[0].token, Token::Modifier(ModifierKind::Hi));
+        assert_eq!(tokens[2].token, Token::Label("sym".to_string()));
+        assert_eq!(tokens[3].token, Token::Operator(Operator::Plus));
+        assert_eq!(tokens[4].token, Token::Immediate(4));
+        assert_eq!(tokens[5].token, Token::RParenthesis);
     }
 
     #[test]
-    fn test_modifier_invalid_symbol() {
-        let res = tokenize("%hi(123)");
-        assert_eq!(res.unwrap_err(), LexError::new(1, 5, LexErrorKind::UnexpectedChar('1')));
+    fn test_pc_relative_modifiers() {
+        let tokens = tokenize("%pcrel_hi(sym), %pcrel_lo(1b), %got_pcrel_hi(sym)").unwrap();
+        assert_eq!(tokens[0].token, Token::Modifier(ModifierKind::PcrelHi));
+        assert_eq!(tokens[5].token, Token::Modifier(ModifierKind::PcrelLo));
+        assert_eq!(tokens[7].token, Token::Label("1b".to_string()));
+        assert_eq!(tokens[10].token, Token::Modifier(ModifierKind::GotPcrelHi));
+        assert_eq!(tokens[10].token.to_string(), "%got_pcrel_hi");
     }
 
     #[test]
     fn test_modifier_with_whitespace() {
         let res = tokenize("%hi ( label ) ");
         let tokens = res.expect("Should tokenize successfully");
-        assert_eq!(tokens[0].token, Token::Modifier(ModifierKind::Hi, "label".to_string()));
+        assert_eq!(tokens[0].token, Token::Modifier(ModifierKind::Hi));
+        assert_eq!(tokens[2].token, Token::Label("label".to_string()));
     }
 
     #[test]
@@ -767,9 +1099,91 @@ mod tests {
         assert_eq!(tokens[0].token, Token::Instruction("lw".to_string()));
         assert_eq!(tokens[1].token, Token::Register(1));
         assert_eq!(tokens[2].token, Token::Comma);
-        assert_eq!(tokens[3].token, Token::Modifier(ModifierKind::Hi, "label".to_string()));
+        assert_eq!(tokens[3].token, Token::Modifier(ModifierKind::Hi));
         assert_eq!(tokens[4].token, Token::LParenthesis);
-        assert_eq!(tokens[5].token, Token::Register(2));
+        assert_eq!(tokens[5].token, Token::Label("label".to_string()));
         assert_eq!(tokens[6].token, Token::RParenthesis);
+        assert_eq!(tokens[7].token, Token::LParenthesis);
+        assert_eq!(tokens[8].token, Token::Register(2));
+        assert_eq!(tokens[9].token, Token::RParenthesis);
+    }
+
+    #[test]
+    fn test_operators_and_negative_numbers() {
+        let tokens = tokenize("addi a0, a0, end-start").unwrap();
+        assert_eq!(tokens[5].token, Token::Label("end".to_string()));
+        assert_eq!(tokens[6].token, Token::Operator(Operator::Minus));
+
+        // after a value '-' subtracts, elsewhere it is part of the number
+        let tokens: Vec<Token> = tokenize("8-4, -4, (1)-1, -x").unwrap().into_iter().map(|t| t.token).collect();
+        assert_eq!(tokens[..8], [
+            Token::Immediate(8), Token::Operator(Operator::Minus), Token::Immediate(4), Token::Comma,
+            Token::Immediate(-4), Token::Comma, Token::LParenthesis, Token::Immediate(1),
+        ]);
+        assert_eq!(tokens[9..13], [
+            Token::Operator(Operator::Minus), Token::Immediate(1), Token::Comma, Token::Operator(Operator::Minus),
+        ]);
+
+        let tokens: Vec<Token> = tokenize("1<<12 >> ~MASK % 3").unwrap().into_iter().map(|t| t.token).collect();
+        assert_eq!(tokens[1], Token::Operator(Operator::ShiftLeft));
+        assert_eq!(tokens[3], Token::Operator(Operator::ShiftRight));
+        assert_eq!(tokens[4], Token::Operator(Operator::Tilde));
+        assert_eq!(tokens[6], Token::Operator(Operator::Percent));
+        // after a value '%' is the remainder, elsewhere it starts a modifier
+        let tokens: Vec<Token> = tokenize("N%size, (a)%lo(x)").unwrap().into_iter().map(|t| t.token).collect();
+        assert_eq!(tokens[1], Token::Operator(Operator::Percent));
+        assert_eq!(tokens[2], Token::Label("size".to_string()));
+        assert_eq!(tokens[7], Token::Operator(Operator::Percent));
+        assert_eq!(tokens[8], Token::Label("lo".to_string()));
+        assert_eq!(tokenize("x, %lo(x)").unwrap()[2].token, Token::Modifier(ModifierKind::Lo));
+        let tokens: Vec<Token> = tokenize("a<=b != c && !d").unwrap().into_iter().map(|t| t.token).collect();
+        assert_eq!(tokens[1], Token::Operator(Operator::LessEqual));
+        assert_eq!(tokens[3], Token::Operator(Operator::NotEqual));
+        assert_eq!(tokens[5], Token::Operator(Operator::LogicalAnd));
+        assert_eq!(tokens[6], Token::Operator(Operator::Not));
+    }
+
+    #[test]
+    fn test_char_literals() {
+        let tokens = tokenize("li a0, 'A'\n.byte '\\n', '\\''").unwrap();
+        assert_eq!(tokens[3].token, Token::Immediate(65));
+        assert_eq!(tokens[6].token, Token::Immediate(10));
+        assert_eq!(tokens[8].token, Token::Immediate(39));
+        assert_eq!(tokenize("'AB'").unwrap_err(), [LexError::new(1, 3, LexErrorKind::Expected('\''))]);
+    }
+
+    #[test]
+    fn test_numeric_local_labels() {
+        let tokens = tokenize("1: beqz a0, 1f\nj 12b\nli a0, 0b101\nli a1, 0x1f").unwrap();
+        assert_eq!(tokens[0].token, Token::Label("1".to_string()));
+        assert_eq!(tokens[1].token, Token::Colon);
+        assert_eq!(tokens[5].token, Token::Label("1f".to_string()));
+        assert_eq!((tokens[5].column, tokens[5].width), (13, 2));
+        assert_eq!(tokens[8].token, Token::Label("12b".to_string()));
+        assert_eq!(tokens[13].token, Token::Immediate(5));
+        assert_eq!(tokens[18].token, Token::Immediate(31));
+    }
+
+    #[test]
+    fn test_csr_names() {
+        // the parser decides where a name is a CSR, so they are not reserved
+        let tokens = tokenize("csrrw a0, MSTATUS, a1\ntime: j time").unwrap();
+        assert_eq!(tokens[0].token, Token::Instruction("csrrw".to_string()));
+        assert_eq!(tokens[3].token, Token::Label("MSTATUS".to_string()));
+        assert_eq!(tokens[7].token, Token::Label("time".to_string()));
+        assert_eq!(tokens[10].token, Token::Label("time".to_string()));
+    }
+
+    #[test]
+    fn test_errors_on_every_line() {
+        let errors = tokenize("li a0, 0x\nnop\nsw a0, 0(x99)\n").unwrap_err();
+        assert_eq!(errors, [
+            LexError::new(1, 8, LexErrorKind::EmptyNumberPrefix("0x".to_string())),
+            LexError::new(3, 10, LexErrorKind::InvalidRegister("x99".to_string())),
+        ]);
+
+        // the end is after the last line
+        let tokens = tokenize("nop\nret").unwrap();
+        assert_eq!(tokens.iter().map(|t| (t.line, t.column)).collect::<Vec<_>>(), [(1, 1), (1, 4), (2, 1), (2, 4)]);
     }
 }
@@ -0,0 +1,27 @@
+pub mod bus;
+pub mod cli;
+pub mod clint;
+pub mod config;
+pub mod csr;
+pub mod diagnostic;
+pub mod lexer;
+pub mod macros;
+pub mod parser;
+pub mod symbols;
+pub mod assembler;
+pub mod linker;
+pub mod listing;
+pub mod memory;
+pub mod memory_map;
+pub mod processor;
+pub mod program;
+pub mod source;
+pub mod disassembler;
+pub mod elf;
+pub mod export;
+pub mod pseudo;
+pub mod pipeline;
+pub mod section;
+pub mod syscalls;
+pub mod tui;
+pub mod uart;
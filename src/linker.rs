@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use crate::assembler::{Assembler, AssemblerError, DebugInfo};
+use crate::diagnostic::Diagnostic;
+use crate::parser::{Operand, Statement, StatementKind};
+use crate::memory_map::MemoryMap;
+use crate::program::{Program, Segment, SymbolBinding};
+use crate::section::{self, Section, Sections};
+use crate::source::FileId;
+use crate::symbols::SymbolTable;
+
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    Symbol(Vec<Diagnostic>),
+    DuplicateSymbol { name: String, first: FileId, second: FileId },
+    // Global symbols whose values depend on each other across files
+    Unresolved(Vec<String>),
+    // Sections that do not fit in the memory map
+    Layout(String),
+    Assembler(Vec<AssemblerError>),
+}
+
+/// One source file, parsed and with its pseudo-instructions expanded, ready
+/// to be placed in memory and encoded.
+pub struct Object {
+    pub file: FileId,
+    pub statements: Vec<Statement>,
+}
+
+/// Links objects into one program, with a segment for each section. The part
+/// of a section from each object follows the one before it, aligned, and
+/// sections are placed in the regions of `map` as `section::place` does. Symbols
+/// declared with `.globl` are visible to the other objects; names an object
+/// does not define are looked up among them, and a global defined by two
+/// objects is an error.
+pub fn link(objects: &[Object], map: &MemoryMap) -> Result<Program, LinkError> {
+    link_with_debug_info(objects, map).map(|(program, _)| program)
+}
+
+/// Like `link`, also returning what each statement of the objects assembled
+/// to, for listings.
+pub fn link_with_debug_info(objects: &[Object], map: &MemoryMap) -> Result<(Program, DebugInfo), LinkError> {
+    // The layout is first done with every global at 0, then again with the
+    // addresses found, until they no longer change. Only constants that use
+    // other files' symbols, or sizes that do, need more than one round.
+    let mut globals: HashMap<String, u32> = objects.iter()
+        .flat_map(|object| declared_globals(&object.statements))
+        .map(|name| (name, 0))
+        .collect();
+    let mut settled = None;
+    for _ in 0..objects.len() + 2 {
+        let (tables, sections) = layout(objects, &globals, map)?;
+        let defined = defined_globals(objects, &tables)?;
+        if defined == globals {
+            settled = Some((tables, sections));
+            break;
+        }
+        globals = defined;
+    }
+    let Some((tables, sections)) = settled else {
+        let mut names: Vec<String> = globals.into_keys().collect();
+        names.sort();
+        return Err(LinkError::Unresolved(names));
+    };
+
+    let mut assemblers = Vec::with_capacity(objects.len());
+    let mut errors = Vec::new();
+    for (object, table) in objects.iter().zip(&tables) {
+        let mut assembler = Assembler::new();
+        match assembler.assemble(&object.statements, table) {
+            Ok(()) => assemblers.push(assembler),
+            Err(object_errors) => errors.extend(object_errors),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(LinkError::Assembler(errors));
+    }
+
+    // One segment per section of the program, with each object's part where it was placed
+    let segments = sections.into_iter()
+        .filter(|section| section.size > 0)
+        .map(|section| {
+            let mut data = Vec::new();
+            for (table, assembler) in tables.iter().zip(&assemblers) {
+                if let Some(part) = table.sections().get(&section.name).filter(|_| !section.nobits) {
+                    data.resize((part.base - section.base) as usize, 0);
+                    data.extend_from_slice(assembler.section(&section.name));
+                }
+            }
+            Segment {
+                name: section.name.trim_start_matches('.').to_string(),
+                vaddr: section.base,
+                data,
+                mem_size: section.size,
+                permissions: section.permissions,
+            }
+        })
+        .collect();
+
+    let mut program = Program { entry: map.text_base(), segments, symbols: Vec::new() };
+    program.symbols = tables.iter().flat_map(SymbolTable::program_symbols).collect();
+    program.symbols.sort_by(|a, b| a.address.cmp(&b.address).then_with(|| a.name.cmp(&b.name)));
+    let mut debug_info = DebugInfo::default();
+    for assembler in assemblers {
+        debug_info.extend(assembler.debug_info);
+    }
+    Ok((program, debug_info))
+}
+
+// Builds the symbol table of each object with its sections placed after the
+// previous object's, once measured. Returns the sections of the whole program.
+fn layout(objects: &[Object], globals: &HashMap<String, u32>, map: &MemoryMap) -> Result<(Vec<SymbolTable>, Vec<Section>), LinkError> {
+    // The errors of every object are reported together
+    let build = |sections: Vec<Sections>| {
+        let mut tables = Vec::with_capacity(objects.len());
+        let mut errors = Vec::new();
+        for (object, sections) in objects.iter().zip(sections) {
+            let mut table = SymbolTable::with_sections(sections);
+            table.set_externals(globals.clone());
+            match table.build(&object.statements) {
+                Ok(()) => tables.push(table),
+                Err(object_errors) => errors.extend(object_errors),
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(tables),
+            false => Err(LinkError::Symbol(errors)),
+        }
+    };
+
+    let mut sections: Vec<Sections> = build(vec![Sections::default(); objects.len()])?
+        .iter()
+        .map(|table| table.sections().clone())
+        .collect();
+    let program = section::place(&mut sections, map).map_err(LinkError::Layout)?;
+    Ok((build(sections)?, program))
+}
+
+fn defined_globals(objects: &[Object], tables: &[SymbolTable]) -> Result<HashMap<String, u32>, LinkError> {
+    let mut globals = HashMap::new();
+    let mut owners: HashMap<&str, FileId> = HashMap::new();
+    for (object, table) in objects.iter().zip(tables) {
+        for (name, address) in table.iter() {
+            if table.attributes(name).binding != SymbolBinding::Global {
+                continue;
+            }
+            if let Some(&first) = owners.get(name) {
+                return Err(LinkError::DuplicateSymbol { name: name.to_string(), first, second: object.file });
+            }
+            owners.insert(name, object.file);
+            globals.insert(name.to_string(), address);
+        }
+    }
+    Ok(globals)
+}
+
+fn declared_globals(statements: &[Statement]) -> Vec<String> {
+    statements.iter()
+        .filter_map(|statement| match &statement.kind {
+            StatementKind::Directive(name, operands) if name == ".globl" || name == ".global" => Some(operands),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|operand| match operand {
+            Operand::Label(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::lexer::tokenize_file;
+    use crate::parser::Parser;
+    use crate::pseudo;
+
+    fn objects(sources: &[&str]) -> Vec<Object> {
+        sources.iter().enumerate()
+            .map(|(file, source)| {
+                let statements = Parser::new(tokenize_file(source, file).unwrap()).parse().unwrap();
+                Object { file, statements: pseudo::expand(statements).unwrap() }
+            })
+            .collect()
+    }
+
+    fn address(program: &Program, name: &str) -> u32 {
+        program.symbols.iter().find(|symbol| symbol.name == name).unwrap().address
+    }
+
+    #[test]
+    fn test_link_sections_and_globals() {
+        let program = link(&objects(&[
+            ".globl main\nmain: jal ra, helper\nlui a0, %hi(count)\nlw a0, %lo(count)(a0)\n.data\nlocal: .byte 1\n",
+            ".globl helper, count\n.equ LIMIT, 9\nhelper: ret\n.data\nlocal: .byte 2\n.align 2\ncount: .word LIMIT\n",
+        ]), &MemoryMap::default()).unwrap();
+
+        // the second file's text and data follow the first's, aligned as the second's .align needs
+        assert_eq!(address(&program, "helper"), config::TEXT_BASE + 12);
+        assert_eq!(address(&program, "count"), config::DATA_BASE + 8);
+        let text = &program.segments[0].data;
+        assert_eq!(text.len(), 16);
+        assert_eq!(text[0..4], 0x00c000efu32.to_le_bytes()); // jal ra, 12
+        assert_eq!(program.segments[1].data, [1, 0, 0, 0, 2, 0, 0, 0, 9, 0, 0, 0]);
+        // each file keeps its own local symbols
+        assert_eq!(program.symbols.iter().filter(|symbol| symbol.name == "local").count(), 2);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let error = link(&objects(&[".globl f\nf: nop\n", ".globl f\nf: ret\n"]), &MemoryMap::default()).unwrap_err();
+        assert_eq!(error, LinkError::DuplicateSymbol { name: "f".to_string(), first: 0, second: 1 });
+
+        // a symbol another file does not export stays undefined
+        let LinkError::Assembler(errors) = link(&objects(&["jal ra, f\n", "f: ret\n"]), &MemoryMap::default()).unwrap_err() else { panic!() };
+        assert_eq!((errors[0].file, errors[0].line), (0, 1));
+        assert!(errors[0].message.contains("Unknown label 'f'"));
+
+        let error = link(&objects(&[".globl a\n.equ a, b+1\n", ".globl b\n.equ b, a+1\n"]), &MemoryMap::default()).unwrap_err();
+        assert_eq!(error, LinkError::Unresolved(vec!["a".to_string(), "b".to_string()]));
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt::Write;
+
+use crate::assembler::{AssembledStatement, DebugInfo};
+use crate::program::{Program, Segment, SymbolBinding, SymbolType};
+use crate::source::SourceFiles;
+
+const BYTES_PER_ROW: usize = 4;
+// Longer data, like a big .space, is cut after this many rows
+const MAX_ROWS: usize = 4;
+
+/// The assembler listing: the address and bytes of every statement, side by
+/// side with the source line it came from. Pseudo-instructions show each
+/// instruction they expand to on a row of its own, and constants show with no
+/// address or section.
+pub fn listing(debug_info: &DebugInfo, sources: &SourceFiles) -> String {
+    let mut out = format!("{:<8}  {:<11}  {:<40} {:>5}  {}\n", "Address", "Bytes", "Assembled", "Line", "Source");
+    let mut place = None;
+    let mut statements = debug_info.statements.as_slice();
+    while let Some(first) = statements.first() {
+        let (file, line) = (first.source.file, first.source.line);
+        let count = statements.iter().take_while(|statement| (statement.source.file, statement.source.line) == (file, line)).count();
+        let (group, rest) = statements.split_at(count);
+        statements = rest;
+
+        if !first.absolute && place != Some((file, &first.source.section)) {
+            place = Some((file, &first.source.section));
+            match sources.name(file) {
+                "" => writeln!(out, "\n{}", first.source.section).unwrap(),
+                name => writeln!(out, "\n{} {}", name, first.source.section).unwrap(),
+            }
+        }
+
+        // Labels show on their own only on lines with nothing else
+        let mut shown: Vec<&AssembledStatement> = group.iter().filter(|statement| !statement.label).collect();
+        if shown.is_empty() {
+            shown = group.iter().collect();
+        }
+        let source = sources.text(file).lines().nth(line.saturating_sub(1)).unwrap_or("").trim_end();
+        for (row, statement) in shown.into_iter().enumerate() {
+            let (line, source) = if row == 0 { (line.to_string(), source) } else { (String::new(), "") };
+            let mut chunks = statement.bytes.chunks(BYTES_PER_ROW);
+            let bytes = hex(chunks.next().unwrap_or_default());
+            let address = if statement.absolute { String::new() } else { format!("{:08x}", statement.address) };
+            let row = format!("{:<8}  {:<11}  {:<40} {:>5}  {}", address, bytes, statement.source.raw_text, line, source);
+            writeln!(out, "{}", row.trim_end()).unwrap();
+            for (index, chunk) in chunks.enumerate() {
+                let address = statement.address + ((index + 1) * BYTES_PER_ROW) as u32;
+                if index + 1 == MAX_ROWS {
+                    writeln!(out, "{:08x}  ...", address).unwrap();
+                    break;
+                }
+                writeln!(out, "{:08x}  {}", address, hex(chunk)).unwrap();
+            }
+        }
+    }
+    out
+}
+
+/// The map of a program: its sections, then its symbols by address with
+/// their sizes and the section they are in.
+pub fn symbol_map(program: &Program) -> String {
+    let mut out = String::from("Sections\n");
+    writeln!(out, "{:<8}  {:<8}  {:<5}  Name", "Address", "Size", "Flags").unwrap();
+    for segment in &program.segments {
+        let size = segment.mem_size.max(segment.data.len() as u32);
+        writeln!(out, "{:08x}  {:08x}  {:<5}  .{}", segment.vaddr, size, segment.permissions.to_string(), segment.name).unwrap();
+    }
+
+    writeln!(out, "\nSymbols").unwrap();
+    writeln!(out, "{:<8}  {:<8}  {:<16}  {:<6}  {:<8}  Name", "Address", "Size", "Section", "Bind", "Type").unwrap();
+    let mut symbols: Vec<_> = program.symbols.iter().collect();
+    symbols.sort_by(|a, b| a.address.cmp(&b.address).then_with(|| a.name.cmp(&b.name)));
+    for symbol in symbols {
+        // A label at the end of a section, as for its size, belongs to it
+        let section = if symbol.absolute {
+            "*ABS*".to_string()
+        } else {
+            program.segments.iter()
+                .find(|segment| (segment.vaddr as u64..segment_end(segment)).contains(&(symbol.address as u64)))
+                .or_else(|| program.segments.iter().find(|segment| segment_end(segment) == symbol.address as u64))
+                .map_or("*UND*".to_string(), |segment| format!(".{}", segment.name))
+        };
+        let binding = match symbol.binding {
+            SymbolBinding::Local => "local",
+            SymbolBinding::Global => "global",
+        };
+        let symbol_type = match symbol.symbol_type {
+            SymbolType::NoType => "notype",
+            SymbolType::Object => "object",
+            SymbolType::Function => "function",
+        };
+        writeln!(out, "{:08x}  {:08x}  {:<16}  {:<6}  {:<8}  {}", symbol.address, symbol.size, section, binding, symbol_type, symbol.name).unwrap();
+    }
+    out
+}
+
+// The address after a segment, 2^32 for one at the top of the address space
+fn segment_end(segment: &Segment) -> u64 {
+    segment.vaddr as u64 + segment.mem_size as u64
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_map::MemoryMap;
+    use crate::pipeline;
+
+    #[test]
+    fn test_listing_and_symbol_map() {
+        let mut sources = SourceFiles::default();
+        let source = ".globl main\n.type main, @function\nmain: la a0, msg   # the message\n\
+            ret\n.size main, 12\n.equ LIMIT, 9\n.data\nmsg: .string \"Hello, world\"\n.bss\nbuf: .space 64\n";
+        let file = sources.add(source, None);
+        let (program, debug_info) = pipeline::assemble_with_debug_info(&mut sources, &[file], &MemoryMap::default()).unwrap();
+
+        let listing = listing(&debug_info, &sources);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "Address   Bytes        Assembled                                 Line  Source");
+        assert_eq!(lines[1..10], [
+            "",
+            ".text",
+            "00010000               .globl main                                  1  .globl main",
+            "00010000               .type main, @function                        2  .type main, @function",
+            // a pseudo-instruction and what it expands to
+            "00010000  17 05 10 00  auipc x10, %pcrel_hi(msg)                    3  main: la a0, msg   # the message",
+            "00010004  13 05 05 00  addi x10, x10, %pcrel_lo(msg)",
+            "00010008  67 80 00 00  jalr x0, x1, 0                               4  ret",
+            "0001000c               .size main, 12                               5  .size main, 12",
+            // a constant has no address
+            "                       .equ LIMIT, 9                                6  .equ LIMIT, 9",
+        ]);
+        let data = lines.iter().position(|line| *line == ".data").unwrap();
+        assert_eq!(lines[data + 1..data + 6], [
+            "00110000  48 65 6c 6c  .string \"Hello, world\"                       8  msg: .string \"Hello, world\"",
+            "00110004  6f 2c 20 77",
+            "00110008  6f 72 6c 64",
+            "0011000c  00",
+            "",
+        ]);
+        // .bss has no bytes
+        assert!(listing.ends_with(".bss\n0011000d               .space 64                                   10  buf: .space 64\n"), "{}", listing);
+
+        // generated names are spelled as in the source
+        let file = sources.add(".equ N, 1\n1: j 1f\n.set N, N + 1\n1: beqz a0, 1b\ncall f\nf: ret\n", None);
+        let (_, debug_info) = pipeline::assemble_with_debug_info(&mut sources, &[file], &MemoryMap::default()).unwrap();
+        let listing = super::listing(&debug_info, &sources);
+        let rows: Vec<&str> = listing.lines().skip(1).map(|row| row.get(8..row.len().min(62)).unwrap_or("").trim()).collect();
+        assert_eq!(rows, [
+            ".equ N, 1", // with no section heading before it
+            "",
+            "", // .text
+            "6f 00 40 00  jal x0, 1f",
+            ".set N, N+1",
+            "63 00 05 00  beq x10, x0, 1b",
+            "97 00 00 00  auipc x1, %pcrel_hi(f)",
+            "e7 80 80 00  jalr x1, x1, %pcrel_lo(f)",
+            "67 80 00 00  jalr x0, x1, 0",
+        ]);
+
+        // data longer than four rows is cut
+        let file = sources.add(".data\n.space 32\n", None);
+        let (_, debug_info) = pipeline::assemble_with_debug_info(&mut sources, &[file], &MemoryMap::default()).unwrap();
+        assert!(super::listing(&debug_info, &sources).ends_with("0011000c  00 00 00 00\n00110010  ...\n"));
+
+        let map = symbol_map(&program);
+        assert_eq!(map, "\
+Sections
+Address   Size      Flags  Name
+00010000  0000000c  r-x    .text
+00110000  0000000d  rw-    .data
+0011000d  00000040  rw-    .bss
+
+Symbols
+Address   Size      Section           Bind    Type      Name
+00000009  00000000  *ABS*             local   notype    LIMIT
+00010000  0000000c  .text             global  function  main
+00110000  00000000  .data             local   notype    msg
+0011000d  00000000  .bss              local   notype    buf
+");
+    }
+}
@@ -0,0 +1,672 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::lexer::{self, SpannedToken, Token};
+use crate::parser::Parser;
+use crate::source::{FileId, SourceFiles};
+
+// Deeper nesting than this is taken to be a macro that expands itself forever
+const MAX_DEPTH: usize = 100;
+// A larger .rept count is taken to be a mistake rather than a program
+const MAX_REPETITIONS: i32 = 100_000;
+
+#[derive(Debug, PartialEq)]
+pub struct MacroError {
+    pub file: FileId,
+    pub line: usize,
+    pub message: String,
+}
+
+impl MacroError {
+    // An error on the line that `at` is on
+    fn new(at: &SpannedToken, message: String) -> Self {
+        Self { file: at.file, line: at.line, message }
+    }
+}
+
+impl fmt::Display for MacroError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// A line where a macro was expanded, so that errors reported there can
+/// also point at the macro definition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expansion {
+    pub file: FileId,
+    pub line: usize,
+    pub name: String,
+    pub definition: String, // where the macro is defined, like "line 3"
+}
+
+#[derive(Clone)]
+struct Macro {
+    parameters: Vec<(String, Option<Vec<SpannedToken>>)>, // name and default value
+    body: Vec<SpannedToken>,
+    file: FileId,
+    line: usize,
+}
+
+// An open .if: whether its current branch is assembled, and whether one already was
+struct Conditional<'a> {
+    at: &'a SpannedToken,
+    active: bool,
+    taken: bool,
+}
+
+/// Expands GNU assembler style macros (`.macro`/`.endm`), repetitions
+/// (`.rept`, `.irp`, `.irpc`), conditional assembly (`.if`, `.ifdef`,
+/// `.ifndef`, `.else`, `.endif`) and `.include` in a token stream before it
+/// is parsed. Tokens produced by a macro carry the file and line of the call.
+#[derive(Default)]
+pub struct MacroProcessor {
+    macros: HashMap<String, Macro>,
+    constants: HashMap<String, i32>, // .equ/.set values seen so far, for .if and .rept
+    defined: HashSet<String>,        // labels and constants seen so far, for .ifdef
+    expansions: Vec<Expansion>,
+    including: Vec<FileId>,          // the files being read, outermost first
+    counter: usize,                  // the value of \@, one more for each macro expanded
+    depth: usize,
+    errors: Vec<MacroError>,         // a line with an error is left out, and expansion goes on
+}
+
+impl MacroProcessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expands the tokens of a file of `sources`, reading the files it includes
+    /// from there. Returns the error of every line that has one.
+    pub fn expand(&mut self, mut tokens: Vec<SpannedToken>, sources: &mut SourceFiles) -> Result<Vec<SpannedToken>, Vec<MacroError>> {
+        let eof = tokens.pop_if(|t| t.token == Token::Eof);
+        self.including = eof.iter().map(|t| t.file).collect();
+        let mut out = Vec::with_capacity(tokens.len());
+        self.process(&tokens, &mut out, sources);
+
+        // References left over were outside any macro or repetition that defines them
+        for t in out.iter().filter(|t| matches!(t.token, Token::MacroText(_))) {
+            self.report(MacroError::new(t, format!("'{}' does not name an argument of an enclosing macro, .irp or .irpc", t.token)));
+        }
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+        out.extend(eof);
+        Ok(out)
+    }
+
+    /// Text to append to an error reported at `line` of `file`, naming the macros expanded there.
+    pub fn context(&self, file: FileId, line: usize) -> String {
+        self.expansions.iter()
+            .filter(|expansion| expansion.file == file && expansion.line == line)
+            .map(|expansion| format!(" (in macro '{}' defined at {})", expansion.name, expansion.definition))
+            .collect()
+    }
+
+    // Records an error, once: the body of a repetition can give the same one each time
+    fn report(&mut self, error: MacroError) {
+        if !self.errors.contains(&error) {
+            self.errors.push(error);
+        }
+    }
+
+    fn process(&mut self, tokens: &[SpannedToken], out: &mut Vec<SpannedToken>, sources: &mut SourceFiles) {
+        let lines: Vec<&[SpannedToken]> = tokens.split_inclusive(|t| t.token == Token::Newline).collect();
+        let mut conditionals: Vec<Conditional> = Vec::new();
+
+        let mut i = 0;
+        while i < lines.len() {
+            if let Err(e) = self.process_line(&lines, &mut i, &mut conditionals, out, sources) {
+                self.report(e);
+            }
+        }
+
+        if let Some(conditional) = conditionals.last() {
+            self.report(MacroError::new(conditional.at, "'.if' without '.endif'".to_string()));
+        }
+    }
+
+    // Expands the line at `i`, and moves `i` past it and any block it opens
+    fn process_line<'a>(
+        &mut self,
+        lines: &[&'a [SpannedToken]],
+        i: &mut usize,
+        conditionals: &mut Vec<Conditional<'a>>,
+        out: &mut Vec<SpannedToken>,
+        sources: &mut SourceFiles,
+    ) -> Result<(), MacroError> {
+        let line = lines[*i];
+        let at = &line[0];
+        *i += 1;
+
+        let (labels, statement) = line.split_at(statement_start(line));
+        let directive = match statement.first().map(|t| &t.token) {
+            Some(Token::Directive(name)) => name.as_str(),
+            _ => "",
+        };
+        let operands = statement.get(1..).unwrap_or_default();
+        let operands = match operands.last() {
+            Some(t) if t.token == Token::Newline => &operands[..operands.len() - 1],
+            _ => operands,
+        };
+        let active = conditionals.iter().all(|c| c.active);
+
+        match directive {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let condition = if active { self.condition(directive, operands, at) } else { Ok(false) };
+                // a condition with an error assembles neither branch
+                let taken = condition.as_ref().map_or(true, |&condition| condition);
+                conditionals.push(Conditional { at, active: condition == Ok(true), taken });
+                return condition.map(|_| ());
+            }
+            ".else" => {
+                let conditional = conditionals.last_mut()
+                    .ok_or_else(|| MacroError::new(at, "'.else' without '.if'".to_string()))?;
+                conditional.active = !conditional.taken;
+                conditional.taken = true;
+                return Ok(());
+            }
+            ".endif" => {
+                conditionals.pop().ok_or_else(|| MacroError::new(at, "'.endif' without '.if'".to_string()))?;
+                return Ok(());
+            }
+            _ if !active => return Ok(()),
+            _ => {}
+        }
+
+        for token in labels {
+            if let Token::Label(name) = &token.token {
+                self.defined.insert(name.clone());
+            }
+        }
+
+        match directive {
+            ".macro" => {
+                let body = collect(lines, i, directive, at)?;
+                self.define(operands, body, at)?;
+            }
+            ".rept" | ".irp" | ".irpc" => {
+                let body = collect(lines, i, directive, at)?;
+                out.extend(labels.iter().cloned());
+                self.repeat(directive, operands, &body, at, out, sources)?;
+            }
+            ".endm" | ".endr" => {
+                return Err(MacroError::new(at, format!("'{}' without a matching opening directive", directive)));
+            }
+            ".include" => {
+                out.extend(labels.iter().cloned());
+                self.include(operands, at, out, sources)?;
+            }
+            ".equ" | ".set" => {
+                if let [SpannedToken { token: Token::Label(name), .. }, SpannedToken { token: Token::Comma, .. }, value @ ..] = operands {
+                    self.defined.insert(name.clone());
+                    if let Ok(value) = self.evaluate(value, at) {
+                        self.constants.insert(name.clone(), value);
+                    }
+                }
+                out.extend(line.iter().cloned());
+            }
+            _ => match statement.first().map(|t| &t.token) {
+                Some(Token::Label(name) | Token::Instruction(name)) if self.macros.contains_key(name) => {
+                    out.extend(labels.iter().cloned());
+                    self.invoke(&name.clone(), operands, at, out, sources)?;
+                    // the call line's own newline ends the expansion
+                    if let Some(newline) = line.last().filter(|t| t.token == Token::Newline) {
+                        out.push(newline.clone());
+                    }
+                }
+                _ => out.extend(line.iter().cloned()),
+            },
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, operands: &[SpannedToken], body: Vec<SpannedToken>, at: &SpannedToken) -> Result<(), MacroError> {
+        let name = match operands.first().map(|t| &t.token) {
+            Some(Token::Label(name) | Token::Instruction(name)) => name.clone(),
+            _ => return Err(MacroError::new(at, "Directive .macro requires a macro name".to_string())),
+        };
+        if self.macros.contains_key(&name) {
+            return Err(MacroError::new(at, format!("Macro '{}' is already defined", name)));
+        }
+
+        // Parameters are separated by commas or spaces; 'name=value' gives a default
+        let mut parameters = Vec::new();
+        let mut rest = &operands[1..];
+        while let Some(token) = rest.first() {
+            rest = &rest[1..];
+            let parameter = match &token.token {
+                Token::Comma => continue,
+                Token::Label(parameter) | Token::Instruction(parameter) => parameter.clone(),
+                other => return Err(MacroError::new(at, format!("Invalid parameter '{}' for macro '{}'", other, name))),
+            };
+            let mut default = None;
+            if rest.first().is_some_and(|t| t.token == Token::Equals) {
+                let end = rest.iter().position(|t| t.token == Token::Comma).unwrap_or(rest.len());
+                default = Some(rest[1..end].to_vec());
+                rest = &rest[end..];
+            }
+            parameters.push((parameter, default));
+        }
+
+        self.macros.insert(name, Macro { parameters, body, file: at.file, line: at.line });
+        Ok(())
+    }
+
+    fn invoke(&mut self, name: &str, arguments: &[SpannedToken], at: &SpannedToken, out: &mut Vec<SpannedToken>, sources: &mut SourceFiles) -> Result<(), MacroError> {
+        let definition = self.macros[name].clone();
+        let expansion = Expansion {
+            file: at.file,
+            line: at.line,
+            name: name.to_string(),
+            definition: sources.location(definition.file, definition.line),
+        };
+        let suffix = format!(" (in macro '{}' defined at {})", name, expansion.definition);
+        let in_macro = |e: MacroError| match e.message.ends_with(&suffix) {
+            true => e,
+            false => MacroError { message: e.message + &suffix, ..e },
+        };
+        if self.depth >= MAX_DEPTH {
+            return Err(in_macro(MacroError::new(at, format!("Macro '{}' is nested more than {} levels deep", name, MAX_DEPTH))));
+        }
+
+        let mut values = HashMap::new();
+        let mut position = 0;
+        for argument in split_arguments(arguments) {
+            if let [SpannedToken { token: Token::Label(key), .. }, SpannedToken { token: Token::Equals, .. }, value @ ..] = argument
+                && definition.parameters.iter().any(|(parameter, _)| parameter == key)
+            {
+                values.insert(key.clone(), value.to_vec());
+                continue;
+            }
+            let Some((parameter, _)) = definition.parameters.get(position) else {
+                return Err(in_macro(MacroError::new(at, format!("Too many arguments for macro '{}'", name))));
+            };
+            position += 1;
+            if !argument.is_empty() {
+                values.insert(parameter.clone(), argument.to_vec());
+            }
+        }
+        for (parameter, default) in &definition.parameters {
+            if !values.contains_key(parameter) {
+                let Some(default) = default else {
+                    return Err(in_macro(MacroError::new(at, format!("Missing argument '{}' for macro '{}'", parameter, name))));
+                };
+                values.insert(parameter.clone(), default.clone());
+            }
+        }
+
+        let unique = self.counter.to_string();
+        self.counter += 1;
+        let body = substitute(&definition.body, &values, Some(&unique), Some(at)).map_err(in_macro)?;
+
+        if !self.expansions.contains(&expansion) {
+            self.expansions.push(expansion);
+        }
+
+        // Errors in the body are also in this macro
+        let first = self.errors.len();
+        self.depth += 1;
+        self.process(&body, out, sources);
+        self.depth -= 1;
+        let errors = self.errors.split_off(first);
+        self.errors.extend(errors.into_iter().map(in_macro));
+        Ok(())
+    }
+
+    fn repeat(&mut self, directive: &str, operands: &[SpannedToken], body: &[SpannedToken], at: &SpannedToken, out: &mut Vec<SpannedToken>, sources: &mut SourceFiles) -> Result<(), MacroError> {
+        if directive == ".rept" {
+            let count = self.evaluate(operands, at)?;
+            if count > MAX_REPETITIONS {
+                return Err(MacroError::new(at, format!("Directive .rept count {} is more than {}", count, MAX_REPETITIONS)));
+            }
+            for _ in 0..count.max(0) {
+                self.process(body, out, sources);
+            }
+            return Ok(());
+        }
+
+        let (symbol, values) = match operands {
+            [SpannedToken { token: Token::Label(symbol), .. }, rest @ ..] => (symbol, rest),
+            _ => return Err(MacroError::new(at, format!("Directive {} requires a symbol and a list of values", directive))),
+        };
+        let values = match values.first() {
+            Some(t) if t.token == Token::Comma => &values[1..],
+            _ => values,
+        };
+
+        let values: Vec<Vec<SpannedToken>> = if directive == ".irp" {
+            // with no values the body is assembled once, with the symbol empty
+            let values = split_arguments(values);
+            if values.is_empty() { vec![Vec::new()] } else { values.into_iter().map(<[_]>::to_vec).collect() }
+        } else {
+            let text: String = values.iter().map(|t| t.token.to_string()).collect();
+            text.chars()
+                .map(|c| lexer::tokenize(&c.to_string())
+                    .map(|tokens| tokens.into_iter().filter(|t| t.token != Token::Eof).collect())
+                    .map_err(|errors| MacroError::new(at, format!("Invalid character '{}' in .irpc: {}", c, errors[0]))))
+                .collect::<Result<_, _>>()?
+        };
+
+        for value in values {
+            let bindings = HashMap::from([(symbol.clone(), value)]);
+            let body = substitute(body, &bindings, None, None)?;
+            self.process(&body, out, sources);
+        }
+        Ok(())
+    }
+
+    // Reads and expands an included file in place of the '.include' line
+    fn include(&mut self, operands: &[SpannedToken], at: &SpannedToken, out: &mut Vec<SpannedToken>, sources: &mut SourceFiles) -> Result<(), MacroError> {
+        let [SpannedToken { token: Token::StringLiteral(name), .. }] = operands else {
+            return Err(MacroError::new(at, "Directive .include requires a file name in quotes".to_string()));
+        };
+        let file = sources.include(name, at.file).map_err(|e| MacroError::new(at, e))?;
+        if self.including.contains(&file) {
+            let chain: Vec<&str> = self.including.iter().chain([&file])
+                .skip_while(|&&including| including != file)
+                .map(|&including| sources.name(including))
+                .collect();
+            return Err(MacroError::new(at, format!("Circular include: {}", chain.join(" -> "))));
+        }
+
+        let mut tokens = match lexer::tokenize_file(sources.text(file), file) {
+            Ok(tokens) => tokens,
+            Err(errors) => {
+                for e in errors {
+                    self.report(MacroError { file, line: e.line, message: e.to_string() });
+                }
+                return Ok(());
+            }
+        };
+        // the last line of the file may not end with a newline
+        if let Some(eof) = tokens.last_mut() {
+            eof.token = Token::Newline;
+        }
+
+        self.including.push(file);
+        self.process(&tokens, out, sources);
+        self.including.pop();
+        Ok(())
+    }
+
+    fn condition(&self, directive: &str, operands: &[SpannedToken], at: &SpannedToken) -> Result<bool, MacroError> {
+        match directive {
+            ".if" => Ok(self.evaluate(operands, at)? != 0),
+            _ => match operands {
+                [SpannedToken { token: Token::Label(name), .. }] => Ok(self.defined.contains(name) == (directive == ".ifdef")),
+                _ => Err(MacroError::new(at, format!("Directive {} requires a symbol name", directive))),
+            },
+        }
+    }
+
+    // Evaluates an expression over numbers and the constants defined so far
+    fn evaluate(&self, tokens: &[SpannedToken], at: &SpannedToken) -> Result<i32, MacroError> {
+        let mut tokens = tokens.to_vec();
+        tokens.push(SpannedToken { token: Token::Eof, ..at.clone() });
+        let expr = Parser::new(tokens).parse_expression_only()
+            .map_err(|e| MacroError::new(at, e.message))?;
+        expr.evaluate(&|name| self.constants.get(name).map(|&value| value as u32))
+            .map_err(|message| MacroError::new(at, format!("{} (only constants defined above can be used here)", message)))
+    }
+}
+
+// Index of the statement after any 'label:' prefixes
+fn statement_start(line: &[SpannedToken]) -> usize {
+    let mut start = 0;
+    while let [SpannedToken { token: Token::Label(_), .. }, SpannedToken { token: Token::Colon, .. }, ..] = &line[start..] {
+        start += 2;
+    }
+    start
+}
+
+// Takes the lines up to the directive closing `opener`, and skips past it
+fn collect(lines: &[&[SpannedToken]], i: &mut usize, opener: &str, at: &SpannedToken) -> Result<Vec<SpannedToken>, MacroError> {
+    let (openers, closer): (&[&str], &str) = if opener == ".macro" {
+        (&[".macro"], ".endm")
+    } else {
+        (&[".rept", ".irp", ".irpc"], ".endr")
+    };
+
+    let mut depth = 1;
+    let mut body = Vec::new();
+    while let Some(tokens) = lines.get(*i) {
+        *i += 1;
+        if let Some(Token::Directive(name)) = tokens.get(statement_start(tokens)).map(|t| &t.token) {
+            if openers.contains(&name.as_str()) {
+                depth += 1;
+            } else if name == closer {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(body);
+                }
+            }
+        }
+        body.extend(tokens.iter().cloned());
+    }
+    Err(MacroError::new(at, format!("'{}' without '{}'", opener, closer)))
+}
+
+// Splits macro arguments at the commas outside parentheses
+fn split_arguments(tokens: &[SpannedToken]) -> Vec<&[SpannedToken]> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    let mut arguments = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, t) in tokens.iter().enumerate() {
+        match t.token {
+            Token::LParenthesis => depth += 1,
+            Token::RParenthesis => depth -= 1,
+            Token::Comma if depth == 0 => {
+                arguments.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    arguments.push(&tokens[start..]);
+    arguments
+}
+
+// Replaces the argument references in `body`. A lone '\name' becomes the
+// argument's tokens; words like 'loop\@' are pasted as text and lexed again.
+// With a `site`, every token is moved to it, so that errors point at the call.
+fn substitute(
+    body: &[SpannedToken],
+    values: &HashMap<String, Vec<SpannedToken>>,
+    unique: Option<&str>,
+    site: Option<&SpannedToken>,
+) -> Result<Vec<SpannedToken>, MacroError> {
+    let mut result = Vec::with_capacity(body.len());
+    for token in body {
+        let SpannedToken { file, line, column, width, .. } = *site.unwrap_or(token);
+        let place = |token: Token| SpannedToken { token, line, column, width, file };
+        let Token::MacroText(text) = &token.token else {
+            result.push(place(token.token.clone()));
+            continue;
+        };
+
+        if let Some(value) = text.strip_prefix('\\').and_then(|name| values.get(name)) {
+            result.extend(value.iter().map(|t| place(t.token.clone())));
+            continue;
+        }
+
+        let pasted = paste(text, values, unique);
+        if pasted.contains('\\') {
+            // left for an enclosing .irp or macro, or reported once expansion is done
+            result.push(place(Token::MacroText(pasted)));
+            continue;
+        }
+        let tokens = lexer::tokenize(&pasted).map_err(|errors| MacroError {
+            file,
+            line,
+            message: format!("Invalid text '{}' after substituting macro arguments: {}", pasted, errors[0]),
+        })?;
+        result.extend(tokens.into_iter()
+            .filter(|t| t.token != Token::Eof)
+            .map(|t| place(t.token)));
+    }
+    Ok(result)
+}
+
+// Pastes argument text into a word. '\()' separates a reference from the characters after it.
+fn paste(text: &str, values: &HashMap<String, Vec<SpannedToken>>, unique: Option<&str>) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(index) = rest.find('\\') {
+        out.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+        if let Some(after) = after.strip_prefix('@') {
+            out.push_str(unique.unwrap_or("\\@"));
+            rest = after;
+        } else if let Some(after) = after.strip_prefix("()") {
+            rest = after;
+        } else {
+            let end = after.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(after.len());
+            let name = &after[..end];
+            match values.get(name) {
+                Some(value) => out.extend(value.iter().map(|t| t.token.to_string())),
+                None => {
+                    // kept for an enclosing .irp, along with its separator
+                    out.push('\\');
+                    out.push_str(name);
+                    if after[end..].starts_with("\\()") {
+                        out.push_str("\\()");
+                        rest = &after[end + 3..];
+                        continue;
+                    }
+                }
+            }
+            rest = &after[end..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expands `source` and prints it back, one line per statement, or gives the first error
+    fn expand(source: &str) -> Result<String, MacroError> {
+        let tokens = MacroProcessor::new().expand(lexer::tokenize(source).unwrap(), &mut SourceFiles::default())
+            .map_err(|errors| errors.into_iter().next().unwrap())?;
+        let lines: Vec<String> = tokens.split(|t| t.token == Token::Newline || t.token == Token::Eof)
+            .filter(|line| !line.is_empty())
+            .map(|line| line.iter().map(|t| t.token.to_string()).collect::<Vec<_>>().join(" "))
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    #[test]
+    fn test_macro_arguments() {
+        let source = "\
+.macro push reg, offset=0
+    sw \\reg, \\offset(sp)
+.endm
+.macro spin count
+loop\\@: addi \\count, \\count, -1
+    bnez \\count, loop\\@
+.endm
+push a0
+push a1, 4
+push offset=8, reg=ra
+spin t0
+spin t1
+";
+        assert_eq!(expand(source).unwrap(), "\
+sw x10 , 0 ( x2 )
+sw x11 , 4 ( x2 )
+sw x1 , 8 ( x2 )
+loop3 : addi x5 , x5 , -1
+bnez x5 , loop3
+loop4 : addi x6 , x6 , -1
+bnez x6 , loop4");
+    }
+
+    #[test]
+    fn test_repetitions() {
+        let source = "\
+.rept 2
+nop
+.endr
+.irp reg, a0, a1
+sw \\reg, 0(sp)
+.endr
+.irpc n, 123
+.byte \\n
+.endr
+";
+        assert_eq!(expand(source).unwrap(), "nop\nnop\nsw x10 , 0 ( x2 )\nsw x11 , 0 ( x2 )\n.byte 1\n.byte 2\n.byte 3");
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let source = "\
+.equ DEBUG, 1
+start:
+.if DEBUG && 2 > 1
+li a0, 1
+.else
+li a0, 2
+.endif
+.ifdef start
+nop
+.endif
+.ifndef missing
+.if 0
+ebreak
+.else
+ecall
+.endif
+.endif
+";
+        assert_eq!(expand(source).unwrap(), ".equ DEBUG , 1\nstart :\nli x10 , 1\nnop\necall");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(expand(".macro m\nnop\n").unwrap_err().message, "'.macro' without '.endm'");
+        assert_eq!(expand(".if 1\nnop\n").unwrap_err(), MacroError { file: 0, line: 1, message: "'.if' without '.endif'".to_string() });
+        assert_eq!(expand("nop\n.endif\n").unwrap_err().line, 2);
+        assert_eq!(expand(".rept later\nnop\n.endr\n.equ later, 2\n").unwrap_err().line, 1);
+        assert!(expand("addi \\reg, zero, 1\n").is_err());
+        let error = expand(".rept 100000000\nnop\n.endr\n").unwrap_err();
+        assert_eq!(error.message, "Directive .rept count 100000000 is more than 100000");
+
+        let error = expand(".macro m a\nnop\n.endm\n\nm 1, 2\n").unwrap_err();
+        assert_eq!(error, MacroError { file: 0, line: 5, message: "Too many arguments for macro 'm' (in macro 'm' defined at line 1)".to_string() });
+        let error = expand(".macro forever\nforever\n.endm\nforever\n").unwrap_err();
+        assert_eq!(error.line, 4);
+        assert_eq!(error.message, "Macro 'forever' is nested more than 100 levels deep (in macro 'forever' defined at line 1)");
+    }
+
+    #[test]
+    fn test_errors_on_every_line() {
+        let source = "\
+.macro m a
+addi \\a, \\a, \\b
+.endm
+.if missing
+nop
+.else
+ebreak
+.endif
+m a0
+.rept 2
+.endm
+.endr
+";
+        let tokens = lexer::tokenize(source).unwrap();
+        let errors = MacroProcessor::new().expand(tokens, &mut SourceFiles::default()).unwrap_err();
+        let errors: Vec<(usize, &str)> = errors.iter().map(|e| (e.line, e.message.as_str())).collect();
+        assert_eq!(errors, [
+            (4, "Unknown label 'missing' (only constants defined above can be used here)"),
+            (11, "'.endm' without a matching opening directive"),
+            (9, "'\\b' does not name an argument of an enclosing macro, .irp or .irpc"),
+        ]);
+    }
+}
@@ -1,14 +1,18 @@
-mod config;
-mod lexer;
-mod parser;
-mod symbols;
-mod assembler;
-mod processor;
-mod pseudo;
-mod tui;
+use ruscv::{cli, tui};
 
 fn main() -> Result<(), std::io::Error> {
     let args: Vec<String> = std::env::args().collect();
-    let initial_file = args.get(1).cloned();
-    tui::run(initial_file)
+    match args.get(1).map(String::as_str) {
+        Some("run") => std::process::exit(cli::run(&args[2..])),
+        Some("asm") => std::process::exit(cli::asm(&args[2..])),
+        Some("disasm") => std::process::exit(cli::disasm(&args[2..])),
+        _ => {}
+    }
+    match cli::tui_options(&args[1..]) {
+        Ok((initial_file, memory_map)) => tui::run(initial_file, memory_map),
+        Err(e) => {
+            eprintln!("ruscv: {}\n{}", e, cli::USAGE);
+            std::process::exit(cli::EXIT_USAGE);
+        }
+    }
 }
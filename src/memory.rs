@@ -0,0 +1,377 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::program::Permissions;
+
+pub const PAGE_SIZE: u32 = 4096;
+
+#[derive(Debug, PartialEq)]
+pub enum MemoryFault {
+    OutOfBounds { address: u32 },
+    ReadFromNonReadable { address: u32 },
+    WriteToReadOnly { address: u32 },
+    UnalignedAccess { address: u32 },
+    ExecuteFromNonExecutable { address: u32 },
+}
+
+impl MemoryFault {
+    pub fn address(&self) -> u32 {
+        match *self {
+            MemoryFault::OutOfBounds { address }
+            | MemoryFault::ReadFromNonReadable { address }
+            | MemoryFault::WriteToReadOnly { address }
+            | MemoryFault::UnalignedAccess { address }
+            | MemoryFault::ExecuteFromNonExecutable { address } => address,
+        }
+    }
+}
+
+// What an access needs from the region it touches
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Write,
+    Execute,
+    Debug,  // debugger and loader: only requires the address to be mapped
+}
+
+/// A named, contiguous range of the address space with its access rights.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+    pub permissions: Permissions,
+}
+
+impl Region {
+    pub fn contains(&self, address: u32) -> bool {
+        address.wrapping_sub(self.start) < self.size
+    }
+
+    // exclusive end, as u64 so a region can reach the top of the address space
+    fn end(&self) -> u64 {
+        self.start as u64 + self.size as u64
+    }
+}
+
+/// Sparse 32-bit address space. Only addresses inside a mapped region are
+/// accessible, subject to the region's permissions; backing pages are
+/// allocated on the first write, and untouched pages read as zero.
+#[derive(Default)]
+pub struct Memory {
+    pages: HashMap<u32, Box<[u8; PAGE_SIZE as usize]>>,
+    regions: BTreeMap<u32, Region>,  // keyed by start address
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Unmaps every region and drops all pages.
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.regions.clear();
+    }
+
+    pub fn map(&mut self, name: &str, start: u32, size: u32, permissions: Permissions) -> Result<(), String> {
+        let region = Region { name: name.to_string(), start, size, permissions };
+        if size == 0 {
+            return Err(format!("Region '{}' at 0x{:08x} is empty", name, start));
+        }
+        if region.end() > 1 << 32 {
+            return Err(format!("Region '{}' at 0x{:08x} exceeds the 32-bit address space", name, start));
+        }
+        if let Some(other) = self.regions.values().find(|r| (r.start as u64) < region.end() && (start as u64) < r.end()) {
+            return Err(format!(
+                "Region '{}' (0x{:08x}-0x{:08x}) overlaps '{}' (0x{:08x}-0x{:08x})",
+                name, start, region.end() - 1, other.name, other.start, other.end() - 1
+            ));
+        }
+        self.regions.insert(start, region);
+        Ok(())
+    }
+
+    /// Removes the region starting at `start` and clears its contents.
+    pub fn unmap(&mut self, start: u32) -> Option<Region> {
+        let region = self.regions.remove(&start)?;
+        self.zero_range(region.start, region.size);
+        Some(region)
+    }
+
+    /// Changes the size of the region starting at `start`; memory released by
+    /// shrinking reads as zero if the region grows again.
+    pub fn resize(&mut self, start: u32, size: u32) -> Result<(), String> {
+        let region = self.regions.remove(&start).ok_or_else(|| format!("No region starts at 0x{:08x}", start))?;
+        if size < region.size {
+            self.zero_range(start + size, region.size - size);
+        }
+        if let Err(e) = self.map(&region.name, start, size, region.permissions) {
+            self.regions.insert(start, region);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn region_at(&self, address: u32) -> Option<&Region> {
+        self.regions.range(..=address).next_back()
+            .map(|(_, region)| region)
+            .filter(|region| region.contains(address))
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.values()
+    }
+
+    /// Number of pages that have been written to.
+    pub fn allocated_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    // Zeroes a range, releasing the pages it covers completely
+    fn zero_range(&mut self, start: u32, size: u32) {
+        let end = start as u64 + size as u64;
+        let mut address = start as u64;
+        while address < end {
+            let page_number = (address / PAGE_SIZE as u64) as u32;
+            let page_start = page_number as u64 * PAGE_SIZE as u64;
+            let page_end = (page_start + PAGE_SIZE as u64).min(end);
+            if address == page_start && page_end == page_start + PAGE_SIZE as u64 {
+                self.pages.remove(&page_number);
+            } else if let Some(page) = self.pages.get_mut(&page_number) {
+                page[(address - page_start) as usize..(page_end - page_start) as usize].fill(0);
+            }
+            address = page_end;
+        }
+    }
+
+    fn check(&self, address: u32, access: Access) -> Result<&Region, MemoryFault> {
+        let region = self.region_at(address).ok_or(MemoryFault::OutOfBounds { address })?;
+        let permissions = region.permissions;
+        match access {
+            Access::Read if !permissions.read => Err(MemoryFault::ReadFromNonReadable { address }),
+            Access::Write if !permissions.write => Err(MemoryFault::WriteToReadOnly { address }),
+            Access::Execute if !permissions.execute => Err(MemoryFault::ExecuteFromNonExecutable { address }),
+            _ => Ok(region),
+        }
+    }
+
+    // Whether `len` bytes from `address` lie in one permitted region and one
+    // page, so the access can be served by a single page lookup
+    fn single_page_access(&self, address: u32, len: u32, access: Access) -> bool {
+        let offset = address % PAGE_SIZE;
+        offset + len <= PAGE_SIZE
+            && self.check(address, access).is_ok_and(|region| region.contains(address + (len - 1)))
+    }
+
+    fn read<const N: usize>(&self, address: u32, access: Access) -> Result<[u8; N], MemoryFault> {
+        let mut bytes = [0u8; N];
+        if self.single_page_access(address, N as u32, access) {
+            let offset = (address % PAGE_SIZE) as usize;
+            if let Some(page) = self.pages.get(&(address / PAGE_SIZE)) {
+                bytes.copy_from_slice(&page[offset..offset + N]);
+            }
+        } else {
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = self.read_byte_as(address.wrapping_add(i as u32), access)?;
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn write<const N: usize>(&mut self, address: u32, bytes: [u8; N], access: Access) -> Result<(), MemoryFault> {
+        if self.single_page_access(address, N as u32, access) {
+            let offset = (address % PAGE_SIZE) as usize;
+            self.page_mut(address)[offset..offset + N].copy_from_slice(&bytes);
+        } else {
+            // check the whole range first so a faulting access does not write partially
+            for i in 0..N as u32 {
+                self.check(address.wrapping_add(i), access)?;
+            }
+            for (i, byte) in bytes.into_iter().enumerate() {
+                self.write_byte_as(address.wrapping_add(i as u32), byte, access)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn page_mut(&mut self, address: u32) -> &mut [u8; PAGE_SIZE as usize] {
+        self.pages.entry(address / PAGE_SIZE).or_insert_with(|| Box::new([0; PAGE_SIZE as usize]))
+    }
+
+    fn read_byte_as(&self, address: u32, access: Access) -> Result<u8, MemoryFault> {
+        self.check(address, access)?;
+        Ok(self.pages.get(&(address / PAGE_SIZE)).map_or(0, |page| page[(address % PAGE_SIZE) as usize]))
+    }
+
+    fn write_byte_as(&mut self, address: u32, value: u8, access: Access) -> Result<(), MemoryFault> {
+        self.check(address, access)?;
+        self.page_mut(address)[(address % PAGE_SIZE) as usize] = value;
+        Ok(())
+    }
+
+    pub fn read_byte(&self, address: u32) -> Result<u8, MemoryFault> {
+        self.read_byte_as(address, Access::Read)
+    }
+
+    pub fn write_byte(&mut self, address: u32, value: u8) -> Result<(), MemoryFault> {
+        self.write_byte_as(address, value, Access::Write)
+    }
+
+    pub fn read_half(&self, address: u32) -> Result<u16, MemoryFault> {
+        Ok(u16::from_le_bytes(self.read(address, Access::Read)?))
+    }
+
+    pub fn read_word(&self, address: u32) -> Result<u32, MemoryFault> {
+        Ok(u32::from_le_bytes(self.read(address, Access::Read)?))
+    }
+
+    pub fn write_half(&mut self, address: u32, value: u16) -> Result<(), MemoryFault> {
+        self.write(address, value.to_le_bytes(), Access::Write)
+    }
+
+    pub fn write_word(&mut self, address: u32, value: u32) -> Result<(), MemoryFault> {
+        self.write(address, value.to_le_bytes(), Access::Write)
+    }
+
+    /// Reads an instruction, which requires an executable region.
+    pub fn fetch_word(&self, address: u32) -> Result<u32, MemoryFault> {
+        Ok(u32::from_le_bytes(self.read(address, Access::Execute)?))
+    }
+
+    /// Reads a byte for the debugger, ignoring permissions.
+    pub fn peek_byte(&self, address: u32) -> Result<u8, MemoryFault> {
+        self.read_byte_as(address, Access::Debug)
+    }
+
+    /// Reads a word for the debugger, ignoring permissions.
+    pub fn peek_word(&self, address: u32) -> Result<u32, MemoryFault> {
+        Ok(u32::from_le_bytes(self.read(address, Access::Debug)?))
+    }
+
+    /// Copies a program image into mapped memory, ignoring write protection.
+    pub fn load_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), MemoryFault> {
+        for (i, chunk) in bytes.chunks(4).enumerate() {
+            let chunk_address = address.wrapping_add(i as u32 * 4);
+            match *chunk {
+                [b0, b1, b2, b3] => self.write(chunk_address, [b0, b1, b2, b3], Access::Debug)?,
+                _ => {
+                    for (j, &byte) in chunk.iter().enumerate() {
+                        self.write_byte_as(chunk_address.wrapping_add(j as u32), byte, Access::Debug)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unmapped_access_faults() {
+        let mut memory = Memory::new();
+        memory.map("data", 0x1000, 0x100, Permissions::RW).unwrap();
+        assert_eq!(memory.read_byte(0x0FFF), Err(MemoryFault::OutOfBounds { address: 0x0FFF }));
+        assert_eq!(memory.write_word(0x1100, 1), Err(MemoryFault::OutOfBounds { address: 0x1100 }));
+        // a word straddling the end of the region reports the first unmapped byte
+        assert_eq!(memory.read_word(0x10FE), Err(MemoryFault::OutOfBounds { address: 0x1100 }));
+    }
+
+    #[test]
+    fn test_pages_are_allocated_on_first_write() {
+        let mut memory = Memory::new();
+        memory.map("big", 0x1000_0000, 0x1000_0000, Permissions::RW).unwrap();
+        assert_eq!(memory.read_word(0x1800_0000), Ok(0));
+        assert_eq!(memory.allocated_pages(), 0);
+
+        memory.write_word(0x1800_0000, 0xDEADBEEF).unwrap();
+        assert_eq!(memory.allocated_pages(), 1);
+        assert_eq!(memory.read_word(0x1800_0000), Ok(0xDEADBEEF));
+        assert_eq!(memory.read_byte(0x1800_0000), Ok(0xEF)); // little endian
+        assert_eq!(memory.read_half(0x1800_0002), Ok(0xDEAD));
+    }
+
+    #[test]
+    fn test_access_across_page_boundary() {
+        let mut memory = Memory::new();
+        memory.map("data", 0, 2 * PAGE_SIZE, Permissions::RW).unwrap();
+        memory.write_word(PAGE_SIZE - 2, 0x11223344).unwrap();
+        assert_eq!(memory.read_word(PAGE_SIZE - 2), Ok(0x11223344));
+        assert_eq!(memory.read_half(PAGE_SIZE), Ok(0x1122));
+        assert_eq!(memory.allocated_pages(), 2);
+    }
+
+    #[test]
+    fn test_access_across_adjacent_regions() {
+        let mut memory = Memory::new();
+        memory.map("a", 0x100, 0x10, Permissions::RW).unwrap();
+        memory.map("b", 0x110, 0x10, Permissions::RW).unwrap();
+        memory.write_word(0x10E, 0xAABBCCDD).unwrap();
+        assert_eq!(memory.read_word(0x10E), Ok(0xAABBCCDD));
+        assert_eq!(memory.region_at(0x10F).unwrap().name, "a");
+        assert_eq!(memory.region_at(0x110).unwrap().name, "b");
+    }
+
+    #[test]
+    fn test_overlapping_regions_are_rejected() {
+        let mut memory = Memory::new();
+        memory.map("text", 0x1000, 0x1000, Permissions::RX).unwrap();
+        assert!(memory.map("data", 0x1FFF, 0x10, Permissions::RW).is_err());
+        assert!(memory.map("data", 0x0F00, 0x101, Permissions::RW).is_err());
+        assert!(memory.map("data", 0x1000, 0x10, Permissions::RW).is_err());
+        assert!(memory.map("data", 0x2000, 0x10, Permissions::RW).is_ok());
+        assert!(memory.map("empty", 0x3000, 0, Permissions::RW).is_err());
+    }
+
+    #[test]
+    fn test_region_at_top_of_address_space() {
+        let mut memory = Memory::new();
+        memory.map("top", 0xFFFF_F000, PAGE_SIZE, Permissions::RW).unwrap();
+        memory.write_word(0xFFFF_FFFC, 7).unwrap();
+        assert_eq!(memory.read_word(0xFFFF_FFFC), Ok(7));
+        assert!(memory.map("wrap", 0xFFFF_FF00, 0x200, Permissions::RW).is_err());
+    }
+
+    #[test]
+    fn test_unmap_clears_contents() {
+        let mut memory = Memory::new();
+        memory.map("heap", 0x2000, 0x2000, Permissions::RW).unwrap();
+        memory.load_bytes(0x2FFE, &[1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(memory.read_byte(0x3002), Ok(5));
+
+        memory.unmap(0x2000).unwrap();
+        assert_eq!(memory.allocated_pages(), 0);
+        memory.map("heap", 0x2000, 0x2000, Permissions::RW).unwrap();
+        assert_eq!(memory.read_word(0x2FFE), Ok(0));
+    }
+
+    #[test]
+    fn test_permissions_are_enforced() {
+        let mut memory = Memory::new();
+        memory.map("text", 0x1000, 0x100, Permissions::RX).unwrap();
+        memory.map("data", 0x2000, 0x100, Permissions::RW).unwrap();
+        memory.map("xonly", 0x3000, 0x100, Permissions { read: false, write: false, execute: true }).unwrap();
+        memory.load_bytes(0x1000, &[0x13, 0, 0, 0]).unwrap();
+
+        assert_eq!(memory.write_word(0x1000, 0), Err(MemoryFault::WriteToReadOnly { address: 0x1000 }));
+        assert_eq!(memory.write_byte(0x1003, 0), Err(MemoryFault::WriteToReadOnly { address: 0x1003 }));
+        assert_eq!(memory.read_word(0x1000), Ok(0x13));
+        assert_eq!(memory.fetch_word(0x1000), Ok(0x13));
+
+        assert_eq!(memory.fetch_word(0x2000), Err(MemoryFault::ExecuteFromNonExecutable { address: 0x2000 }));
+        assert_eq!(memory.read_word(0x3000), Err(MemoryFault::ReadFromNonReadable { address: 0x3000 }));
+        assert_eq!(memory.peek_word(0x3000), Ok(0));
+    }
+
+    #[test]
+    fn test_faulting_store_across_regions_writes_nothing() {
+        let mut memory = Memory::new();
+        memory.map("data", 0x1000, 0x10, Permissions::RW).unwrap();
+        memory.map("rodata", 0x1010, 0x10, Permissions::R).unwrap();
+        assert_eq!(memory.write_word(0x100E, 0xFFFF_FFFF), Err(MemoryFault::WriteToReadOnly { address: 0x1010 }));
+        assert_eq!(memory.read_half(0x100E), Ok(0));
+    }
+}
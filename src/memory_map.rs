@@ -0,0 +1,323 @@
+use std::path::Path;
+
+use crate::config;
+use crate::program::Permissions;
+use crate::section::Section;
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+
+/// A part of the address space the sections of a program are placed in, like
+/// a MEMORY region of a linker script.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub origin: u32,
+    pub length: u64,
+    pub permissions: Permissions,
+    // Sections placed here, by name or by prefix as in '.text*'. Sections no
+    // region names go to the first region whose permissions allow them.
+    pub sections: Vec<String>,
+}
+
+impl Region {
+    pub fn new(name: &str, origin: u32, length: u64, permissions: Permissions) -> Self {
+        Self { name: name.to_string(), origin, length, permissions, sections: Vec::new() }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.origin as u64 + self.length
+    }
+
+    fn names(&self, section: &str) -> bool {
+        self.sections.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => section.starts_with(prefix),
+            None => section == pattern,
+        })
+    }
+
+    fn allows(&self, section: &Section) -> bool {
+        (self.permissions.write || !section.permissions.write) && (self.permissions.execute || !section.permissions.execute)
+    }
+}
+
+/// Where the program goes in memory: the regions its sections are placed in,
+/// and the stack, which grows down from `stack_top`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+    pub stack_top: u32,
+    pub stack_size: u32,
+}
+
+/// Code and read-only data from `TEXT_BASE` up to `DATA_BASE`, writable data
+/// from there up to the stack.
+impl Default for MemoryMap {
+    fn default() -> Self {
+        let stack_bottom = config::STACK_BASE - config::STACK_SIZE as u32;
+        Self {
+            regions: vec![
+                Region::new("text", config::TEXT_BASE, (config::DATA_BASE - config::TEXT_BASE) as u64, Permissions::RX),
+                Region::new("data", config::DATA_BASE, (stack_bottom - config::DATA_BASE) as u64, Permissions::RW),
+            ],
+            stack_top: config::STACK_BASE,
+            stack_size: config::STACK_SIZE as u32,
+        }
+    }
+}
+
+impl MemoryMap {
+    /// A map with code from `text_base` and data from `data_base`, each up to
+    /// the other or to the end of the address space.
+    pub fn with_bases(text_base: u32, data_base: u32) -> Self {
+        let length = |origin: u32, next: u32| if next > origin { (next - origin) as u64 } else { (1 << 32) - origin as u64 };
+        Self {
+            regions: vec![
+                Region::new("text", text_base, length(text_base, data_base), Permissions::RX),
+                Region::new("data", data_base, length(data_base, text_base), Permissions::RW),
+            ],
+            ..Self::default()
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Reads a map written in a small part of TOML: a `[[region]]` table for
+    /// each region, with `name`, `origin`, `length`, `permissions` ("rx" by
+    /// default) and optionally `sections`, and a `[stack]` table with `top`
+    /// and `size`. Lengths may be written as "256K" or "1M".
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut map = Self { regions: Vec::new(), ..Self::default() };
+        let mut table = "";
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| format!("Line {}: {}", index + 1, message);
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "[[region]]" {
+                map.regions.push(Region::new("", 0, 0, Permissions::RX));
+                table = "region";
+                continue;
+            }
+            if line == "[stack]" {
+                table = "stack";
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| error(format!("Expected a table or key = value, got '{}'", line)))?;
+            let (key, value) = (key.trim(), value.trim());
+            match (table, key) {
+                ("region", _) => {
+                    let region = map.regions.last_mut().expect("a region table was opened");
+                    match key {
+                        "name" => region.name = string(value).map_err(error)?,
+                        "origin" => region.origin = address(value).map_err(error)?,
+                        "length" => region.length = size(value).map_err(error)?,
+                        "permissions" => region.permissions = permissions(&string(value).map_err(error)?).map_err(error)?,
+                        "sections" => region.sections = strings(value).map_err(error)?,
+                        _ => return Err(error(format!("Unknown region key '{}'", key))),
+                    }
+                }
+                ("stack", "top") => map.stack_top = address(value).map_err(error)?,
+                ("stack", "size") => {
+                    map.stack_size = u32::try_from(size(value).map_err(error)?).map_err(|_| error("The stack size is too large".to_string()))?
+                }
+                _ => return Err(error(format!("Unknown key '{}'", key))),
+            }
+        }
+        if map.regions.iter().any(|region| region.name.is_empty()) {
+            return Err("Every region needs a name".to_string());
+        }
+        map.validate()?;
+        Ok(map)
+    }
+
+    /// Checks that the regions and the stack fit in the address space and do
+    /// not overlap each other.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.regions.is_empty() {
+            return Err("The memory map has no regions".to_string());
+        }
+        let stack = Region::new("stack", self.stack_top.wrapping_sub(self.stack_size), self.stack_size as u64, Permissions::RW);
+        if self.stack_size > self.stack_top {
+            return Err(format!("The {} stack does not fit below 0x{:08x}", format_size(stack.length), self.stack_top));
+        }
+        let mut areas: Vec<&Region> = self.regions.iter().collect();
+        areas.push(&stack);
+        for (index, region) in areas.iter().enumerate() {
+            if region.end() > 1 << 32 {
+                return Err(format!("Region '{}' goes past the end of the address space", region.name));
+            }
+            let overlaps = |other: &&&Region| {
+                region.length > 0 && other.length > 0 && (region.origin as u64) < other.end() && (other.origin as u64) < region.end()
+            };
+            if let Some(other) = areas[..index].iter().find(overlaps) {
+                return Err(format!("Regions '{}' and '{}' overlap", other.name, region.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// The region a section goes to: the first that names it, or else the
+    /// first whose permissions allow it, preferring read-only regions for
+    /// read-only sections.
+    pub fn region_for(&self, section: &Section) -> Result<usize, String> {
+        if let Some(index) = self.regions.iter().position(|region| region.names(&section.name)) {
+            return Ok(index);
+        }
+        self.regions.iter().enumerate()
+            .filter(|(_, region)| region.allows(section))
+            .min_by_key(|(_, region)| region.permissions.write && !section.permissions.write)
+            .map(|(index, _)| index)
+            .ok_or_else(|| format!("No region of the memory map can hold section '{}' ({})", section.name, section.permissions))
+    }
+
+    /// Where `.text` goes, and so where programs start.
+    pub fn text_base(&self) -> u32 {
+        self.origin_for(".text")
+    }
+
+    /// Where `.data` goes, the first address programs can write.
+    pub fn data_base(&self) -> u32 {
+        self.origin_for(".data")
+    }
+
+    fn origin_for(&self, name: &str) -> u32 {
+        let index = self.region_for(&Section::named(name)).unwrap_or(0);
+        self.regions[index].origin
+    }
+}
+
+/// Sizes as people write them: "1 MiB", "256 KiB" or "12 bytes".
+pub fn format_size(size: u64) -> String {
+    match size {
+        0 => "0 bytes".to_string(),
+        size if size % MIB == 0 => format!("{} MiB", size / MIB),
+        size if size % KIB == 0 => format!("{} KiB", size / KIB),
+        size => format!("{} bytes", size),
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn string(value: &str) -> Result<String, String> {
+    value.strip_prefix('"').and_then(|value| value.strip_suffix('"'))
+        .map(str::to_string)
+        .ok_or_else(|| format!("Expected a string in quotes, got {}", value))
+}
+
+fn strings(value: &str) -> Result<Vec<String>, String> {
+    let items = value.strip_prefix('[').and_then(|value| value.strip_suffix(']'))
+        .ok_or_else(|| format!("Expected a list of strings like [\".text\", \".rodata*\"], got {}", value))?;
+    items.split(',').map(str::trim).filter(|item| !item.is_empty()).map(string).collect()
+}
+
+fn number(value: &str) -> Option<u64> {
+    let value = value.replace('_', "");
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn address(value: &str) -> Result<u32, String> {
+    number(value).and_then(|value| u32::try_from(value).ok())
+        .ok_or_else(|| format!("Expected a 32-bit address, got {}", value))
+}
+
+// A number of bytes, or a string with a K or M suffix
+fn size(value: &str) -> Result<u64, String> {
+    let parsed = match string(value) {
+        Ok(text) => {
+            let text = text.trim().trim_end_matches("iB").trim_end_matches('B');
+            let (digits, unit) = match text.strip_suffix(['K', 'k']) {
+                Some(digits) => (digits, KIB),
+                None => text.strip_suffix('M').map_or((text, 1), |digits| (digits, MIB)),
+            };
+            number(digits.trim()).and_then(|digits| digits.checked_mul(unit))
+        }
+        Err(_) => number(value),
+    };
+    parsed.filter(|&size| size <= 1 << 32).ok_or_else(|| format!("Expected a size like 4096 or \"64K\", got {}", value))
+}
+
+/// Permissions written as in a linker script, "rx" or "rw".
+pub fn permissions(text: &str) -> Result<Permissions, String> {
+    let mut permissions = Permissions { read: false, write: false, execute: false };
+    for flag in text.chars() {
+        match flag {
+            'r' => permissions.read = true,
+            'w' => permissions.write = true,
+            'x' => permissions.execute = true,
+            other => return Err(format!("Unknown permission '{}', expected r, w or x", other)),
+        }
+    }
+    Ok(permissions)
+}
+
+/// A region written as `name:origin:length[:permissions]`, as for the
+/// `--region` option.
+pub fn parse_region(text: &str) -> Result<Region, String> {
+    let parts: Vec<&str> = text.split(':').collect();
+    let (name, origin, length, flags) = match parts.as_slice() {
+        [name, origin, length] => (name, origin, length, "rx"),
+        [name, origin, length, flags] => (name, origin, length, *flags),
+        _ => return Err(format!("Invalid region '{}', expected <name>:<origin>:<length>[:<permissions>]", text)),
+    };
+    let length = size(length).or_else(|_| size(&format!("\"{}\"", length)))?;
+    Ok(Region::new(name, address(origin)?, length, permissions(flags)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory_map() {
+        let map = MemoryMap::parse("\
+            # a microcontroller\n\
+            [[region]]\nname = \"rom\"\norigin = 0x0000_0000\nlength = \"256K\"\n\
+            [[region]]\nname = \"ram\" # on chip\norigin = 0x8000_0000\nlength = \"64K\"\npermissions = \"rw\"\n\
+            sections = [\".data*\", \".bss\"]\n\
+            [stack]\ntop = 0x8002_0000\nsize = 4096\n").unwrap();
+        assert_eq!(map.regions, [
+            Region::new("rom", 0, 256 * KIB, Permissions::RX),
+            Region { sections: vec![".data*".to_string(), ".bss".to_string()], ..Region::new("ram", 0x8000_0000, 64 * KIB, Permissions::RW) },
+        ]);
+        assert_eq!((map.stack_top, map.stack_size), (0x8002_0000, 4096));
+        assert_eq!((map.text_base(), map.data_base()), (0, 0x8000_0000));
+
+        let error = MemoryMap::parse("[[region]]\nname = \"a\"\nlength = 0x100\n[[region]]\nname = \"b\"\norigin = 0xFF\nlength = 1\n").unwrap_err();
+        assert_eq!(error, "Regions 'a' and 'b' overlap");
+        let error = MemoryMap::parse("[[region]]\nname = \"a\"\nsize = 4\n").unwrap_err();
+        assert_eq!(error, "Line 3: Unknown region key 'size'");
+        assert!(MemoryMap::parse("[[region]]\nname = \"a\"\norigin = 0x7FFF0000\nlength = \"1M\"\n").unwrap_err().contains("'a' and 'stack'"));
+        assert_eq!(parse_region("rom:0:1M").unwrap(), Region::new("rom", 0, MIB, Permissions::RX));
+        assert!(MemoryMap::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_region_for_sections() {
+        let mut map = MemoryMap::with_bases(0, 0x8000_0000);
+        map.regions.push(Region { sections: vec![".fast*".to_string()], ..Region::new("tcm", 0x4000_0000, KIB, Permissions::RWX) });
+        let region = |name: &str| map.regions[map.region_for(&Section::named(name)).unwrap()].name.clone();
+        assert_eq!(region(".rodata"), "text");
+        assert_eq!(region(".bss"), "data");
+        assert_eq!(region(".fast.loop"), "tcm");
+        assert_eq!(format_size(MIB), "1 MiB");
+    }
+}
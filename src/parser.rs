@@ -1,11 +1,16 @@
 use std::mem::discriminant;
 use std::fmt;
+use std::ops::Range;
 
-use crate::lexer::{SpannedToken, Token, ModifierKind};
+use crate::csr;
+use crate::lexer::{SpannedToken, Token, ModifierKind, Operator};
+use crate::source::FileId;
 
 #[derive(Debug, PartialEq)]
 pub struct ParseError {
+    pub file: FileId,
     pub line: usize,
+    pub columns: Range<usize>, // of the offending tokens
     pub message: String,
 }
 
@@ -15,11 +20,151 @@ impl fmt::Display for ParseError {
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// A constant expression over numbers and symbols, like `BUF_SIZE*4-1` or
+/// `%hi(msg+4)`. Arithmetic is on 32-bit two's complement values and wraps.
+/// Comparisons give -1 for true, as in the GNU assembler, and the logical
+/// operators `&&`, `||` and `!` give 1.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Number(i32),
+    Symbol(String),
+    Modifier(ModifierKind, Box<Expr>),
+    Unary(Operator, Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Evaluates the expression, looking up the value of each symbol with `symbol`.
+    pub fn evaluate(&self, symbol: &dyn Fn(&str) -> Option<u32>) -> Result<i32, String> {
+        match self {
+            Expr::Number(n) => Ok(*n),
+            Expr::Symbol(name) => symbol(name)
+                .map(|value| value as i32)
+                .ok_or_else(|| format!("Unknown label '{}'", name)),
+            Expr::Modifier(kind, _) if kind.is_pc_relative() => {
+                Err(format!("%{} cannot be used inside an expression", modifier_name(kind)))
+            }
+            Expr::Modifier(kind, expr) => Ok(kind.apply(expr.evaluate(symbol)?)),
+            Expr::Unary(op, expr) => {
+                let value = expr.evaluate(symbol)?;
+                match op {
+                    Operator::Minus => Ok(value.wrapping_neg()),
+                    Operator::Tilde => Ok(!value),
+                    Operator::Not => Ok((value == 0) as i32),
+                    _ => Ok(value),
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.evaluate(symbol)?, rhs.evaluate(symbol)?);
+                match op {
+                    Operator::Plus => Ok(lhs.wrapping_add(rhs)),
+                    Operator::Minus => Ok(lhs.wrapping_sub(rhs)),
+                    Operator::Star => Ok(lhs.wrapping_mul(rhs)),
+                    Operator::Slash | Operator::Percent if rhs == 0 => Err("Division by zero in expression".to_string()),
+                    Operator::Slash => Ok(lhs.wrapping_div(rhs)),
+                    Operator::Percent => Ok(lhs.wrapping_rem(rhs)),
+                    Operator::ShiftLeft | Operator::ShiftRight if !(0..32).contains(&rhs) => {
+                        Err(format!("Shift amount {} out of range (0-31)", rhs))
+                    }
+                    Operator::ShiftLeft => Ok(lhs << rhs),
+                    Operator::ShiftRight => Ok(lhs >> rhs),
+                    Operator::Ampersand => Ok(lhs & rhs),
+                    Operator::Pipe => Ok(lhs | rhs),
+                    Operator::Caret => Ok(lhs ^ rhs),
+                    Operator::Equal => Ok(-((lhs == rhs) as i32)),
+                    Operator::NotEqual => Ok(-((lhs != rhs) as i32)),
+                    Operator::Less => Ok(-((lhs < rhs) as i32)),
+                    Operator::LessEqual => Ok(-((lhs <= rhs) as i32)),
+                    Operator::Greater => Ok(-((lhs > rhs) as i32)),
+                    Operator::GreaterEqual => Ok(-((lhs >= rhs) as i32)),
+                    Operator::LogicalAnd => Ok((lhs != 0 && rhs != 0) as i32),
+                    Operator::LogicalOr => Ok((lhs != 0 || rhs != 0) as i32),
+                    Operator::Tilde | Operator::Not => unreachable!("'~' and '!' are only parsed as unary operators"),
+                }
+            }
+        }
+    }
+
+    fn symbols_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Expr::Number(_) => Vec::new(),
+            Expr::Symbol(name) => vec![name],
+            Expr::Modifier(_, expr) | Expr::Unary(_, expr) => expr.symbols_mut(),
+            Expr::Binary(_, lhs, rhs) => {
+                let mut symbols = lhs.symbols_mut();
+                symbols.extend(rhs.symbols_mut());
+                symbols
+            }
+        }
+    }
+
+    fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => Vec::new(),
+            Expr::Symbol(name) => vec![name],
+            Expr::Modifier(_, expr) | Expr::Unary(_, expr) => expr.symbols(),
+            Expr::Binary(_, lhs, rhs) => {
+                let mut symbols = lhs.symbols();
+                symbols.extend(rhs.symbols());
+                symbols
+            }
+        }
+    }
+
+    // Whether the value is known without a symbol table
+    fn is_constant(&self) -> bool {
+        match self {
+            Expr::Number(_) => true,
+            Expr::Symbol(_) => false,
+            Expr::Modifier(kind, _) if kind.is_pc_relative() => false,
+            Expr::Modifier(_, expr) | Expr::Unary(_, expr) => expr.is_constant(),
+            Expr::Binary(_, lhs, rhs) => lhs.is_constant() && rhs.is_constant(),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(n) => write!(f, "{}", n),
+            Expr::Symbol(name) => write!(f, "{}", name),
+            Expr::Modifier(kind, expr) => write!(f, "%{}({})", modifier_name(kind), expr),
+            Expr::Unary(op, expr) if matches!(**expr, Expr::Binary(..)) => write!(f, "{}({})", op, expr),
+            Expr::Unary(op, expr) => write!(f, "{}{}", op, expr),
+            Expr::Binary(op, lhs, rhs) => {
+                // nested operations are parenthesized, so the text reads back the same
+                for (i, side) in [lhs, rhs].into_iter().enumerate() {
+                    if i == 1 {
+                        write!(f, "{}", op)?;
+                    }
+                    if matches!(**side, Expr::Binary(..)) {
+                        write!(f, "({})", side)?;
+                    } else {
+                        write!(f, "{}", side)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn modifier_name(kind: &ModifierKind) -> &'static str {
+    match kind {
+        ModifierKind::Hi => "hi",
+        ModifierKind::Lo => "lo",
+        ModifierKind::PcrelHi => "pcrel_hi",
+        ModifierKind::PcrelLo => "pcrel_lo",
+        ModifierKind::GotPcrelHi => "got_pcrel_hi",
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum MemoryOffset {
     Immediate(i32),
     Label(String),
     Modifier(ModifierKind, String),
+    Expression(Expr),
 }
 
 impl fmt::Display for MemoryOffset {
@@ -27,53 +172,76 @@ impl fmt::Display for MemoryOffset {
         match self {
             MemoryOffset::Immediate(n) => write!(f, "{}", n),
             MemoryOffset::Label(s) => write!(f, "{}", s),
-            MemoryOffset::Modifier(kind, symbol) => {
-                let kind_str = match kind {
-                    ModifierKind::Hi => "hi",
-                    ModifierKind::Lo => "lo",
-                };
-                write!(f, "%{}({})", kind_str, symbol)
-            }
+            MemoryOffset::Modifier(kind, symbol) => write!(f, "%{}({})", modifier_name(kind), symbol),
+            MemoryOffset::Expression(expr) => write!(f, "{}", expr),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Operand {
     Register(u8),
+    Csr(u16),
     Immediate(i32),
     Label(String),
     StringLiteral(String),
+    Attribute(String),
     Modifier(ModifierKind, String),
+    Expression(Expr),
     Memory { offset: MemoryOffset, reg: u8 },
 }
 
+impl Operand {
+    /// The symbol names the operand refers to, so they can be renamed.
+    pub fn symbols_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Operand::Label(name)
+            | Operand::Modifier(_, name)
+            | Operand::Memory { offset: MemoryOffset::Label(name) | MemoryOffset::Modifier(_, name), .. } => vec![name],
+            Operand::Expression(expr) | Operand::Memory { offset: MemoryOffset::Expression(expr), .. } => expr.symbols_mut(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The symbol names the operand refers to.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Operand::Label(name)
+            | Operand::Modifier(_, name)
+            | Operand::Memory { offset: MemoryOffset::Label(name) | MemoryOffset::Modifier(_, name), .. } => vec![name],
+            Operand::Expression(expr) | Operand::Memory { offset: MemoryOffset::Expression(expr), .. } => expr.symbols(),
+            _ => Vec::new(),
+        }
+    }
+}
+
 impl fmt::Display for Operand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Operand::Register(n) => write!(f, "x{}", n),
+            Operand::Csr(address) => match csr::name_of(*address) {
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "0x{:03x}", address),
+            },
             Operand::Immediate(n) => write!(f, "{}", n),
             Operand::Label(s) => write!(f, "{}", s),
-            Operand::StringLiteral(s) => write!(f, "\"{}\"", s),
-            Operand::Modifier(kind, symbol) => {
-                let kind_str = match kind {
-                    ModifierKind::Hi => "hi",
-                    ModifierKind::Lo => "lo",
-                };
-                write!(f, "%{}({})", kind_str, symbol)
-            }
+            Operand::StringLiteral(s) => write!(f, "\"{}\"", s.escape_debug()),
+            Operand::Attribute(s) => write!(f, "@{}", s),
+            Operand::Modifier(kind, symbol) => write!(f, "%{}({})", modifier_name(kind), symbol),
+            Operand::Expression(expr) => write!(f, "{}", expr),
             Operand::Memory { offset, reg } => write!(f, "{}(x{})", offset, reg),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Statement {
     pub kind: StatementKind,
     pub line: usize,
+    pub file: FileId,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum StatementKind {
     Instruction(String, Vec<Operand>),
     Label(String),
@@ -153,38 +321,68 @@ impl Parser {
         if self.check(expected) {
             Ok(self.advance())
         } else {
-            Err(ParseError {
-                line: self.tokens[self.position].line,
-                message: format!("{}. Found: {:?}", error_message, self.peek()),
-            })
+            Err(self.error(format!("{}. Found: {:?}", error_message, self.peek())))
         }
     }
 
+    // An error at the current token
+    fn error(&self, message: String) -> ParseError {
+        self.error_since(self.position, message)
+    }
+
+    // An error about the tokens from `start` up to the current one, or at it if
+    // none were consumed since
+    fn error_since(&self, start: usize, message: String) -> ParseError {
+        let first = &self.tokens[start];
+        let last = &self.tokens[self.position.saturating_sub(1).max(start)];
+        let end = (last.column + last.width).max(first.column + first.width);
+        ParseError { file: first.file, line: first.line, columns: first.column..end, message }
+    }
+
+    // The file and line of the current token
+    fn location(&self) -> (FileId, usize) {
+        let token = &self.tokens[self.position];
+        (token.file, token.line)
+    }
+
     // Checks if we've reached the end of the token stream
     fn is_at_end(&self) -> bool {
         matches!(self.peek(), Token::Eof)
     }
 
-    // Parses the token stream into a list of statements
-    pub fn parse(&mut self) -> Result<Vec<Statement>, ParseError> {
+    // Parses the token stream into a list of statements. After an error the
+    // rest of its line is skipped, and parsing goes on to find the next one.
+    pub fn parse(&mut self) -> Result<Vec<Statement>, Vec<ParseError>> {
         let mut nodes = Vec::new();
+        let mut errors = Vec::new();
         while !self.is_at_end() {
             match self.parse_line() {
                 Ok(Some(stmt)) => nodes.push(stmt),
                 Ok(None) => {
                     continue;
                 },
-                Err(e) => return Err(e),
+                Err(e) => {
+                    errors.push(e);
+                    self.skip_line();
+                }
             }
         }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
         Ok(nodes)
     }
 
+    // Advances past the next newline, or to the end
+    fn skip_line(&mut self) {
+        while !self.is_at_end() && self.advance() != Token::Newline {}
+    }
+
     fn parse_line(&mut self) -> Result<Option<Statement>, ParseError> {
         if self.is_at_end() { return Ok(None); }
 
         let current_token = self.peek().clone();
-        let line = self.tokens[self.position].line;
+        let (file, line) = self.location();
 
         let statement_kind = match current_token {
             Token::Label(label) => {
@@ -206,6 +404,7 @@ impl Parser {
                         operands.push(self.parse_operand()?);
                     }
                 }
+                name_csr_operand(&mnemonic, &mut operands);
                 StatementKind::Instruction(mnemonic, operands)
             },
 
@@ -229,19 +428,16 @@ impl Parser {
                 return Ok(None);
             }
 
-            _ => return Err(ParseError {
-                line,
-                message: format!("Unexpected token: {:?}", current_token)
-            }),
+            _ => return Err(self.error(format!("Unexpected token: {:?}", current_token))),
 
         };
 
-        Ok(Some(Statement { kind: statement_kind, line }))
+        Ok(Some(Statement { kind: statement_kind, line, file }))
     }
 
     fn parse_operand(&mut self) -> Result<Operand, ParseError> {
         let current_token = self.peek().clone();
-        let line = self.tokens[self.position].line;
+        let start = self.position;
 
         match current_token {
             Token::Register(reg) => {
@@ -249,87 +445,145 @@ impl Parser {
                 Ok(Operand::Register(reg))
             }
 
-            Token::Immediate(imm) => {
-                self.advance();
+            // A base register alone, as in 'lw a0, (sp)', has a zero offset
+            Token::LParenthesis if matches!(self.tokens[self.position + 1].token, Token::Register(_)) => {
+                let reg = self.parse_base_register()?;
+                Ok(Operand::Memory { offset: MemoryOffset::Immediate(0), reg })
+            }
+
+            Token::Immediate(_) | Token::Label(_) | Token::Modifier(_) | Token::Operator(_) | Token::LParenthesis => {
+                let expr = self.parse_expression()?;
+                let expr = if expr.is_constant() {
+                    Expr::Number(expr.evaluate(&|_| None).map_err(|message| self.error_since(start, message))?)
+                } else {
+                    expr
+                };
 
                 // Check for memory directions
                 if self.check(&Token::LParenthesis) {
-                    self.advance(); // consume left parenthesis
-
-                    // consume the register inside the parentheses
-                    let reg_token = self.consume(
-                        &Token::Register(0),
-                        "A register was expected inside parentheses for memory addressing"
-                    )?;
-
-                    let reg = match reg_token {
-                        Token::Register(r) => r,
-                        _ => unreachable!(),
+                    let reg = self.parse_base_register()?;
+                    let offset = match expr {
+                        Expr::Number(imm) => MemoryOffset::Immediate(imm),
+                        Expr::Symbol(label) => MemoryOffset::Label(label),
+                        Expr::Modifier(kind, symbol) if matches!(*symbol, Expr::Symbol(_)) => {
+                            let Expr::Symbol(symbol) = *symbol else { unreachable!() };
+                            MemoryOffset::Modifier(kind, symbol)
+                        }
+                        expr => MemoryOffset::Expression(expr),
                     };
-
-                    self.consume(&Token::RParenthesis, "Right parenthesis expected after base register")?;
-
-                    Ok(Operand::Memory { offset: MemoryOffset::Immediate(imm), reg })
+                    Ok(Operand::Memory { offset, reg })
                 } else {
-                    Ok(Operand::Immediate(imm))
+                    Ok(match expr {
+                        Expr::Number(imm) => Operand::Immediate(imm),
+                        Expr::Symbol(label) => Operand::Label(label),
+                        Expr::Modifier(kind, symbol) if matches!(*symbol, Expr::Symbol(_)) => {
+                            let Expr::Symbol(symbol) = *symbol else { unreachable!() };
+                            Operand::Modifier(kind, symbol)
+                        }
+                        expr => Operand::Expression(expr),
+                    })
                 }
             }
 
-            Token::Label(label) => {
-                self.advance();
-                // Check if this is a memory operand with label offset
-                if self.check(&Token::LParenthesis) {
-                    self.advance(); // consume left parenthesis
-
-                    // consume the register inside the parentheses
-                    let reg_token = self.consume(
-                        &Token::Register(0),
-                        "A register was expected inside parentheses for memory addressing"
-                    )?;
-
-                    let reg = match reg_token {
-                        Token::Register(r) => r,
-                        _ => unreachable!(),
-                    };
+            _ => Err(self.error(format!("An operand was expected (register, immediate or label), but was not found: {:?}", current_token))),
+        }
+    }
 
-                    self.consume(&Token::RParenthesis, "Right parenthesis expected after base register")?;
+    // Parses '(reg)' after a memory offset
+    fn parse_base_register(&mut self) -> Result<u8, ParseError> {
+        self.consume(&Token::LParenthesis, "Left parenthesis expected before base register")?;
 
-                    Ok(Operand::Memory { offset: MemoryOffset::Label(label), reg })
-                } else {
-                    Ok(Operand::Label(label))
-                }
-            }
+        // consume the register inside the parentheses
+        let reg_token = self.consume(
+            &Token::Register(0),
+            "A register was expected inside parentheses for memory addressing"
+        )?;
 
-            Token::Modifier(kind, symbol) => {
-                self.advance();
+        let reg = match reg_token {
+            Token::Register(r) => r,
+            _ => unreachable!(),
+        };
 
-                // Check if this is a memory operand with modifier offset
-                if self.check(&Token::LParenthesis) {
-                    self.advance(); // consume left parenthesis
+        self.consume(&Token::RParenthesis, "Right parenthesis expected after base register")?;
+        Ok(reg)
+    }
 
-                    // consume the register inside the parentheses
-                    let reg_token = self.consume(
-                        &Token::Register(0),
-                        "A register was expected inside parentheses for memory addressing"
-                    )?;
+    /// Parses the whole token stream as one expression, for directives that are
+    /// evaluated before parsing, like `.if` and `.rept`.
+    pub fn parse_expression_only(&mut self) -> Result<Expr, ParseError> {
+        let expr = self.parse_expression()?;
+        if !self.is_at_end() {
+            return Err(self.error(format!("Unexpected token after expression: {:?}", self.peek())));
+        }
+        Ok(expr)
+    }
 
-                    let reg = match reg_token {
-                        Token::Register(r) => r,
-                        _ => unreachable!(),
-                    };
+    // Expressions follow the GNU assembler's precedence, from lowest to highest:
+    //   ||
+    //   &&
+    //   == != < <= > >=
+    //   + -
+    //   | ^ &
+    //   * / % << >>
+    //   unary - ~ ! +
+    fn parse_expression(&mut self) -> Result<Expr, ParseError> {
+        self.parse_binary(0)
+    }
 
-                    self.consume(&Token::RParenthesis, "Right parenthesis expected after base register")?;
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, ParseError> {
+        const LEVELS: [&[Operator]; 6] = [
+            &[Operator::LogicalOr],
+            &[Operator::LogicalAnd],
+            &[Operator::Equal, Operator::NotEqual, Operator::Less, Operator::LessEqual, Operator::Greater, Operator::GreaterEqual],
+            &[Operator::Plus, Operator::Minus],
+            &[Operator::Pipe, Operator::Caret, Operator::Ampersand],
+            &[Operator::Star, Operator::Slash, Operator::Percent, Operator::ShiftLeft, Operator::ShiftRight],
+        ];
+        if level == LEVELS.len() {
+            return self.parse_unary();
+        }
 
-                    Ok(Operand::Memory { offset: MemoryOffset::Modifier(kind, symbol), reg })
-                } else {
-                    Ok(Operand::Modifier(kind, symbol))
-                }
+        let mut lhs = self.parse_binary(level + 1)?;
+        while let Token::Operator(op) = *self.peek() {
+            if !LEVELS[level].contains(&op) {
+                break;
             }
+            self.advance();
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
 
-            _ => Err(ParseError {
-                line,
-                message: format!("An operand was expected (register, immediate or label), but was not found: {:?}", current_token),
-            }),
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        match self.peek().clone() {
+            Token::Operator(op @ (Operator::Minus | Operator::Tilde | Operator::Not | Operator::Plus)) => {
+                self.advance();
+                let expr = self.parse_unary()?;
+                Ok(if op == Operator::Plus { expr } else { Expr::Unary(op, Box::new(expr)) })
+            }
+            Token::Immediate(n) => {
+                self.advance();
+                Ok(Expr::Number(n))
+            }
+            Token::Label(name) => {
+                self.advance();
+                Ok(Expr::Symbol(name))
+            }
+            Token::Modifier(kind) => {
+                self.advance();
+                self.consume(&Token::LParenthesis, "Left parenthesis expected after modifier")?;
+                let expr = self.parse_expression()?;
+                self.consume(&Token::RParenthesis, "Right parenthesis expected after modifier expression")?;
+                Ok(Expr::Modifier(kind, Box::new(expr)))
+            }
+            Token::LParenthesis => {
+                self.advance();
+                let expr = self.parse_expression()?;
+                self.consume(&Token::RParenthesis, "Right parenthesis expected to close expression")?;
+                Ok(expr)
+            }
+            token => Err(self.error(format!("A number or symbol was expected in expression, but was not found: {:?}", token))),
         }
     }
 
@@ -341,11 +595,36 @@ impl Parser {
                 self.advance();
                 Ok(Operand::StringLiteral(s))
             },
+            Token::Attribute(s) => {
+                self.advance();
+                Ok(Operand::Attribute(s))
+            },
+            // Section names like '.rodata' are lexed as directives
+            Token::Directive(s) => {
+                self.advance();
+                Ok(Operand::Label(s))
+            },
             _ => self.parse_operand(),
         }
     }
 }
 
+// Names like 'mstatus' or 'time' only name a CSR in the CSR operand of the CSR
+// instructions and pseudo-instructions; anywhere else they are symbols
+fn name_csr_operand(mnemonic: &str, operands: &mut [Operand]) {
+    let index = match mnemonic {
+        "csrrw" | "csrrs" | "csrrc" | "csrrwi" | "csrrsi" | "csrrci" | "csrr" => 1,
+        "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => 0,
+        _ => return,
+    };
+    if let Some(operand) = operands.get_mut(index)
+        && let Operand::Label(name) = operand
+        && let Some(address) = csr::address_of(&name.to_lowercase())
+    {
+        *operand = Operand::Csr(address);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::lexer::tokenize;
@@ -409,6 +688,24 @@ mod tests {
         assert_eq!(nodes[1].line, 2);
     }
 
+    #[test]
+    fn test_csr_operands() {
+        let tokens = tokenize("time: csrrw a0, MSTATUS, a1\ncsrw mie, time\nj time").unwrap();
+        let nodes = Parser::new(tokens).parse().unwrap();
+        assert_eq!(nodes[0].kind, StatementKind::Label("time".to_string()));
+        assert_eq!(nodes[1].kind, StatementKind::Instruction("csrrw".to_string(), vec![
+            Operand::Register(10),
+            Operand::Csr(0x300),
+            Operand::Register(11),
+        ]));
+        // only the CSR operand names a CSR
+        assert_eq!(nodes[2].kind, StatementKind::Instruction("csrw".to_string(), vec![
+            Operand::Csr(0x304),
+            Operand::Label("time".to_string()),
+        ]));
+        assert_eq!(nodes[3].kind, StatementKind::Instruction("j".to_string(), vec![Operand::Label("time".to_string())]));
+    }
+
     #[test]
     fn test_directive_parsing() {
         let tokens = tokenize(".data\nmyVar: .word 42").unwrap();
@@ -483,4 +780,64 @@ mod tests {
             },
         ]));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_expression_parsing() {
+        let tokens = tokenize("addi a0, a0, label+8\nli a1, (4*4)-1\nsw a0, %lo(buf+4)(a1)").unwrap();
+        let nodes = Parser::new(tokens).parse().unwrap();
+        let label_plus_8 = Expr::Binary(Operator::Plus, Box::new(Expr::Symbol("label".to_string())), Box::new(Expr::Number(8)));
+        assert_eq!(nodes[0].kind, StatementKind::Instruction("addi".to_string(), vec![
+            Operand::Register(10),
+            Operand::Register(10),
+            Operand::Expression(label_plus_8),
+        ]));
+        // constant expressions are folded while parsing
+        assert_eq!(nodes[1].kind, StatementKind::Instruction("li".to_string(), vec![
+            Operand::Register(11),
+            Operand::Immediate(15),
+        ]));
+        let StatementKind::Instruction(_, ops) = &nodes[2].kind else { panic!() };
+        assert_eq!(ops[1].to_string(), "%lo(buf+4)(x11)");
+    }
+
+    #[test]
+    fn test_expression_precedence() {
+        let value = |source: &str| {
+            let tokens = tokenize(&format!(".word {}", source)).unwrap();
+            let nodes = Parser::new(tokens).parse().unwrap();
+            match &nodes[0].kind {
+                StatementKind::Directive(_, ops) => match ops[0] {
+                    Operand::Immediate(n) => n,
+                    ref op => panic!("not folded: {:?}", op),
+                },
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(value("1<<12"), 4096);
+        assert_eq!(value("2+3*4"), 14);
+        assert_eq!(value("1+2|4"), 7); // '|' binds tighter than '+', as in GNU as
+        assert_eq!(value("~0xF & 0xFF"), 0xF0);
+        assert_eq!(value("-(2+3) % 3"), -2);
+        assert_eq!(value("'A'+1"), 66);
+        assert_eq!(value("%hi(0x12345800)"), 0x12346);
+        assert_eq!(value("1+2 == 3"), -1);
+        assert_eq!(value("2 < 1 || !0 && 3 >= 3"), 1);
+
+        let tokens = tokenize(".word 1/0").unwrap();
+        let errors = Parser::new(tokens).parse().unwrap_err();
+        assert_eq!(errors[0].message, "Division by zero in expression");
+    }
+
+    #[test]
+    fn test_errors_on_every_line() {
+        let tokens = tokenize("addi x1, x2,\nnop\nloop 5\n.word 1, , 2\nret\n").unwrap();
+        let errors = Parser::new(tokens).parse().unwrap_err();
+        assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<_>>(), [1, 3, 4]);
+        // each error has the columns of the token it is about
+        assert_eq!(errors.iter().map(|e| e.columns.clone()).collect::<Vec<_>>(), [13..14, 6..7, 10..11]);
+
+        // or of the whole expression that cannot be evaluated
+        let errors = Parser::new(tokenize("addi a0, a0, 1 / (2 - 2)\n").unwrap()).parse().unwrap_err();
+        assert_eq!(errors[0].columns, 14..25);
+    }
+}
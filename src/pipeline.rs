@@ -0,0 +1,460 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::assembler::{Assembler, DebugInfo};
+use crate::diagnostic::{Diagnostic, Stage};
+use crate::lexer;
+use crate::linker::{self, LinkError, Object};
+use crate::macros::MacroProcessor;
+use crate::memory_map::MemoryMap;
+use crate::parser::{self, StatementKind};
+use crate::program::{ObjectSection, ObjectSymbol, Program, Relocatable, SymbolBinding, SymbolSection};
+use crate::pseudo;
+use crate::section::{Sections, SECTION_DIRECTIVES};
+use crate::symbols::{self, SymbolTable};
+use crate::source::{FileId, SourceFiles};
+
+/// Assembles a source file into a loadable program: lex, expand macros,
+/// parse, expand pseudo-instructions, resolve symbols and encode, with the
+/// default memory map. Errors are formatted for display, one line per problem.
+pub fn assemble(source: &str) -> Result<Program, String> {
+    let mut sources = SourceFiles::default();
+    let file = sources.add(source, None);
+    assemble_sources(&mut sources, &[file], &MemoryMap::default())
+}
+
+/// Assembles files of `sources` separately and links them into one
+/// program, in the order given, placed in the regions of `map`. Errors name
+/// the file they are in. Every file is parsed, and each stage goes on past
+/// the lines with errors, so that all the errors of the first stage that
+/// has some are reported.
+pub fn assemble_sources(sources: &mut SourceFiles, files: &[FileId], map: &MemoryMap) -> Result<Program, String> {
+    assemble_with_debug_info(sources, files, map).map(|(program, _)| program)
+}
+
+/// Like `assemble_sources`, also returning what each statement assembled to,
+/// for listings.
+pub fn assemble_with_debug_info(sources: &mut SourceFiles, files: &[FileId], map: &MemoryMap) -> Result<(Program, DebugInfo), String> {
+    let mut objects = Vec::with_capacity(files.len());
+    let mut macros = Vec::with_capacity(files.len());
+    let mut diagnostics = Vec::new();
+    for &file in files {
+        let mut processor = MacroProcessor::new();
+        match parse_file(sources, file, &mut processor) {
+            Ok(object) => objects.push(object),
+            Err(errors) => diagnostics.extend(errors),
+        }
+        macros.push(processor);
+    }
+    let render = |diagnostics: Vec<Diagnostic>| render(&diagnostics, sources, &macros);
+    if !diagnostics.is_empty() {
+        return Err(render(diagnostics));
+    }
+
+    linker::link_with_debug_info(&objects, map).map_err(|e| match e {
+        LinkError::Symbol(diagnostics) => render(diagnostics),
+        LinkError::DuplicateSymbol { name, first, second } => {
+            format!("Symbol error: '{}' is defined in both {} and {}", name, sources.name(first), sources.name(second))
+        }
+        LinkError::Unresolved(names) => {
+            format!("Symbol error: the values of {} depend on each other", names.join(", "))
+        }
+        LinkError::Layout(message) => format!("Memory map error: {}", message),
+        LinkError::Assembler(errors) => render(errors.into_iter().map(Diagnostic::from).collect()),
+    })
+}
+
+/// Assembles one file into a relocatable object, with each section from
+/// address 0, for a linker to place. Names the file uses but does not define
+/// are left undefined, to be found in the other objects linked with it.
+pub fn assemble_object(sources: &mut SourceFiles, file: FileId) -> Result<Relocatable, String> {
+    let mut processor = MacroProcessor::new();
+    let object = parse_file(sources, file, &mut processor)
+        .map_err(|diagnostics| render(&diagnostics, sources, std::slice::from_ref(&processor)))?;
+    let render = |diagnostics: Vec<Diagnostic>| render(&diagnostics, sources, std::slice::from_ref(&processor));
+
+    let defined: HashSet<&str> = object.statements.iter()
+        .filter_map(|statement| match &statement.kind {
+            StatementKind::Label(name) => Some(name.as_str()),
+            StatementKind::Directive(name, operands) if name == ".equ" || name == ".set" => operands.first()?.symbols().first().copied(),
+            _ => None,
+        })
+        .collect();
+    // A numeric label reference with no definition, like '1f', is an unknown label
+    let undefined: HashSet<&str> = object.statements.iter()
+        .flat_map(|statement| match &statement.kind {
+            StatementKind::Directive(name, _) if SECTION_DIRECTIVES.contains(&name.as_str()) => &[],
+            StatementKind::Instruction(_, operands) | StatementKind::Directive(_, operands) => operands.as_slice(),
+            StatementKind::Label(_) => &[],
+        })
+        .filter(|operand| !matches!(operand, parser::Operand::Attribute(_) | parser::Operand::StringLiteral(_)))
+        .flat_map(parser::Operand::symbols)
+        .filter(|name| !defined.contains(name) && !name.starts_with(|c: char| c.is_ascii_digit()))
+        .collect();
+
+    let mut table = SymbolTable::with_sections(Sections::default());
+    table.set_externals(undefined.iter().map(|name| (name.to_string(), 0)).collect::<HashMap<_, _>>());
+    table.build(&object.statements).map_err(render)?;
+    let mut assembler = Assembler::relocatable();
+    assembler.assemble(&object.statements, &table)
+        .map_err(|errors| render(errors.into_iter().map(Diagnostic::from).collect()))?;
+
+    // The global offset table is the linker's to build
+    let sections = table.sections().iter()
+        .filter(|section| section.name != ".got")
+        .map(|section| ObjectSection {
+            name: section.name.clone(),
+            data: assembler.section(&section.name).to_vec(),
+            size: section.size,
+            alignment: section.alignment,
+            permissions: section.permissions,
+            nobits: section.nobits,
+        })
+        .collect();
+    let relocations = assembler.relocations().to_vec();
+    // Local labels like '.Lpcrel_hi0' are only kept if a relocation names them
+    let mut symbols: Vec<ObjectSymbol> = table.iter()
+        .filter(|(name, _)| !name.starts_with(".L") || relocations.iter().any(|relocation| relocation.symbol == *name))
+        .map(|(name, value)| {
+            let attributes = table.attributes(name);
+            ObjectSymbol {
+                name: name.to_string(),
+                value,
+                size: attributes.size,
+                binding: attributes.binding,
+                symbol_type: attributes.symbol_type,
+                section: match table.section_of(name) {
+                    Some(section) => SymbolSection::Section(section.to_string()),
+                    None => SymbolSection::Absolute,
+                },
+            }
+        })
+        .chain(undefined.iter().map(|name| ObjectSymbol {
+            name: name.to_string(),
+            value: 0,
+            size: 0,
+            binding: SymbolBinding::Global,
+            symbol_type: Default::default(),
+            section: SymbolSection::Undefined,
+        }))
+        .collect();
+    symbols.sort_by(|a, b| a.value.cmp(&b.value).then_with(|| a.name.cmp(&b.name)));
+    Ok(Relocatable { sections, symbols, relocations })
+}
+
+// Diagnostics for display, one per line. Macro errors already name the macro
+// they are in; the others name the macro invocation they come from.
+fn render(diagnostics: &[Diagnostic], sources: &SourceFiles, macros: &[MacroProcessor]) -> String {
+    diagnostics.iter()
+        .map(|d| match d.stage {
+            Stage::Macros => d.render(sources),
+            _ => format!("{}{}", d.render(sources), macros.iter().map(|processor| processor.context(d.file, d.line)).collect::<String>()),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Lexes, expands and parses one file, with the files it includes. Each stage
+// runs only if the ones before it found no errors.
+fn parse_file(sources: &mut SourceFiles, file: FileId, macros: &mut MacroProcessor) -> Result<Object, Vec<Diagnostic>> {
+    let tokens = lexer::tokenize_file(sources.text(file), file).map_err(diagnostics)?;
+    let tokens = macros.expand(tokens, sources).map_err(diagnostics)?;
+    let statements = parser::Parser::new(tokens).parse().map_err(diagnostics)?;
+    let mut statements = pseudo::expand(statements)?;
+    symbols::number_local_labels(&mut statements);
+    symbols::number_constants(&mut statements);
+    Ok(Object { file, statements })
+}
+
+fn diagnostics<E: Into<Diagnostic>>(errors: Vec<E>) -> Vec<Diagnostic> {
+    errors.into_iter().map(Into::into).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::memory::MemoryFault;
+    use crate::processor::{Processor, StepError};
+    use crate::program::Permissions;
+
+    #[test]
+    fn test_assemble_reports_errors_by_line() {
+        let program = assemble("main: addi a0, zero, 1\n").unwrap();
+        assert_eq!(program.entry, config::TEXT_BASE);
+        assert_eq!(program.symbols[0].name, "main");
+
+        let error = assemble("nop\naddi a0, zero\n").unwrap_err();
+        assert!(error.starts_with("Line 2:"), "{}", error);
+
+        // every line with an error is reported, with its column if known
+        let error = assemble("li a0, 0x\nnop\nsw a0, 0(x99)\n").unwrap_err();
+        assert_eq!(error, "Line 1, column 8: Empty number prefix: '0x'\nLine 3, column 10: Invalid register name: 'x99'");
+        let error = assemble("la a0\nnop\nmv a0, 1\n").unwrap_err();
+        assert_eq!(error, "Line 1: Pseudo-instruction error: Invalid number of operands for 'la' pseudo-instruction. Expected 2, got 1\n\
+            Line 3: Pseudo-instruction error: Invalid second operand for 'mv' pseudo-instruction. Expected a register, got 1");
+        let error = assemble(".data\nnop\n.word 1\n.half\nx:\nx:\n").unwrap_err();
+        assert_eq!(error, "Line 2: Symbol error: Error: Instruction found on .data section\nLine 6: Symbol error: Error: Duplicated label 'x'");
+
+        // and in every file
+        let mut sources = SourceFiles::default();
+        let a = sources.add("nop\nlw a0,\n", Some(std::path::Path::new("a.s")));
+        let b = sources.add("addi a0, a0, 0x\n", Some(std::path::Path::new("b.s")));
+        let error = assemble_sources(&mut sources, &[a, b], &MemoryMap::default()).unwrap_err();
+        assert_eq!(error, "a.s:2:7: An operand was expected (register, immediate or label), but was not found: Newline\nb.s:1:14: Empty number prefix: '0x'");
+    }
+
+    #[test]
+    fn test_assemble_object_leaves_addresses_to_the_linker() {
+        use crate::program::{Relocation, RelocationKind};
+
+        let mut sources = SourceFiles::default();
+        let file = sources.add(".globl main\nmain: call helper\nlui a0, %hi(count)\nsw a0, %lo(count)(a0)\n\
+            loop: bnez a0, loop\nbeqz a0, done\n.data\n.word main+4, loop-main\ncount: .word 0\n.text\ndone: ret\n", None);
+        let object = assemble_object(&mut sources, file).unwrap();
+
+        let relocation = |section: &str, offset, kind, symbol: &str, addend| Relocation {
+            section: section.to_string(), offset, kind, symbol: symbol.to_string(), addend,
+        };
+        assert_eq!(object.relocations, [
+            relocation(".text", 0, RelocationKind::PcrelHi20, "helper", 0),
+            relocation(".text", 4, RelocationKind::PcrelLo12I, ".Lpcrel_hi0", 0),
+            relocation(".text", 8, RelocationKind::Hi20, "count", 0),
+            relocation(".text", 12, RelocationKind::Lo12S, "count", 0),
+            relocation(".data", 0, RelocationKind::Abs32, "main", 4),
+        ]);
+        // branches within a section and differences of labels need no relocation
+        let text = &object.sections[0];
+        assert_eq!(text.data[16..20], 0x00051063u32.to_le_bytes()); // bnez a0, 0
+        assert_eq!(text.data[20..24], 0x00050263u32.to_le_bytes()); // beqz a0, 4
+        assert_eq!(object.sections[1].data, [0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0]);
+
+        let symbol = |name: &str| object.symbols.iter().find(|symbol| symbol.name == name).unwrap();
+        assert_eq!(symbol("helper").section, SymbolSection::Undefined);
+        assert_eq!(symbol("helper").binding, SymbolBinding::Global);
+        assert_eq!((symbol("count").value, &symbol("count").section), (8, &SymbolSection::Section(".data".to_string())));
+        assert_eq!(symbol("done").value, 24);
+
+        // a call or la of a label in the same section is completed here
+        let file = sources.add("main: call helper\ntail helper\nla a0, main\nhelper: ret\n", None);
+        let object = assemble_object(&mut sources, file).unwrap();
+        assert_eq!(object.relocations, []);
+        let text = &object.sections[0].data;
+        assert_eq!(text[0..8], [0x00000097u32.to_le_bytes(), 0x018080e7u32.to_le_bytes()].concat()); // call 24
+        assert_eq!(text[8..16], [0x00000317u32.to_le_bytes(), 0x01030067u32.to_le_bytes()].concat()); // tail 16
+        assert_eq!(text[16..24], [0x00000517u32.to_le_bytes(), 0xff050513u32.to_le_bytes()].concat()); // la a0, -16
+
+        let file = sources.add("addi a0, a0, x\n.half x\n", None);
+        let error = assemble_object(&mut sources, file).unwrap_err();
+        assert!(error.starts_with("Line 1: The address of 'x' is not known in a relocatable object"), "{}", error);
+        assert!(error.contains("\nLine 2: The address of 'x' does not fit in .half"), "{}", error);
+    }
+
+    #[test]
+    fn test_constants_are_immediates() {
+        let program = assemble(".equ SIZE, 0x10\n.globl main\nmain: li a0, SIZE\naddi a1, zero, SIZE-1\nslli a2, a2, SIZE/4\n").unwrap();
+        let text = &program.segments[0].data;
+        // li of a small constant is a single addi
+        assert_eq!(text.len(), 12);
+        assert_eq!(text[0..4], 0x01000513u32.to_le_bytes()); // addi a0, zero, 16
+        assert_eq!(text[4..8], 0x00f00593u32.to_le_bytes()); // addi a1, zero, 15
+        assert_eq!(text[8..12], 0x00461613u32.to_le_bytes()); // slli a2, a2, 4
+
+        let size = program.symbols.iter().find(|symbol| symbol.name == "SIZE").unwrap();
+        assert!(size.absolute);
+        let main = program.symbols.iter().find(|symbol| symbol.name == "main").unwrap();
+        assert_eq!(main.binding, crate::program::SymbolBinding::Global);
+    }
+
+    #[test]
+    fn test_constants_assigned_again() {
+        let source = ".set N, 1\nmain: li a0, N\n.set N, N + 1\naddi a1, zero, N\n.data\nbuf:\n.set N, buf\n.word N\n.set N, 7\n";
+        let program = assemble(source).unwrap();
+        let text = &program.segments[0].data;
+        assert_eq!(text[0..4], 0x00100513u32.to_le_bytes()); // addi a0, zero, 1
+        assert_eq!(text[4..8], 0x00200593u32.to_le_bytes()); // addi a1, zero, 2
+        assert_eq!(program.segments[1].data, config::DATA_BASE.to_le_bytes());
+        // the symbol has its last value, and the earlier ones are not program symbols
+        let names: Vec<_> = program.symbols.iter().map(|symbol| (symbol.name.as_str(), symbol.address)).collect();
+        assert_eq!(names, [("N", 7), ("main", config::TEXT_BASE), ("buf", config::DATA_BASE)]);
+
+        let error = assemble("N: nop\n.set N, 1\n.set N, 2\n").unwrap_err();
+        assert!(error.contains("Duplicated label 'N'"), "{}", error);
+    }
+
+    #[test]
+    fn test_numeric_local_labels() {
+        let program = assemble("main: li t0, 3\n1: addi t0, t0, -1\nbnez t0, 1b\nbeqz t0, 1f\nnop\n1: j 1f\n1: j 1b\n").unwrap();
+        let text = &program.segments[0].data;
+        assert_eq!(text[8..12], 0xfe029ee3u32.to_le_bytes()); // bnez t0, -4
+        assert_eq!(text[12..16], 0x00028463u32.to_le_bytes()); // beqz t0, 8
+        assert_eq!(text[20..24], 0x0040006fu32.to_le_bytes()); // j 4
+        assert_eq!(text[24..28], 0x0000006fu32.to_le_bytes()); // j 0
+        // the renamed labels are not program symbols
+        assert_eq!(program.symbols.len(), 1);
+
+        let error = assemble("1: nop\nj 1f\n").unwrap_err();
+        assert!(error.starts_with("Line 2:") && error.contains("Unknown label '1f'"), "{}", error);
+    }
+
+    #[test]
+    fn test_pc_relative_addresses() {
+        let source = "main: la a0, msg\ncall f\nlw a1, msg\nsw a1, msg, t0\n\
+            1: auipc a2, %pcrel_hi(msg)\naddi a2, a2, %pcrel_lo(1b)\nlga a3, msg\nf: ret\n\
+            .data\n.space 0x804\nmsg: .word 7\n";
+        let program = assemble(source).unwrap();
+        let words: Vec<u32> = program.segments[0].data.chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(words, [
+            0x00101517, 0x80450513, // auipc a0, 0x101; addi a0, a0, -2044
+            0x00000097, 0x028080e7, // auipc ra, 0; jalr ra, 40(ra)
+            0x00100597, 0x7f45a583, // auipc a1, 0x100; lw a1, 2036(a1)
+            0x00100297, 0x7eb2a623, // auipc t0, 0x100; sw a1, 2028(t0)
+            0x00100617, 0x7e460613, // auipc a2, 0x100; addi a2, a2, 2020
+            0x00100697, 0x7e06a683, // auipc a3, 0x100; lw a3, 2016(a3), from the GOT
+            0x00008067,             // ret
+        ]);
+        // the GOT entry for msg is in a section of its own after the data
+        let got = &program.segments[2];
+        assert_eq!((got.name.as_str(), got.vaddr), ("got", config::DATA_BASE + 0x808));
+        assert_eq!(got.data, (config::DATA_BASE + 0x804).to_le_bytes());
+        // the auipc labels are not program symbols
+        assert_eq!(program.symbols.iter().map(|symbol| symbol.name.as_str()).collect::<Vec<_>>(), ["main", "f", "msg"]);
+
+        let error = assemble("addi a0, a0, %pcrel_lo(main)\nmain: nop\n").unwrap_err();
+        assert!(error.contains("%pcrel_lo(main) must name an earlier auipc"), "{}", error);
+
+        // an undefined target is reported once, not again for the %pcrel_lo of its auipc
+        let error = assemble("call g\nla a0, g\n").unwrap_err();
+        assert_eq!(error, "Line 1: Unknown label 'g'\nLine 2: Unknown label 'g'");
+    }
+
+    #[test]
+    fn test_sections() {
+        let source = ".section .text.startup, \"ax\"\nmain: la t0, table\nsw zero, 0(t0)\n\
+            .rodata\ntable: .byte 1, 2, 3\n.bss\nbuffer: .space 64\n\
+            .pushsection .data.counters\n.align 2\ncount: .word 5\n.popsection\nend: .space 4\n\
+            .data\nflag: .byte 1\n.previous\nlast: .space 4\n";
+        let program = assemble(source).unwrap();
+        let layout: Vec<(&str, u32, u32, Permissions)> = program.segments.iter()
+            .map(|segment| (segment.name.as_str(), segment.vaddr, segment.mem_size, segment.permissions))
+            .collect();
+        assert_eq!(layout, [
+            ("text.startup", config::TEXT_BASE, 12, Permissions::RX),
+            ("rodata", config::TEXT_BASE + 0x1000, 3, Permissions::R), // on a page of its own
+            ("data", config::DATA_BASE, 1, Permissions::RW),
+            ("data.counters", config::DATA_BASE + 4, 4, Permissions::RW),
+            ("bss", config::DATA_BASE + 8, 72, Permissions::RW),
+        ]);
+        // .bss takes no bytes in the image, and goes on after .previous
+        assert!(program.segments[4].data.is_empty());
+        let address = |name| program.symbols.iter().find(|symbol| symbol.name == name).unwrap().address;
+        assert_eq!((address("end"), address("last")), (config::DATA_BASE + 8 + 64, config::DATA_BASE + 8 + 68));
+
+        // each section is loaded with its own permissions
+        let mut processor = Processor::new(config::TEXT_BASE, config::DATA_BASE, config::STACK_BASE, 1024);
+        processor.load(&program).unwrap();
+        assert_eq!(processor.read_memory_byte(address("table") + 1), Ok(2));
+        assert_eq!(processor.read_memory_byte(address("buffer") + 63), Ok(0));
+        processor.step().unwrap();
+        processor.step().unwrap();
+        let fault = MemoryFault::WriteToReadOnly { address: address("table") };
+        assert_eq!(processor.step(), Err(StepError::MemoryFault(fault)));
+
+        let error = assemble(".data\nnop\n").unwrap_err();
+        assert!(error.contains("Instruction found on .data section"), "{}", error);
+        let error = assemble(".bss\n.word 1\n").unwrap_err();
+        assert!(error.contains("Directive .word cannot be used in .bss"), "{}", error);
+        let error = assemble(".section .x, \"aw\"\n.byte 1\n.section .x, \"ax\"\n").unwrap_err();
+        assert!(error.contains("Section '.x' was already used with other flags"), "{}", error);
+    }
+
+    #[test]
+    fn test_segments_with_other_permissions_do_not_share_a_page() {
+        let program = assemble("main: nop\n.rodata\n.byte 1\n.section .init, \"ax\"\nnop\n.data\n.byte 2\n.bss\n.space 8\n").unwrap();
+        let elf = crate::elf::write_executable(&program);
+        let field = |offset: usize| u32::from_le_bytes(elf[offset..offset + 4].try_into().unwrap());
+        let (phoff, phnum) = (field(28) as usize, u16::from_le_bytes([elf[44], elf[45]]) as usize);
+        // the flags and the pages of each PT_LOAD
+        let loads: Vec<(u32, u32, u32)> = (0..phnum)
+            .map(|index| phoff + 32 * index)
+            .filter(|&header| field(header) == 1)
+            .map(|header| (field(header + 24), field(header + 8) / 0x1000, (field(header + 8) + field(header + 20) - 1) / 0x1000))
+            .collect();
+        assert_eq!(loads.len(), 5);
+        for (index, &(flags, first, last)) in loads.iter().enumerate() {
+            for &(other_flags, other_first, other_last) in &loads[index + 1..] {
+                assert!(flags == other_flags || last < other_first || other_last < first, "{:x?}", loads);
+            }
+        }
+    }
+
+    #[test]
+    fn test_section_at_the_top_of_the_address_space() {
+        let map = MemoryMap::with_bases(0xffff_f000, config::DATA_BASE);
+        let mut sources = SourceFiles::default();
+        let file = sources.add("main: nop\n.space 4092\n.align 2\n.data\nx: .word 1\n", None);
+        let program = assemble_sources(&mut sources, &[file], &map).unwrap();
+        assert_eq!((program.segments[0].vaddr, program.segments[0].mem_size), (0xffff_f000, 0x1000));
+        assert!(crate::listing::symbol_map(&program).contains("fffff000  00000000  .text"));
+
+        // but a label there would be at 2^32
+        let file = sources.add("main: nop\n.space 4092\nend:\n", None);
+        let error = assemble_sources(&mut sources, &[file], &map).unwrap_err();
+        assert_eq!(error, "Line 3: Symbol error: Label 'end' overflows the address space");
+        let file = sources.add(".data\n.space 0xffffffff\n.space 1\n", None);
+        let error = assemble_sources(&mut sources, &[file], &map).unwrap_err();
+        assert_eq!(error, "Line 3: Symbol error: Section .data overflows the address space");
+    }
+
+    #[test]
+    fn test_macro_errors_name_the_definition() {
+        let source = ".macro load reg, value\naddi \\reg, zero, \\value\n.endm\nload a0, 1\n";
+        let program = assemble(source).unwrap();
+        assert_eq!(program.segments[0].data[0..4], 0x00100513u32.to_le_bytes()); // addi a0, zero, 1
+
+        let error = assemble(&format!("{}load a1\n", source)).unwrap_err();
+        assert_eq!(error, "Line 5: Missing argument 'value' for macro 'load' (in macro 'load' defined at line 1)");
+        let error = assemble(&format!("{}load a1, 5000\n", source)).unwrap_err();
+        assert!(error.starts_with("Line 5:") && error.ends_with("(in macro 'load' defined at line 1)"), "{}", error);
+    }
+
+    #[test]
+    fn test_include_and_link_files() {
+        let dir = std::env::temp_dir().join(format!("ruscv-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("inc")).unwrap();
+        std::fs::write(dir.join("inc/defs.s"), ".equ EXIT, 93\n.macro exit code\nli a0, \\code\nli a7, EXIT\necall\n.endm\n").unwrap();
+        std::fs::write(dir.join("main.s"), ".include \"defs.s\"\n.globl main\nmain: call helper\nexit 0\n").unwrap();
+        std::fs::write(dir.join("helper.s"), ".globl helper\nhelper: ret\n").unwrap();
+        std::fs::write(dir.join("loop.s"), ".include \"loop.s\"\n").unwrap();
+
+        let mut sources = SourceFiles::new(vec![dir.join("inc")]);
+        let main = sources.read(&dir.join("main.s")).unwrap();
+        let helper = sources.read(&dir.join("helper.s")).unwrap();
+        let program = assemble_sources(&mut sources, &[main, helper], &MemoryMap::default()).unwrap();
+        let helper = program.symbols.iter().find(|symbol| symbol.name == "helper").unwrap();
+        assert_eq!(helper.address, config::TEXT_BASE + 20); // after call (2), li, li and ecall
+
+        // errors name the file, and the included file is not found without its include path
+        let mut sources = SourceFiles::default();
+        let main = sources.read(&dir.join("main.s")).unwrap();
+        let error = assemble_sources(&mut sources, &[main], &MemoryMap::default()).unwrap_err();
+        assert_eq!(error, format!("{}:1: Cannot find included file 'defs.s'", dir.join("main.s").display()));
+
+        let mut sources = SourceFiles::default();
+        let file = sources.read(&dir.join("loop.s")).unwrap();
+        let error = assemble_sources(&mut sources, &[file], &MemoryMap::default()).unwrap_err();
+        let path = dir.join("loop.s").display().to_string();
+        assert_eq!(error, format!("{}:1: Circular include: {} -> {}", path, path, path));
+
+        let mut sources = SourceFiles::new(vec![dir.join("inc")]);
+        let file = sources.add(".include \"defs.s\"\nexit\n", Some(&dir.join("bad.s")));
+        let error = assemble_sources(&mut sources, &[file], &MemoryMap::default()).unwrap_err();
+        assert_eq!(error, format!(
+            "{}:2: Missing argument 'code' for macro 'exit' (in macro 'exit' defined at {}:2)",
+            dir.join("bad.s").display(),
+            dir.join("inc/defs.s").display(),
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
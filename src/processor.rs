@@ -1,95 +1,52 @@
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+
+use crate::bus::{Bus, Device};
+use crate::clint::{self, Clint};
 use crate::config;
+use crate::csr::{self, CsrFile};
+use crate::memory::{Memory, MemoryFault, PAGE_SIZE};
+use crate::memory_map::MemoryMap;
+use crate::program::{Permissions, Program, ProgramSymbol};
+use crate::syscalls::{RarsSyscalls, StdConsole, SyscallHandler};
 
-// TODO: this is not a good way to represent memory, it should be a
-// contiguous block of memory with different segments;
-// view the read_byte and write_byte methods to see how memory is accessed.
-struct Memory {
-    text: Vec<u8>,
-    data: Vec<u8>,
-    stack: Vec<u8>,
-    text_base: u32,
-    data_base: u32,
+pub struct Processor {
+    pc: u32,
+    registers: [u32; config::NUM_REGISTERS],
+    csrs: CsrFile,
+    clint: Rc<RefCell<Clint>>,  // also attached to the bus
+    bus: Bus,
+    memory: Memory,
+    heap_base: u32,
+    program_break: u32,
     stack_base: u32,
+    stack_size: usize,
+    entry: u32,
+    symbols: Vec<ProgramSymbol>,
+    misaligned_access: MisalignedAccess,
+    trap_mode: TrapMode,
+    // Taken out while a call is being serviced so the handler can borrow the processor
+    syscalls: Option<Box<dyn SyscallHandler>>,
 }
 
-#[derive(Debug, PartialEq)]
-pub enum MemoryFault {
-    OutOfBounds { address: u32 },
-    WriteToReadOnly { address: u32 },           // TODO
-    UnalignedAccess { address: u32 },           // TODO
-    ExecuteFromNonExecutable { address: u32 },  // TODO: check in fetch
-}
-
-impl Memory {
-    fn read_byte(&self, address: u32) -> Result<u8, MemoryFault> {
-        if address >= self.text_base && address < self.text_base + self.text.len() as u32 {
-            Ok(self.text[(address - self.text_base) as usize])
-        } else if address >= self.data_base && address < self.data_base + self.data.len() as u32 {
-            Ok(self.data[(address - self.data_base) as usize])
-        } else if address >= self.stack_base && address < self.stack_base + self.stack.len() as u32 {
-            Ok(self.stack[(address - self.stack_base) as usize])
-        } else {
-            Err(MemoryFault::OutOfBounds { address })
-        }
-    }
-
-    fn write_byte(&mut self, address: u32, value: u8) -> Result<(), MemoryFault> {
-        if address >= self.text_base && address < self.text_base + self.text.len() as u32 {
-            self.text[(address - self.text_base) as usize] = value;
-        } else if address >= self.data_base && address < self.data_base + self.data.len() as u32 {
-            self.data[(address - self.data_base) as usize] = value;
-        } else if address >= self.stack_base && address < self.stack_base + self.stack.len() as u32 {
-            self.stack[(address - self.stack_base) as usize] = value;
-        } else {
-            return Err(MemoryFault::OutOfBounds { address });
-        }
-        Ok(())
-    }
-
-    fn write_half(&mut self, address: u32, value: u16) -> Result<(), MemoryFault> {
-        let byte0 = value as u8;
-        let byte1 = (value >> 8) as u8;
-        self.write_byte(address, byte0)?;
-        self.write_byte(address + 1, byte1)?;
-        Ok(())
-    }
-
-    fn write_word(&mut self, address: u32, value: u32) -> Result<(), MemoryFault> {
-        let byte0 = value as u8;
-        let byte1 = (value >> 8) as u8;
-        let byte2 = (value >> 16) as u8;
-        let byte3 = (value >> 24) as u8;
-        self.write_byte(address, byte0)?;
-        self.write_byte(address + 1, byte1)?;
-        self.write_byte(address + 2, byte2)?;
-        self.write_byte(address + 3, byte3)?;
-        Ok(())
-    }
-
-    fn read_half(&self, address: u32) -> Result<u16, MemoryFault> {
-        let byte0 = self.read_byte(address)?;
-        let byte1 = self.read_byte(address + 1)?;
-        Ok((byte1 as u16) << 8 | (byte0 as u16))
-    }
-
-    fn read_word(&self, address: u32) -> Result<u32, MemoryFault> {
-        let byte0 = self.read_byte(address)?;
-        let byte1 = self.read_byte(address + 1)?;
-        let byte2 = self.read_byte(address + 2)?;
-        let byte3 = self.read_byte(address + 3)?;
-        Ok(
-            (byte3 as u32) << 24 |
-            (byte2 as u32) << 16 |
-            (byte1 as u32) << 8 |
-            (byte0 as u32)
-        )
-    }
+/// What a halfword or word load/store does when its address is not a
+/// multiple of its size.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MisalignedAccess {
+    #[default]
+    Fault,
+    Emulate,  // performed byte by byte, as some hardware does transparently
 }
 
-pub struct Processor {
-    pc: u32,
-    registers: [u32; config::NUM_REGISTERS],
-    memory: Memory,
+/// What happens when an instruction raises an exception.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TrapMode {
+    /// Trap to `mtvec` once the program has installed a handler; while
+    /// `mtvec` is zero, stepping stops with the error
+    #[default]
+    Handler,
+    /// Always stop with the error, even if a handler is installed
+    Halt,
 }
 
 #[derive(Debug, PartialEq)]
@@ -97,6 +54,12 @@ pub enum StepError {
     IllegalInstruction,
     MemoryFault(MemoryFault),
     Ebreak,
+    Ecall,  // no system call handler is installed
+    Exit(i32),
+    // An ECALL needs console input that is not available yet; the PC is not
+    // advanced, so stepping again retries the call once input has arrived
+    WaitingForInput,
+    Syscall(String),
 }
 
 impl From<MemoryFault> for StepError {
@@ -106,8 +69,9 @@ impl From<MemoryFault> for StepError {
     }
 }
 
+/// A decoded instruction, shared by the executor and the disassembler.
 #[derive(Debug, PartialEq)]
-enum Instruction {
+pub(crate) enum Instruction {
     // R-type: register op register
     Add  { rd: usize, rs1: usize, rs2: usize },
     Sub  { rd: usize, rs1: usize, rs2: usize },
@@ -120,6 +84,16 @@ enum Instruction {
     Slt  { rd: usize, rs1: usize, rs2: usize },
     Sltu { rd: usize, rs1: usize, rs2: usize },
 
+    // R-type: RV32M multiply and divide
+    Mul    { rd: usize, rs1: usize, rs2: usize },
+    Mulh   { rd: usize, rs1: usize, rs2: usize },
+    Mulhsu { rd: usize, rs1: usize, rs2: usize },
+    Mulhu  { rd: usize, rs1: usize, rs2: usize },
+    Div    { rd: usize, rs1: usize, rs2: usize },
+    Divu   { rd: usize, rs1: usize, rs2: usize },
+    Rem    { rd: usize, rs1: usize, rs2: usize },
+    Remu   { rd: usize, rs1: usize, rs2: usize },
+
     // I-type: register op immediate
     Addi  { rd: usize, rs1: usize, imm: i32 },
     Andi  { rd: usize, rs1: usize, imm: i32 },
@@ -162,6 +136,16 @@ enum Instruction {
     // System
     Ecall,
     Ebreak,
+    Mret,
+    Wfi,
+
+    // Zicsr: atomic read and write/set/clear of a CSR
+    Csrrw  { rd: usize, rs1: usize, csr: u16 },
+    Csrrs  { rd: usize, rs1: usize, csr: u16 },
+    Csrrc  { rd: usize, rs1: usize, csr: u16 },
+    Csrrwi { rd: usize, uimm: u32, csr: u16 },
+    Csrrsi { rd: usize, uimm: u32, csr: u16 },
+    Csrrci { rd: usize, uimm: u32, csr: u16 },
 }
 
 impl Processor {
@@ -169,32 +153,88 @@ impl Processor {
         let mut registers = [0; config::NUM_REGISTERS];
         registers[2] = stack_base; // x2 is the architectural stack pointer (sp) in RISC-V
 
-        Processor {
+        let mut processor = Processor {
             pc: text_base,              // Default starts at text_base
             registers,
-            memory: Memory {
-                text: Vec::new(),       // filled by load
-                data: Vec::new(),       // filled by load
-                stack: vec![0u8; stack_size],  // pre-allocated, grows downward from stack_base
-                text_base,
-                data_base,
-                stack_base,
-            },
+            csrs: CsrFile::new(),
+            clint: Rc::new(RefCell::new(Clint::new())),
+            bus: Bus::new(),
+            memory: Memory::new(),      // segments are mapped by load
+            heap_base: data_base,       // moved after the program image by load
+            program_break: data_base,
+            stack_base,
+            stack_size,
+            entry: text_base,
+            symbols: Vec::new(),
+            misaligned_access: MisalignedAccess::default(),
+            trap_mode: TrapMode::default(),
+            syscalls: Some(Box::new(RarsSyscalls::new(StdConsole))),
+        };
+        processor.map_stack().expect("the stack is the only region of an empty address space");
+        processor.bus.attach("clint", clint::BASE, clint::SIZE, Box::new(processor.clint.clone()))
+            .expect("the bus starts empty");
+        processor
+    }
+
+    /// A processor with the stack the memory map gives, for programs placed in its regions.
+    pub fn with_memory_map(map: &MemoryMap) -> Self {
+        Self::new(map.text_base(), map.data_base(), map.stack_top, map.stack_size as usize)
+    }
+
+    // The stack grows downward from stack_base; the rest of its page stays
+    // mapped so the initial sp can be dereferenced (e.g. argc for Linux programs)
+    fn map_stack(&mut self) -> Result<(), String> {
+        let start = self.stack_base.saturating_sub(self.stack_size as u32);
+        let end = (self.stack_base as u64).next_multiple_of(PAGE_SIZE as u64);
+        if end > start as u64 {
+            self.memory.map("stack", start, (end - start as u64) as u32, Permissions::RW)?;
         }
+        Ok(())
     }
 
-    pub fn load(&mut self, text: &Vec<u8>, data: &Vec<u8>) {
-        self.memory.text = text.clone();
-        self.memory.data = data.clone();
-        self.pc = self.memory.text_base;
+    /// Maps every segment of the program and jumps to its entry point.
+    /// Fails if segments overlap each other or the stack.
+    pub fn load(&mut self, program: &Program) -> Result<(), String> {
+        self.memory.clear();
+        self.map_stack()?;
+
+        let mut image_end = None;
+        for segment in &program.segments {
+            // zero-fill up to the memory size, e.g. .bss
+            let size = segment.mem_size.max(segment.data.len() as u32);
+            if size == 0 {
+                continue;
+            }
+            self.memory.map(&segment.name, segment.vaddr, size, segment.permissions)?;
+            self.memory.load_bytes(segment.vaddr, &segment.data)
+                .map_err(|fault| format!("Cannot load segment '{}': {:?}", segment.name, fault))?;
+            image_end = image_end.max(Some(segment.vaddr as u64 + size as u64));
+        }
+
+        // the heap begins at the first word-aligned address after the highest
+        // segment; an image reaching the top of the address space leaves no room
+        // for one after it, so the heap stays where it was
+        if let Some(heap_base) = image_end.map(|end| (end + 3) & !3).and_then(|base| u32::try_from(base).ok()) {
+            self.heap_base = heap_base;
+        }
+        self.program_break = self.heap_base;
+
+        self.entry = program.entry;
+        self.symbols = program.symbols.clone();
+        self.csrs = CsrFile::new();
+        self.clint.borrow_mut().reset();
+        self.pc = self.entry;
         // TODO Optionally reset registers or just SP here?
         // Given reset() does it, we keep load focused on the memory load and PC reset.
+        Ok(())
     }
 
     pub fn reset(&mut self) {
-        self.pc = self.memory.text_base;
+        self.pc = self.entry;
         self.registers = [0; config::NUM_REGISTERS];
-        self.registers[2] = self.memory.stack_base; // Initialize SP (x2)
+        self.registers[2] = self.stack_base; // Initialize SP (x2)
+        self.csrs = CsrFile::new();
+        self.clint.borrow_mut().reset();
         // Note: Stack and other memory are effectively overwritten dynamically;
         // but resetting registers and PC is enough for a clean restart.
     }
@@ -202,44 +242,146 @@ impl Processor {
     pub fn step(&mut self) -> Result<(), StepError> {
         // TODO return StepResult for the visibility outside the processor? i.e. UI?
         // separation of concerns vs monitoring
-        let memory_instruction = self.fetch()?;
-        let instruction = self.decode(memory_instruction)?;
-        self.execute(instruction)?;
+        self.csrs.tick();
+        self.bus.tick();
+        self.csrs.set_time(self.clint.borrow().mtime());
+        if self.take_interrupt() {
+            return Ok(());
+        }
+
+        let memory_instruction = match self.fetch() {
+            Ok(word) => word,
+            Err(error) => return self.raise(error, None),
+        };
+        match Self::decode(memory_instruction).and_then(|instruction| self.execute(instruction)) {
+            Ok(()) => {
+                self.csrs.retire();
+                Ok(())
+            }
+            Err(error) => self.raise(error, Some(memory_instruction)),
+        }
+    }
+
+    // Updates mip from the devices and, if an enabled interrupt is pending,
+    // traps to its handler before the instruction at pc executes
+    fn take_interrupt(&mut self) -> bool {
+        let raised = self.bus.interrupts();
+        for interrupt in [csr::MSI, csr::MTI, csr::MEI] {
+            self.csrs.set_pending(interrupt, raised & interrupt != 0);
+        }
+
+        let pending = self.csrs.enabled_interrupts();
+        if pending == 0 || !self.csrs.interrupts_enabled()
+            || self.trap_mode == TrapMode::Halt || !self.csrs.has_trap_handler() {
+            return false;
+        }
+        // external interrupts have the highest priority, then software, then timer
+        let cause = if pending & csr::MEI != 0 {
+            csr::CAUSE_EXTERNAL_INTERRUPT
+        } else if pending & csr::MSI != 0 {
+            csr::CAUSE_SOFTWARE_INTERRUPT
+        } else {
+            csr::CAUSE_TIMER_INTERRUPT
+        };
+        self.pc = self.csrs.enter_trap(self.pc, cause, 0);
+        true
+    }
+
+    // Takes the exception behind `error` through mtvec, or hands the error back
+    // when exceptions halt. `instruction` is None if the fetch itself failed.
+    fn raise(&mut self, error: StepError, instruction: Option<u32>) -> Result<(), StepError> {
+        let Some((cause, tval)) = self.exception_cause(&error, instruction) else { return Err(error) };
+        if self.trap_mode == TrapMode::Halt || !self.csrs.has_trap_handler() {
+            return Err(error);
+        }
+        self.pc = self.csrs.enter_trap(self.pc, cause, tval);
         Ok(())
     }
 
+    // The mcause and mtval of an error, if it is an exception at all
+    fn exception_cause(&self, error: &StepError, instruction: Option<u32>) -> Option<(u32, u32)> {
+        let Some(instruction) = instruction else {
+            return match error {
+                StepError::MemoryFault(MemoryFault::UnalignedAccess { address }) => Some((csr::CAUSE_MISALIGNED_FETCH, *address)),
+                StepError::MemoryFault(fault) => Some((csr::CAUSE_FETCH_ACCESS, fault.address())),
+                _ => None,
+            };
+        };
+        let is_store = instruction & 0x7F == 0b0100011;
+        match error {
+            StepError::IllegalInstruction => Some((csr::CAUSE_ILLEGAL_INSTRUCTION, instruction)),
+            StepError::Ebreak => Some((csr::CAUSE_BREAKPOINT, self.pc)),
+            StepError::Ecall => Some((csr::CAUSE_MACHINE_ECALL, 0)),
+            StepError::MemoryFault(MemoryFault::UnalignedAccess { address }) if is_store => Some((csr::CAUSE_MISALIGNED_STORE, *address)),
+            StepError::MemoryFault(MemoryFault::UnalignedAccess { address }) => Some((csr::CAUSE_MISALIGNED_LOAD, *address)),
+            StepError::MemoryFault(fault) if is_store => Some((csr::CAUSE_STORE_ACCESS, fault.address())),
+            StepError::MemoryFault(fault) => Some((csr::CAUSE_LOAD_ACCESS, fault.address())),
+            _ => None,
+        }
+    }
+
     fn fetch(&self) -> Result<u32, StepError> {
-        // TODO handle overflow as well as negative offsets MemoryFaults
-        let offset = (self.pc - self.memory.text_base) as usize;
+        // there is no compressed extension, so every instruction is word aligned
+        if !self.pc.is_multiple_of(4) {
+            return Err(MemoryFault::UnalignedAccess { address: self.pc }.into());
+        }
+        // instructions are stored little endian, like any other word, but only
+        // in executable regions
+        Ok(self.memory.fetch_word(self.pc)?)
+    }
+
+    fn check_alignment(&self, address: u32, size: u32) -> Result<(), MemoryFault> {
+        if self.misaligned_access == MisalignedAccess::Fault && !address.is_multiple_of(size) {
+            return Err(MemoryFault::UnalignedAccess { address });
+        }
+        Ok(())
+    }
 
-        // obtain 4 bytes representing the instruction
-        let bytes = self.memory.text.get(offset..offset + 4)
-            .ok_or(MemoryFault::OutOfBounds { address: self.pc })?;
+    // Loads go to a device on the bus or to memory; the value is zero extended
+    fn read_data(&mut self, address: u32, size: u32) -> Result<u32, MemoryFault> {
+        self.check_alignment(address, size)?;
+        if let Some(result) = self.bus.read(address, size) {
+            return result;
+        }
+        match size {
+            1 => Ok(self.memory.read_byte(address)? as u32),
+            2 => Ok(self.memory.read_half(address)? as u32),
+            _ => self.memory.read_word(address),
+        }
+    }
 
-        // assemble 4 bytes into u32, assuming little endian
-        let instruction = u32::from_le_bytes(bytes.try_into().unwrap());
-        Ok(instruction)
+    // Stores the low `size` bytes of `value`
+    fn write_data(&mut self, address: u32, size: u32, value: u32) -> Result<(), MemoryFault> {
+        self.check_alignment(address, size)?;
+        if let Some(result) = self.bus.write(address, size, value) {
+            return result;
+        }
+        match size {
+            1 => self.memory.write_byte(address, value as u8),
+            2 => self.memory.write_half(address, value as u16),
+            _ => self.memory.write_word(address, value),
+        }
     }
 
-    fn decode(&self, memory_instruction: u32) -> Result<Instruction, StepError> {
+    pub(crate) fn decode(memory_instruction: u32) -> Result<Instruction, StepError> {
         let opcode = memory_instruction & 0x7F;
 
         match opcode {
-            0b0110011 => self.decode_r_type(memory_instruction),
-            0b0010011 => self.decode_i_type(memory_instruction),
-            0b0000011 => self.decode_load_type(memory_instruction),
-            0b0100011 => self.decode_s_type(memory_instruction),
-            0b1100011 => self.decode_b_type(memory_instruction),
-            0b1101111 => self.decode_j_type(memory_instruction), // jal
-            0b1100111 => self.decode_jalr_type(memory_instruction), // jalr
-            0b0110111 => self.decode_u_type(memory_instruction), // lui
-            0b0010111 => self.decode_u_type(memory_instruction), // auipc
-            0b1110011 => self.decode_system_type(memory_instruction), // ecall, ebreak
+            0b0110011 => Self::decode_r_type(memory_instruction),
+            0b0010011 => Self::decode_i_type(memory_instruction),
+            0b0000011 => Self::decode_load_type(memory_instruction),
+            0b0100011 => Self::decode_s_type(memory_instruction),
+            0b1100011 => Self::decode_b_type(memory_instruction),
+            0b1101111 => Self::decode_j_type(memory_instruction), // jal
+            0b1100111 => Self::decode_jalr_type(memory_instruction), // jalr
+            0b0110111 => Self::decode_u_type(memory_instruction), // lui
+            0b0010111 => Self::decode_u_type(memory_instruction), // auipc
+            0b1110011 => Self::decode_system_type(memory_instruction), // ecall, ebreak
             _ => Err(StepError::IllegalInstruction),
         }
     }
 
-    fn decode_r_type(&self, memory_instruction: u32) -> Result<Instruction, StepError> {
+    fn decode_r_type(memory_instruction: u32) -> Result<Instruction, StepError> {
         let rd = ((memory_instruction >> 7) & 0x1F) as usize;
         let rs1 = ((memory_instruction >> 15) & 0x1F) as usize;
         let rs2 = ((memory_instruction >> 20) & 0x1F) as usize;
@@ -258,11 +400,19 @@ impl Processor {
             (0x5, 0x20) => Ok(Instruction::Sra { rd, rs1, rs2 }),
             (0x2, 0x00) => Ok(Instruction::Slt { rd, rs1, rs2 }),
             (0x3, 0x00) => Ok(Instruction::Sltu { rd, rs1, rs2 }),
+            (0x0, 0x01) => Ok(Instruction::Mul { rd, rs1, rs2 }),
+            (0x1, 0x01) => Ok(Instruction::Mulh { rd, rs1, rs2 }),
+            (0x2, 0x01) => Ok(Instruction::Mulhsu { rd, rs1, rs2 }),
+            (0x3, 0x01) => Ok(Instruction::Mulhu { rd, rs1, rs2 }),
+            (0x4, 0x01) => Ok(Instruction::Div { rd, rs1, rs2 }),
+            (0x5, 0x01) => Ok(Instruction::Divu { rd, rs1, rs2 }),
+            (0x6, 0x01) => Ok(Instruction::Rem { rd, rs1, rs2 }),
+            (0x7, 0x01) => Ok(Instruction::Remu { rd, rs1, rs2 }),
             _ => Err(StepError::IllegalInstruction),
         }
     }
 
-    fn decode_i_type(&self, memory_instruction: u32) -> Result<Instruction, StepError> {
+    fn decode_i_type(memory_instruction: u32) -> Result<Instruction, StepError> {
         let rd = ((memory_instruction >> 7) & 0x1F) as usize;
         let rs1 = ((memory_instruction >> 15) & 0x1F) as usize;
         let imm = (memory_instruction as i32) >> 20;  // arithmetic shift propagates sign
@@ -274,15 +424,15 @@ impl Processor {
             0x4 => Ok(Instruction::Xori { rd, rs1, imm }),
             0x6 => Ok(Instruction::Ori { rd, rs1, imm }),
             0x7 => Ok(Instruction::Andi { rd, rs1, imm }),
-            0x1 => self.decode_i_shift(memory_instruction),
-            0x5 => self.decode_i_shift(memory_instruction),
+            0x1 => Self::decode_i_shift(memory_instruction),
+            0x5 => Self::decode_i_shift(memory_instruction),
             0x2 => Ok(Instruction::Slti { rd, rs1, imm }),
             0x3 => Ok(Instruction::Sltiu { rd, rs1, imm }),
             _ => Err(StepError::IllegalInstruction),
         }
     }
 
-    fn decode_i_shift(&self, memory_instruction: u32) -> Result<Instruction, StepError> {
+    fn decode_i_shift(memory_instruction: u32) -> Result<Instruction, StepError> {
         let rd = ((memory_instruction >> 7) & 0x1F) as usize;
         let rs1 = ((memory_instruction >> 15) & 0x1F) as usize;
         let func7 = (memory_instruction >> 25) & 0x7F;  // bits 31:25
@@ -297,7 +447,7 @@ impl Processor {
         }
     }
 
-    fn decode_load_type(&self, memory_instruction: u32) -> Result<Instruction, StepError> {
+    fn decode_load_type(memory_instruction: u32) -> Result<Instruction, StepError> {
         let rd = ((memory_instruction >> 7) & 0x1F) as usize;
         let rs1 = ((memory_instruction >> 15) & 0x1F) as usize;
         let imm = (memory_instruction as i32) >> 20;  // arithmetic shift propagates sign
@@ -314,7 +464,7 @@ impl Processor {
         }
     }
 
-    fn decode_s_type(&self, memory_instruction: u32) -> Result<Instruction, StepError> {
+    fn decode_s_type(memory_instruction: u32) -> Result<Instruction, StepError> {
         let rs1 = ((memory_instruction >> 15) & 0x1F) as usize;
         let rs2 = ((memory_instruction >> 20) & 0x1F) as usize;
         let imm_11_5 = (memory_instruction as i32) >> 25; // Arithmetic shift propagates sign to 31:6
@@ -331,7 +481,7 @@ impl Processor {
         }
     }
 
-    fn decode_b_type(&self, memory_instruction: u32) -> Result<Instruction, StepError> {
+    fn decode_b_type(memory_instruction: u32) -> Result<Instruction, StepError> {
         let rs1 = ((memory_instruction >> 15) & 0x1F) as usize;
         let rs2 = ((memory_instruction >> 20) & 0x1F) as usize;
 
@@ -355,7 +505,7 @@ impl Processor {
         }
     }
 
-    fn decode_u_type(&self, memory_instruction: u32) -> Result<Instruction, StepError> {
+    fn decode_u_type(memory_instruction: u32) -> Result<Instruction, StepError> {
         let rd = ((memory_instruction >> 7) & 0x1F) as usize;
         let imm = (memory_instruction & 0xFFFFF000) as i32;
 
@@ -368,7 +518,7 @@ impl Processor {
         }
     }
 
-    fn decode_j_type(&self, memory_instruction: u32) -> Result<Instruction, StepError> {
+    fn decode_j_type(memory_instruction: u32) -> Result<Instruction, StepError> {
         let rd = ((memory_instruction >> 7) & 0x1F) as usize;
 
         let imm_20 = (memory_instruction >> 31) & 0x1;
@@ -382,7 +532,7 @@ impl Processor {
         Ok(Instruction::Jal { rd, imm })
     }
 
-    fn decode_jalr_type(&self, memory_instruction: u32) -> Result<Instruction, StepError> {
+    fn decode_jalr_type(memory_instruction: u32) -> Result<Instruction, StepError> {
         let rd = ((memory_instruction >> 7) & 0x1F) as usize;
         let rs1 = ((memory_instruction >> 15) & 0x1F) as usize;
         let imm = (memory_instruction as i32) >> 20;  // arithmetic shift propagates sign
@@ -395,12 +545,27 @@ impl Processor {
         Ok(Instruction::Jalr { rd, rs1, imm })
     }
 
-    fn decode_system_type(&self, memory_instruction: u32) -> Result<Instruction, StepError> {
-        let imm = ((memory_instruction >> 20) & 0xFFF) as i32;
-
-        match imm {
-            0x0 => Ok(Instruction::Ecall),
-            0x1 => Ok(Instruction::Ebreak),
+    fn decode_system_type(memory_instruction: u32) -> Result<Instruction, StepError> {
+        let rd = ((memory_instruction >> 7) & 0x1F) as usize;
+        let funct3 = (memory_instruction >> 12) & 0x7;
+        let rs1 = ((memory_instruction >> 15) & 0x1F) as usize;
+        let csr = ((memory_instruction >> 20) & 0xFFF) as u16;
+
+        match funct3 {
+            0x0 => match (csr, rs1, rd) {
+                (0x000, 0, 0) => Ok(Instruction::Ecall),
+                (0x001, 0, 0) => Ok(Instruction::Ebreak),
+                (0x302, 0, 0) => Ok(Instruction::Mret),
+                (0x105, 0, 0) => Ok(Instruction::Wfi),
+                _ => Err(StepError::IllegalInstruction),
+            },
+            0x1 => Ok(Instruction::Csrrw { rd, rs1, csr }),
+            0x2 => Ok(Instruction::Csrrs { rd, rs1, csr }),
+            0x3 => Ok(Instruction::Csrrc { rd, rs1, csr }),
+            // the immediate forms reuse the rs1 field as a 5-bit unsigned value
+            0x5 => Ok(Instruction::Csrrwi { rd, uimm: rs1 as u32, csr }),
+            0x6 => Ok(Instruction::Csrrsi { rd, uimm: rs1 as u32, csr }),
+            0x7 => Ok(Instruction::Csrrci { rd, uimm: rs1 as u32, csr }),
             _ => Err(StepError::IllegalInstruction),
         }
     }
@@ -452,6 +617,52 @@ impl Processor {
                 let result = if self.read_register(rs1) < self.read_register(rs2) { 1 } else { 0 };
                 self.write_register(rd, result);
             },
+            Instruction::Mul { rd, rs1, rs2 } => {
+                // lower 32 bits of the product, identical for signed and unsigned operands
+                let result = self.read_register(rs1).wrapping_mul(self.read_register(rs2));
+                self.write_register(rd, result);
+            },
+            Instruction::Mulh { rd, rs1, rs2 } => {
+                // upper 32 bits of signed x signed
+                let product = (self.read_register(rs1) as i32 as i64) * (self.read_register(rs2) as i32 as i64);
+                self.write_register(rd, (product >> 32) as u32);
+            },
+            Instruction::Mulhsu { rd, rs1, rs2 } => {
+                // upper 32 bits of signed rs1 x unsigned rs2, the product always fits in an i64
+                let product = (self.read_register(rs1) as i32 as i64) * (self.read_register(rs2) as i64);
+                self.write_register(rd, (product >> 32) as u32);
+            },
+            Instruction::Mulhu { rd, rs1, rs2 } => {
+                // upper 32 bits of unsigned x unsigned
+                let product = (self.read_register(rs1) as u64) * (self.read_register(rs2) as u64);
+                self.write_register(rd, (product >> 32) as u32);
+            },
+            Instruction::Div { rd, rs1, rs2 } => {
+                // The spec defines division by zero as -1 (all bits set) and
+                // the overflow case i32::MIN / -1 as i32::MIN; neither traps.
+                let dividend = self.read_register(rs1) as i32;
+                let divisor = self.read_register(rs2) as i32;
+                let result = if divisor == 0 { -1 } else { dividend.wrapping_div(divisor) };
+                self.write_register(rd, result as u32);
+            },
+            Instruction::Divu { rd, rs1, rs2 } => {
+                // division by zero returns 2^32 - 1
+                let result = self.read_register(rs1).checked_div(self.read_register(rs2)).unwrap_or(u32::MAX);
+                self.write_register(rd, result);
+            },
+            Instruction::Rem { rd, rs1, rs2 } => {
+                // remainder by zero returns the dividend, the overflow case i32::MIN % -1 returns 0
+                let dividend = self.read_register(rs1) as i32;
+                let divisor = self.read_register(rs2) as i32;
+                let result = if divisor == 0 { dividend } else { dividend.wrapping_rem(divisor) };
+                self.write_register(rd, result as u32);
+            },
+            Instruction::Remu { rd, rs1, rs2 } => {
+                // remainder by zero returns the dividend
+                let dividend = self.read_register(rs1);
+                let result = dividend.checked_rem(self.read_register(rs2)).unwrap_or(dividend);
+                self.write_register(rd, result);
+            },
             Instruction::Addi { rd, rs1, imm } => {
                 // casting i32 to u32 preserves the bit pattern
                 let result = self.read_register(rs1).wrapping_add(imm as u32);
@@ -495,47 +706,47 @@ impl Processor {
             Instruction::Lb { rd, rs1, imm } => {
                 // rd = M[rs1+imm][0:7] (sign extended)
                 let address = self.read_register(rs1).wrapping_add(imm as u32);
-                let value = self.memory.read_byte(address)?;
-                self.write_register(rd, value as i8 as u32);
+                let value = self.read_data(address, 1)?;
+                self.write_register(rd, value as u8 as i8 as u32);
             },
             Instruction::Lh { rd, rs1, imm } => {
                 // rd = M[rs1+imm][0:15] (sign extended)
                 let address = self.read_register(rs1).wrapping_add(imm as u32);
-                let value = self.memory.read_half(address)?;
-                self.write_register(rd, value as i16 as u32);
+                let value = self.read_data(address, 2)?;
+                self.write_register(rd, value as u16 as i16 as u32);
             },
             Instruction::Lw { rd, rs1, imm } => {
                 // rd = M[rs1+imm][0:31]
                 let address = self.read_register(rs1).wrapping_add(imm as u32);
-                let value = self.memory.read_word(address)?;
+                let value = self.read_data(address, 4)?;
                 self.write_register(rd, value);
             },
             Instruction::Lbu { rd, rs1, imm } => {
                 // rd = M[rs1+imm][0:7] (zero extended)
                 let address = self.read_register(rs1).wrapping_add(imm as u32);
-                let value = self.memory.read_byte(address)?;
-                self.write_register(rd, value as u32);
+                let value = self.read_data(address, 1)?;
+                self.write_register(rd, value);
             },
             Instruction::Lhu { rd, rs1, imm } => {
                 // rd = M[rs1+imm][0:15] (zero extended)
                 let address = self.read_register(rs1).wrapping_add(imm as u32);
-                let value = self.memory.read_half(address)?;
-                self.write_register(rd, value as u32);
+                let value = self.read_data(address, 2)?;
+                self.write_register(rd, value);
             },
             Instruction::Sb { rs1, rs2, imm } => {
                 // M[rs1+imm][0:7] = rs2[0:7]
                 let address = self.read_register(rs1).wrapping_add(imm as u32);
-                self.memory.write_byte(address, self.read_register(rs2) as u8)?;
+                self.write_data(address, 1, self.read_register(rs2))?;
             },
             Instruction::Sh { rs1, rs2, imm } => {
                 // M[rs1+imm][0:15] = rs2[0:15]
                 let address = self.read_register(rs1).wrapping_add(imm as u32);
-                self.memory.write_half(address, self.read_register(rs2) as u16)?;
+                self.write_data(address, 2, self.read_register(rs2))?;
             },
             Instruction::Sw { rs1, rs2, imm } => {
                 // M[rs1+imm][0:31] = rs2[0:31]
                 let address = self.read_register(rs1).wrapping_add(imm as u32);
-                self.memory.write_word(address, self.read_register(rs2))?;
+                self.write_data(address, 4, self.read_register(rs2))?;
             },
             Instruction::Beq { rs1, rs2, imm } => {
                 // if(rs1 == rs2) PC += imm
@@ -579,10 +790,10 @@ impl Processor {
                 next_pc = self.pc.wrapping_add(imm as u32);
             },
             Instruction::Jalr { rd, rs1, imm } => {
-                // rd = PC+4; PC = rs1 + imm
-                self.write_register(rd, self.pc.wrapping_add(4));
+                // rd = PC+4; PC = rs1 + imm, with rs1 read first as it may be rd (as in 'jalr ra, 0(ra)')
                 // The & !1 masks out bit 0, ensuring the target is always 2-byte aligned
                 next_pc = self.read_register(rs1).wrapping_add(imm as u32) & !1;
+                self.write_register(rd, self.pc.wrapping_add(4));
             },
             Instruction::Lui { rd, imm } => {
                 // rd = upper imm (upper mask already applied by the decoder)
@@ -592,23 +803,109 @@ impl Processor {
                 // rd = PC + upper imm (upper mask already applied by the decoder)
                 self.write_register(rd, self.pc.wrapping_add(imm as u32));
             },
+            Instruction::Ecall => {
+                let mut handler = self.syscalls.take().ok_or(StepError::Ecall)?;
+                let result = handler.handle(self);
+                self.syscalls = Some(handler);
+                result?;
+            },
             Instruction::Ebreak => return Err(StepError::Ebreak),
-            // TODO  ecall
-            _ => return Err(StepError::IllegalInstruction),
+            Instruction::Mret => next_pc = self.csrs.return_from_trap(),
+            Instruction::Wfi => {
+                // nothing else runs on this hart, so waiting means jumping ahead
+                // to the next timer event; without one, wfi is a nop
+                if self.csrs.enabled_interrupts() == 0 && self.csrs.read(csr::MIE).is_ok_and(|mie| mie & csr::MTI != 0) {
+                    let mut clint = self.clint.borrow_mut();
+                    clint.skip_to_timer();
+                    self.csrs.set_time(clint.mtime());
+                }
+            },
+            Instruction::Csrrw { rd, rs1, csr } => {
+                let value = self.read_register(rs1);
+                self.access_csr(rd, csr, Some(|_| value))?;
+            },
+            Instruction::Csrrs { rd, rs1, csr } => {
+                // with rs1 = x0 nothing is written, so read-only CSRs can be read
+                let mask = self.read_register(rs1);
+                self.access_csr(rd, csr, (rs1 != 0).then_some(|old| old | mask))?;
+            },
+            Instruction::Csrrc { rd, rs1, csr } => {
+                let mask = self.read_register(rs1);
+                self.access_csr(rd, csr, (rs1 != 0).then_some(|old| old & !mask))?;
+            },
+            Instruction::Csrrwi { rd, uimm, csr } => {
+                self.access_csr(rd, csr, Some(|_| uimm))?;
+            },
+            Instruction::Csrrsi { rd, uimm, csr } => {
+                self.access_csr(rd, csr, (uimm != 0).then_some(|old| old | uimm))?;
+            },
+            Instruction::Csrrci { rd, uimm, csr } => {
+                self.access_csr(rd, csr, (uimm != 0).then_some(|old| old & !uimm))?;
+            },
         }
 
         self.pc = next_pc;
         Ok(())
     }
 
-    fn read_register(&self, index: usize) -> u32 {
+    // Reads the old value of a CSR into rd and, if the instruction writes at
+    // all, stores update(old). Unknown CSRs and writes to read-only ones are
+    // illegal instructions.
+    fn access_csr(&mut self, rd: usize, csr: u16, update: Option<impl FnOnce(u32) -> u32>) -> Result<(), StepError> {
+        let old = self.csrs.read(csr).map_err(|_| StepError::IllegalInstruction)?;
+        if let Some(update) = update {
+            self.csrs.write(csr, update(old)).map_err(|_| StepError::IllegalInstruction)?;
+        }
+        self.write_register(rd, old);
+        Ok(())
+    }
+
+    pub fn set_syscall_handler(&mut self, handler: Box<dyn SyscallHandler>) {
+        self.syscalls = Some(handler);
+    }
+
+    /// Uninstalls the system call handler, so ECALL raises an environment
+    /// call exception for the program's own trap handler.
+    pub fn remove_syscall_handler(&mut self) -> Option<Box<dyn SyscallHandler>> {
+        self.syscalls.take()
+    }
+
+    pub fn clint(&self) -> Ref<'_, Clint> {
+        self.clint.borrow()
+    }
+
+    /// Makes the CLINT's `mtime` advance once every `instructions` steps.
+    pub fn set_timer_ratio(&mut self, instructions: u32) {
+        self.clint.borrow_mut().set_instructions_per_tick(instructions);
+    }
+
+    /// Maps a peripheral at `base..base + size`. Loads and stores there reach
+    /// the device instead of memory, and it is ticked on every step. Devices
+    /// stay attached when a new program is loaded.
+    pub fn attach_device(&mut self, name: &str, base: u32, size: u32, device: Box<dyn Device>) -> Result<(), String> {
+        self.bus.attach(name, base, size, device)
+    }
+
+    pub fn detach_device(&mut self, base: u32) -> Option<Box<dyn Device>> {
+        self.bus.detach(base)
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn set_trap_mode(&mut self, mode: TrapMode) {
+        self.trap_mode = mode;
+    }
+
+    pub fn read_register(&self, index: usize) -> u32 {
         if index == 0 {
             return 0;
         }
         self.registers[index]
     }
 
-    fn write_register(&mut self, index: usize, value: u32) {
+    pub fn write_register(&mut self, index: usize, value: u32) {
         if index == 0 {
             return;
         }
@@ -628,24 +925,87 @@ impl Processor {
         &self.registers
     }
 
+    pub fn csrs(&self) -> &CsrFile {
+        &self.csrs
+    }
+
+    pub fn set_misaligned_access(&mut self, policy: MisalignedAccess) {
+        self.misaligned_access = policy;
+    }
+
     pub fn read_memory_word(&self, address: u32) -> Result<u32, MemoryFault> {
         self.memory.read_word(address)
     }
 
-    pub fn text_base(&self) -> u32 {
-        self.memory.text_base
+    pub fn read_memory_byte(&self, address: u32) -> Result<u8, MemoryFault> {
+        self.memory.read_byte(address)
+    }
+
+    pub fn write_memory_byte(&mut self, address: u32, value: u8) -> Result<(), MemoryFault> {
+        self.memory.write_byte(address, value)
     }
 
-    pub fn data_base(&self) -> u32 {
-        self.memory.data_base
+    /// Moves the program break up by `increment` bytes and returns the previous break.
+    pub fn sbrk(&mut self, increment: u32) -> Result<u32, MemoryFault> {
+        let old_break = self.program_break;
+        let new_break = old_break.checked_add(increment)
+            .ok_or(MemoryFault::OutOfBounds { address: old_break.wrapping_add(increment) })?;
+        self.set_program_break(new_break)?;
+        Ok(old_break)
+    }
+
+    pub fn program_break(&self) -> u32 {
+        self.program_break
+    }
+
+    /// Grows or shrinks the heap region, which may not run into another region.
+    pub fn set_program_break(&mut self, new_break: u32) -> Result<(), MemoryFault> {
+        if new_break < self.heap_base {
+            return Err(MemoryFault::OutOfBounds { address: new_break });
+        }
+        let old_size = self.program_break - self.heap_base;
+        let new_size = new_break - self.heap_base;
+
+        let result = match (old_size, new_size) {
+            (0, 0) => Ok(()),
+            (0, _) => self.memory.map("heap", self.heap_base, new_size, Permissions::RW),
+            (_, 0) => {
+                self.memory.unmap(self.heap_base);
+                Ok(())
+            }
+            _ => self.memory.resize(self.heap_base, new_size),
+        };
+        result.map_err(|_| MemoryFault::OutOfBounds { address: new_break })?;
+
+        self.program_break = new_break;
+        Ok(())
+    }
+
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    /// Symbols of the loaded program, for display.
+    pub fn symbols(&self) -> &[ProgramSymbol] {
+        &self.symbols
+    }
+
+    pub fn symbol_at(&self, address: u32) -> Option<&str> {
+        self.symbols.iter()
+            .find(|symbol| symbol.address == address && !symbol.absolute)
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
     }
 
     pub fn stack_base(&self) -> u32 {
-        self.memory.stack_base
+        self.stack_base
     }
 
     pub fn stack_size(&self) -> usize {
-        self.memory.stack.len()
+        self.stack_size
     }
 }
 
@@ -655,118 +1015,108 @@ mod tests {
 
     #[test]
     fn test_decode_add() {
-        let processor = Processor::new(0, 0, 0, 0);
         // 0000000 (f7) | 00011 (rs2) | 00010 (rs1) | 000 (f3) | 00001 (rd) | 0110011 (op)
-        let instruction = processor.decode(0x003100B3).unwrap();
+        let instruction = Processor::decode(0x003100B3).unwrap();
         assert_eq!(instruction, Instruction::Add { rd: 1, rs1: 2, rs2: 3 });
     }
 
     #[test]
     fn test_decode_addi() {
-        let processor = Processor::new(0, 0, 0, 0);
         // addi x1, x2, -1
         // imm[11:0] = -1 (0xFFF) | rs1=2 | f3=0 | rd=1 | op=0010011
-        let instruction = processor.decode(0xFFF10093).unwrap();
+        let instruction = Processor::decode(0xFFF10093).unwrap();
         assert_eq!(instruction, Instruction::Addi { rd: 1, rs1: 2, imm: -1 });
 
         // addi x1, x2, 1
-        let instruction = processor.decode(0x00110093).unwrap();
+        let instruction = Processor::decode(0x00110093).unwrap();
         assert_eq!(instruction, Instruction::Addi { rd: 1, rs1: 2, imm: 1 });
     }
 
     #[test]
     fn test_decode_sw() {
-        let processor = Processor::new(0, 0, 0, 0);
         // sw x3, -4(x2)
         // imm[11:5] = -1 (0xfe0 >> 5 = 0x7f) | rs2=3 | rs1=2 | f3=2 | imm[4:0] = -4 & 0x1f (0x1c) | op=0100011
         // inst = 0xFE312E23
-        let instruction = processor.decode(0xFE312E23).unwrap();
+        let instruction = Processor::decode(0xFE312E23).unwrap();
         assert_eq!(instruction, Instruction::Sw { rs1: 2, rs2: 3, imm: -4 });
     }
 
     #[test]
     fn test_decode_beq() {
-        let processor = Processor::new(0, 0, 0, 0);
         // beq x1, x2, -4
         // imm = -4 (0xfffffffc)
         // imm[12]=1, imm[11]=1, imm[10:5]=0x3f, imm[4:1]=0xe
         // inst[31]=1, inst[7]=1, inst[30:25]=0x3f, inst[11:8]=0xe, rs2=2, rs1=1, f3=0, op=1100011
         // inst = 0xFE208EE3
-        let instruction = processor.decode(0xFE208EE3).unwrap();
+        let instruction = Processor::decode(0xFE208EE3).unwrap();
         assert_eq!(instruction, Instruction::Beq { rs1: 1, rs2: 2, imm: -4 });
     }
 
     #[test]
     fn test_decode_lui() {
-        let processor = Processor::new(0, 0, 0, 0);
         // lui x5, 0x12345
         // imm[31:12]=0x12345, rd=5, op=0110111
-        let instruction = processor.decode(0x123452B7).unwrap();
+        let instruction = Processor::decode(0x123452B7).unwrap();
         assert_eq!(instruction, Instruction::Lui { rd: 5, imm: 0x12345000 });
     }
 
     #[test]
     fn test_decode_jal() {
-        let processor = Processor::new(0, 0, 0, 0);
         // jal x1, -4
         // imm = -4 (0xfffffffc)
         // imm[20]=1, imm[19:12]=0xff, imm[11]=1, imm[10:1]=0x3fe
         // inst[31]=1, inst[30:21]=0x3fe, inst[20]=1, inst[19:12]=0xff, rd=1, op=1101111
         // Binary: 1 1111111110 1 11111111 00001 1101111
         // Groups: 1111 1111 1101 1111 1111 0000 1110 1111 => 0xFFDFF0EF
-        let instruction = processor.decode(0xFFDFF0EF).unwrap();
+        let instruction = Processor::decode(0xFFDFF0EF).unwrap();
         assert_eq!(instruction, Instruction::Jal { rd: 1, imm: -4 });
     }
 
     #[test]
     fn test_decode_jalr() {
-        let processor = Processor::new(0, 0, 0, 0);
         // jalr x1, 4(x2)
         // imm=4 | rs1=2 | f3=0 | rd=1 | op=1100111
-        let instruction = processor.decode(0x004100E7).unwrap();
+        let instruction = Processor::decode(0x004100E7).unwrap();
         assert_eq!(instruction, Instruction::Jalr { rd: 1, rs1: 2, imm: 4 });
     }
 
     #[test]
     fn test_decode_shifts() {
-        let processor = Processor::new(0, 0, 0, 0);
 
         // slli x1, x2, 5
         // imm[11:5]=0 | shamt=5 | rs1=2 | f3=1 | rd=1 | op=0010011
         // 0000000 00101 00010 001 00001 0010011 => 0x00511093
-        let instruction = processor.decode(0x00511093).unwrap();
+        let instruction = Processor::decode(0x00511093).unwrap();
         assert_eq!(instruction, Instruction::Slli { rd: 1, rs1: 2, shamt: 5 });
 
         // srli x1, x2, 5
         // imm[11:5]=0 | shamt=5 | rs1=2 | f3=5 | rd=1 | op=0010011
         // 0000000 00101 00010 101 00001 0010011 => 0x00515093
-        let instruction = processor.decode(0x00515093).unwrap();
+        let instruction = Processor::decode(0x00515093).unwrap();
         assert_eq!(instruction, Instruction::Srli { rd: 1, rs1: 2, shamt: 5 });
 
         // srai x1, x2, 5
         // imm[11:5]=0x20 | shamt=5 | rs1=2 | f3=5 | rd=1 | op=0010011
         // 0100000 00101 00010 101 00001 0010011 => 0x40515093
-        let instruction = processor.decode(0x40515093).unwrap();
+        let instruction = Processor::decode(0x40515093).unwrap();
         assert_eq!(instruction, Instruction::Srai { rd: 1, rs1: 2, shamt: 5 });
     }
 
     #[test]
     fn test_decode_shift_max_shamt() {
-        let processor = Processor::new(0, 0, 0, 0);
         // slli x1, x2, 31  — maximum meaningful shift for 32-bit registers
         // funct7=0000000 | shamt=11111 | rs1=00010 | funct3=001 | rd=00001 | op=0010011
         // 0x01F11093
-        let instruction = processor.decode(0x01F11093).unwrap();
+        let instruction = Processor::decode(0x01F11093).unwrap();
         assert_eq!(instruction, Instruction::Slli { rd: 1, rs1: 2, shamt: 31 });
     }
 
     #[test]
     fn test_decode_shift_invalid_func7() {
-        let processor = Processor::new(0, 0, 0, 0);
         // srli with funct7=0x10 (invalid — only 0x00 and 0x20 are valid for funct3=0x5)
         // funct7=0010000 | shamt=00100 | rs1=00010 | funct3=101 | rd=00001 | op=0010011
         // 0x20415093
-        let result = processor.decode(0x20415093);
+        let result = Processor::decode(0x20415093);
         assert_eq!(result, Err(StepError::IllegalInstruction));
     }
 
@@ -805,8 +1155,7 @@ mod tests {
     fn test_step_pc_increment() {
         let mut processor = Processor::new(0x400000, 0, 0, 0);
         // add x3, x1, x2 (0x002081B3)
-        processor.memory.text = vec![0xB3, 0x81, 0x20, 0x00];
-        processor.pc = 0x400000;
+        processor.load(&Program::from_sections(0x400000, &[0xB3, 0x81, 0x20, 0x00], 0, &[])).unwrap();
 
         processor.step().unwrap();
         assert_eq!(processor.pc, 0x400000 + 4);
@@ -865,9 +1214,104 @@ mod tests {
         assert_eq!(processor.registers[3], 1);
     }
 
+    #[test]
+    fn test_decode_m_extension() {
+        // mul x1, x2, x3
+        // 0000001 (f7) | 00011 (rs2) | 00010 (rs1) | 000 (f3) | 00001 (rd) | 0110011 (op)
+        let instruction = Processor::decode(0x023100B3).unwrap();
+        assert_eq!(instruction, Instruction::Mul { rd: 1, rs1: 2, rs2: 3 });
+
+        // div x1, x2, x3 (f3=100)
+        let instruction = Processor::decode(0x023140B3).unwrap();
+        assert_eq!(instruction, Instruction::Div { rd: 1, rs1: 2, rs2: 3 });
+
+        // remu x1, x2, x3 (f3=111)
+        let instruction = Processor::decode(0x023170B3).unwrap();
+        assert_eq!(instruction, Instruction::Remu { rd: 1, rs1: 2, rs2: 3 });
+    }
+
+    #[test]
+    fn test_execute_mul_wraps() {
+        let mut p = Processor::new(0, 0, 0, 0);
+        p.write_register(1, 0x8000_0001);
+        p.write_register(2, 4);
+        p.execute(Instruction::Mul { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), 4); // only the lower 32 bits are kept
+    }
+
+    #[test]
+    fn test_execute_mulh_variants() {
+        let mut p = Processor::new(0, 0, 0, 0);
+        p.write_register(1, -2i32 as u32); // 0xFFFFFFFE
+        p.write_register(2, 3);
+
+        // signed x signed: -2 * 3 = -6 → upper word is all ones
+        p.execute(Instruction::Mulh { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), 0xFFFFFFFF);
+
+        // unsigned x unsigned: 0xFFFFFFFE * 3 = 0x2_FFFFFFFA → upper word is 2
+        p.execute(Instruction::Mulhu { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), 2);
+
+        // signed x unsigned: -2 * 0xFFFFFFFF = -0x1_FFFFFFFE → upper word is 0xFFFFFFFE
+        p.write_register(2, 0xFFFFFFFF);
+        p.execute(Instruction::Mulhsu { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), 0xFFFFFFFE);
+    }
+
+    #[test]
+    fn test_execute_div_rounds_towards_zero() {
+        let mut p = Processor::new(0, 0, 0, 0);
+        p.write_register(1, -7i32 as u32);
+        p.write_register(2, 2);
+        p.execute(Instruction::Div { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3) as i32, -3);
+        p.execute(Instruction::Rem { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3) as i32, -1); // sign follows the dividend
+    }
+
+    #[test]
+    fn test_execute_division_by_zero() {
+        let mut p = Processor::new(0, 0, 0, 0);
+        p.write_register(1, 42);
+        p.write_register(2, 0);
+
+        p.execute(Instruction::Div { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), 0xFFFFFFFF);
+        p.execute(Instruction::Divu { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), 0xFFFFFFFF);
+        p.execute(Instruction::Rem { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), 42);
+        p.execute(Instruction::Remu { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), 42);
+    }
+
+    #[test]
+    fn test_execute_signed_division_overflow() {
+        let mut p = Processor::new(0, 0, 0, 0);
+        p.write_register(1, i32::MIN as u32);
+        p.write_register(2, -1i32 as u32);
+
+        p.execute(Instruction::Div { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), i32::MIN as u32);
+        p.execute(Instruction::Rem { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), 0);
+    }
+
+    #[test]
+    fn test_execute_divu_treats_operands_as_unsigned() {
+        let mut p = Processor::new(0, 0, 0, 0);
+        p.write_register(1, 0xFFFFFFFF);
+        p.write_register(2, 2);
+        p.execute(Instruction::Divu { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), 0x7FFFFFFF);
+        p.execute(Instruction::Remu { rd: 3, rs1: 1, rs2: 2 }).unwrap();
+        assert_eq!(p.read_register(3), 1);
+    }
+
     fn processor_with_data(data: Vec<u8>) -> Processor {
         let mut p = Processor::new(0x0, 0x10000000, 0x7FFFFFFF, 1024);
-        p.memory.data = data;
+        p.load(&Program::from_sections(0x0, &[], 0x10000000, &data)).unwrap();
         p
     }
 
@@ -912,7 +1356,7 @@ mod tests {
         p.write_register(1, 0x10000001); // point rs1 past the first byte
         p.write_register(2, 0x42);
         p.execute(Instruction::Sb { rs1: 1, rs2: 2, imm: -1 }).unwrap();
-        assert_eq!(p.memory.data[0], 0x42);
+        assert_eq!(p.memory.read_byte(0x10000000), Ok(0x42));
     }
 
     #[test]
@@ -980,6 +1424,17 @@ mod tests {
         assert_eq!(p.pc, 0x200); // LSB cleared → 0x200
     }
 
+    #[test]
+    fn test_jalr_reads_rs1_before_writing_rd() {
+        // the second half of 'call', jalr ra, 12(ra)
+        let mut p = Processor::new(0, 0, 0, 0);
+        p.pc = 0x100;
+        p.write_register(1, 0x200);
+        p.execute(Instruction::Jalr { rd: 1, rs1: 1, imm: 12 }).unwrap();
+        assert_eq!(p.read_register(1), 0x104);
+        assert_eq!(p.pc, 0x20C);
+    }
+
     #[test]
     fn test_lui_loads_upper_immediate() {
         let mut p = Processor::new(0, 0, 0, 0);
@@ -1033,4 +1488,360 @@ mod tests {
         p.reset();
         assert_eq!(p.registers[2], stack_base);
     }
+
+    #[test]
+    fn test_stack_grows_downward_from_sp() {
+        let mut p = Processor::new(0x1000, 0x2000, 0x7FFF_FFF0, 1024);
+        p.write_register(1, 0xCAFE);
+        p.execute(Instruction::Sw { rs1: 2, rs2: 1, imm: -4 }).unwrap();
+        assert_eq!(p.read_memory_word(0x7FFF_FFEC), Ok(0xCAFE));
+        assert_eq!(p.read_memory_word(0x7FFF_FFF0), Ok(0)); // readable above the initial sp
+
+        let below_stack = 0x7FFF_FFF0 - 1024 - 4;
+        assert_eq!(p.read_memory_word(below_stack), Err(MemoryFault::OutOfBounds { address: below_stack }));
+    }
+
+    #[test]
+    fn test_heap_grows_and_shrinks() {
+        let mut p = Processor::new(0x1000, 0x2000, 0x7FFF_FFF0, 1024);
+        p.load(&Program::from_sections(0x1000, &[0; 4], 0x2000, &[1, 2])).unwrap();
+        assert_eq!(p.program_break(), 0x2004);
+        assert!(p.read_memory_byte(0x2004).is_err());
+
+        assert_eq!(p.sbrk(8), Ok(0x2004));
+        p.write_memory_byte(0x200B, 0xFF).unwrap();
+        p.set_program_break(0x2008).unwrap();
+        assert!(p.read_memory_byte(0x200B).is_err());
+        p.set_program_break(0x200C).unwrap();
+        assert_eq!(p.read_memory_byte(0x200B), Ok(0)); // released memory comes back zeroed
+
+        // the heap cannot grow into the stack
+        assert!(p.set_program_break(0x7FFF_FFF0).is_err());
+        assert_eq!(p.program_break(), 0x200C);
+    }
+
+    #[test]
+    fn test_load_segment_ending_at_the_top_of_memory() {
+        let mut p = Processor::new(0x1000, 0x2000, 0x7FFF_FFF0, 1024);
+        let program = Program::from_sections(0x1000, &[0; 4], 0xFFFF_F000, &[0; 0x1000]);
+        p.load(&program).unwrap();
+        // no room for a heap after the image, so it stays where it was
+        assert_eq!(p.program_break(), 0x2000);
+        assert_eq!(p.read_memory_byte(0xFFFF_FFFF), Ok(0));
+    }
+
+    #[test]
+    fn test_load_rejects_overlapping_segments() {
+        let mut p = Processor::new(0x1000, 0x2000, 0x7FFF_FFF0, 1024);
+        let program = Program::from_sections(0x1000, &[0; 8], 0x1004, &[0; 4]);
+        assert!(p.load(&program).is_err());
+    }
+
+    #[test]
+    fn test_store_to_text_faults() {
+        let mut p = Processor::new(0x1000, 0x2000, 0x7FFF_FFF0, 1024);
+        p.load(&Program::from_sections(0x1000, &[0x13, 0, 0, 0], 0x2000, &[0; 4])).unwrap();
+        p.write_register(1, 0x1000);
+        let result = p.execute(Instruction::Sw { rs1: 1, rs2: 0, imm: 0 });
+        assert_eq!(result, Err(StepError::MemoryFault(MemoryFault::WriteToReadOnly { address: 0x1000 })));
+        assert_eq!(p.read_memory_word(0x1000), Ok(0x13));
+    }
+
+    #[test]
+    fn test_fetch_from_data_and_stack_faults() {
+        let mut p = Processor::new(0x1000, 0x2000, 0x7FFF_FFF0, 1024);
+        p.load(&Program::from_sections(0x1000, &[0x13, 0, 0, 0], 0x2000, &[0x13, 0, 0, 0])).unwrap();
+        p.pc = 0x2000;
+        assert_eq!(p.step(), Err(StepError::MemoryFault(MemoryFault::ExecuteFromNonExecutable { address: 0x2000 })));
+        p.pc = 0x7FFF_FF00;
+        assert_eq!(p.step(), Err(StepError::MemoryFault(MemoryFault::ExecuteFromNonExecutable { address: 0x7FFF_FF00 })));
+    }
+
+    #[test]
+    fn test_fetch_below_text_base_faults() {
+        let mut p = Processor::new(0x1000, 0x2000, 0x7FFF_FFF0, 1024);
+        p.load(&Program::from_sections(0x1000, &[0x13, 0, 0, 0], 0x2000, &[])).unwrap();
+        p.pc = 0xFFC;
+        assert_eq!(p.step(), Err(StepError::MemoryFault(MemoryFault::OutOfBounds { address: 0xFFC })));
+        p.pc = 0x1002;
+        assert_eq!(p.step(), Err(StepError::MemoryFault(MemoryFault::UnalignedAccess { address: 0x1002 })));
+    }
+
+    #[test]
+    fn test_misaligned_access_faults_by_default() {
+        let mut p = processor_with_data(vec![0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        p.write_register(1, 0x10000001);
+        let result = p.execute(Instruction::Lw { rd: 2, rs1: 1, imm: 0 });
+        assert_eq!(result, Err(StepError::MemoryFault(MemoryFault::UnalignedAccess { address: 0x10000001 })));
+        let result = p.execute(Instruction::Sh { rs1: 1, rs2: 0, imm: 0 });
+        assert_eq!(result, Err(StepError::MemoryFault(MemoryFault::UnalignedAccess { address: 0x10000001 })));
+        // byte accesses are always aligned
+        p.execute(Instruction::Lbu { rd: 2, rs1: 1, imm: 0 }).unwrap();
+        assert_eq!(p.read_register(2), 0x22);
+    }
+
+    #[test]
+    fn test_misaligned_access_can_be_emulated() {
+        let mut p = processor_with_data(vec![0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        p.set_misaligned_access(MisalignedAccess::Emulate);
+        p.write_register(1, 0x10000001);
+        p.execute(Instruction::Lw { rd: 2, rs1: 1, imm: 0 }).unwrap();
+        assert_eq!(p.read_register(2), 0x55443322);
+        p.write_register(3, 0xBEEF);
+        p.execute(Instruction::Sh { rs1: 1, rs2: 3, imm: 2 }).unwrap();
+        p.execute(Instruction::Lhu { rd: 2, rs1: 1, imm: 2 }).unwrap();
+        assert_eq!(p.read_register(2), 0xBEEF);
+    }
+
+    #[test]
+    fn test_decode_csr_instructions() {
+        // csrrw a0, mstatus, a1
+        assert_eq!(Processor::decode(0x30059573).unwrap(), Instruction::Csrrw { rd: 10, rs1: 11, csr: 0x300 });
+        // csrrwi a0, mscratch, 31
+        assert_eq!(Processor::decode(0x340FD573).unwrap(), Instruction::Csrrwi { rd: 10, uimm: 31, csr: 0x340 });
+        // rdinstreth a1
+        assert_eq!(Processor::decode(0xC82025F3).unwrap(), Instruction::Csrrs { rd: 11, rs1: 0, csr: 0xC82 });
+        // funct3 = 4 is reserved
+        assert_eq!(Processor::decode(0x30004073), Err(StepError::IllegalInstruction));
+    }
+
+    #[test]
+    fn test_csr_read_modify_write() {
+        let mut p = Processor::new(0, 0, 0, 0);
+        p.write_register(1, 0xF0);
+        p.execute(Instruction::Csrrw { rd: 0, rs1: 1, csr: csr::MSCRATCH }).unwrap();
+        p.write_register(1, 0x0F);
+        p.execute(Instruction::Csrrs { rd: 2, rs1: 1, csr: csr::MSCRATCH }).unwrap();
+        assert_eq!(p.read_register(2), 0xF0);
+        p.execute(Instruction::Csrrci { rd: 2, uimm: 0x3, csr: csr::MSCRATCH }).unwrap();
+        assert_eq!(p.read_register(2), 0xFF);
+        // rd = rs1: the old value is returned, the new one comes from the register
+        p.write_register(3, 0x1);
+        p.execute(Instruction::Csrrw { rd: 3, rs1: 3, csr: csr::MSCRATCH }).unwrap();
+        assert_eq!(p.read_register(3), 0xFC);
+        assert_eq!(p.csrs().read(csr::MSCRATCH), Ok(0x1));
+    }
+
+    #[test]
+    fn test_csr_illegal_accesses() {
+        let mut p = Processor::new(0, 0, 0, 0);
+        p.write_register(1, 1);
+        // reading a read-only counter is fine, writing it is not
+        assert!(p.execute(Instruction::Csrrs { rd: 2, rs1: 0, csr: csr::CYCLE }).is_ok());
+        assert_eq!(p.execute(Instruction::Csrrs { rd: 2, rs1: 1, csr: csr::CYCLE }), Err(StepError::IllegalInstruction));
+        assert_eq!(p.execute(Instruction::Csrrwi { rd: 0, uimm: 0, csr: csr::INSTRET }), Err(StepError::IllegalInstruction));
+        assert_eq!(p.execute(Instruction::Csrrs { rd: 2, rs1: 0, csr: 0x7C0 }), Err(StepError::IllegalInstruction));
+    }
+
+    #[test]
+    fn test_counters_advance_with_each_step() {
+        let mut p = Processor::new(0x1000, 0x2000, 0x7FFF_FFF0, 1024);
+        // nop; nop; rdinstret a0
+        let text = [0x13, 0, 0, 0, 0x13, 0, 0, 0, 0x73, 0x25, 0x20, 0xC0];
+        p.load(&Program::from_sections(0x1000, &text, 0x2000, &[])).unwrap();
+        for _ in 0..3 {
+            p.step().unwrap();
+        }
+        assert_eq!(p.read_register(10), 2);
+        assert_eq!(p.csrs().read(csr::CYCLE), Ok(3));
+        assert_eq!(p.csrs().read(csr::INSTRET), Ok(3));
+    }
+
+    fn processor_with_words(words: &[u32]) -> Processor {
+        let text: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut p = Processor::new(0x1000, 0x2000, 0x7FFF_FFF0, 1024);
+        p.load(&Program::from_sections(0x1000, &text, 0x2000, &[])).unwrap();
+        p
+    }
+
+    // Faulting instructions at 0x1000 followed by a handler at 0x1018 that
+    // records mcause/mtval in a0/a2 and resumes after the faulting instruction
+    fn processor_with_trap_handler() -> Processor {
+        let words: [u32; 12] = [
+            0xFFFFFFFF, // illegal
+            0x00D02023, // sw a3, 0(zero)
+            0x00102683, // lw a3, 1(zero)
+            0x00100073, // ebreak
+            0x00000073, // ecall
+            0x00000013, // nop
+            0x34202573, // csrr a0, mcause
+            0x341025F3, // csrr a1, mepc
+            0x00458593, // addi a1, a1, 4
+            0x34159073, // csrw mepc, a1
+            0x34302673, // csrr a2, mtval
+            0x30200073, // mret
+        ];
+        let mut p = processor_with_words(&words);
+        p.csrs.write(csr::MTVEC, 0x1018).unwrap();
+        p.remove_syscall_handler();
+        p
+    }
+
+    #[test]
+    fn test_exceptions_trap_to_mtvec_and_mret_resumes() {
+        let mut p = processor_with_trap_handler();
+        let expected = [
+            (csr::CAUSE_ILLEGAL_INSTRUCTION, 0xFFFFFFFF),
+            (csr::CAUSE_STORE_ACCESS, 0),
+            (csr::CAUSE_MISALIGNED_LOAD, 1),
+            (csr::CAUSE_BREAKPOINT, 0x100C),
+            (csr::CAUSE_MACHINE_ECALL, 0),
+        ];
+        for (i, (cause, tval)) in expected.into_iter().enumerate() {
+            let faulting_pc = 0x1000 + 4 * i as u32;
+            assert_eq!(p.pc(), faulting_pc);
+            p.step().unwrap();
+            assert_eq!(p.pc(), 0x1018);
+            assert_eq!(p.csrs().read(csr::MEPC), Ok(faulting_pc));
+            for _ in 0..6 {
+                p.step().unwrap();
+            }
+            assert_eq!((p.read_register(10), p.read_register(12)), (cause, tval));
+        }
+        assert_eq!(p.pc(), 0x1014);
+    }
+
+    #[test]
+    fn test_trap_saves_and_restores_interrupt_enable() {
+        let mut p = processor_with_trap_handler();
+        p.csrs.write(csr::MSTATUS, csr::MSTATUS_MIE).unwrap();
+        p.step().unwrap();
+        assert_eq!(p.csrs().read(csr::MSTATUS).unwrap() & (csr::MSTATUS_MIE | csr::MSTATUS_MPIE), csr::MSTATUS_MPIE);
+        for _ in 0..6 {
+            p.step().unwrap();
+        }
+        assert_eq!(p.csrs().read(csr::MSTATUS).unwrap() & csr::MSTATUS_MIE, csr::MSTATUS_MIE);
+    }
+
+    #[test]
+    fn test_exceptions_halt_without_handler_or_in_halt_mode() {
+        let mut p = processor_with_trap_handler();
+        p.set_trap_mode(TrapMode::Halt);
+        assert_eq!(p.step(), Err(StepError::IllegalInstruction));
+        assert_eq!(p.pc(), 0x1000);
+
+        let mut p = processor_with_trap_handler();
+        p.csrs.write(csr::MTVEC, 0).unwrap();
+        assert_eq!(p.step(), Err(StepError::IllegalInstruction));
+        assert_eq!(p.csrs().read(csr::MCAUSE), Ok(0));
+    }
+
+    #[test]
+    fn test_fetch_fault_traps_with_target_address() {
+        let mut p = processor_with_trap_handler();
+        p.pc = 0x2000;
+        p.step().unwrap();
+        assert_eq!(p.csrs().read(csr::MCAUSE), Ok(csr::CAUSE_FETCH_ACCESS));
+        assert_eq!(p.csrs().read(csr::MTVAL), Ok(0x2000));
+        assert_eq!(p.csrs().read(csr::MEPC), Ok(0x2000));
+    }
+
+    #[test]
+    fn test_wfi_fast_forwards_to_timer_interrupt() {
+        let mut p = processor_with_words(&[
+            0x020042B7, // lui t0, 0x2004 (mtimecmp)
+            0x06400313, // li t1, 100
+            0x0062A023, // sw t1, 0(t0)
+            0x0002A223, // sw zero, 4(t0)
+            0x08000393, // li t2, 0x80 (MTIE)
+            0x30439073, // csrw mie, t2
+            0x30046073, // csrsi mstatus, 8 (MIE)
+            0x10500073, // wfi
+            0x00100593, // li a1, 1
+            0x34202573, // handler: csrr a0, mcause
+            0xC0102673, // csrr a2, time
+        ]);
+        p.csrs.write(csr::MTVEC, 0x1024).unwrap();
+        for _ in 0..8 {
+            p.step().unwrap();
+        }
+        assert_eq!(p.pc(), 0x1020);
+        assert_eq!(p.clint().mtime(), 100);
+
+        p.step().unwrap();  // the interrupt is taken before li a1, 1
+        assert_eq!(p.pc(), 0x1024);
+        assert_eq!(p.csrs().read(csr::MEPC), Ok(0x1020));
+        p.step().unwrap();
+        p.step().unwrap();
+        assert_eq!(p.read_register(10), csr::CAUSE_TIMER_INTERRUPT);
+        assert!(p.read_register(12) >= 100);
+        assert_eq!(p.read_register(11), 0);
+    }
+
+    #[test]
+    fn test_software_interrupt_needs_mie_and_mstatus() {
+        let mut p = processor_with_words(&[
+            0x020002B7, // lui t0, 0x2000 (msip)
+            0x00100313, // li t1, 1
+            0x0062A023, // sw t1, 0(t0)
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // handler: nop
+        ]);
+        p.csrs.write(csr::MTVEC, 0x1014).unwrap();
+        p.csrs.write(csr::MIE, csr::MSI).unwrap();
+        for _ in 0..4 {
+            p.step().unwrap();
+        }
+        // pending and enabled in mie, but interrupts are globally disabled
+        assert_eq!(p.pc(), 0x1010);
+        assert_eq!(p.csrs().read(csr::MIP), Ok(csr::MSI));
+
+        p.csrs.write(csr::MSTATUS, csr::MSTATUS_MIE).unwrap();
+        p.step().unwrap();
+        assert_eq!(p.pc(), 0x1014);
+        assert_eq!(p.csrs().read(csr::MCAUSE), Ok(csr::CAUSE_SOFTWARE_INTERRUPT));
+        assert_eq!(p.csrs().read(csr::MSTATUS).unwrap() & csr::MSTATUS_MIE, 0);
+    }
+
+    // A device with one register; writing a non-zero value raises an external interrupt
+    #[derive(Default)]
+    struct Doorbell {
+        value: u32,
+    }
+
+    impl Device for Doorbell {
+        fn read(&mut self, offset: u32, _size: u32) -> Option<u32> {
+            (offset == 0).then_some(self.value)
+        }
+
+        fn write(&mut self, offset: u32, _size: u32, value: u32) -> Option<()> {
+            (offset == 0).then(|| self.value = value)
+        }
+
+        fn interrupts(&self) -> u32 {
+            if self.value != 0 { csr::MEI } else { 0 }
+        }
+    }
+
+    #[test]
+    fn test_attached_device_handles_accesses_and_interrupts() {
+        let mut p = processor_with_words(&[
+            0x100002B7, // lui t0, 0x10000
+            0x00700313, // li t1, 7
+            0x0062A023, // sw t1, 0(t0)
+            0x0002A383, // lw t2, 0(t0)
+            0x00000013, // nop
+        ]);
+        let doorbell = Rc::new(RefCell::new(Doorbell::default()));
+        p.attach_device("doorbell", 0x1000_0000, 0x10, Box::new(doorbell.clone())).unwrap();
+        assert!(p.attach_device("clash", clint::BASE, 0x10, Box::new(Doorbell::default())).is_err());
+        p.csrs.write(csr::MTVEC, 0x1010).unwrap();
+        p.csrs.write(csr::MIE, csr::MEI).unwrap();
+
+        for _ in 0..4 {
+            p.step().unwrap();
+        }
+        assert_eq!(doorbell.borrow().value, 7);
+        assert_eq!(p.read_register(7), 7);
+
+        p.csrs.write(csr::MSTATUS, csr::MSTATUS_MIE).unwrap();
+        p.step().unwrap();
+        assert_eq!(p.csrs().read(csr::MCAUSE), Ok(csr::CAUSE_EXTERNAL_INTERRUPT));
+        assert_eq!(p.csrs().read(csr::MEPC), Ok(0x1010));
+
+        // the device survives loading another program, and faults unknown offsets
+        p.load(&Program::from_sections(0x1000, &[0x83, 0xA3, 0x42, 0x00], 0x2000, &[])).unwrap(); // lw t2, 4(t0)
+        assert_eq!(p.step(), Err(StepError::MemoryFault(MemoryFault::OutOfBounds { address: 0x1000_0004 })));
+    }
 }
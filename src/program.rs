@@ -0,0 +1,162 @@
+use std::fmt;
+
+/// Access rights of a memory segment, mirroring the ELF `PF_R`/`PF_W`/`PF_X` flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const R: Self = Self { read: true, write: false, execute: false };
+    pub const RW: Self = Self { read: true, write: true, execute: false };
+    pub const RX: Self = Self { read: true, write: false, execute: true };
+    pub const RWX: Self = Self { read: true, write: true, execute: true };
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            if self.read { 'r' } else { '-' },
+            if self.write { 'w' } else { '-' },
+            if self.execute { 'x' } else { '-' },
+        )
+    }
+}
+
+/// A contiguous block of the program image. Bytes past `data.len()` up to
+/// `mem_size` are zero-filled when loaded (e.g. `.bss`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub name: String,
+    pub vaddr: u32,
+    pub data: Vec<u8>,
+    pub mem_size: u32,
+    pub permissions: Permissions,
+}
+
+/// Whether a symbol is visible outside its source file, as set by `.globl` and `.local`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymbolBinding {
+    #[default]
+    Local,
+    Global,
+}
+
+/// What a symbol names, as set by `.type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymbolType {
+    #[default]
+    NoType,
+    Object,
+    Function,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ProgramSymbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+    pub binding: SymbolBinding,
+    pub symbol_type: SymbolType,
+    pub absolute: bool, // a constant (.equ) rather than a location in the image
+}
+
+/// An executable image ready to be loaded into the processor, produced either
+/// by the assembler or by the ELF loader.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<ProgramSymbol>,
+}
+
+impl Program {
+    /// Builds the classic two segment layout: executable text and writable data.
+    pub fn from_sections(text_base: u32, text: &[u8], data_base: u32, data: &[u8]) -> Self {
+        let segment = |name: &str, vaddr: u32, bytes: &[u8], permissions| Segment {
+            name: name.to_string(),
+            vaddr,
+            data: bytes.to_vec(),
+            mem_size: bytes.len() as u32,
+            permissions,
+        };
+
+        Self {
+            entry: text_base,
+            segments: vec![
+                segment("text", text_base, text, Permissions::RX),
+                segment("data", data_base, data, Permissions::RW),
+            ],
+            symbols: Vec::new(),
+        }
+    }
+}
+
+/// Where a symbol of a relocatable object is defined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SymbolSection {
+    Section(String),
+    Absolute, // a constant (.equ)
+    Undefined, // left for the linker to find in another object
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub value: u32, // from the start of its section
+    pub size: u32,
+    pub binding: SymbolBinding,
+    pub symbol_type: SymbolType,
+    pub section: SymbolSection,
+}
+
+/// How the linker patches the place a relocation names, as the RISC-V psABI
+/// relocations of the same names do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    Abs32,      // .word symbol
+    Branch,     // B-type offset
+    Jal,        // J-type offset
+    GotHi20,    // auipc with %got_pcrel_hi
+    PcrelHi20,  // auipc with %pcrel_hi
+    PcrelLo12I, // I-type %pcrel_lo, naming the auipc's label
+    PcrelLo12S, // S-type %pcrel_lo
+    Hi20,       // lui with %hi
+    Lo12I,      // I-type %lo
+    Lo12S,      // S-type %lo
+}
+
+/// A place in a section of a relocatable object that holds the address of a
+/// symbol, plus `addend`, once the linker has placed it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub section: String,
+    pub offset: u32,
+    pub kind: RelocationKind,
+    pub symbol: String,
+    pub addend: i32,
+}
+
+/// A section of a relocatable object; it starts at address 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectSection {
+    pub name: String,
+    pub data: Vec<u8>, // empty for sections without contents, like .bss
+    pub size: u32,
+    pub alignment: u32,
+    pub permissions: Permissions,
+    pub nobits: bool,
+}
+
+/// One assembled file whose references to symbols in other sections or
+/// objects are left to a linker, as written to an ELF relocatable object.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Relocatable {
+    pub sections: Vec<ObjectSection>,
+    pub symbols: Vec<ObjectSymbol>,
+    pub relocations: Vec<Relocation>,
+}
@@ -1,28 +1,76 @@
+use std::collections::HashMap;
+
+use crate::csr;
+use crate::diagnostic::{Diagnostic, Stage};
 use crate::lexer::ModifierKind;
-use crate::parser::{MemoryOffset, Operand, Statement, StatementKind};
+use crate::parser::{Expr, MemoryOffset, Operand, Statement, StatementKind};
+use crate::source::FileId;
 
-pub fn expand(statements: Vec<Statement>) -> Result<Vec<Statement>, String> {
+/// Replaces pseudo-instructions with the instructions they stand for.
+/// Returns an error for every statement that cannot be expanded.
+pub fn expand(statements: Vec<Statement>) -> Result<Vec<Statement>, Vec<Diagnostic>> {
     // Preallocate memory for the expanded statements
     let mut expanded_statements = Vec::with_capacity(statements.len());
-    for statement in statements {
-        expanded_statements.extend(expand_statement(statement)?);
+    let mut errors = Vec::new();
+    let mut constants = HashMap::new();
+    let mut labels = 0;
+    for mut statement in statements {
+        fold_constant(&mut statement, &mut constants);
+        let (file, line) = (statement.file, statement.line);
+        match expand_statement(statement, &mut labels) {
+            Ok(expanded) => expanded_statements.extend(expanded),
+            Err(message) => errors.push(Diagnostic::error(Stage::Pseudo, file, line, message)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
     }
     Ok(expanded_statements)
 }
 
+// Records the value of '.equ' constants defined from numbers and earlier constants, and
+// replaces them in 'li', so that 'li a0, SIZE' takes one instruction when SIZE is small.
+// Anything else is left for the symbol table to resolve.
+fn fold_constant(statement: &mut Statement, constants: &mut HashMap<String, u32>) {
+    let value_of = |op: &Operand, constants: &HashMap<String, u32>| match op {
+        Operand::Immediate(n) => Some(*n),
+        Operand::Label(name) => constants.get(name).map(|&value| value as i32),
+        Operand::Expression(expr) => expr.evaluate(&|name| constants.get(name).copied()).ok(),
+        _ => None,
+    };
+    match &mut statement.kind {
+        StatementKind::Directive(name, ops) if name == ".equ" || name == ".set" => {
+            // a constant assigned again from something unknown here is no longer known
+            if let [Operand::Label(symbol), value] = ops.as_slice() {
+                match value_of(value, constants) {
+                    Some(value) => constants.insert(symbol.clone(), value as u32),
+                    None => constants.remove(symbol),
+                };
+            }
+        }
+        StatementKind::Instruction(name, ops) if name == "li" && ops.len() == 2 => {
+            if let Some(value) = value_of(&ops[1], constants) {
+                ops[1] = Operand::Immediate(value);
+            }
+        }
+        _ => {}
+    }
+}
+
 // Given an statement, return it as [statement] if it is not a pseudo-instruction.
 // If it is a pseudo-instruction, expand it to one or more base instructions
 // and return the new list of instructions.
-fn expand_statement(statement: Statement) -> Result<Vec<Statement>, String> {
-    let line = statement.line;
+// 'labels' counts the labels given to auipc instructions, so each gets a new one.
+fn expand_statement(statement: Statement, labels: &mut usize) -> Result<Vec<Statement>, String> {
+    let (line, file) = (statement.line, statement.file);
     let StatementKind::Instruction(name, ops) = statement.kind else { return Ok(vec![statement]) };
 
     match name.as_str() {
-        "la" => {
-            // TODO: Technically, 'la' should expand to %pcrel_hi/%pcrel_lo for PC-relative addressing.
-            // Currently using %hi/%lo (absolute) as PC-relative modifiers are not yet implemented.
+        "la" | "lla" | "lga" => {
+            // auipc rd, %pcrel_hi(symbol)      or for lga: auipc rd, %got_pcrel_hi(symbol)
+            // addi rd, rd, %pcrel_lo(label)                 lw rd, %pcrel_lo(label)(rd)
             if ops.len() != 2 {
-                return Err(format!("Invalid number of operands for 'la' pseudo-instruction. Expected 2, got {}", ops.len()));
+                return Err(format!("Invalid number of operands for '{}' pseudo-instruction. Expected 2, got {}", name, ops.len()));
             }
 
             let mut ops_iter = ops.into_iter();
@@ -30,73 +78,66 @@ fn expand_statement(statement: Statement) -> Result<Vec<Statement>, String> {
             let symbol = ops_iter.next().unwrap();
             let rd_reg = match rd {
                 Operand::Register(n) => n,
-                _ => return Err(format!("Invalid first operand for 'la' pseudo-instruction. Expected a register, got {}", rd)),
+                _ => return Err(format!("Invalid first operand for '{}' pseudo-instruction. Expected a register, got {}", name, rd)),
             };
-            let symbol = match symbol {
-                Operand::Label(label) => label,
-                _ => return Err(format!("Invalid second operand for 'la' pseudo-instruction. Expected a label, got {}", symbol)),
+            // the GOT has entries for symbols, not for expressions over them
+            let valid = if name == "lga" { matches!(symbol, Operand::Label(_)) } else { is_address(&symbol) };
+            if !valid {
+                return Err(format!("Invalid second operand for '{}' pseudo-instruction. Expected a label, got {}", name, symbol));
+            }
+            let kind = if name == "lga" { ModifierKind::GotPcrelHi } else { ModifierKind::PcrelHi };
+            let (mut statements, label) = pcrel_hi(kind, rd_reg, &symbol, line, file, labels);
+            let low = if name == "lga" {
+                ("lw", vec![Operand::Register(rd_reg), Operand::Memory { offset: MemoryOffset::Modifier(ModifierKind::PcrelLo, label), reg: rd_reg }])
+            } else {
+                ("addi", vec![Operand::Register(rd_reg), Operand::Register(rd_reg), Operand::Modifier(ModifierKind::PcrelLo, label)])
             };
-            Ok(vec![
-                Statement {
-                    kind: StatementKind::Instruction("auipc".to_string(), vec![Operand::Register(rd_reg), Operand::Modifier(ModifierKind::Hi, symbol.clone())]),
-                    line,
-                },
-                Statement {
-                    kind: StatementKind::Instruction("addi".to_string(), vec![Operand::Register(rd_reg), Operand::Register(rd_reg), Operand::Modifier(ModifierKind::Lo, symbol)]),
-                    line,
-                }
-            ])
+            statements.push(Statement { kind: StatementKind::Instruction(low.0.to_string(), low.1), line, file });
+            Ok(statements)
         }
         "lb" | "lh" | "lw" => {
             if ops.len() != 2 {
                 // If number of operands is not 2, we consider it a base instruction and return it as is.
                 // This is not an error because the assembler will fail later and reference an instruction l{b|h|w} with an invalid operand.
-                return Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line }]);
+                return Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line, file }]);
             }
-            // auipc rd, symbol[31:12]
-            // l{b|h|w} rd, symbol[11:0](rd)
+            // auipc rd, %pcrel_hi(symbol)
+            // l{b|h|w} rd, %pcrel_lo(label)(rd)
             // if second operand is a Label, we consider it a pseudo-instruction and expand it.
             // Otherwise, we consider it a base instruction and return it as is.
-            if !matches!(ops[1], Operand::Label(_)) {
-                return Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line }]);
+            if !is_address(&ops[1]) {
+                return Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line, file }]);
             }
 
             let mut ops_iter = ops.into_iter();
             let rd = ops_iter.next().unwrap();
             let symbol = ops_iter.next().unwrap();
 
-            let symbol = match symbol {
-                Operand::Label(label) => label,
-                _ => unreachable!(),
-            };
             let rd_reg = match rd {
                 Operand::Register(n) => n,
                 _ => return Err(format!("Invalid first operand for '{}' pseudo-instruction. Expected a register, got {}", name, rd)),
             };
-            Ok(vec![
-                Statement {
-                    kind: StatementKind::Instruction("auipc".to_string(), vec![
-                        Operand::Register(rd_reg),
-                        Operand::Modifier(ModifierKind::Hi, symbol.clone())
-                    ]),
-                    line,
-                },
-                Statement {
-                    kind: StatementKind::Instruction(name.to_string(), vec![Operand::Register(rd_reg), Operand::Memory { offset: MemoryOffset::Modifier(ModifierKind::Lo, symbol), reg: rd_reg }]),
-                    line,
-                }
-            ])
+            let (mut statements, label) = pcrel_hi(ModifierKind::PcrelHi, rd_reg, &symbol, line, file, labels);
+            statements.push(Statement {
+                kind: StatementKind::Instruction(name.to_string(), vec![
+                    Operand::Register(rd_reg),
+                    Operand::Memory { offset: MemoryOffset::Modifier(ModifierKind::PcrelLo, label), reg: rd_reg }
+                ]),
+                line,
+                file,
+            });
+            Ok(statements)
         }
         "sb" | "sh" | "sw" => {
             if ops.len() != 3 {
                 // If number of operands is not 3, we consider it a base instruction and return it as is.
-                return Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line }]);
+                return Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line, file }]);
             }
             // Pseudo-instruction: s{b|h|w} rd, symbol, rt
-            // Base instructions:  auipc rt, symbol[31:12]
-            //                     s{b|h|w} rd, symbol[11:0](rt)
-            if !matches!(ops[1], Operand::Label(_)) {
-                return Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line }]);
+            // Base instructions:  auipc rt, %pcrel_hi(symbol)
+            //                     s{b|h|w} rd, %pcrel_lo(label)(rt)
+            if !is_address(&ops[1]) {
+                return Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line, file }]);
             }
 
             let mut ops_iter = ops.into_iter();
@@ -108,33 +149,23 @@ fn expand_statement(statement: Statement) -> Result<Vec<Statement>, String> {
                 Operand::Register(n) => n,
                 _ => return Err(format!("Invalid first operand for '{}' pseudo-instruction. Expected a register, got {}", name, rd)),
             };
-            let symbol = match symbol {
-                Operand::Label(label) => label,
-                _ => unreachable!(),
-            };
             let rt_reg = match rt {
                 Operand::Register(n) => n,
                 _ => return Err(format!("Invalid third operand for '{}' pseudo-instruction. Expected a register, got {}", name, rt)),
             };
-            Ok(vec![
-                Statement {
-                    kind: StatementKind::Instruction("auipc".to_string(), vec![
-                        Operand::Register(rt_reg),
-                        Operand::Modifier(ModifierKind::Hi, symbol.clone())
-                    ]),
-                    line,
-                },
-                Statement {
-                    kind: StatementKind::Instruction(name.to_string(), vec![
-                        Operand::Register(rd_reg),
-                        Operand::Memory { offset: MemoryOffset::Modifier(ModifierKind::Lo, symbol), reg: rt_reg }
-                    ]),
-                    line,
-                }
-            ])
+            let (mut statements, label) = pcrel_hi(ModifierKind::PcrelHi, rt_reg, &symbol, line, file, labels);
+            statements.push(Statement {
+                kind: StatementKind::Instruction(name.to_string(), vec![
+                    Operand::Register(rd_reg),
+                    Operand::Memory { offset: MemoryOffset::Modifier(ModifierKind::PcrelLo, label), reg: rt_reg }
+                ]),
+                line,
+                file,
+            });
+            Ok(statements)
         }
         "nop" => {
-            expand_0op(&name, ops, "addi", line, vec![Operand::Register(0), Operand::Register(0), Operand::Immediate(0)])
+            expand_0op(&name, ops, "addi", line, file, vec![Operand::Register(0), Operand::Register(0), Operand::Immediate(0)])
         }
         "li" => {
             if ops.len() != 2 {
@@ -151,6 +182,21 @@ fn expand_statement(statement: Statement) -> Result<Vec<Statement>, String> {
             };
             let imm = match imm_op {
                 Operand::Immediate(n) => n,
+                // The value is not known until symbols are resolved, so always use both instructions
+                ref value if is_address(value) => {
+                    return Ok(vec![
+                        Statement {
+                            kind: StatementKind::Instruction("lui".to_string(), vec![Operand::Register(rd_reg), modifier(ModifierKind::Hi, value)]),
+                            line,
+                            file,
+                        },
+                        Statement {
+                            kind: StatementKind::Instruction("addi".to_string(), vec![Operand::Register(rd_reg), Operand::Register(rd_reg), modifier(ModifierKind::Lo, value)]),
+                            line,
+                            file,
+                        }
+                    ]);
+                }
                 _ => return Err(format!("Invalid second operand for 'li' pseudo-instruction. Expected an immediate, got {}", imm_op)),
             };
 
@@ -158,6 +204,7 @@ fn expand_statement(statement: Statement) -> Result<Vec<Statement>, String> {
                 Ok(vec![Statement {
                     kind: StatementKind::Instruction("addi".to_string(), vec![Operand::Register(rd_reg), Operand::Register(0), Operand::Immediate(imm)]),
                     line,
+                    file,
                 }])
             } else {
                 let hi20 = ((imm as i64 + 0x800) >> 12) as i32;
@@ -166,212 +213,226 @@ fn expand_statement(statement: Statement) -> Result<Vec<Statement>, String> {
                     Statement {
                         kind: StatementKind::Instruction("lui".to_string(), vec![Operand::Register(rd_reg), Operand::Immediate(hi20)]),
                         line,
+                        file,
                     },
                     Statement {
                         kind: StatementKind::Instruction("addi".to_string(), vec![Operand::Register(rd_reg), Operand::Register(rd_reg), Operand::Immediate(lo12)]),
                         line,
+                        file,
                     }
                 ])
             }
         }
         "mv" => {
-            expand_2reg_to_3op(&name, ops, "addi", line, |rd, rs| {
+            expand_2reg_to_3op(&name, ops, "addi", line, file, |rd, rs| {
                 vec![Operand::Register(rd), Operand::Register(rs), Operand::Immediate(0)]
             })
         }
         "not" => {
-            expand_2reg_to_3op(&name, ops, "xori", line, |rd, rs| {
+            expand_2reg_to_3op(&name, ops, "xori", line, file, |rd, rs| {
                 vec![Operand::Register(rd), Operand::Register(rs), Operand::Immediate(-1)]
             })
         }
         "neg" => {
-            expand_2reg_to_3op(&name, ops, "sub", line, |rd, rs| {
+            expand_2reg_to_3op(&name, ops, "sub", line, file, |rd, rs| {
                 vec![Operand::Register(rd), Operand::Register(0), Operand::Register(rs)]
             })
         }
         "seqz" => {
-            expand_2reg_to_3op(&name, ops, "sltiu", line, |rd, rs| {
+            expand_2reg_to_3op(&name, ops, "sltiu", line, file, |rd, rs| {
                 vec![Operand::Register(rd), Operand::Register(rs), Operand::Immediate(1)]
             })
         }
         "snez" => {
-            expand_2reg_to_3op(&name, ops, "sltu", line, |rd, rs| {
+            expand_2reg_to_3op(&name, ops, "sltu", line, file, |rd, rs| {
                 vec![Operand::Register(rd), Operand::Register(0), Operand::Register(rs)]
             })
         }
         "sltz" => {
-            expand_2reg_to_3op(&name, ops, "slti", line, |rd, rs| {
+            expand_2reg_to_3op(&name, ops, "slti", line, file, |rd, rs| {
                 vec![Operand::Register(rd), Operand::Register(rs), Operand::Immediate(0)]
             })
         }
         "sgtz" => {
-            expand_2reg_to_3op(&name, ops, "slt", line, |rd, rs| {
+            expand_2reg_to_3op(&name, ops, "slt", line, file, |rd, rs| {
                 vec![Operand::Register(rd), Operand::Register(0), Operand::Register(rs)]
             })
         }
         // Branch if compares with zero
         "beqz" => {
-            expand_branch_compares_with_zero(&name, ops, "beq", line, |rs, offset| {
+            expand_branch_compares_with_zero(&name, ops, "beq", line, file, |rs, offset| {
                 vec![Operand::Register(rs), Operand::Register(0), offset]
             })
         },
         "bnez" => {
-            expand_branch_compares_with_zero(&name, ops, "bne", line, |rs, offset| {
+            expand_branch_compares_with_zero(&name, ops, "bne", line, file, |rs, offset| {
                 vec![Operand::Register(rs), Operand::Register(0), offset]
             })
         },
         "blez" => {
-            expand_branch_compares_with_zero(&name, ops, "bge", line, |rs, offset| {
+            expand_branch_compares_with_zero(&name, ops, "bge", line, file, |rs, offset| {
                 vec![Operand::Register(0), Operand::Register(rs), offset]
             })
         },
         "bgez" => {
-            expand_branch_compares_with_zero(&name, ops, "bge", line, |rs, offset| {
+            expand_branch_compares_with_zero(&name, ops, "bge", line, file, |rs, offset| {
                 vec![Operand::Register(rs), Operand::Register(0), offset]
             })
         },
         "bltz" => {
-            expand_branch_compares_with_zero(&name, ops, "blt", line, |rs, offset| {
+            expand_branch_compares_with_zero(&name, ops, "blt", line, file, |rs, offset| {
                 vec![Operand::Register(rs), Operand::Register(0), offset]
             })
         },
         "bgtz" => {
-            expand_branch_compares_with_zero(&name, ops, "blt", line, |rs, offset| {
+            expand_branch_compares_with_zero(&name, ops, "blt", line, file, |rs, offset| {
                 vec![Operand::Register(0), Operand::Register(rs), offset]
             })
         },
         // Branch if
         "bgt" => {
-            expand_2reg_1op_to_3op(&name, ops, "blt", line, |rs, rt, offset| {
+            expand_2reg_1op_to_3op(&name, ops, "blt", line, file, |rs, rt, offset| {
                 vec![Operand::Register(rt), Operand::Register(rs), offset]
             })
         },
         "ble" => {
-            expand_2reg_1op_to_3op(&name, ops, "bge", line, |rs, rt, offset| {
+            expand_2reg_1op_to_3op(&name, ops, "bge", line, file, |rs, rt, offset| {
                 vec![Operand::Register(rt), Operand::Register(rs), offset]
             })
         },
         "bgtu" => {
-            expand_2reg_1op_to_3op(&name, ops, "bltu", line, |rs, rt, offset| {
+            expand_2reg_1op_to_3op(&name, ops, "bltu", line, file, |rs, rt, offset| {
                 vec![Operand::Register(rt), Operand::Register(rs), offset]
             })
         },
         "bleu" => {
-            expand_2reg_1op_to_3op(&name, ops, "bgeu", line, |rs, rt, offset| {
+            expand_2reg_1op_to_3op(&name, ops, "bgeu", line, file, |rs, rt, offset| {
                 vec![Operand::Register(rt), Operand::Register(rs), offset]
             })
         },
         // Jumps, calls and returns
         "j" => {
-            expand_1op(&name, ops, "jal", line, |offset| vec![Operand::Register(0), offset])
+            expand_1op(&name, ops, "jal", line, file, |offset| vec![Operand::Register(0), offset])
         }
         "jal" => {
             if ops.len() == 1 {
-                expand_1op(&name, ops, "jal", line, |offset| vec![Operand::Register(1), offset])
+                expand_1op(&name, ops, "jal", line, file, |offset| vec![Operand::Register(1), offset])
             } else {
-                Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line }])
+                Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line, file }])
             }
         }
         "jr" => {
-            expand_1reg_to_3op(&name, ops, "jalr", line, |rs| {
+            expand_1reg_to_3op(&name, ops, "jalr", line, file, |rs| {
                 vec![Operand::Register(0), Operand::Register(rs), Operand::Immediate(0)]
             })
         }
         "jalr" => {
             if ops.len() == 1 {
-                expand_1reg_to_3op(&name, ops, "jalr", line, |rs| {
+                expand_1reg_to_3op(&name, ops, "jalr", line, file, |rs| {
                     vec![Operand::Register(1), Operand::Register(rs), Operand::Immediate(0)]
                 })
             } else {
-                Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line }])
+                Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line, file }])
             }
         }
         "ret" => {
-            expand_0op(&name, ops, "jalr", line, vec![Operand::Register(0), Operand::Register(1), Operand::Immediate(0)])
+            expand_0op(&name, ops, "jalr", line, file, vec![Operand::Register(0), Operand::Register(1), Operand::Immediate(0)])
         }
-        "call" => {
-            if ops.len() == 1 {
-                let offset = ops.into_iter().next().unwrap();
-                // Validate offset is an Immediate or Label
-                let (offset_high, offset_low) = match offset {
-                    Operand::Immediate(imm) => (
-                        Operand::Immediate(((imm as i64 + 0x800) >> 12) as i32),
-                        Operand::Immediate((imm << 20) >> 20),
-                    ),
-                    Operand::Label(label) => (
-                        Operand::Modifier(ModifierKind::Hi, label.clone()),
-                        Operand::Modifier(ModifierKind::Lo, label)
-                    ),
-                    _ => return Err(format!("Invalid operand for 'call' pseudo-instruction. Expected an immediate or label, got {}", offset)),
-                };
-                Ok(vec![Statement {
-                    kind: StatementKind::Instruction("auipc".to_string(), vec![
-                        Operand::Register(1),
-                        offset_high,
-                    ]),
-                    line,
-                },
-                    Statement {
-                    kind: StatementKind::Instruction("jalr".to_string(), vec![
-                        Operand::Register(1),
-                        Operand::Register(1),
-                        offset_low,
-                    ]),
-                    line,
-                }])
-            } else {
-                Err(format!("Invalid number of operands for 'call' pseudo-instruction. Expected 1, got {}", ops.len()))
+        "call" | "tail" => {
+            // call: auipc ra, %pcrel_hi(symbol)     tail: auipc t1, %pcrel_hi(symbol)
+            //       jalr ra, ra, %pcrel_lo(label)         jalr zero, t1, %pcrel_lo(label)
+            let (link, scratch) = if name == "call" { (1, 1) } else { (0, 6) };
+            if ops.len() != 1 {
+                return Err(format!("Invalid number of operands for '{}' pseudo-instruction. Expected 1, got {}", name, ops.len()));
             }
+            let offset = ops.into_iter().next().unwrap();
+            // Validate offset is an Immediate or Label
+            let (mut statements, offset_low) = match offset {
+                Operand::Immediate(imm) => (
+                    vec![Statement {
+                        kind: StatementKind::Instruction("auipc".to_string(), vec![
+                            Operand::Register(scratch),
+                            Operand::Immediate(((imm as i64 + 0x800) >> 12) as i32),
+                        ]),
+                        line,
+                        file,
+                    }],
+                    Operand::Immediate((imm << 20) >> 20),
+                ),
+                ref target if is_address(target) => {
+                    let (statements, label) = pcrel_hi(ModifierKind::PcrelHi, scratch, target, line, file, labels);
+                    (statements, Operand::Modifier(ModifierKind::PcrelLo, label))
+                }
+                _ => return Err(format!("Invalid operand for '{}' pseudo-instruction. Expected an immediate or label, got {}", name, offset)),
+            };
+            statements.push(Statement {
+                kind: StatementKind::Instruction("jalr".to_string(), vec![
+                    Operand::Register(link),
+                    Operand::Register(scratch),
+                    offset_low,
+                ]),
+                line,
+                file,
+            });
+            Ok(statements)
         }
-        "tail" => {
-            if ops.len() == 1 {
-                let offset = ops.into_iter().next().unwrap();
-                // Validate offset is an Immediate or Label
-                let (offset_high, offset_low) = match offset {
-                    Operand::Immediate(imm) => (
-                        Operand::Immediate(((imm as i64 + 0x800) >> 12) as i32),
-                        Operand::Immediate((imm << 20) >> 20),
-                    ),
-                    Operand::Label(label) => (
-                        Operand::Modifier(ModifierKind::Hi, label.clone()),
-                        Operand::Modifier(ModifierKind::Lo, label)
-                    ),
-                    _ => return Err(format!("Invalid operand for 'tail' pseudo-instruction. Expected an immediate or label, got {}", offset)),
-                };
-                Ok(vec![Statement {
-                    kind: StatementKind::Instruction("auipc".to_string(), vec![
-                        Operand::Register(6),
-                        offset_high,
-                    ]),
-                    line,
-                },
-                    Statement {
-                    kind: StatementKind::Instruction("jalr".to_string(), vec![
-                        Operand::Register(0),
-                        Operand::Register(6),
-                        offset_low,
-                    ]),
-                    line,
-                }])
-            } else {
-                Err(format!("Invalid number of operands for 'tail' pseudo-instruction. Expected 1, got {}", ops.len()))
-            }
+        // CSR access; operands are checked by the assembler
+        "csrr" => {
+            expand_2op(&name, ops, "csrrs", line, file, |rd, csr| vec![rd, csr, Operand::Register(0)])
+        }
+        "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => {
+            // csrw -> csrrw, csrsi -> csrrsi, ...
+            let base_name = format!("csrr{}", &name[3..]);
+            expand_2op(&name, ops, &base_name, line, file, |csr, source| vec![Operand::Register(0), csr, source])
+        }
+        "rdcycle" | "rdcycleh" | "rdtime" | "rdtimeh" | "rdinstret" | "rdinstreth" => {
+            let address = csr::address_of(&name[2..]).expect("every counter pseudo-instruction reads a known CSR");
+            expand_1reg_to_3op(&name, ops, "csrrs", line, file, |rd| {
+                vec![Operand::Register(rd), Operand::Csr(address), Operand::Register(0)]
+            })
         }
-        _ => Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line }]),
+        _ => Ok(vec![Statement { kind: StatementKind::Instruction(name, ops), line, file }]),
     }
 }
 
-fn expand_0op(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, base_ops: Vec<Operand>) -> Result<Vec<Statement>, String> {
+// Whether the operand is an address to split into %hi/%lo parts: a label or an expression over labels
+fn is_address(op: &Operand) -> bool {
+    matches!(op, Operand::Label(_) | Operand::Expression(_))
+}
+
+// 'auipc rd, %pcrel_hi(address)', or %got_pcrel_hi, under a label of its own. The
+// label is returned for the %pcrel_lo of the instruction that completes the address.
+fn pcrel_hi(kind: ModifierKind, rd: u8, address: &Operand, line: usize, file: FileId, labels: &mut usize) -> (Vec<Statement>, String) {
+    let label = format!(".Lpcrel_hi{}", labels);
+    *labels += 1;
+    let statements = vec![
+        Statement { kind: StatementKind::Label(label.clone()), line, file },
+        Statement { kind: StatementKind::Instruction("auipc".to_string(), vec![Operand::Register(rd), modifier(kind, address)]), line, file },
+    ];
+    (statements, label)
+}
+
+// Applies %hi or %lo to an address operand
+fn modifier(kind: ModifierKind, address: &Operand) -> Operand {
+    match address {
+        Operand::Label(label) => Operand::Modifier(kind, label.clone()),
+        Operand::Expression(expr) => Operand::Expression(Expr::Modifier(kind, Box::new(expr.clone()))),
+        _ => unreachable!("only called on addresses"),
+    }
+}
+
+fn expand_0op(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, file: FileId, base_ops: Vec<Operand>) -> Result<Vec<Statement>, String> {
     if !ops.is_empty() {
         return Err(format!("Invalid number of operands for '{}' pseudo-instruction. Expected 0, got {}", name, ops.len()));
     }
     Ok(vec![Statement {
         kind: StatementKind::Instruction(base_name.to_string(), base_ops),
         line,
+        file,
     }])
 }
 
-fn expand_1op<F>(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, op_builder: F) -> Result<Vec<Statement>, String>
+fn expand_1op<F>(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, file: FileId, op_builder: F) -> Result<Vec<Statement>, String>
 where
     F: FnOnce(Operand) -> Vec<Operand>
 {
@@ -382,10 +443,28 @@ where
     Ok(vec![Statement {
         kind: StatementKind::Instruction(base_name.to_string(), op_builder(op)),
         line,
+        file,
     }])
 }
 
-fn expand_1reg_to_3op<F>(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, op_builder: F) -> Result<Vec<Statement>, String>
+fn expand_2op<F>(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, file: FileId, op_builder: F) -> Result<Vec<Statement>, String>
+where
+    F: FnOnce(Operand, Operand) -> Vec<Operand>
+{
+    if ops.len() != 2 {
+        return Err(format!("Invalid number of operands for '{}' pseudo-instruction. Expected 2, got {}", name, ops.len()));
+    }
+    let mut ops_iter = ops.into_iter();
+    let first = ops_iter.next().unwrap();
+    let second = ops_iter.next().unwrap();
+    Ok(vec![Statement {
+        kind: StatementKind::Instruction(base_name.to_string(), op_builder(first, second)),
+        line,
+        file,
+    }])
+}
+
+fn expand_1reg_to_3op<F>(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, file: FileId, op_builder: F) -> Result<Vec<Statement>, String>
 where
     F: FnOnce(u8) -> Vec<Operand>
 {
@@ -400,10 +479,11 @@ where
     Ok(vec![Statement {
         kind: StatementKind::Instruction(base_name.to_string(), op_builder(rd_reg)),
         line,
+        file,
     }])
 }
 
-fn expand_2reg_to_3op<F>(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, op_builder: F) -> Result<Vec<Statement>, String>
+fn expand_2reg_to_3op<F>(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, file: FileId, op_builder: F) -> Result<Vec<Statement>, String>
 where
     F: FnOnce(u8, u8) -> Vec<Operand>
 {
@@ -424,10 +504,11 @@ where
     Ok(vec![Statement {
         kind: StatementKind::Instruction(base_name.to_string(), op_builder(rd_reg, rs_reg)),
         line,
+        file,
     }])
 }
 
-fn expand_branch_compares_with_zero<F>(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, op_builder: F) -> Result<Vec<Statement>, String>
+fn expand_branch_compares_with_zero<F>(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, file: FileId, op_builder: F) -> Result<Vec<Statement>, String>
 where
     F: FnOnce(u8, Operand) -> Vec<Operand>
 {
@@ -450,10 +531,11 @@ where
     Ok(vec![Statement {
         kind: StatementKind::Instruction(base_name.to_string(), op_builder(rd_reg, Operand::Label(symbol))),
         line,
+        file,
     }])
 }
 
-fn expand_2reg_1op_to_3op<F>(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, op_builder: F) -> Result<Vec<Statement>, String>
+fn expand_2reg_1op_to_3op<F>(name: &str, ops: Vec<Operand>, base_name: &str, line: usize, file: FileId, op_builder: F) -> Result<Vec<Statement>, String>
 where
     F: FnOnce(u8, u8, Operand) -> Vec<Operand>
 {
@@ -477,6 +559,7 @@ where
     Ok(vec![Statement {
         kind: StatementKind::Instruction(base_name.to_string(), op_builder(rs_reg, rt_reg, offset)),
         line,
+        file,
     }])
 }
 
@@ -490,8 +573,9 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("add".to_string(), vec![Operand::Register(1), Operand::Register(2), Operand::Register(3)]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
+        let expanded = expand_statement(statement, &mut 0).unwrap();
         assert_eq!(expanded.len(), 1);
         assert_eq!(expanded[0].kind, StatementKind::Instruction("add".to_string(), vec![Operand::Register(1), Operand::Register(2), Operand::Register(3)]));
     }
@@ -501,11 +585,53 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("la".to_string(), vec![Operand::Register(1), Operand::Label("label".to_string())]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
-        assert_eq!(expanded.len(), 2);
-        assert_eq!(expanded[0].kind, StatementKind::Instruction("auipc".to_string(), vec![Operand::Register(1), Operand::Modifier(ModifierKind::Hi, "label".to_string())]));
-        assert_eq!(expanded[1].kind, StatementKind::Instruction("addi".to_string(), vec![Operand::Register(1), Operand::Register(1), Operand::Modifier(ModifierKind::Lo, "label".to_string())]));
+        let mut labels = 3;
+        let expanded = expand_statement(statement, &mut labels).unwrap();
+        assert_eq!(expanded.len(), 3);
+        // the auipc gets a label of its own, which the addi names to get the offset's lower bits
+        assert_eq!(expanded[0].kind, StatementKind::Label(".Lpcrel_hi3".to_string()));
+        assert_eq!(expanded[1].kind, StatementKind::Instruction("auipc".to_string(), vec![Operand::Register(1), Operand::Modifier(ModifierKind::PcrelHi, "label".to_string())]));
+        assert_eq!(expanded[2].kind, StatementKind::Instruction("addi".to_string(), vec![Operand::Register(1), Operand::Register(1), Operand::Modifier(ModifierKind::PcrelLo, ".Lpcrel_hi3".to_string())]));
+        assert_eq!(labels, 4);
+
+        let statement = Statement {
+            kind: StatementKind::Instruction("lga".to_string(), vec![Operand::Register(1), Operand::Label("label".to_string())]),
+            line: 1,
+            file: 0,
+        };
+        let expanded = expand_statement(statement, &mut 0).unwrap();
+        assert_eq!(expanded[1].kind, StatementKind::Instruction("auipc".to_string(), vec![Operand::Register(1), Operand::Modifier(ModifierKind::GotPcrelHi, "label".to_string())]));
+        assert_eq!(expanded[2].kind, StatementKind::Instruction("lw".to_string(), vec![
+            Operand::Register(1), Operand::Memory { offset: MemoryOffset::Modifier(ModifierKind::PcrelLo, ".Lpcrel_hi0".to_string()), reg: 1 }]));
+    }
+
+    #[test]
+    fn test_expand_address_expressions() {
+        // la, li and loads take an expression as the address and apply %hi or %pcrel_hi to all of it
+        let address = Expr::Binary(crate::lexer::Operator::Plus, Box::new(Expr::Symbol("buf".to_string())), Box::new(Expr::Number(4)));
+        let statement = Statement {
+            kind: StatementKind::Instruction("li".to_string(), vec![Operand::Register(1), Operand::Expression(address.clone())]),
+            line: 1,
+            file: 0,
+        };
+        let expanded = expand_statement(statement, &mut 0).unwrap();
+        assert_eq!(expanded[0].kind, StatementKind::Instruction("lui".to_string(), vec![
+            Operand::Register(1), Operand::Expression(Expr::Modifier(ModifierKind::Hi, Box::new(address.clone())))]));
+        assert_eq!(expanded[1].kind, StatementKind::Instruction("addi".to_string(), vec![
+            Operand::Register(1), Operand::Register(1), Operand::Expression(Expr::Modifier(ModifierKind::Lo, Box::new(address.clone())))]));
+
+        let pcrel_hi = Expr::Modifier(ModifierKind::PcrelHi, Box::new(address.clone()));
+        for name in ["la", "lw"] {
+            let statement = Statement {
+                kind: StatementKind::Instruction(name.to_string(), vec![Operand::Register(1), Operand::Expression(address.clone())]),
+                line: 1,
+                file: 0,
+            };
+            let expanded = expand_statement(statement, &mut 0).unwrap();
+            assert_eq!(expanded[1].kind, StatementKind::Instruction("auipc".to_string(), vec![Operand::Register(1), Operand::Expression(pcrel_hi.clone())]));
+        }
     }
 
     #[test]
@@ -514,8 +640,9 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("la".to_string(), vec![Operand::Immediate(1), Operand::Immediate(2), Operand::Immediate(3)]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement);
+        let expanded = expand_statement(statement, &mut 0);
         assert!(expanded.is_err());
         assert_eq!(expanded.unwrap_err(), "Invalid number of operands for 'la' pseudo-instruction. Expected 2, got 3");
 
@@ -525,16 +652,18 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("la".to_string(), vec![Operand::Immediate(1), Operand::Label("label".to_string())]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement);
+        let expanded = expand_statement(statement, &mut 0);
         assert!(expanded.is_err());
         assert_eq!(expanded.unwrap_err(), "Invalid first operand for 'la' pseudo-instruction. Expected a register, got 1");
         // invalid second parameter, expected label
         let statement = Statement {
             kind: StatementKind::Instruction("la".to_string(), vec![Operand::Register(1), Operand::Register(2)]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement);
+        let expanded = expand_statement(statement, &mut 0);
         assert!(expanded.is_err());
         assert_eq!(expanded.unwrap_err(), "Invalid second operand for 'la' pseudo-instruction. Expected a label, got x2");
     }
@@ -548,8 +677,9 @@ mod tests {
                 Operand::Memory { offset: MemoryOffset::Immediate(4), reg: 2 }
             ]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
+        let expanded = expand_statement(statement, &mut 0).unwrap();
         assert_eq!(expanded.len(), 1);
         assert_eq!(expanded[0].kind, StatementKind::Instruction(
             "lb".to_string(),
@@ -565,11 +695,13 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("lb".to_string(), vec![Operand::Register(3), Operand::Label("label".to_string())]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
-        assert_eq!(expanded.len(), 2);
-        assert_eq!(expanded[0].kind, StatementKind::Instruction("auipc".to_string(), vec![Operand::Register(3), Operand::Modifier(ModifierKind::Hi, "label".to_string())]));
-        assert_eq!(expanded[1].kind, StatementKind::Instruction("lb".to_string(), vec![Operand::Register(3), Operand::Memory { offset: MemoryOffset::Modifier(ModifierKind::Lo, "label".to_string()), reg: 3 }]));
+        let expanded = expand_statement(statement, &mut 0).unwrap();
+        assert_eq!(expanded.len(), 3);
+        assert_eq!(expanded[1].kind, StatementKind::Instruction("auipc".to_string(), vec![Operand::Register(3), Operand::Modifier(ModifierKind::PcrelHi, "label".to_string())]));
+        assert_eq!(expanded[2].kind, StatementKind::Instruction("lb".to_string(), vec![
+            Operand::Register(3), Operand::Memory { offset: MemoryOffset::Modifier(ModifierKind::PcrelLo, ".Lpcrel_hi0".to_string()), reg: 3 }]));
     }
 
     #[test]
@@ -581,8 +713,9 @@ mod tests {
                 Operand::Label("label".to_string())
             ]),
             line: 1,
+            file: 0,
         };
-        assert!(expand_statement(statement).is_err());
+        assert!(expand_statement(statement, &mut 0).is_err());
     }
 
     #[test]
@@ -590,13 +723,14 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("sb".to_string(), vec![Operand::Register(3), Operand::Label("label".to_string()), Operand::Register(4)]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
-        assert_eq!(expanded.len(), 2);
-        assert_eq!(expanded[0].kind, StatementKind::Instruction("auipc".to_string(), vec![
-            Operand::Register(4), Operand::Modifier(ModifierKind::Hi, "label".to_string())]));
-        assert_eq!(expanded[1].kind, StatementKind::Instruction("sb".to_string(), vec![
-            Operand::Register(3), Operand::Memory { offset: MemoryOffset::Modifier(ModifierKind::Lo, "label".to_string()), reg: 4 }]));
+        let expanded = expand_statement(statement, &mut 0).unwrap();
+        assert_eq!(expanded.len(), 3);
+        assert_eq!(expanded[1].kind, StatementKind::Instruction("auipc".to_string(), vec![
+            Operand::Register(4), Operand::Modifier(ModifierKind::PcrelHi, "label".to_string())]));
+        assert_eq!(expanded[2].kind, StatementKind::Instruction("sb".to_string(), vec![
+            Operand::Register(3), Operand::Memory { offset: MemoryOffset::Modifier(ModifierKind::PcrelLo, ".Lpcrel_hi0".to_string()), reg: 4 }]));
     }
 
     #[test]
@@ -606,8 +740,9 @@ mod tests {
             kind: StatementKind::Instruction("sb".to_string(), vec![
                 Operand::Register(3), Operand::Memory { offset: MemoryOffset::Immediate(0), reg: 2 }]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
+        let expanded = expand_statement(statement, &mut 0).unwrap();
         assert_eq!(expanded.len(), 1);
         assert_eq!(expanded[0].kind, StatementKind::Instruction("sb".to_string(), vec![
             Operand::Register(3), Operand::Memory { offset: MemoryOffset::Immediate(0), reg: 2 }]));
@@ -618,8 +753,9 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("li".to_string(), vec![Operand::Register(1), Operand::Immediate(100)]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
+        let expanded = expand_statement(statement, &mut 0).unwrap();
         assert_eq!(expanded.len(), 1);
         assert_eq!(expanded[0].kind, StatementKind::Instruction("addi".to_string(), vec![
             Operand::Register(1), Operand::Register(0), Operand::Immediate(100)]));
@@ -630,8 +766,9 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("li".to_string(), vec![Operand::Register(1), Operand::Immediate(0x12345678)]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
+        let expanded = expand_statement(statement, &mut 0).unwrap();
         assert_eq!(expanded.len(), 2);
         // hi20 = (0x12345678 + 0x800) >> 12 = 0x12345
         // lo12 = (0x12345678 << 20) >> 20 = 0x678
@@ -646,8 +783,9 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("li".to_string(), vec![Operand::Register(1), Operand::Immediate(-100)]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
+        let expanded = expand_statement(statement, &mut 0).unwrap();
         assert_eq!(expanded.len(), 1);
         assert_eq!(expanded[0].kind, StatementKind::Instruction("addi".to_string(), vec![Operand::Register(1), Operand::Register(0), Operand::Immediate(-100)]));
     }
@@ -659,8 +797,9 @@ mod tests {
             kind: StatementKind::Instruction("li".to_string(),
                 vec![Operand::Register(1), Operand::Immediate(0x12345ABC_u32 as i32)]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
+        let expanded = expand_statement(statement, &mut 0).unwrap();
         assert_eq!(expanded.len(), 2);
         // hi = (0x12345ABC + 0x800) >> 12 = 0x12346  ← note: 0x12346, not 0x12345
         // lo = sign_extend(0xABC) = -1348
@@ -681,8 +820,9 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("li".to_string(), vec![Operand::Register(1), Operand::Immediate(0x7FFFFFFF)]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
+        let expanded = expand_statement(statement, &mut 0).unwrap();
         assert_eq!(expanded.len(), 2);
         // hi20 = (0x7FFFFFFF + 0x800) >> 12 = 0x80000 (wrapping)
         // lo12 = (0x7FFFFFFF << 20) >> 20 = -1
@@ -697,16 +837,17 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("call".to_string(), vec![Operand::Label("loop".to_string())]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
-        assert_eq!(expanded.len(), 2);
-        assert_eq!(expanded[0].kind, StatementKind::Instruction(
+        let expanded = expand_statement(statement, &mut 0).unwrap();
+        assert_eq!(expanded.len(), 3);
+        assert_eq!(expanded[1].kind, StatementKind::Instruction(
             "auipc".to_string(),
-            vec![Operand::Register(1), Operand::Modifier(ModifierKind::Hi, "loop".to_string())]
+            vec![Operand::Register(1), Operand::Modifier(ModifierKind::PcrelHi, "loop".to_string())]
         ));
-        assert_eq!(expanded[1].kind, StatementKind::Instruction(
+        assert_eq!(expanded[2].kind, StatementKind::Instruction(
             "jalr".to_string(),
-            vec![Operand::Register(1), Operand::Register(1), Operand::Modifier(ModifierKind::Lo, "loop".to_string())]
+            vec![Operand::Register(1), Operand::Register(1), Operand::Modifier(ModifierKind::PcrelLo, ".Lpcrel_hi0".to_string())]
         ));
     }
 
@@ -717,8 +858,9 @@ mod tests {
             kind: StatementKind::Instruction("call".to_string(),
                 vec![Operand::Immediate(0x12800)]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
+        let expanded = expand_statement(statement, &mut 0).unwrap();
         // hi = (0x12800 + 0x800) >> 12 = 0x13
         // lo = -2048
         assert_eq!(expanded[0].kind, StatementKind::Instruction("auipc".to_string(),
@@ -732,16 +874,17 @@ mod tests {
         let statement = Statement {
             kind: StatementKind::Instruction("tail".to_string(), vec![Operand::Label("loop".to_string())]),
             line: 1,
+            file: 0,
         };
-        let expanded = expand_statement(statement).unwrap();
-        assert_eq!(expanded.len(), 2);
-        assert_eq!(expanded[0].kind, StatementKind::Instruction(
+        let expanded = expand_statement(statement, &mut 0).unwrap();
+        assert_eq!(expanded.len(), 3);
+        assert_eq!(expanded[1].kind, StatementKind::Instruction(
             "auipc".to_string(),
-            vec![Operand::Register(6), Operand::Modifier(ModifierKind::Hi, "loop".to_string())]
+            vec![Operand::Register(6), Operand::Modifier(ModifierKind::PcrelHi, "loop".to_string())]
         ));
-        assert_eq!(expanded[1].kind, StatementKind::Instruction(
+        assert_eq!(expanded[2].kind, StatementKind::Instruction(
             "jalr".to_string(),
-            vec![Operand::Register(0), Operand::Register(6), Operand::Modifier(ModifierKind::Lo, "loop".to_string())]
+            vec![Operand::Register(0), Operand::Register(6), Operand::Modifier(ModifierKind::PcrelLo, ".Lpcrel_hi0".to_string())]
         ));
     }
 
@@ -778,10 +921,30 @@ mod tests {
             let statement = Statement {
                 kind: StatementKind::Instruction(name.to_string(), ops),
                 line: 1,
+                file: 0,
             };
-            let expanded = expand_statement(statement).unwrap();
+            let expanded = expand_statement(statement, &mut 0).unwrap();
             assert_eq!(expanded.len(), 1, "Failed expansion for {}", name);
             assert_eq!(expanded[0].kind, StatementKind::Instruction(expected_name.to_string(), expected_ops), "Mismatch for {}", name);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_expand_csr_pseudo_instructions() {
+        let test_cases = vec![
+            ("csrr", vec![Operand::Register(10), Operand::Csr(0x305)], "csrrs", vec![Operand::Register(10), Operand::Csr(0x305), Operand::Register(0)]),
+            ("csrw", vec![Operand::Csr(0x305), Operand::Register(5)], "csrrw", vec![Operand::Register(0), Operand::Csr(0x305), Operand::Register(5)]),
+            ("csrs", vec![Operand::Csr(0x300), Operand::Register(5)], "csrrs", vec![Operand::Register(0), Operand::Csr(0x300), Operand::Register(5)]),
+            ("csrc", vec![Operand::Csr(0x300), Operand::Register(5)], "csrrc", vec![Operand::Register(0), Operand::Csr(0x300), Operand::Register(5)]),
+            ("csrsi", vec![Operand::Csr(0x300), Operand::Immediate(8)], "csrrsi", vec![Operand::Register(0), Operand::Csr(0x300), Operand::Immediate(8)]),
+            ("rdtime", vec![Operand::Register(10)], "csrrs", vec![Operand::Register(10), Operand::Csr(0xC01), Operand::Register(0)]),
+        ];
+        for (name, ops, base_name, base_ops) in test_cases {
+            let statement = Statement { kind: StatementKind::Instruction(name.to_string(), ops), line: 1, file: 0 };
+            let expanded = expand_statement(statement, &mut 0).unwrap();
+            assert_eq!(expanded, vec![Statement { kind: StatementKind::Instruction(base_name.to_string(), base_ops), line: 1, file: 0 }]);
+        }
+        let statement = Statement { kind: StatementKind::Instruction("csrr".to_string(), vec![Operand::Register(10)]), line: 1, file: 0 };
+        assert!(expand_statement(statement, &mut 0).is_err());
+    }
+}
@@ -0,0 +1,325 @@
+use crate::memory::PAGE_SIZE;
+use crate::memory_map::{format_size, MemoryMap};
+use crate::parser::Operand;
+use crate::program::Permissions;
+
+/// Directives that switch the section statements go to.
+pub const SECTION_DIRECTIVES: [&str; 8] = [".text", ".data", ".rodata", ".bss", ".section", ".pushsection", ".popsection", ".previous"];
+
+/// A named part of the program image, placed in memory as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub permissions: Permissions,
+    pub nobits: bool, // zero-initialized, with no bytes in the image (.bss)
+    pub alignment: u32,
+    pub base: u32,
+    pub size: u32,
+    flagged: bool, // whether a '.section' directive gave its flags
+}
+
+impl Section {
+    /// A section with the flags its name implies, as '.rodata.str' is read-only
+    /// data like '.rodata'. Other names are read-only data unless flags say otherwise.
+    pub fn named(name: &str) -> Self {
+        let is = |prefix: &str| name == prefix || name.starts_with(&format!("{}.", prefix));
+        let (permissions, nobits) = if is(".text") {
+            (Permissions::RX, false)
+        } else if is(".data") || is(".sdata") || is(".got") {
+            (Permissions::RW, false)
+        } else if is(".bss") || is(".sbss") {
+            (Permissions::RW, true)
+        } else {
+            (Permissions::R, false)
+        };
+        Self {
+            name: name.to_string(),
+            permissions,
+            nobits,
+            alignment: if permissions.execute { 4 } else { 1 },
+            base: 0,
+            size: 0,
+            flagged: false,
+        }
+    }
+
+    // Applies the flags of a '.section' directive: "a", "w" and "x" as in the
+    // GNU assembler, and @progbits or @nobits
+    fn set_flags(&mut self, flags: &str, kind: Option<&str>) -> Result<(), String> {
+        let mut permissions = Permissions::R;
+        for flag in flags.chars() {
+            match flag {
+                'a' => {}
+                'w' => permissions.write = true,
+                'x' => permissions.execute = true,
+                other => return Err(format!("Unsupported section flag '{}' for section '{}'", other, self.name)),
+            }
+        }
+        self.nobits = match kind {
+            None | Some("progbits") => false,
+            Some("nobits") => true,
+            Some(other) => return Err(format!("Unsupported section type '@{}' for section '{}'", other, self.name)),
+        };
+        if permissions.execute {
+            self.alignment = self.alignment.max(4);
+        }
+        self.permissions = permissions;
+        self.flagged = true;
+        Ok(())
+    }
+
+    /// The address after the section, which is 2^32 for one that ends at the
+    /// top of the address space.
+    pub fn end(&self) -> u64 {
+        self.base as u64 + self.size as u64
+    }
+}
+
+/// The sections of a file, or of a linked program, in the order they were
+/// first used. `.text` and `.data` are always there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sections {
+    sections: Vec<Section>,
+}
+
+impl Default for Sections {
+    fn default() -> Self {
+        Self { sections: vec![Section::named(".text"), Section::named(".data")] }
+    }
+}
+
+impl Sections {
+    /// `.text` at `text_base` and `.data` at `data_base`.
+    pub fn new(text_base: u32, data_base: u32) -> Self {
+        let mut sections = Self::default();
+        sections.sections[0].base = text_base;
+        sections.sections[1].base = data_base;
+        sections
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Section> {
+        self.sections.iter_mut().find(|section| section.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Section> {
+        self.sections.iter()
+    }
+
+    /// Adds a section the first time it is named. Flags given for a section
+    /// that was already given flags must be the same.
+    pub fn declare(&mut self, name: &str, flags: Option<&str>, kind: Option<&str>) -> Result<&mut Section, String> {
+        let index = match self.sections.iter().position(|section| section.name == name) {
+            Some(index) => index,
+            None => {
+                self.sections.push(Section::named(name));
+                self.sections.len() - 1
+            }
+        };
+        let section = &mut self.sections[index];
+        if let Some(flags) = flags {
+            let mut flagged = section.clone();
+            flagged.set_flags(flags, kind)?;
+            if section.flagged && (flagged.permissions, flagged.nobits) != (section.permissions, section.nobits) {
+                return Err(format!("Section '{}' was already used with other flags", name));
+            }
+            *section = flagged;
+        }
+        Ok(section)
+    }
+}
+
+/// The alignment in bytes that `.align power` asks for, 2^power. Larger
+/// powers than 31 do not fit in the address space.
+pub fn alignment(power: i32) -> Result<u32, String> {
+    match power {
+        0..=31 => Ok(1 << power),
+        _ => Err("Directive .align value out of range".to_string()),
+    }
+}
+
+/// Which section statements go to, as the section directives of one file
+/// switch between them.
+pub struct SectionStack {
+    current: String,
+    previous: String,
+    stack: Vec<(String, String)>, // saved by .pushsection
+}
+
+impl Default for SectionStack {
+    fn default() -> Self {
+        Self { current: ".text".to_string(), previous: ".text".to_string(), stack: Vec::new() }
+    }
+}
+
+impl SectionStack {
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+
+    /// Follows a section directive, declaring in `sections` the section it
+    /// names. Returns false if the directive is not a section directive.
+    pub fn switch(&mut self, directive: &str, operands: &[Operand], sections: &mut Sections) -> Result<bool, String> {
+        let target = match directive {
+            ".text" | ".data" | ".rodata" | ".bss" => {
+                sections.declare(directive, None, None)?;
+                directive.to_string()
+            }
+            ".section" | ".pushsection" => {
+                let (name, flags, kind) = match operands {
+                    [name] => (section_name(directive, name)?, None, None),
+                    [name, Operand::StringLiteral(flags)] => (section_name(directive, name)?, Some(flags.as_str()), None),
+                    [name, Operand::StringLiteral(flags), Operand::Attribute(kind)] => {
+                        (section_name(directive, name)?, Some(flags.as_str()), Some(kind.as_str()))
+                    }
+                    _ => return Err(format!("Directive {} requires a section name, and optionally flags like \"aw\" and @progbits or @nobits", directive)),
+                };
+                sections.declare(&name, flags, kind)?;
+                if directive == ".pushsection" {
+                    self.stack.push((self.current.clone(), self.previous.clone()));
+                }
+                name
+            }
+            ".popsection" => {
+                let (current, previous) = self.stack.pop().ok_or("Directive .popsection without a matching .pushsection")?;
+                self.current = current;
+                self.previous = previous;
+                return Ok(true);
+            }
+            ".previous" => {
+                std::mem::swap(&mut self.current, &mut self.previous);
+                return Ok(true);
+            }
+            _ => return Ok(false),
+        };
+        self.previous = std::mem::replace(&mut self.current, target);
+        Ok(true)
+    }
+}
+
+// The name of a section is written as a directive ('.rodata'), a name or a string
+fn section_name(directive: &str, operand: &Operand) -> Result<String, String> {
+    match operand {
+        Operand::Label(name) | Operand::StringLiteral(name) => Ok(name.clone()),
+        _ => Err(format!("Directive {} requires a section name, got {}", directive, operand)),
+    }
+}
+
+/// Places the sections of the files being linked in the regions of `map`.
+/// Sections with the same name go together, each file's part after the
+/// previous file's and aligned. In each region `.text` and `.data` come first
+/// and zero-initialized sections last, and a section with other permissions
+/// than the one before it starts on a new page, as loaders map whole pages
+/// with one set of permissions. Returns the sections of the whole
+/// program, in address order within each region, or which one does not fit.
+pub fn place(files: &mut [Sections], map: &MemoryMap) -> Result<Vec<Section>, String> {
+    let mut names: Vec<(&Section, (usize, u8))> = Vec::new();
+    for section in files.iter().flat_map(Sections::iter) {
+        if names.iter().all(|(named, _)| named.name != section.name) {
+            let rank = match section.name.as_str() {
+                ".text" | ".data" => 0,
+                _ if section.nobits => 2,
+                _ => 1,
+            };
+            names.push((section, (map.region_for(section)?, rank)));
+        }
+    }
+    names.sort_by_key(|&(_, order)| order); // stable, so otherwise in order of first use
+    let names: Vec<(String, usize)> = names.into_iter().map(|(section, (region, _))| (section.name.clone(), region)).collect();
+
+    let mut addresses: Vec<u64> = map.regions.iter().map(|region| region.origin as u64).collect();
+    // The permissions of the last section with contents in each region
+    let mut permissions: Vec<Option<Permissions>> = vec![None; map.regions.len()];
+    let mut program = Vec::new();
+    for (name, region) in names {
+        let mut merged: Option<Section> = None;
+        for section in files.iter_mut().filter_map(|sections| sections.get_mut(&name)) {
+            let new_page = merged.is_none() && permissions[region].is_some_and(|previous| previous != section.permissions);
+            let alignment = if new_page { section.alignment.max(PAGE_SIZE) } else { section.alignment };
+            let start = addresses[region].next_multiple_of(alignment as u64);
+            addresses[region] = start + section.size as u64;
+            if addresses[region] > map.regions[region].end() {
+                let region = &map.regions[region];
+                return Err(format!("{} exceeds the {} region '{}'", name, format_size(region.length), region.name));
+            }
+            section.base = start as u32;
+            let merged = merged.get_or_insert_with(|| Section { size: 0, ..section.clone() });
+            merged.alignment = merged.alignment.max(section.alignment);
+            merged.size = u32::try_from(section.end() - merged.base as u64)
+                .map_err(|_| format!("{} overflows the address space", name))?;
+        }
+        if let Some(merged) = merged.as_ref().filter(|merged| merged.size > 0) {
+            permissions[region] = Some(merged.permissions);
+        }
+        program.extend(merged);
+    }
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section_directives() {
+        let mut sections = Sections::default();
+        let mut stack = SectionStack::default();
+        let name = |name: &str| Operand::Label(name.to_string());
+
+        assert!(stack.switch(".rodata", &[], &mut sections).unwrap());
+        stack.switch(".pushsection", &[name(".bss.buffers")], &mut sections).unwrap();
+        assert_eq!(stack.current(), ".bss.buffers");
+        stack.switch(".popsection", &[], &mut sections).unwrap();
+        assert_eq!(stack.current(), ".rodata");
+        stack.switch(".previous", &[], &mut sections).unwrap();
+        assert_eq!(stack.current(), ".text");
+        assert!(!stack.switch(".word", &[], &mut sections).unwrap());
+
+        let flags = [name("vectors"), Operand::StringLiteral("ax".to_string())];
+        stack.switch(".section", &flags, &mut sections).unwrap();
+        let nobits = [name(".noinit"), Operand::StringLiteral("aw".to_string()), Operand::Attribute("nobits".to_string())];
+        stack.switch(".section", &nobits, &mut sections).unwrap();
+
+        let section = |name: &str| sections.get(name).map(|section| (section.permissions, section.nobits));
+        assert_eq!(section(".rodata"), Some((Permissions::R, false)));
+        assert_eq!(section(".bss.buffers"), Some((Permissions::RW, true)));
+        assert_eq!(section("vectors"), Some((Permissions::RX, false)));
+        assert_eq!(section(".noinit"), Some((Permissions::RW, true)));
+
+        assert!(stack.switch(".popsection", &[], &mut sections).is_err());
+        assert!(stack.switch(".section", &[name(".x"), Operand::StringLiteral("awM".to_string())], &mut sections).is_err());
+    }
+
+    #[test]
+    fn test_place_sections() {
+        let mut a = Sections::default();
+        a.declare(".bss", None, None).unwrap().size = 16;
+        a.declare(".rodata", None, None).unwrap().size = 3;
+        a.get_mut(".text").unwrap().size = 8;
+        a.get_mut(".data").unwrap().size = 1;
+        let mut b = Sections::default();
+        b.get_mut(".text").unwrap().size = 4;
+        b.get_mut(".data").unwrap().alignment = 4;
+        b.get_mut(".data").unwrap().size = 4;
+        let mut files = [a, b];
+
+        let program = place(&mut files, &MemoryMap::with_bases(0x1000, 0x8000)).unwrap();
+        let layout: Vec<(&str, u32, u32)> = program.iter().map(|s| (s.name.as_str(), s.base, s.size)).collect();
+        // .rodata is not executable, so it starts on the page after .text
+        assert_eq!(layout, [(".text", 0x1000, 12), (".rodata", 0x2000, 3), (".data", 0x8000, 8), (".bss", 0x8008, 16)]);
+        // the second file's .data is aligned after the first's
+        assert_eq!(files[1].get(".data").unwrap().base, 0x8004);
+
+        let error = place(&mut files, &MemoryMap::with_bases(0x1000, 0x2000)).unwrap_err();
+        assert_eq!(error, ".rodata exceeds the 4 KiB region 'text'");
+
+        // a section may end at the top of the address space
+        let mut top = [Sections::default()];
+        top[0].get_mut(".text").unwrap().size = 0x1000;
+        let program = place(&mut top, &MemoryMap::with_bases(0xffff_f000, 0x8000)).unwrap();
+        assert_eq!((program[0].base, program[0].size, program[0].end()), (0xffff_f000, 0x1000, 1 << 32));
+    }
+}
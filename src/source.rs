@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+
+/// Index of a file in `SourceFiles`. Tokens, statements and errors carry
+/// one, so that a line number can be traced to its file.
+pub type FileId = usize;
+
+struct SourceFile {
+    name: String,
+    path: Option<PathBuf>, // None for text that is not on disk, like an unsaved buffer
+    text: String,
+}
+
+/// The source files of one assembly: the files given to it, such as the
+/// editor buffer or the files on the command line, and every file they
+/// `.include`.
+#[derive(Default)]
+pub struct SourceFiles {
+    files: Vec<SourceFile>,
+    include_paths: Vec<PathBuf>,
+}
+
+impl SourceFiles {
+    /// `include_paths` are the directories searched for included files that
+    /// are not next to the file including them.
+    pub fn new(include_paths: Vec<PathBuf>) -> Self {
+        Self { files: Vec::new(), include_paths }
+    }
+
+    /// Adds text that is not read from disk. With a `path`, errors name the
+    /// file and the files it includes are looked up next to it.
+    pub fn add(&mut self, text: &str, path: Option<&Path>) -> FileId {
+        let name = path.map_or_else(String::new, |path| path.display().to_string());
+        self.files.push(SourceFile { name, path: path.map(Path::to_path_buf), text: text.to_string() });
+        self.files.len() - 1
+    }
+
+    pub fn read(&mut self, path: &Path) -> Result<FileId, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        Ok(self.add(&text, Some(path)))
+    }
+
+    /// Finds the file named by an `.include` in `from`: next to `from` (or in
+    /// the current directory), then in each include path. A file included
+    /// again keeps its first id.
+    pub fn include(&mut self, name: &str, from: FileId) -> Result<FileId, String> {
+        let directory = self.files[from].path.as_deref()
+            .and_then(Path::parent)
+            .unwrap_or(Path::new(""));
+        let path = std::iter::once(directory)
+            .chain(self.include_paths.iter().map(PathBuf::as_path))
+            .map(|directory| directory.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| format!("Cannot find included file '{}'", name))?;
+
+        let canonical = path.canonicalize().ok();
+        let known = self.files.iter().position(|file| {
+            file.path.as_ref().is_some_and(|known| known.canonicalize().ok() == canonical)
+        });
+        match known {
+            Some(file) => Ok(file),
+            None => self.read(&path),
+        }
+    }
+
+    pub fn name(&self, file: FileId) -> &str {
+        &self.files[file].name
+    }
+
+    pub fn text(&self, file: FileId) -> &str {
+        &self.files[file].text
+    }
+
+    /// Where a line is, for messages: "prog.s:3", or "line 3" in text that
+    /// has no file name.
+    pub fn location(&self, file: FileId, line: usize) -> String {
+        match self.files.get(file) {
+            Some(source) if !source.name.is_empty() => format!("{}:{}", source.name, line),
+            _ => format!("line {}", line),
+        }
+    }
+
+    /// An error message prefixed with its location, like "prog.s:3: message"
+    /// or "Line 3: message".
+    pub fn error(&self, file: FileId, line: usize, message: impl std::fmt::Display) -> String {
+        match self.files.get(file) {
+            Some(source) if !source.name.is_empty() => format!("{}:{}: {}", source.name, line, message),
+            _ => format!("Line {}: {}", line, message),
+        }
+    }
+}
@@ -1,81 +1,454 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use crate::diagnostic::{Diagnostic, Stage};
+use crate::lexer::ModifierKind;
 use crate::parser::{Statement, StatementKind, Operand};
+use crate::memory_map::MemoryMap;
+use crate::program::{ProgramSymbol, SymbolBinding, SymbolType};
+use crate::section::{self, SectionStack, Sections};
+
+/// Directives that only define or describe symbols and emit no bytes.
+pub const SYMBOL_DIRECTIVES: [&str; 7] = [".equ", ".set", ".globl", ".global", ".local", ".type", ".size"];
+
+/// What `.globl`, `.local`, `.type` and `.size` say about a symbol. They may
+/// come before or after the symbol is defined.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SymbolAttributes {
+    pub binding: SymbolBinding,
+    pub symbol_type: SymbolType,
+    pub size: u32,
+}
+
+/// Gives each numeric local label definition (`1:`) a name of its own, and
+/// points each `1b` at the nearest definition of `1` before it and each `1f`
+/// at the nearest one after it. A reference with no such definition is left
+/// as it is, to be reported as an unknown label.
+pub fn number_local_labels(statements: &mut [Statement]) {
+    let mut total: HashMap<String, usize> = HashMap::new();
+    for statement in statements.iter() {
+        if let StatementKind::Label(name) = &statement.kind && is_local_label(name) {
+            *total.entry(name.clone()).or_default() += 1;
+        }
+    }
+    if total.is_empty() {
+        return;
+    }
+
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for statement in statements {
+        match &mut statement.kind {
+            StatementKind::Label(name) if is_local_label(name) => {
+                let count = seen.entry(name.clone()).or_default();
+                *count += 1;
+                *name = local_label_name(name, *count);
+            }
+            StatementKind::Label(_) => {}
+            StatementKind::Instruction(_, operands) | StatementKind::Directive(_, operands) => {
+                for symbol in operands.iter_mut().flat_map(Operand::symbols_mut) {
+                    let Some(number) = symbol.strip_suffix(['b', 'f']).filter(|number| is_local_label(number)) else {
+                        continue;
+                    };
+                    let count = seen.get(number).copied().unwrap_or(0);
+                    let target = match symbol.ends_with('b') {
+                        true if count > 0 => count,
+                        false if count < total.get(number).copied().unwrap_or(0) => count + 1,
+                        _ => continue,
+                    };
+                    *symbol = local_label_name(number, target);
+                }
+            }
+        }
+    }
+}
+
+fn is_local_label(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit())
+}
+
+// The n-th definition of a numeric label. Names starting with '.' cannot be
+// written in source, so they never clash with the program's own labels.
+fn local_label_name(number: &str, n: usize) -> String {
+    format!("{}{}.{}", LOCAL_LABEL_PREFIX, number, n)
+}
+
+const LOCAL_LABEL_PREFIX: &str = ".L";
+
+/// Gives each definition of a constant that `.set` or `.equ` assign more than
+/// once a name of its own, and points each use at the definition in effect
+/// there, so that every use sees the value the constant had at that point.
+/// The last definition keeps the name, as the final value of the symbol. A
+/// use before the first definition refers to the first.
+pub fn number_constants(statements: &mut [Statement]) {
+    let mut total: HashMap<String, usize> = HashMap::new();
+    for statement in statements.iter() {
+        if let StatementKind::Directive(name, operands) = &statement.kind
+            && (name == ".equ" || name == ".set")
+            && let Some(Operand::Label(symbol)) = operands.first()
+        {
+            *total.entry(symbol.clone()).or_default() += 1;
+        }
+    }
+    total.retain(|_, count| *count > 1);
+    if total.is_empty() {
+        return;
+    }
+
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for statement in statements {
+        let (name, operands) = match &mut statement.kind {
+            StatementKind::Instruction(name, operands) | StatementKind::Directive(name, operands) => (name.as_str(), operands),
+            StatementKind::Label(_) => continue,
+        };
+        // .globl, .type and .size describe the symbol itself, which has its final value
+        if matches!(name, ".globl" | ".global" | ".local" | ".type" | ".size") {
+            continue;
+        }
+        let definition = matches!(name, ".equ" | ".set");
+        // the value of a definition is the one before it, as in '.set i, i+1'
+        let values = operands.iter_mut().skip(definition as usize);
+        for symbol in values.flat_map(Operand::symbols_mut) {
+            if let Some(&count) = total.get(symbol.as_str()) {
+                let n = seen.get(symbol.as_str()).copied().unwrap_or(0).max(1);
+                *symbol = constant_name(symbol, n, count);
+            }
+        }
+        if definition
+            && let Some(Operand::Label(symbol)) = operands.first_mut()
+            && let Some(&count) = total.get(symbol.as_str())
+        {
+            let n = seen.entry(symbol.clone()).or_default();
+            *n += 1;
+            *symbol = constant_name(symbol, *n, count);
+        }
+    }
+}
+
+// The n-th of the count definitions of a constant
+fn constant_name(name: &str, n: usize, count: usize) -> String {
+    match n == count {
+        true => name.to_string(),
+        false => format!("{}{}.set.{}", LOCAL_LABEL_PREFIX, name, n),
+    }
+}
+
+/// The statements as the source spells them, for listings: the names that
+/// `number_local_labels` and `number_constants` give are spelled `1`, `1b`,
+/// `1f` or the constant's name again, and a `%pcrel_lo` of the label a
+/// pseudo-instruction puts on its auipc names what the auipc refers to.
+pub fn source_spelling(statements: &[Statement]) -> Vec<Statement> {
+    let defined: HashMap<&str, usize> = statements.iter().enumerate()
+        .filter_map(|(index, statement)| match &statement.kind {
+            StatementKind::Label(name) => Some((name.as_str(), index)),
+            _ => None,
+        })
+        .collect();
+    let mut auipcs = HashMap::new();
+    for (index, pair) in statements.windows(2).enumerate() {
+        if let [label, auipc] = pair
+            && let StatementKind::Label(name) = &label.kind
+            && name.starts_with(".Lpcrel_hi")
+            && let StatementKind::Instruction(_, operands) = &auipc.kind
+            && let Some(target) = operands.get(1).and_then(|operand| operand.symbols().first().copied())
+        {
+            auipcs.insert(name.as_str(), spelling(target, index + 1, &defined));
+        }
+    }
+
+    let mut spelled = statements.to_vec();
+    for (index, statement) in spelled.iter_mut().enumerate() {
+        let symbols = match &mut statement.kind {
+            StatementKind::Label(name) => vec![name],
+            StatementKind::Instruction(_, operands) | StatementKind::Directive(_, operands) => {
+                operands.iter_mut().flat_map(Operand::symbols_mut).collect()
+            }
+        };
+        for symbol in symbols {
+            *symbol = match auipcs.get(symbol.as_str()) {
+                Some(target) => target.clone(),
+                None => spelling(symbol, index, &defined),
+            };
+        }
+    }
+    spelled
+}
+
+// How the source spells a name used by the statement at `index`
+fn spelling(name: &str, index: usize, defined: &HashMap<&str, usize>) -> String {
+    let Some(rest) = name.strip_prefix(LOCAL_LABEL_PREFIX) else {
+        return name.to_string();
+    };
+    if let Some((number, n)) = rest.split_once('.') && is_local_label(number) && is_local_label(n) {
+        return match defined.get(name) {
+            Some(&at) if at == index => number.to_string(),
+            Some(&at) if at < index => format!("{}b", number),
+            _ => format!("{}f", number),
+        };
+    }
+    match rest.rsplit_once(".set.") {
+        Some((constant, _)) => constant.to_string(),
+        None => name.to_string(),
+    }
+}
+
+// Where the pass over a file is. Constants that use symbols defined further
+// down, and sizes, are resolved after it.
+#[derive(Default)]
+struct Pass<'a> {
+    stack: SectionStack,
+    offsets: HashMap<String, u32>,
+    pending_constants: Vec<(&'a Statement, &'a String, &'a Operand)>,
+    sizes: Vec<(&'a Statement, &'a String, &'a Operand)>,
+}
 
 pub struct SymbolTable {
     symbols: HashMap<String, u32>,
-    text_base: u32,
-    data_base: u32,
+    absolute: HashSet<String>, // defined with .equ/.set rather than as a label
+    label_sections: HashMap<String, String>, // the section each label is in
+    attributes: HashMap<String, SymbolAttributes>,
+    externals: HashMap<String, u32>, // global symbols of the other files being linked
+    got: Vec<(String, u32)>, // symbols used with %got_pcrel_hi, and the address of their entry
+    sections: Sections,
+    regions: Option<MemoryMap>, // where build places the sections itself, if they were not placed
 }
 
 impl SymbolTable {
+    /// A table that places the sections of the file itself when built: `.text`
+    /// and read-only sections from `text_base`, writable ones from `data_base`.
     pub fn new(text_base: u32, data_base: u32) -> Self {
+        Self {
+            regions: Some(MemoryMap::with_bases(text_base, data_base)),
+            ..Self::with_sections(Sections::new(text_base, data_base))
+        }
+    }
+
+    /// A table for a file whose sections were already placed, as the linker
+    /// does. Sections not among them are placed at 0.
+    pub fn with_sections(sections: Sections) -> Self {
         Self {
             symbols: HashMap::new(),
-            text_base,
-            data_base,
+            absolute: HashSet::new(),
+            label_sections: HashMap::new(),
+            attributes: HashMap::new(),
+            externals: HashMap::new(),
+            got: Vec::new(),
+            sections,
+            regions: None,
         }
     }
 
-    pub fn build(&mut self, statements: &[Statement]) -> Result<(), String> {
-        let mut text_offset: u32 = 0;
-        let mut data_offset: u32 = 0;
+    /// Symbols defined in other files, used for names this file does not define.
+    pub fn set_externals(&mut self, externals: HashMap<String, u32>) {
+        self.externals = externals;
+    }
 
-        let mut current_section = ".text";
+    /// Gives every label and constant its address or value, and measures the
+    /// sections. A statement with an error is left out and the others are
+    /// still followed, so that every error is returned.
+    pub fn build(&mut self, statements: &[Statement]) -> Result<(), Vec<Diagnostic>> {
+        let file = statements.first().map_or(0, |stmt| stmt.file);
+        // The sizes of the sections are measured with all of them at 0, which
+        // is aligned for any '.align', and then they are placed for real
+        if let Some(map) = &self.regions {
+            let mut measure = SymbolTable::with_sections(Sections::default());
+            measure.set_externals(self.externals.clone());
+            measure.build(statements)?;
+            section::place(std::slice::from_mut(&mut measure.sections), map)
+                .map_err(|message| vec![Diagnostic::error(Stage::Symbols, file, 0, message)])?;
+            self.sections = measure.sections;
+        }
 
+        let mut pass = Pass::default();
+        let mut errors = Vec::new();
         for stmt in statements {
-            match &stmt.kind {
-                StatementKind::Directive(name, _) if name == ".text" || name == ".data" => {
-                    current_section = name.as_str();
+            if let Err(message) = self.build_statement(stmt, &mut pass) {
+                errors.push(Diagnostic::error(Stage::Symbols, stmt.file, stmt.line, message));
+            }
+        }
+        let Pass { mut offsets, mut pending_constants, sizes, .. } = pass;
+
+        // Each round must define at least one constant, or the rest refer to something undefined
+        while !pending_constants.is_empty() {
+            let count = pending_constants.len();
+            let mut unresolved = Vec::new();
+            let mut failed = Vec::new();
+            for (stmt, symbol, value) in pending_constants {
+                match self.evaluate(value) {
+                    Ok(value) => if let Err(message) = self.add_constant(symbol, value) {
+                        errors.push(Diagnostic::error(Stage::Symbols, stmt.file, stmt.line, message));
+                    },
+                    Err(e) => {
+                        failed.push(Diagnostic::error(Stage::Symbols, stmt.file, stmt.line, format!("Cannot resolve '{}': {}", symbol, e)));
+                        unresolved.push((stmt, symbol, value));
+                    }
                 }
+            }
+            if unresolved.len() == count {
+                errors.extend(failed);
+                break;
+            }
+            pending_constants = unresolved;
+        }
 
-                StatementKind::Label(name) => {
-                    let address = if current_section == ".text" {
-                        self.text_base + text_offset
-                    } else {
-                        self.data_base + data_offset
-                    };
+        for (stmt, symbol, size) in sizes {
+            let size = match self.symbols.contains_key(symbol) {
+                true => self.evaluate(size),
+                false => Err(format!("Directive .size refers to undefined symbol '{}'", symbol)),
+            };
+            match size {
+                Ok(size) => self.attributes.entry(symbol.clone()).or_default().size = size as u32,
+                Err(message) => errors.push(Diagnostic::error(Stage::Symbols, stmt.file, stmt.line, message)),
+            }
+        }
 
-                    self.add_label(name.clone(), address)?;
+        // The global offset table has a section of its own, one word per symbol
+        if !self.got.is_empty() {
+            match self.sections.declare(".got", None, None) {
+                Ok(got) => {
+                    got.alignment = 4;
+                    for (index, (_, address)) in self.got.iter_mut().enumerate() {
+                        *address = got.base + 4 * index as u32;
+                    }
+                    offsets.insert(".got".to_string(), 4 * self.got.len() as u32);
                 }
+                Err(message) => errors.push(Diagnostic::error(Stage::Symbols, file, 0, message)),
+            }
+        }
+        for (name, size) in offsets {
+            self.sections.get_mut(&name).expect("sections are declared when entered").size = size;
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(())
+    }
 
-                StatementKind::Instruction(_, _) => {
-                    if current_section == ".text" {
-                        text_offset += 4;
-                    } else {
-                        // Error it there is instructions in the data section
-                        return Err("Error: Instruction found on .data section".to_string());
-                    }
+    // Follows one statement of the pass over the file
+    fn build_statement<'a>(&mut self, stmt: &'a Statement, pass: &mut Pass<'a>) -> Result<(), String> {
+        let stack = &mut pass.stack;
+        let section = self.sections.get(stack.current()).expect("sections are declared when entered");
+        let (section_base, nobits, executable) = (section.base, section.nobits, section.permissions.execute);
+        let offset = pass.offsets.entry(stack.current().to_string()).or_default();
+        match &stmt.kind {
+            StatementKind::Directive(name, operands) if stack.switch(name, operands, &mut self.sections)? => {}
+
+            StatementKind::Directive(name, operands) if name == ".equ" || name == ".set" => {
+                let [Operand::Label(symbol), value] = operands.as_slice() else {
+                    return Err(format!("Directive {} requires a symbol name and a value", name));
+                };
+                match self.evaluate(value) {
+                    Ok(value) => self.add_constant(symbol, value)?,
+                    Err(_) => pass.pending_constants.push((stmt, symbol, value)),
                 }
+            }
 
-                StatementKind::Directive(name, operands) => {
-                    let current_pc = if current_section == ".text" {
-                        self.text_base + text_offset
-                    } else {
-                        self.data_base + data_offset
+            StatementKind::Directive(name, operands) if matches!(name.as_str(), ".globl" | ".global" | ".local") => {
+                let binding = if name == ".local" { SymbolBinding::Local } else { SymbolBinding::Global };
+                for operand in operands {
+                    let Operand::Label(symbol) = operand else {
+                        return Err(format!("Directive {} requires symbol names, got {}", name, operand));
                     };
+                    self.attributes.entry(symbol.clone()).or_default().binding = binding;
+                }
+            }
+
+            StatementKind::Directive(name, operands) if name == ".type" => {
+                let symbol_type = match operands.as_slice() {
+                    [Operand::Label(_), Operand::Attribute(kind) | Operand::StringLiteral(kind)] => match kind.as_str() {
+                        "function" => SymbolType::Function,
+                        "object" => SymbolType::Object,
+                        "notype" => SymbolType::NoType,
+                        other => return Err(format!("Unsupported symbol type '{}'", other)),
+                    },
+                    _ => return Err("Directive .type requires a symbol name and a type (@function, @object or @notype)".to_string()),
+                };
+                let Operand::Label(symbol) = &operands[0] else { unreachable!() };
+                self.attributes.entry(symbol.clone()).or_default().symbol_type = symbol_type;
+            }
 
-                    let size = self.calculate_directive_size(name, operands, current_pc)?;
+            StatementKind::Directive(name, operands) if name == ".size" => {
+                let [Operand::Label(symbol), size] = operands.as_slice() else {
+                    return Err("Directive .size requires a symbol name and a size".to_string());
+                };
+                pass.sizes.push((stmt, symbol, size));
+            }
 
-                    if current_section == ".text" {
-                        text_offset += size;
-                    } else {
-                        data_offset += size;
+            StatementKind::Label(name) => {
+                let address = section_base.checked_add(*offset)
+                    .ok_or_else(|| format!("Label '{}' overflows the address space", name))?;
+                self.add_label(name.clone(), address)?;
+                self.label_sections.insert(name.clone(), stack.current().to_string());
+            }
+
+            StatementKind::Instruction(_, operands) => {
+                for operand in operands {
+                    if let Operand::Modifier(ModifierKind::GotPcrelHi, symbol) = operand
+                        && !self.got.iter().any(|(name, _)| name == symbol)
+                    {
+                        self.got.push((symbol.clone(), 0));
                     }
                 }
+                if executable {
+                    *offset += 4;
+                } else {
+                    // Error it there is instructions in a data section
+                    return Err(format!("Error: Instruction found on {} section", stack.current()));
+                }
+            }
+
+            StatementKind::Directive(name, operands) => {
+                if nobits && !matches!(name.as_str(), ".space" | ".align") {
+                    return Err(format!("Directive {} cannot be used in {}, which has no contents; use .space", name, stack.current()));
+                }
+                // 2^32 for a section that ends at the top aligns as 0 does
+                let current_pc = section_base.wrapping_add(*offset);
+                let size = self.calculate_directive_size(name, operands, current_pc)?;
+                if name == ".align" && let Some(pow) = self.size_operand(operands.first())? {
+                    // The section must start aligned for the alignment within it to hold
+                    let section = self.sections.get_mut(stack.current()).expect("sections are declared when entered");
+                    section.alignment = section.alignment.max(section::alignment(pow)?);
+                }
+                *offset = offset.checked_add(size)
+                    .ok_or_else(|| format!("Section {} overflows the address space", stack.current()))?;
             }
         }
         Ok(())
     }
 
+    // The value of a number, symbol or expression operand, using the symbols defined so far
+    fn evaluate(&self, operand: &Operand) -> Result<i32, String> {
+        match operand {
+            Operand::Immediate(n) => Ok(*n),
+            Operand::Label(name) => self.get_address(name)
+                .map(|address| address as i32)
+                .ok_or_else(|| format!("Unknown label '{}'", name)),
+            Operand::Modifier(kind, _) if kind.is_pc_relative() => Err(format!("{} is relative to an instruction and has no constant value", operand)),
+            Operand::Modifier(kind, name) => self.get_address(name)
+                .map(|address| kind.apply(address as i32))
+                .ok_or_else(|| format!("Unknown label '{}'", name)),
+            Operand::Expression(expr) => expr.evaluate(&|name| self.get_address(name)),
+            _ => Err(format!("Expected a number or an expression, got {}", operand)),
+        }
+    }
+
+    // A constant may be assigned again, as '.set' does, but a label may not be redefined
+    fn add_constant(&mut self, name: &str, value: i32) -> Result<(), String> {
+        if self.absolute.contains(name) {
+            self.symbols.insert(name.to_string(), value as u32);
+            return Ok(());
+        }
+        self.add_label(name.to_string(), value as u32)?;
+        self.absolute.insert(name.to_string());
+        Ok(())
+    }
+
 
     // Size in bytes that the directive will occupy in memory
     fn calculate_directive_size(&self, name: &str, operands: &[Operand], current_pc: u32) -> Result<u32, String> {
         match name {
             ".align" => {
-                if let Some(Operand::Immediate(pow)) = operands.get(0) {
-                    let alignment = 2u32.pow(*pow as u32);
-                    let aligned_pc = (current_pc + alignment - 1) & !(alignment - 1);
-                    Ok(aligned_pc - current_pc)
+                if let Some(pow) = self.size_operand(operands.first())? {
+                    let alignment = section::alignment(pow)? as u64;
+                    Ok(((current_pc as u64).next_multiple_of(alignment) - current_pc as u64) as u32)
                 } else {
                     Err("Directive .align requieres a power of 2 parameter".into())
                 }
@@ -97,28 +470,94 @@ impl SymbolTable {
                 }
                 Ok(total)
             },
-            // TODO review
             ".space" => {
-                if let Some(Operand::Immediate(n)) = operands.get(0) {
-                    Ok(*n as u32)
+                if let Some(n) = self.size_operand(operands.first())? {
+                    Ok(n as u32)
                 } else {
                     Err("Directive .space requires an inmediate value".into())
                 }
             },
-            _ => Ok(0),
+            _ => Err(format!("Unknown directive '{}'", name)),
+        }
+    }
+
+    // Sizes are needed before later labels get an address, so an expression
+    // can only use the labels defined above it
+    fn size_operand(&self, operand: Option<&Operand>) -> Result<Option<i32>, String> {
+        match operand {
+            Some(op @ (Operand::Immediate(_) | Operand::Label(_) | Operand::Modifier(..) | Operand::Expression(_))) => self.evaluate(op)
+                .map(Some)
+                .map_err(|e| format!("{} (only symbols defined above can be used in a size)", e)),
+            _ => Ok(None),
         }
     }
 
     pub fn get_address(&self, label: &str) -> Option<u32> {
-        self.symbols.get(label).cloned()
+        self.symbols.get(label).or_else(|| self.externals.get(label)).cloned()
+    }
+
+    /// The address of the GOT entry holding the address of `symbol`.
+    pub fn got_address(&self, symbol: &str) -> Option<u32> {
+        self.got.iter().find(|(name, _)| name == symbol).map(|&(_, address)| address)
+    }
+
+    /// The symbols in the global offset table, in the order of their entries.
+    pub fn got_entries(&self) -> &[(String, u32)] {
+        &self.got
+    }
+
+    /// The sections of the file, with their addresses and, once built, their sizes.
+    pub fn sections(&self) -> &Sections {
+        &self.sections
+    }
+
+    /// All symbols defined in this file and their addresses, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.symbols.iter().map(|(name, &address)| (name.as_str(), address))
+    }
+
+    /// Whether the symbol is a `.equ`/`.set` constant rather than a label.
+    pub fn is_absolute(&self, name: &str) -> bool {
+        self.absolute.contains(name)
+    }
+
+    /// The section a label is in; none for constants and external symbols.
+    pub fn section_of(&self, name: &str) -> Option<&str> {
+        self.label_sections.get(name).map(String::as_str)
+    }
+
+    /// The binding, type and size given to a symbol; the defaults if none were.
+    pub fn attributes(&self, name: &str) -> SymbolAttributes {
+        self.attributes.get(name).copied().unwrap_or_default()
+    }
+
+    /// The defined symbols with their metadata, ordered by address.
+    pub fn program_symbols(&self) -> Vec<ProgramSymbol> {
+        let mut symbols: Vec<ProgramSymbol> = self.iter()
+            .filter(|(name, _)| !name.starts_with(LOCAL_LABEL_PREFIX))
+            .map(|(name, address)| {
+                let attributes = self.attributes(name);
+                ProgramSymbol {
+                    name: name.to_string(),
+                    address,
+                    size: attributes.size,
+                    binding: attributes.binding,
+                    symbol_type: attributes.symbol_type,
+                    absolute: self.is_absolute(name),
+                }
+            })
+            .collect();
+        symbols.sort_by(|a, b| a.address.cmp(&b.address).then_with(|| a.name.cmp(&b.name)));
+        symbols
     }
 
     pub fn add_label(&mut self, label: String, address: u32) -> Result<(), String> {
-        if self.symbols.contains_key(&label) {
-            Err(format!("Error: Duplicated label '{}'", label))
-        } else {
-            self.symbols.insert(label, address);
-            Ok(())
+        match self.symbols.entry(label) {
+            Entry::Occupied(entry) => Err(format!("Error: Duplicated label '{}'", entry.key())),
+            Entry::Vacant(entry) => {
+                entry.insert(address);
+                Ok(())
+            }
         }
     }
 }
@@ -177,7 +616,12 @@ mod tests {
         let mut sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         sym_table.build(&statements).unwrap();
 
-        assert_eq!(sym_table.get_address("my_aligned_label"), Some(config::DATA_BASE + 0x10)) // 3 for "Hi" + 1 for \0, then aligned to 4 bytes
+        assert_eq!(sym_table.get_address("my_aligned_label"), Some(config::DATA_BASE + 0x10)); // 3 for "Hi" + 1 for \0, then aligned to 4 bytes
+
+        for power in ["-1", "32", "40"] {
+            let errors = build(&format!(".data\n.byte 1\n.align {}\n", power)).err().unwrap();
+            assert_eq!(errors[0].message, "Directive .align value out of range");
+        }
     }
 
     #[test]
@@ -195,4 +639,85 @@ mod tests {
         let mut sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
         assert!(sym_table.build(&statements).is_err());
     }
+
+    fn build(source: &str) -> Result<SymbolTable, Vec<Diagnostic>> {
+        let statements = Parser::new(tokenize(source).unwrap()).parse().unwrap();
+        let mut sym_table = SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
+        sym_table.build(&statements).map(|_| sym_table)
+    }
+
+    #[test]
+    fn test_constants() {
+        let sym_table = build(r#"
+            .equ BUF_SIZE, 16
+            .set WORDS, BUF_SIZE / 4
+            .equ LENGTH, end - buf # defined further down
+            .data
+            buf: .space BUF_SIZE
+            end:
+        "#).unwrap();
+
+        assert_eq!(sym_table.get_address("BUF_SIZE"), Some(16));
+        assert_eq!(sym_table.get_address("WORDS"), Some(4));
+        assert_eq!(sym_table.get_address("LENGTH"), Some(16));
+        assert_eq!(sym_table.get_address("end"), Some(config::DATA_BASE + 16));
+        assert!(sym_table.is_absolute("LENGTH"));
+        assert!(!sym_table.is_absolute("buf"));
+
+        let errors = build(".equ A, B + 1\n.equ B, A").err().unwrap();
+        assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<_>>(), [1, 2]);
+        assert!(errors[0].message.contains("Cannot resolve 'A'"), "{:?}", errors);
+        assert!(build(".equ A, 1\nA: nop").is_err());
+
+        // a constant may be assigned again, but not a label
+        assert_eq!(build(".set A, 1\n.set A, A + 1\n").ok().unwrap().get_address("A"), Some(2));
+        let errors = build("A: nop\n.set A, 1\n").err().unwrap();
+        assert_eq!(errors[0].message, "Error: Duplicated label 'A'");
+    }
+
+    #[test]
+    fn test_symbol_attributes() {
+        let sym_table = build(r#"
+            .globl main
+            .type main, @function
+            main:
+                nop
+                nop
+            main_end:
+            .size main, main_end - main
+            .data
+            table: .word 1, 2
+            .type table, "object"
+        "#).unwrap();
+        assert_eq!(sym_table.attributes("main"), SymbolAttributes {
+            binding: SymbolBinding::Global,
+            symbol_type: SymbolType::Function,
+            size: 8,
+        });
+        assert_eq!(sym_table.attributes("table").symbol_type, SymbolType::Object);
+        assert_eq!(sym_table.attributes("table").binding, SymbolBinding::Local);
+
+        let symbols = sym_table.program_symbols();
+        assert_eq!(symbols[0].name, "main");
+        assert_eq!(symbols[0].binding, SymbolBinding::Global);
+        assert_eq!(symbols[0].size, 8);
+    }
+
+    #[test]
+    fn test_unknown_directive() {
+        assert_eq!(build(".data\n.quad 1").err(), Some(vec![Diagnostic::error(Stage::Symbols, 0, 2, "Unknown directive '.quad'")]));
+        assert!(build(".type main, @ifunc\nmain:").is_err());
+    }
+
+    #[test]
+    fn test_errors_on_every_line() {
+        let errors = build(".data\nnop\nx: .word 1\n.quad 1\nx: .byte 2\n.size y, 4\n").err().unwrap();
+        let lines: Vec<(usize, &str)> = errors.iter().map(|e| (e.line, e.message.as_str())).collect();
+        assert_eq!(lines, [
+            (2, "Error: Instruction found on .data section"),
+            (4, "Unknown directive '.quad'"),
+            (5, "Error: Duplicated label 'x'"),
+            (6, "Directive .size refers to undefined symbol 'y'"),
+        ]);
+    }
 }
@@ -0,0 +1,961 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::processor::{Processor, StepError};
+
+// ABI register indexes used by the calling convention of ECALL
+const A0: usize = 10;
+const A1: usize = 11;
+const A2: usize = 12;
+const A7: usize = 17;
+
+/// Services an ECALL. The handler reads its arguments from the processor
+/// registers and memory and writes the results back before the PC advances.
+pub trait SyscallHandler {
+    fn handle(&mut self, processor: &mut Processor) -> Result<(), StepError>;
+}
+
+/// Where a program's standard input and output go.
+pub trait Console {
+    fn write(&mut self, bytes: &[u8]);
+
+    /// Returns the next line without its terminator, or `None` if no complete line is available.
+    fn read_line(&mut self) -> Option<String>;
+
+    /// Returns the next input byte, or `None` if no input is available.
+    fn read_byte(&mut self) -> Option<u8>;
+
+    /// Whether a `None` from the read methods means end of input rather than
+    /// input that has not arrived yet.
+    fn input_closed(&self) -> bool {
+        false
+    }
+}
+
+/// The process' own stdin/stdout, for running without the TUI.
+pub struct StdConsole;
+
+impl Console for StdConsole {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(bytes);
+        let _ = stdout.flush();
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        match std::io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => None, // EOF
+            Ok(_) => Some(line.trim_end_matches(['\r', '\n']).to_string()),
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0u8];
+        match std::io::stdin().lock().read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    // stdin reads block, so running out of input is always end of file
+    fn input_closed(&self) -> bool {
+        true
+    }
+}
+
+/// The process' stdin/stdout for devices that poll for input, like the UART:
+/// stdin is read on a background thread so reads return `None` instead of
+/// blocking until input arrives.
+#[derive(Default)]
+pub struct PollingStdConsole {
+    input: Option<Receiver<u8>>,
+    line: Vec<u8>,
+    closed: bool,
+}
+
+impl PollingStdConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Console for PollingStdConsole {
+    fn write(&mut self, bytes: &[u8]) {
+        StdConsole.write(bytes);
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        while let Some(byte) = self.read_byte() {
+            if byte == b'\n' {
+                let line = std::mem::take(&mut self.line);
+                return Some(String::from_utf8_lossy(&line).trim_end_matches('\r').to_string());
+            }
+            self.line.push(byte);
+        }
+        // a last line without a terminator
+        if self.closed && !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            return Some(String::from_utf8_lossy(&line).into_owned());
+        }
+        None
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        // stdin is only taken over once the program asks for input
+        let input = self.input.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            std::thread::spawn(move || {
+                for byte in std::io::stdin().lock().bytes() {
+                    match byte {
+                        Ok(byte) if sender.send(byte).is_ok() => {}
+                        _ => break,
+                    }
+                }
+            });
+            receiver
+        });
+        match input.try_recv() {
+            Ok(byte) => Some(byte),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.closed = true;
+                None
+            }
+        }
+    }
+
+    fn input_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+/// In-memory console: input is queued by the caller and output is captured,
+/// used by the TUI console pane and by tests.
+#[derive(Default)]
+pub struct BufferedConsole {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    closed: bool,
+}
+
+impl BufferedConsole {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_input(&mut self, text: &str) {
+        self.input.extend(text.bytes());
+    }
+
+    /// Marks the end of input: once the queue drains, reads report end of file.
+    pub fn close_input(&mut self) {
+        self.closed = true;
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn output_text(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+
+    pub fn clear(&mut self) {
+        self.input.clear();
+        self.output.clear();
+        self.closed = false;
+    }
+}
+
+impl Console for BufferedConsole {
+    fn write(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        let end = self.input.iter().position(|&b| b == b'\n')?;
+        let line: Vec<u8> = self.input.drain(..=end).take(end).collect();
+        Some(String::from_utf8_lossy(&line).trim_end_matches('\r').to_string())
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn input_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+// Lets the owner keep a handle to the console after giving it to the processor
+impl<C: Console + ?Sized> Console for Rc<RefCell<C>> {
+    fn write(&mut self, bytes: &[u8]) {
+        self.borrow_mut().write(bytes);
+    }
+
+    fn read_line(&mut self) -> Option<String> {
+        self.borrow_mut().read_line()
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        self.borrow_mut().read_byte()
+    }
+
+    fn input_closed(&self) -> bool {
+        self.borrow().input_closed()
+    }
+}
+
+/// The RARS system call table: a7 selects the service, arguments in a0/a1,
+/// results returned in a0 (and a1 for 64-bit values).
+pub struct RarsSyscalls {
+    console: Box<dyn Console>,
+}
+
+impl RarsSyscalls {
+    pub const PRINT_INT: u32 = 1;
+    pub const PRINT_STRING: u32 = 4;
+    pub const READ_INT: u32 = 5;
+    pub const READ_STRING: u32 = 8;
+    pub const SBRK: u32 = 9;
+    pub const EXIT: u32 = 10;
+    pub const PRINT_CHAR: u32 = 11;
+    pub const READ_CHAR: u32 = 12;
+    pub const TIME: u32 = 30;
+    pub const SLEEP: u32 = 32;
+    pub const PRINT_INT_HEX: u32 = 34;
+    pub const PRINT_INT_BINARY: u32 = 35;
+    pub const PRINT_INT_UNSIGNED: u32 = 36;
+    pub const EXIT2: u32 = 93;
+
+    pub fn new(console: impl Console + 'static) -> Self {
+        Self { console: Box::new(console) }
+    }
+}
+
+impl SyscallHandler for RarsSyscalls {
+    fn handle(&mut self, p: &mut Processor) -> Result<(), StepError> {
+        let a0 = p.read_register(A0);
+
+        match p.read_register(A7) {
+            Self::PRINT_INT => self.console.write((a0 as i32).to_string().as_bytes()),
+            Self::PRINT_STRING => {
+                let text = read_c_string(p, a0, MAX_PRINT_STRING)?.ok_or_else(|| {
+                    StepError::Syscall(format!("print_string: no null terminator within {} bytes", MAX_PRINT_STRING))
+                })?;
+                self.console.write(&text);
+            },
+            Self::READ_INT => {
+                let line = self.console.read_line().ok_or(StepError::WaitingForInput)?;
+                let value = line.trim().parse::<i32>()
+                    .map_err(|_| StepError::Syscall(format!("read_int: invalid integer '{}'", line.trim())))?;
+                p.write_register(A0, value as u32);
+            },
+            Self::READ_STRING => {
+                // Reads at most a1 - 1 characters and null terminates them, like fgets
+                let max_length = p.read_register(A1) as usize;
+                if max_length == 0 {
+                    return Ok(());
+                }
+                let mut line = self.console.read_line().ok_or(StepError::WaitingForInput)?.into_bytes();
+                line.push(b'\n');
+                line.truncate(max_length - 1);
+                line.push(0);
+                write_bytes(p, a0, &line)?;
+            },
+            Self::SBRK => {
+                if (a0 as i32) < 0 {
+                    return Err(StepError::Syscall(format!("sbrk: negative increment {}", a0 as i32)));
+                }
+                let address = p.sbrk(a0)?;
+                p.write_register(A0, address);
+            },
+            Self::EXIT => return Err(StepError::Exit(0)),
+            Self::PRINT_CHAR => self.console.write(&[a0 as u8]),
+            Self::READ_CHAR => {
+                let byte = self.console.read_byte().ok_or(StepError::WaitingForInput)?;
+                p.write_register(A0, byte as u32);
+            },
+            Self::TIME => {
+                let millis = SystemTime::now().duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or(0);
+                p.write_register(A0, millis as u32);
+                p.write_register(A1, (millis >> 32) as u32);
+            },
+            Self::SLEEP => {}, // the simulator does not model wall-clock time
+            Self::PRINT_INT_HEX => self.console.write(format!("0x{:08x}", a0).as_bytes()),
+            Self::PRINT_INT_BINARY => self.console.write(format!("{:032b}", a0).as_bytes()),
+            Self::PRINT_INT_UNSIGNED => self.console.write(a0.to_string().as_bytes()),
+            Self::EXIT2 => return Err(StepError::Exit(a0 as i32)),
+            service => return Err(StepError::Syscall(format!("Unknown system call {}", service))),
+        }
+        Ok(())
+    }
+}
+
+// errno values returned (negated) in a0 by the Linux ABI
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EFAULT: i32 = 14;
+const EEXIST: i32 = 17;
+const EINVAL: i32 = 22;
+const ENAMETOOLONG: i32 = 36;
+const ENOSYS: i32 = 38;
+
+// openat flags, generic Linux values
+const O_ACCMODE: u32 = 0x3;
+const O_WRONLY: u32 = 0x1;
+const O_RDWR: u32 = 0x2;
+const O_CREAT: u32 = 0x40;
+const O_EXCL: u32 = 0x80;
+const O_TRUNC: u32 = 0x200;
+const O_APPEND: u32 = 0x400;
+const AT_FDCWD: i32 = -100;
+
+// The most copied between guest memory and a file at a time
+const COPY_CHUNK: usize = 4096;
+
+// Like Linux, a single read or write moves at most this much, so the count fits the result
+const MAX_RW_COUNT: usize = 0x7FFF_F000;
+
+// The longest path, including its null terminator
+const PATH_MAX: usize = 4096;
+
+// The longest string print_string copies out of guest memory
+const MAX_PRINT_STRING: usize = 0x10000;
+
+/// Linux user-mode system calls for statically linked RV32 newlib/musl programs:
+/// a7 selects the call, arguments in a0..a5, the result (or `-errno`) in a0.
+///
+/// File descriptors 0-2 are the console. Paths are resolved inside `sandbox`,
+/// which acts as both `/` and the current directory for the program.
+pub struct LinuxSyscalls {
+    console: Box<dyn Console>,
+    sandbox: PathBuf,
+    files: HashMap<u32, File>,
+    started: Instant,
+}
+
+impl LinuxSyscalls {
+    pub const OPENAT: u32 = 56;
+    pub const CLOSE: u32 = 57;
+    pub const LSEEK: u32 = 62;
+    pub const READ: u32 = 63;
+    pub const WRITE: u32 = 64;
+    pub const FSTAT: u32 = 80;
+    pub const EXIT: u32 = 93;
+    pub const EXIT_GROUP: u32 = 94;
+    pub const CLOCK_GETTIME: u32 = 113;
+    pub const UNAME: u32 = 160;
+    pub const BRK: u32 = 214;
+    pub const CLOCK_GETTIME64: u32 = 403;
+
+    pub fn new(console: impl Console + 'static, sandbox: impl Into<PathBuf>) -> Self {
+        Self {
+            console: Box::new(console),
+            sandbox: sandbox.into(),
+            files: HashMap::new(),
+            started: Instant::now(),
+        }
+    }
+
+    // Maps a guest path to a host path, refusing anything that leaves the sandbox
+    fn resolve_path(&self, path: &str) -> Result<PathBuf, i32> {
+        let mut resolved = PathBuf::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(part) => resolved.push(part),
+                Component::ParentDir => {
+                    if !resolved.pop() {
+                        return Err(EACCES);
+                    }
+                }
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+        let host_path = self.sandbox.join(resolved);
+
+        // Symlinks could still point outside, so check the canonical location
+        // of the file (or of its parent directory when it is about to be
+        // created). A dangling symlink cannot be checked, and opening it with
+        // O_CREAT would create its target, so it is refused.
+        let root = self.sandbox.canonicalize().map_err(|e| errno(&e))?;
+        let canonical = match host_path.symlink_metadata() {
+            Ok(_) => host_path.canonicalize().map_err(|_| EACCES)?,
+            Err(_) => host_path.parent().unwrap_or(&self.sandbox).canonicalize().map_err(|e| errno(&e))?,
+        };
+        match canonical.starts_with(&root) {
+            true => Ok(host_path),
+            false => Err(EACCES),
+        }
+    }
+
+    fn openat(&mut self, p: &Processor) -> Result<i32, StepError> {
+        let dirfd = p.read_register(A0) as i32;
+        let path = match read_c_string(p, p.read_register(A1), PATH_MAX) {
+            Ok(Some(bytes)) => String::from_utf8_lossy(&bytes).into_owned(),
+            Ok(None) => return Ok(-ENAMETOOLONG),
+            Err(_) => return Ok(-EFAULT),
+        };
+        let flags = p.read_register(A2);
+
+        // Only paths relative to the working directory (the sandbox root) are supported
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Ok(-EBADF);
+        }
+        let host_path = match self.resolve_path(&path) {
+            Ok(host_path) => host_path,
+            Err(e) => return Ok(-e),
+        };
+
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 && flags & O_EXCL != 0 {
+            options.create_new(true);
+        } else if flags & O_CREAT != 0 {
+            options.create(true);
+        }
+
+        match options.open(host_path) {
+            Ok(file) => {
+                let fd = (3..).find(|fd| !self.files.contains_key(fd)).unwrap();
+                self.files.insert(fd, file);
+                Ok(fd as i32)
+            }
+            Err(e) => Ok(-errno(&e)),
+        }
+    }
+
+    fn read(&mut self, p: &mut Processor) -> Result<i32, StepError> {
+        let fd = p.read_register(A0);
+        let buffer = p.read_register(A1);
+        let count = (p.read_register(A2) as usize).min(MAX_RW_COUNT);
+
+        if fd != 0 {
+            let Some(file) = self.files.get_mut(&fd) else { return Ok(-EBADF) };
+            // The count comes from the program, so the file is read a chunk at a time
+            let mut chunk = [0u8; COPY_CHUNK];
+            let mut total = 0;
+            while total < count {
+                let wanted = (count - total).min(COPY_CHUNK);
+                let n = match file.read(&mut chunk[..wanted]) {
+                    Ok(n) => n,
+                    Err(e) => return Ok(-errno(&e)),
+                };
+                if write_bytes(p, buffer.wrapping_add(total as u32), &chunk[..n]).is_err() {
+                    return Ok(-EFAULT);
+                }
+                total += n;
+                if n < wanted {
+                    break;
+                }
+            }
+            return Ok(total as i32);
+        }
+
+        // Like a terminal, stop at the end of the line
+        let mut bytes = Vec::new();
+        while bytes.len() < count && bytes.last() != Some(&b'\n') {
+            match self.console.read_byte() {
+                Some(byte) => bytes.push(byte),
+                None if bytes.is_empty() && !self.console.input_closed() => return Err(StepError::WaitingForInput),
+                None => break,
+            }
+        }
+        if write_bytes(p, buffer, &bytes).is_err() {
+            return Ok(-EFAULT);
+        }
+        Ok(bytes.len() as i32)
+    }
+
+    fn write(&mut self, p: &Processor) -> Result<i32, StepError> {
+        let fd = p.read_register(A0);
+        let buffer = p.read_register(A1);
+        let count = (p.read_register(A2) as usize).min(MAX_RW_COUNT);
+        if !matches!(fd, 1 | 2) && !self.files.contains_key(&fd) {
+            return Ok(-EBADF);
+        }
+
+        // The count comes from the program, so memory is copied a chunk at a time
+        let mut total = 0;
+        while total < count {
+            let wanted = (count - total).min(COPY_CHUNK);
+            let Ok(chunk) = read_bytes(p, buffer.wrapping_add(total as u32), wanted as u32) else { return Ok(-EFAULT) };
+            match self.files.get_mut(&fd) {
+                Some(file) => {
+                    if let Err(e) = file.write_all(&chunk) {
+                        return Ok(-errno(&e));
+                    }
+                }
+                None => self.console.write(&chunk),
+            }
+            total += wanted;
+        }
+        Ok(total as i32)
+    }
+
+    // newlib's three argument form: lseek(fd, offset, whence) returning the new offset
+    fn lseek(&mut self, p: &Processor) -> i32 {
+        let Some(file) = self.files.get_mut(&p.read_register(A0)) else { return -EBADF };
+        let offset = p.read_register(A1) as i32 as i64;
+        let position = match p.read_register(A2) {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return -EINVAL,
+        };
+        match file.seek(position) {
+            Ok(position) => position as i32,
+            Err(e) => -errno(&e),
+        }
+    }
+
+    // Fills the 128 byte `struct kernel_stat` used by newlib's libgloss
+    fn fstat(&mut self, p: &mut Processor) -> i32 {
+        let fd = p.read_register(A0);
+        let (mode, size, mtime) = match fd {
+            0..=2 => (0o020620, 0, 0), // character device
+            _ => {
+                let Some(file) = self.files.get(&fd) else { return -EBADF };
+                let metadata = match file.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(e) => return -errno(&e),
+                };
+                let file_type = if metadata.is_dir() { 0o040000 } else { 0o100000 };
+                let mtime = metadata.modified().ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |d| d.as_secs());
+                (file_type | 0o644, metadata.len(), mtime)
+            }
+        };
+
+        let mut stat = [0u8; 128];
+        stat[16..20].copy_from_slice(&(mode as u32).to_le_bytes());   // st_mode
+        stat[20..24].copy_from_slice(&1u32.to_le_bytes());            // st_nlink
+        stat[48..56].copy_from_slice(&size.to_le_bytes());            // st_size
+        stat[56..60].copy_from_slice(&4096u32.to_le_bytes());         // st_blksize
+        stat[64..72].copy_from_slice(&size.div_ceil(512).to_le_bytes()); // st_blocks
+        for time_offset in [72, 88, 104] {                            // st_atim, st_mtim, st_ctim
+            stat[time_offset..time_offset + 8].copy_from_slice(&mtime.to_le_bytes());
+        }
+
+        match write_bytes(p, p.read_register(A1), &stat) {
+            Ok(()) => 0,
+            Err(_) => -EFAULT,
+        }
+    }
+
+    // brk(0) queries the break; a failed request returns the unchanged break
+    fn brk(&mut self, p: &mut Processor) -> i32 {
+        let requested = p.read_register(A0);
+        if requested != 0 {
+            let _ = p.set_program_break(requested);
+        }
+        p.program_break() as i32
+    }
+
+    // RV32 has a 64-bit time_t, so both calls fill a 16 byte timespec: the 32-bit
+    // tv_nsec of clock_gettime is padded to the 8 byte alignment of tv_sec, which
+    // reads the same as the 64-bit tv_nsec of clock_gettime64.
+    fn clock_gettime(&mut self, p: &mut Processor) -> i32 {
+        let elapsed = match p.read_register(A0) {
+            0 => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(), // CLOCK_REALTIME
+            1 | 4 => self.started.elapsed(),                                         // CLOCK_MONOTONIC(_RAW)
+            _ => return -EINVAL,
+        };
+
+        let mut timespec = [0u8; 16];
+        timespec[0..8].copy_from_slice(&elapsed.as_secs().to_le_bytes());
+        timespec[8..12].copy_from_slice(&elapsed.subsec_nanos().to_le_bytes());
+        match write_bytes(p, p.read_register(A1), &timespec) {
+            Ok(()) => 0,
+            Err(_) => -EFAULT,
+        }
+    }
+
+    fn uname(&mut self, p: &mut Processor) -> i32 {
+        // struct utsname: six fields of 65 bytes
+        let fields = ["Linux", "ruscv", "6.1.0", "#1", "riscv32", "(none)"];
+        let mut utsname = [0u8; 6 * 65];
+        for (i, field) in fields.iter().enumerate() {
+            utsname[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+        }
+        match write_bytes(p, p.read_register(A0), &utsname) {
+            Ok(()) => 0,
+            Err(_) => -EFAULT,
+        }
+    }
+}
+
+impl SyscallHandler for LinuxSyscalls {
+    fn handle(&mut self, p: &mut Processor) -> Result<(), StepError> {
+        let result = match p.read_register(A7) {
+            Self::OPENAT => self.openat(p)?,
+            Self::CLOSE => {
+                let fd = p.read_register(A0);
+                match fd {
+                    0..=2 => 0,
+                    _ => self.files.remove(&fd).map_or(-EBADF, |_| 0),
+                }
+            },
+            Self::LSEEK => self.lseek(p),
+            Self::READ => self.read(p)?,
+            Self::WRITE => self.write(p)?,
+            Self::FSTAT => self.fstat(p),
+            Self::EXIT | Self::EXIT_GROUP => return Err(StepError::Exit(p.read_register(A0) as i32)),
+            Self::CLOCK_GETTIME | Self::CLOCK_GETTIME64 => self.clock_gettime(p),
+            Self::UNAME => self.uname(p),
+            Self::BRK => self.brk(p),
+            _ => -ENOSYS,
+        };
+        p.write_register(A0, result as u32);
+        Ok(())
+    }
+}
+
+fn errno(error: &std::io::Error) -> i32 {
+    // The host errno matches on Linux; otherwise fall back to the error kind
+    error.raw_os_error().unwrap_or(match error.kind() {
+        std::io::ErrorKind::NotFound => ENOENT,
+        std::io::ErrorKind::PermissionDenied => EACCES,
+        std::io::ErrorKind::AlreadyExists => EEXIST,
+        _ => EIO,
+    })
+}
+
+fn read_bytes(p: &Processor, address: u32, length: u32) -> Result<Vec<u8>, StepError> {
+    (0..length)
+        .map(|i| Ok(p.read_memory_byte(address.wrapping_add(i))?))
+        .collect()
+}
+
+fn write_bytes(p: &mut Processor, address: u32, bytes: &[u8]) -> Result<(), StepError> {
+    for (i, &byte) in bytes.iter().enumerate() {
+        p.write_memory_byte(address.wrapping_add(i as u32), byte)?;
+    }
+    Ok(())
+}
+
+// None when there is no null terminator within `limit` bytes
+fn read_c_string(p: &Processor, address: u32, limit: usize) -> Result<Option<Vec<u8>>, StepError> {
+    let mut bytes = Vec::new();
+    let mut address = address;
+    while bytes.len() < limit {
+        let byte = p.read_memory_byte(address)?;
+        if byte == 0 {
+            return Ok(Some(bytes));
+        }
+        bytes.push(byte);
+        address = address.wrapping_add(1);
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::program::Program;
+
+    const ECALL: [u8; 4] = 0x00000073u32.to_le_bytes();
+
+    fn processor_with_console(data: &[u8]) -> (Processor, Rc<RefCell<BufferedConsole>>) {
+        let console = Rc::new(RefCell::new(BufferedConsole::new()));
+        let mut p = Processor::new(config::TEXT_BASE, config::DATA_BASE, config::STACK_BASE, 1024);
+        p.load(&Program::from_sections(config::TEXT_BASE, &ECALL, config::DATA_BASE, data)).unwrap();
+        p.set_syscall_handler(Box::new(RarsSyscalls::new(console.clone())));
+        (p, console)
+    }
+
+    fn ecall(p: &mut Processor, service: u32, a0: u32) -> Result<(), StepError> {
+        p.write_register(A7, service);
+        p.write_register(A0, a0);
+        p.step()
+    }
+
+    #[test]
+    fn test_print_int_and_char() {
+        let (mut p, console) = processor_with_console(&[]);
+        ecall(&mut p, RarsSyscalls::PRINT_INT, -42i32 as u32).unwrap();
+        assert_eq!(p.pc(), config::TEXT_BASE + 4);
+
+        p.load(&Program::from_sections(config::TEXT_BASE, &ECALL, config::DATA_BASE, &[])).unwrap();
+        ecall(&mut p, RarsSyscalls::PRINT_CHAR, b'!' as u32).unwrap();
+        assert_eq!(console.borrow().output_text(), "-42!");
+    }
+
+    #[test]
+    fn test_print_string_reads_until_null() {
+        let (mut p, console) = processor_with_console(b"Hello\0World\0");
+        ecall(&mut p, RarsSyscalls::PRINT_STRING, config::DATA_BASE).unwrap();
+        assert_eq!(console.borrow().output_text(), "Hello");
+    }
+
+    #[test]
+    fn test_print_int_formats() {
+        let (mut p, console) = processor_with_console(&[]);
+        ecall(&mut p, RarsSyscalls::PRINT_INT_HEX, 0xBEEF).unwrap();
+        p.load(&Program::from_sections(config::TEXT_BASE, &ECALL, config::DATA_BASE, &[])).unwrap();
+        ecall(&mut p, RarsSyscalls::PRINT_INT_UNSIGNED, u32::MAX).unwrap();
+        assert_eq!(console.borrow().output_text(), "0x0000beef4294967295");
+    }
+
+    #[test]
+    fn test_read_int_waits_for_input() {
+        let (mut p, console) = processor_with_console(&[]);
+        assert_eq!(ecall(&mut p, RarsSyscalls::READ_INT, 0), Err(StepError::WaitingForInput));
+        assert_eq!(p.pc(), config::TEXT_BASE); // retried on the next step
+
+        console.borrow_mut().push_input(" -17\n");
+        p.step().unwrap();
+        assert_eq!(p.read_register(A0) as i32, -17);
+        assert_eq!(p.pc(), config::TEXT_BASE + 4);
+    }
+
+    #[test]
+    fn test_read_int_rejects_garbage() {
+        let (mut p, console) = processor_with_console(&[]);
+        console.borrow_mut().push_input("abc\n");
+        assert!(matches!(ecall(&mut p, RarsSyscalls::READ_INT, 0), Err(StepError::Syscall(_))));
+    }
+
+    #[test]
+    fn test_read_string_truncates_and_terminates() {
+        let (mut p, console) = processor_with_console(&[0xFF; 8]);
+        console.borrow_mut().push_input("abcdef\n");
+        p.write_register(A1, 4);
+        ecall(&mut p, RarsSyscalls::READ_STRING, config::DATA_BASE).unwrap();
+
+        let bytes: Vec<u8> = (0..5).map(|i| p.read_memory_byte(config::DATA_BASE + i).unwrap()).collect();
+        assert_eq!(bytes, vec![b'a', b'b', b'c', 0, 0xFF]);
+    }
+
+    #[test]
+    fn test_read_char() {
+        let (mut p, console) = processor_with_console(&[]);
+        console.borrow_mut().push_input("xy");
+        ecall(&mut p, RarsSyscalls::READ_CHAR, 0).unwrap();
+        assert_eq!(p.read_register(A0), b'x' as u32);
+    }
+
+    #[test]
+    fn test_sbrk_allocates_usable_memory() {
+        let (mut p, _) = processor_with_console(&[1, 2, 3]);
+        ecall(&mut p, RarsSyscalls::SBRK, 16).unwrap();
+        let first = p.read_register(A0);
+        assert_eq!(first, config::DATA_BASE + 4); // word aligned after the data
+
+        p.write_memory_byte(first + 15, 0xAB).unwrap();
+        assert_eq!(p.read_memory_byte(first + 15), Ok(0xAB));
+
+        p.load(&Program::from_sections(config::TEXT_BASE, &ECALL, config::DATA_BASE, &[1, 2, 3])).unwrap();
+        ecall(&mut p, RarsSyscalls::SBRK, 8).unwrap();
+        assert_eq!(p.read_register(A0), config::DATA_BASE + 4); // load resets the heap
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let (mut p, _) = processor_with_console(&[]);
+        assert_eq!(ecall(&mut p, RarsSyscalls::EXIT, 5), Err(StepError::Exit(0)));
+        assert_eq!(ecall(&mut p, RarsSyscalls::EXIT2, -3i32 as u32), Err(StepError::Exit(-3)));
+    }
+
+    #[test]
+    fn test_unknown_service() {
+        let (mut p, _) = processor_with_console(&[]);
+        assert!(matches!(ecall(&mut p, 9999, 0), Err(StepError::Syscall(_))));
+    }
+
+    #[test]
+    fn test_buffered_console_lines() {
+        let mut console = BufferedConsole::new();
+        console.push_input("one\r\ntw");
+        assert_eq!(console.read_line(), Some("one".to_string()));
+        assert_eq!(console.read_line(), None);
+        console.push_input("o\n");
+        assert_eq!(console.read_line(), Some("two".to_string()));
+    }
+
+    fn linux_processor(sandbox: &Path, data: &[u8]) -> (Processor, Rc<RefCell<BufferedConsole>>) {
+        let console = Rc::new(RefCell::new(BufferedConsole::new()));
+        let mut p = Processor::new(config::TEXT_BASE, config::DATA_BASE, config::STACK_BASE, 1024);
+        p.load(&Program::from_sections(config::TEXT_BASE, &ECALL.repeat(16), config::DATA_BASE, data)).unwrap();
+        p.set_syscall_handler(Box::new(LinuxSyscalls::new(console.clone(), sandbox)));
+        (p, console)
+    }
+
+    fn linux_call(p: &mut Processor, number: u32, args: &[u32]) -> Result<i32, StepError> {
+        for (i, &arg) in args.iter().enumerate() {
+            p.write_register(A0 + i, arg);
+        }
+        p.write_register(A7, number);
+        p.step()?;
+        Ok(p.read_register(A0) as i32)
+    }
+
+    fn sandbox(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ruscv-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_linux_write_to_stdout() {
+        let (mut p, console) = linux_processor(Path::new("."), b"hello");
+        let written = linux_call(&mut p, LinuxSyscalls::WRITE, &[1, config::DATA_BASE, 5]).unwrap();
+        assert_eq!(written, 5);
+        assert_eq!(console.borrow().output_text(), "hello");
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::WRITE, &[7, config::DATA_BASE, 5]), Ok(-EBADF));
+    }
+
+    #[test]
+    fn test_linux_file_round_trip() {
+        let dir = sandbox("files");
+        let (mut p, _) = linux_processor(&dir, b"/out.txt\0data\0\0\0\0\0\0\0\0");
+        let path = config::DATA_BASE;
+        let buffer = config::DATA_BASE + 9;
+
+        let fd = linux_call(&mut p, LinuxSyscalls::OPENAT, &[AT_FDCWD as u32, path, O_RDWR | O_CREAT | O_TRUNC]).unwrap();
+        assert_eq!(fd, 3);
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::WRITE, &[3, buffer, 4]), Ok(4));
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::LSEEK, &[3, 1, 0]), Ok(1));
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::READ, &[3, buffer + 4, 8]), Ok(3));
+        assert_eq!(read_bytes(&p, buffer, 7).unwrap(), b"dataata");
+        // a count far past the buffer reads only what the file has
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::LSEEK, &[3, 0, 0]), Ok(0));
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::READ, &[3, buffer, u32::MAX]), Ok(4));
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::CLOSE, &[3]), Ok(0));
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::CLOSE, &[3]), Ok(-EBADF));
+
+        assert_eq!(std::fs::read_to_string(dir.join("out.txt")).unwrap(), "data");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_linux_paths_stay_in_sandbox() {
+        let dir = sandbox("escape");
+        let (mut p, _) = linux_processor(&dir, b"../secret\0/missing\0");
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::OPENAT, &[AT_FDCWD as u32, config::DATA_BASE, 0]), Ok(-EACCES));
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::OPENAT, &[AT_FDCWD as u32, config::DATA_BASE + 10, 0]), Ok(-ENOENT));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_linux_dangling_symlink_is_refused() {
+        let dir = sandbox("dangling");
+        let outside = sandbox("dangling-target").join("created");
+        std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+        let (mut p, _) = linux_processor(&dir, b"/link\0");
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::OPENAT, &[AT_FDCWD as u32, config::DATA_BASE, O_WRONLY | O_CREAT]), Ok(-EACCES));
+        assert!(!outside.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(outside.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_linux_fstat_reports_size() {
+        let dir = sandbox("fstat");
+        std::fs::write(dir.join("in"), [0u8; 1000]).unwrap();
+        let mut data = b"in\0\0".to_vec();
+        data.extend_from_slice(&[0; 128]);
+        let (mut p, _) = linux_processor(&dir, &data);
+
+        let fd = linux_call(&mut p, LinuxSyscalls::OPENAT, &[AT_FDCWD as u32, config::DATA_BASE, 0]).unwrap();
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::FSTAT, &[fd as u32, config::DATA_BASE + 4]), Ok(0));
+        let size = read_bytes(&p, config::DATA_BASE + 4 + 48, 8).unwrap();
+        assert_eq!(u64::from_le_bytes(size.try_into().unwrap()), 1000);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_linux_read_stdin() {
+        let (mut p, console) = linux_processor(Path::new("."), &[0; 8]);
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::READ, &[0, config::DATA_BASE, 8]), Err(StepError::WaitingForInput));
+
+        console.borrow_mut().push_input("ab\ncd");
+        assert_eq!(p.step(), Ok(()));
+        assert_eq!(p.read_register(A0), 3); // stops after the newline
+        assert_eq!(read_bytes(&p, config::DATA_BASE, 3).unwrap(), b"ab\n");
+
+        console.borrow_mut().close_input();
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::READ, &[0, config::DATA_BASE, 8]), Ok(2));
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::READ, &[0, config::DATA_BASE, 8]), Ok(0)); // EOF
+    }
+
+    #[test]
+    fn test_linux_brk() {
+        let (mut p, _) = linux_processor(Path::new("."), &[0; 5]);
+        let initial = linux_call(&mut p, LinuxSyscalls::BRK, &[0]).unwrap() as u32;
+        assert_eq!(initial, config::DATA_BASE + 8);
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::BRK, &[initial + 64]), Ok((initial + 64) as i32));
+        p.write_memory_byte(initial + 63, 1).unwrap();
+        // requests that cannot be satisfied leave the break unchanged
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::BRK, &[0xFFFF_0000]), Ok((initial + 64) as i32));
+    }
+
+    #[test]
+    fn test_linux_uname_and_clock() {
+        let (mut p, _) = linux_processor(Path::new("."), &[0; 6 * 65]);
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::UNAME, &[config::DATA_BASE]), Ok(0));
+        assert_eq!(read_c_string(&p, config::DATA_BASE, 65), Ok(Some(b"Linux".to_vec())));
+        assert_eq!(read_c_string(&p, config::DATA_BASE + 4 * 65, 65), Ok(Some(b"riscv32".to_vec())));
+
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::CLOCK_GETTIME, &[0, config::DATA_BASE]), Ok(0));
+        let seconds = u64::from_le_bytes(read_bytes(&p, config::DATA_BASE, 8).unwrap().try_into().unwrap());
+        assert!(seconds > 1_600_000_000);
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::CLOCK_GETTIME, &[99, config::DATA_BASE]), Ok(-EINVAL));
+    }
+
+    #[test]
+    fn test_linux_clock_fills_a_16_byte_timespec() {
+        for number in [LinuxSyscalls::CLOCK_GETTIME, LinuxSyscalls::CLOCK_GETTIME64] {
+            let (mut p, _) = linux_processor(Path::new("."), &[0xFF; 20]);
+            assert_eq!(linux_call(&mut p, number, &[1, config::DATA_BASE]), Ok(0));
+            let timespec = read_bytes(&p, config::DATA_BASE, 20).unwrap();
+            let nanoseconds = u64::from_le_bytes(timespec[8..16].try_into().unwrap());
+            assert!(nanoseconds < 1_000_000_000);
+            assert_eq!(timespec[16..], [0xFF; 4]); // nothing past the struct
+        }
+    }
+
+    #[test]
+    fn test_linux_lengths_from_the_program_are_bounded() {
+        let mut data = vec![b'a'; PATH_MAX];
+        data.extend_from_slice(b"\0ok");
+        let (mut p, console) = linux_processor(Path::new("."), &data);
+        let path = config::DATA_BASE;
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::OPENAT, &[AT_FDCWD as u32, path, 0]), Ok(-ENAMETOOLONG));
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::OPENAT, &[AT_FDCWD as u32, 0, 0]), Ok(-EFAULT));
+
+        // a count far past the buffer faults once the copy leaves memory
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::WRITE, &[1, path + PATH_MAX as u32 + 1, u32::MAX]), Ok(-EFAULT));
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::WRITE, &[1, path + PATH_MAX as u32 + 1, 2]), Ok(2));
+        assert_eq!(console.borrow().output_text(), "ok");
+    }
+
+    #[test]
+    fn test_linux_exit_and_unknown_calls() {
+        let (mut p, _) = linux_processor(Path::new("."), &[]);
+        assert_eq!(linux_call(&mut p, 4000, &[]), Ok(-ENOSYS));
+        assert_eq!(linux_call(&mut p, LinuxSyscalls::EXIT_GROUP, &[7]), Err(StepError::Exit(7)));
+    }
+}
@@ -1,10 +1,14 @@
-use crate::processor::Processor;
-use crate::config;
-use crate::lexer;
-use crate::parser;
-use crate::pseudo;
-use crate::symbols;
-use crate::assembler;
+use crate::processor::{Processor, StepError};
+use crate::syscalls::{BufferedConsole, Console, RarsSyscalls};
+use crate::disassembler::Disassembler;
+use crate::pipeline;
+use crate::assembler::DebugInfo;
+use crate::elf;
+use crate::listing;
+use crate::memory_map::MemoryMap;
+use crate::program::Program;
+use crate::source::SourceFiles;
+use crate::uart::{self, Uart};
 
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
@@ -12,7 +16,10 @@ use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{backend::CrosstermBackend, Terminal};
+use std::cell::RefCell;
 use std::io;
+use std::rc::Rc;
+use std::time::Duration;
 use tui_textarea::TextArea;
 
 #[derive(Debug, PartialEq)]
@@ -21,6 +28,8 @@ pub enum Pane {
     Registers,
     Memory,
     Logs,
+    Console,
+    Uart,
 }
 
 #[derive(Debug, PartialEq)]
@@ -45,23 +54,48 @@ pub struct App<'a> {
     pub mode: RunMode,
     pub registers_scroll: u16,
     pub memory_scroll: u32,
+    pub show_disassembly: bool,
     pub logs: Vec<String>,
+    // Set when an ELF executable was opened; it is reloaded instead of assembling the editor
+    pub elf_program: Option<Program>,
+    pub source_path: Option<String>,
+    // Where programs are placed and the stack is, for the memory pane's shortcuts too
+    pub memory_map: MemoryMap,
+    pub console: Rc<RefCell<BufferedConsole>>,
+    pub console_input: String,
+    // The serial line of the UART: keystrokes in its pane are received by the program as they are typed
+    pub uart: Rc<RefCell<BufferedConsole>>,
+    pub waiting_for_input: bool,
     pub should_quit: bool,
 }
 
 impl<'a> App<'a> {
-    pub fn new(initial_file: Option<String>) -> App<'a> {
+    pub fn new(initial_file: Option<String>, memory_map: MemoryMap) -> App<'a> {
         let mut logs = Vec::new();
+        let mut elf_program = None;
+        let source_path = initial_file.clone();
         let mut editor = if let Some(path) = initial_file {
-            match std::fs::read_to_string(&path) {
-                Ok(content) => {
-                    let lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
-                    logs.push(format!("Loaded file: {}", path));
-                    TextArea::new(lines)
+            let is_elf = std::fs::read(&path).is_ok_and(|bytes| bytes.starts_with(b"\x7fELF"));
+            if is_elf {
+                match elf::load_file(&path) {
+                    Ok(program) => {
+                        logs.push(format!("Loaded ELF executable: {} (entry 0x{:08x})", path, program.entry));
+                        elf_program = Some(program);
+                    }
+                    Err(e) => logs.push(format!("Error loading ELF {}: {}", path, e)),
                 }
-                Err(e) => {
-                    logs.push(format!("Error loading file {}: {}", path, e));
-                    TextArea::default()
+                TextArea::default()
+            } else {
+                match std::fs::read_to_string(&path) {
+                    Ok(content) => {
+                        let lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
+                        logs.push(format!("Loaded file: {}", path));
+                        TextArea::new(lines)
+                    }
+                    Err(e) => {
+                        logs.push(format!("Error loading file {}: {}", path, e));
+                        TextArea::default()
+                    }
                 }
             }
         } else {
@@ -71,24 +105,32 @@ impl<'a> App<'a> {
         editor.set_block(
             ratatui::widgets::Block::default()
                 .borders(ratatui::widgets::Borders::ALL)
-                .title("Code Editor (F2: Load, F5: Run, F10: Step, Tab: Switch)"),
+                .title("Code Editor (F2: Load, F5: Run, F6: Export ELF, F7: Listing, F10: Step, Tab: Switch)"),
         );
 
         App {
-            processor: Processor::new(config::TEXT_BASE, config::DATA_BASE, config::STACK_BASE, config::STACK_SIZE),
+            processor: Processor::with_memory_map(&memory_map),
             editor,
             active_pane: Pane::Editor,
             number_format: NumFormat::Hex,
             mode: RunMode::Editing,
             registers_scroll: 0,
-            memory_scroll: config::TEXT_BASE,
+            memory_scroll: memory_map.text_base(),
+            show_disassembly: false,
             logs,
+            elf_program,
+            source_path,
+            memory_map,
+            console: Rc::new(RefCell::new(BufferedConsole::new())),
+            console_input: String::new(),
+            uart: Rc::new(RefCell::new(BufferedConsole::new())),
+            waiting_for_input: false,
             should_quit: false,
         }
     }
 }
 
-pub fn run(initial_file: Option<String>) -> Result<(), io::Error> {
+pub fn run(initial_file: Option<String>, memory_map: MemoryMap) -> Result<(), io::Error> {
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -97,7 +139,7 @@ pub fn run(initial_file: Option<String>) -> Result<(), io::Error> {
     let mut terminal = Terminal::new(backend)?;
 
     // create app and run it
-    let app = App::new(initial_file);
+    let app = App::new(initial_file, memory_map);
     let res = run_app(&mut terminal, app);
 
     // restore terminal
@@ -113,38 +155,110 @@ pub fn run(initial_file: Option<String>) -> Result<(), io::Error> {
 }
 
 fn compile_and_load(app: &mut App) -> Result<(), String> {
-    let source = app.editor.lines().join("\n");
+    let program = match &app.elf_program {
+        Some(program) => program.clone(),
+        None => assemble_editor(app)?,
+    };
 
-    let tokens = match lexer::tokenize(&source) {
-        Ok(tokens) => tokens,
-        Err(e) => return Err(format!("Line {}: {}", e.line, e)),
+    app.processor = Processor::with_memory_map(&app.memory_map);
+    app.processor.load(&program)?;
+    app.console.borrow_mut().clear();
+    app.processor.set_syscall_handler(Box::new(RarsSyscalls::new(app.console.clone())));
+    app.uart.borrow_mut().clear();
+    app.processor.attach_device("uart", uart::BASE, uart::SIZE, Box::new(Uart::new(app.uart.clone())))?;
+    app.waiting_for_input = false;
+    app.logs.push("Program loaded! CPU reset.".to_string());
+    app.memory_scroll = program.entry; // scroll to the entry point by default
+    Ok(())
+}
+
+// Writes the program next to its source file with an .elf extension
+fn export_elf(app: &mut App) -> Result<String, String> {
+    let program = match &app.elf_program {
+        Some(program) => program.clone(),
+        None => assemble_editor(app)?,
+    };
+    let path = match &app.source_path {
+        Some(source) => std::path::Path::new(source).with_extension("elf"),
+        None => std::path::PathBuf::from("out.elf"),
     };
+    elf::save_executable(&program, &path).map_err(|e| format!("Cannot write {}: {}", path.display(), e))?;
+    Ok(path.display().to_string())
+}
 
-    let mut parser = parser::Parser::new(tokens);
-    let statements = match parser.parse() {
-        Ok(stmt) => stmt,
-        Err(e) => return Err(format!("Line {}: {}", e.line, e)),
+// Writes the listing and the symbol map next to the source file, as .lst and
+// .map. An ELF executable has no source to list, only its map.
+fn export_listing(app: &mut App) -> Result<Vec<String>, String> {
+    let base = std::path::PathBuf::from(app.source_path.as_deref().unwrap_or("out.s"));
+    let mut files = Vec::new();
+    let program = match &app.elf_program {
+        Some(program) => program.clone(),
+        None => {
+            let (program, debug_info, sources) = assemble_editor_with_debug_info(app)?;
+            files.push((base.with_extension("lst"), listing::listing(&debug_info, &sources)));
+            program
+        }
     };
+    files.push((base.with_extension("map"), listing::symbol_map(&program)));
+    for (path, text) in &files {
+        std::fs::write(path, text).map_err(|e| format!("Cannot write {}: {}", path.display(), e))?;
+    }
+    Ok(files.into_iter().map(|(path, _)| path.display().to_string()).collect())
+}
 
-    let statements = pseudo::expand(statements).map_err(|e| format!("Pseudo-instruction error: {}", e))?;
+fn assemble_editor(app: &mut App) -> Result<Program, String> {
+    assemble_editor_with_debug_info(app).map(|(program, _, _)| program)
+}
 
-    let mut symbol_table = symbols::SymbolTable::new(config::TEXT_BASE, config::DATA_BASE);
-    symbol_table.build(&statements).map_err(|e| format!("Symbol error: {}", e))?;
+// Files the buffer includes are looked up next to its file, if it has one
+fn assemble_editor_with_debug_info(app: &mut App) -> Result<(Program, DebugInfo, SourceFiles), String> {
+    let source = app.editor.lines().join("\n");
+    let mut sources = SourceFiles::default();
+    let file = sources.add(&source, app.source_path.as_deref().map(std::path::Path::new));
+    let (program, debug_info) = pipeline::assemble_with_debug_info(&mut sources, &[file], &app.memory_map)?;
+    app.logs.push("Assembly successful!".to_string());
+    Ok((program, debug_info, sources))
+}
 
-    let mut assembler = assembler::Assembler::new(config::TEXT_BASE, config::DATA_BASE);
-    if let Err(errors) = assembler.assemble(&statements, &symbol_table) {
-        let mut msg = String::new();
-        for err in errors {
-            msg.push_str(&format!("Line {}: {}\n", err.line, err.message));
+// Steps run before the UI is redrawn and checked for keystrokes
+const STEPS_PER_RUN: u32 = 100_000;
+
+// Runs until the program halts or blocks on console input, or for at most
+// STEPS_PER_RUN steps; run_app keeps calling it while the mode is Running
+fn run_program(app: &mut App) {
+    app.mode = RunMode::Running;
+    for _ in 0..STEPS_PER_RUN {
+        if let Err(e) = app.processor.step() {
+            handle_step_error(app, e);
+            break;
         }
-        return Err(msg);
     }
+}
 
-    app.processor = Processor::new(config::TEXT_BASE, config::DATA_BASE, config::STACK_BASE, config::STACK_SIZE);
-    app.processor.load(&assembler.text_bin, &assembler.data_bin);
-    app.logs.push("Assembly successful! CPU reset and loaded.".to_string());
-    app.memory_scroll = config::TEXT_BASE; // scroll to text base by default
-    Ok(())
+fn step_program(app: &mut App) {
+    if let Err(e) = app.processor.step() {
+        handle_step_error(app, e);
+    }
+}
+
+fn handle_step_error(app: &mut App, error: StepError) {
+    match error {
+        StepError::WaitingForInput => {
+            if !app.waiting_for_input {
+                app.logs.push("Waiting for console input...".to_string());
+            }
+            app.waiting_for_input = true;
+            app.active_pane = Pane::Console;
+        }
+        StepError::Exit(code) => {
+            app.logs.push(format!("Program exited with code {}", code));
+            app.mode = RunMode::Editing;
+        }
+        e => {
+            app.logs.push(format!("Halted: {:?}", e));
+            app.mode = RunMode::Editing;
+        }
+    }
 }
 
 fn run_app<B: ratatui::backend::Backend>(
@@ -154,109 +268,154 @@ fn run_app<B: ratatui::backend::Backend>(
     loop {
         terminal.draw(|f| ui::draw(f, &mut app))?;
 
-        if let Event::Key(key) = event::read()? {
-            if key.kind == event::KeyEventKind::Press {
-                if key.code == KeyCode::Esc {
-                    app.should_quit = true;
-                }
+        // A running program keeps going between keystrokes, e.g. while it polls the UART
+        if app.mode == RunMode::Running && !app.waiting_for_input && !event::poll(Duration::ZERO)? {
+            run_program(&mut app);
+            continue;
+        }
+
+        if let Event::Key(key) = event::read()?
+            && key.kind == event::KeyEventKind::Press
+        {
+            if key.code == KeyCode::Esc {
+                app.should_quit = true;
+            }
+
+            if app.should_quit {
+                return Ok(());
+            }
+
+            if key.code == KeyCode::Tab {
+                app.active_pane = match app.active_pane {
+                    Pane::Editor => Pane::Registers,
+                    Pane::Registers => Pane::Memory,
+                    Pane::Memory => Pane::Logs,
+                    Pane::Logs => Pane::Console,
+                    Pane::Console => Pane::Uart,
+                    Pane::Uart => Pane::Editor,
+                };
+                continue;
+            }
 
-                if app.should_quit {
-                    return Ok(());
+            if key.code == KeyCode::F(2) {
+                // Just Load
+                if app.mode == RunMode::Editing
+                    && let Err(e) = compile_and_load(&mut app)
+                {
+                    app.logs.push(format!("Compile Error:\n{}", e));
                 }
+                continue;
+            }
 
-                if key.code == KeyCode::Tab {
-                    app.active_pane = match app.active_pane {
-                        Pane::Editor => Pane::Registers,
-                        Pane::Registers => Pane::Memory,
-                        Pane::Memory => Pane::Logs,
-                        Pane::Logs => Pane::Editor,
-                    };
-                    continue;
+            if key.code == KeyCode::F(6) {
+                match export_elf(&mut app) {
+                    Ok(path) => app.logs.push(format!("Exported ELF executable to {}", path)),
+                    Err(e) => app.logs.push(format!("Export Error:\n{}", e)),
                 }
+                continue;
+            }
 
-                if key.code == KeyCode::F(2) {
-                    // Just Load
-                    if app.mode == RunMode::Editing {
-                        if let Err(e) = compile_and_load(&mut app) {
-                            app.logs.push(format!("Compile Error:\n{}", e));
-                        }
-                    }
-                    continue;
+            if key.code == KeyCode::F(7) {
+                match export_listing(&mut app) {
+                    Ok(paths) => app.logs.push(format!("Exported listing to {}", paths.join(", "))),
+                    Err(e) => app.logs.push(format!("Export Error:\n{}", e)),
                 }
+                continue;
+            }
 
-                if key.code == KeyCode::F(9) {
-                    app.number_format = match app.number_format {
-                        NumFormat::Hex => NumFormat::Binary,
-                        NumFormat::Binary => NumFormat::Decimal,
-                        NumFormat::Decimal => NumFormat::Hex,
-                    };
+            if key.code == KeyCode::F(9) {
+                app.number_format = match app.number_format {
+                    NumFormat::Hex => NumFormat::Binary,
+                    NumFormat::Binary => NumFormat::Decimal,
+                    NumFormat::Decimal => NumFormat::Hex,
+                };
+                continue;
+            }
+
+            if key.code == KeyCode::F(5) { // Run
+                if app.mode == RunMode::Editing
+                    && let Err(e) = compile_and_load(&mut app)
+                {
+                    app.logs.push(format!("Compile Error:\n{}", e));
                     continue;
                 }
+                run_program(&mut app);
+                continue;
+            }
 
-                if key.code == KeyCode::F(5) { // Run
-                    if app.mode == RunMode::Editing {
-                        if let Err(e) = compile_and_load(&mut app) {
-                            app.logs.push(format!("Compile Error:\n{}", e));
-                            continue;
-                        }
-                    }
-                    app.mode = RunMode::Running;
-                    loop {
-                        match app.processor.step() {
-                            Ok(_) => {}
-                            Err(e) => {
-                                app.logs.push(format!("Halted: {:?}", e));
-                                app.mode = RunMode::Editing;
-                                break;
-                            }
-                        }
+            if key.code == KeyCode::F(10) { // Step
+                if app.mode == RunMode::Editing {
+                    if let Err(e) = compile_and_load(&mut app) {
+                        app.logs.push(format!("Compile Error:\n{}", e));
+                        continue;
                     }
-                    continue;
+                    app.mode = RunMode::Stepping;
                 }
+                step_program(&mut app);
+                continue;
+            }
 
-                if key.code == KeyCode::F(10) { // Step
-                    if app.mode == RunMode::Editing {
-                        if let Err(e) = compile_and_load(&mut app) {
-                            app.logs.push(format!("Compile Error:\n{}", e));
-                            continue;
-                        }
-                        app.mode = RunMode::Stepping;
-                    }
-                    match app.processor.step() {
-                        Ok(_) => {}
-                        Err(e) => {
-                            app.logs.push(format!("Halted: {:?}", e));
-                            app.mode = RunMode::Editing;
-                        }
+            match app.active_pane {
+                Pane::Editor => {
+                    app.editor.input(key);
+                    app.mode = RunMode::Editing;
+                }
+                Pane::Registers => {
+                    match key.code {
+                        KeyCode::Up => app.registers_scroll = app.registers_scroll.saturating_sub(1),
+                        KeyCode::Down => app.registers_scroll = app.registers_scroll.saturating_add(1).min(31),
+                        _ => {}
                     }
-                    continue;
                 }
-
-                match app.active_pane {
-                    Pane::Editor => {
-                        app.editor.input(key);
-                        app.mode = RunMode::Editing;
+                Pane::Memory => {
+                    match key.code {
+                        KeyCode::Up => app.memory_scroll = app.memory_scroll.saturating_sub(4),
+                        KeyCode::Down => app.memory_scroll = app.memory_scroll.wrapping_add(4),
+                        KeyCode::Char('t') | KeyCode::Char('T') => app.memory_scroll = app.processor.entry(),
+                        KeyCode::Char('d') | KeyCode::Char('D') => app.memory_scroll = app.memory_map.data_base(),
+                        KeyCode::Char('s') | KeyCode::Char('S') => app.memory_scroll = app.memory_map.stack_top.saturating_sub(64),
+                        KeyCode::Char('c') | KeyCode::Char('C') => app.memory_scroll = app.processor.pc(),
+                        KeyCode::Char('i') | KeyCode::Char('I') => app.show_disassembly = !app.show_disassembly,
+                        _ => {}
                     }
-                    Pane::Registers => {
-                        match key.code {
-                            KeyCode::Up => app.registers_scroll = app.registers_scroll.saturating_sub(1),
-                            KeyCode::Down => app.registers_scroll = app.registers_scroll.saturating_add(1).min(31),
-                            _ => {}
+                }
+                Pane::Console => {
+                    match key.code {
+                        KeyCode::Char(c) => app.console_input.push(c),
+                        KeyCode::Backspace => { app.console_input.pop(); }
+                        KeyCode::Enter => {
+                            let line = format!("{}\n", std::mem::take(&mut app.console_input));
+                            let mut console = app.console.borrow_mut();
+                            console.write(line.as_bytes()); // echo, as a terminal would
+                            console.push_input(&line);
+                            drop(console);
+
+                            // Resume the program that was blocked on this input
+                            if app.waiting_for_input {
+                                app.waiting_for_input = false;
+                                match app.mode {
+                                    RunMode::Running => run_program(&mut app),
+                                    RunMode::Stepping => step_program(&mut app),
+                                    RunMode::Editing => {}
+                                }
+                            }
                         }
+                        _ => {}
                     }
-                    Pane::Memory => {
-                        match key.code {
-                            KeyCode::Up => app.memory_scroll = app.memory_scroll.saturating_sub(4),
-                            KeyCode::Down => app.memory_scroll = app.memory_scroll.wrapping_add(4),
-                            KeyCode::Char('t') | KeyCode::Char('T') => app.memory_scroll = config::TEXT_BASE,
-                            KeyCode::Char('d') | KeyCode::Char('D') => app.memory_scroll = config::DATA_BASE,
-                            KeyCode::Char('s') | KeyCode::Char('S') => app.memory_scroll = config::STACK_BASE.saturating_sub(64),
-                            KeyCode::Char('c') | KeyCode::Char('C') => app.memory_scroll = app.processor.pc(),
-                            _ => {}
-                        }
+                }
+                Pane::Uart => {
+                    // Sent as a serial terminal would, without local echo
+                    let byte = match key.code {
+                        KeyCode::Char(c) if c.is_ascii() => Some(c as u8),
+                        KeyCode::Enter => Some(b'\r'),
+                        KeyCode::Backspace => Some(0x7F),
+                        _ => None,
+                    };
+                    if let Some(byte) = byte {
+                        app.uart.borrow_mut().push_input(&(byte as char).to_string());
                     }
-                    _ => {}
                 }
+                _ => {}
             }
         }
     }
@@ -290,13 +449,14 @@ mod ui {
         .block(Block::default().borders(Borders::ALL));
         f.render_widget(top_msg, chunks[0]);
 
-        // Middle section
+        // Middle section; the memory pane takes room from the editor to fit the disassembly
+        let (editor_width, memory_width) = if app.show_disassembly { (40, 40) } else { (60, 20) };
         let middle_chunks = Layout::default()
             .direction(Direction::Horizontal)
             .constraints([
-                Constraint::Percentage(60), // Editor
+                Constraint::Percentage(editor_width), // Editor
                 Constraint::Percentage(20), // Registers
-                Constraint::Percentage(20), // Memory
+                Constraint::Percentage(memory_width), // Memory
             ])
             .split(chunks[1]);
 
@@ -306,18 +466,18 @@ mod ui {
             Block::default()
                 .borders(Borders::ALL)
                 .border_style(editor_style)
-                .title("Code Editor (F2: Load, F5: Run, F10: Step, Tab: Switch)"),
+                .title("Code Editor (F2: Load, F5: Run, F6: Export ELF, F7: Listing, F10: Step, Tab: Switch)"),
         );
-        f.render_widget(app.editor.widget(), middle_chunks[0]);
+        f.render_widget(&app.editor, middle_chunks[0]);
 
         // Registers
         let mut reg_str = String::new();
         let regs = app.processor.registers();
-        for i in 0..32 {
+        for (i, reg) in regs.iter().enumerate() {
             match app.number_format {
-                NumFormat::Hex => reg_str.push_str(&format!("x{:<2}: 0x{:08x}\n", i, regs[i])),
-                NumFormat::Binary => reg_str.push_str(&format!("x{:<2}: 0b{:032b}\n", i, regs[i])),
-                NumFormat::Decimal => reg_str.push_str(&format!("x{:<2}: {:<10}\n", i, regs[i] as i32)),
+                NumFormat::Hex => reg_str.push_str(&format!("x{:<2}: 0x{:08x}\n", i, reg)),
+                NumFormat::Binary => reg_str.push_str(&format!("x{:<2}: 0b{:032b}\n", i, reg)),
+                NumFormat::Decimal => reg_str.push_str(&format!("x{:<2}: {:<10}\n", i, *reg as i32)),
             }
         }
         let regs_style = if app.active_pane == Pane::Registers { Style::default().fg(Color::Yellow) } else { Style::default() };
@@ -338,15 +498,24 @@ mod ui {
         // We use a Vec of Lines so we can color individual addresses, such as the active PC
         let mut mem_lines: Vec<Line> = Vec::new();
 
+        let mut disassembler = Disassembler::new();
+        disassembler.add_program_symbols(app.processor.symbols());
+
         for i in 0..mem_size_words {
             let addr = mem_start + (i * 4);
-            match app.processor.read_memory_word(addr) {
+            match app.processor.memory().peek_word(addr) {
                 Ok(word) => {
-                    let formatted = match app.number_format {
+                    let mut formatted = match app.number_format {
                         NumFormat::Hex => format!("0x{:08x}: 0x{:08x}", addr, word),
                         NumFormat::Binary => format!("0x{:08x}: 0b{:032b}", addr, word),
                         NumFormat::Decimal => format!("0x{:08x}: {:<11}", addr, word),
                     };
+                    if let Some(symbol) = app.processor.symbol_at(addr) {
+                        formatted.push_str(&format!(" <{}>", symbol));
+                    }
+                    if app.show_disassembly {
+                        formatted.push_str(&format!("  {}", disassembler.disassemble(word, addr)));
+                    }
 
                     // If this address is the current Program Counter, highlight it in Green
                     if addr == app.processor.pc() {
@@ -370,14 +539,11 @@ mod ui {
             }
         }
 
-        let section = if mem_start >= config::STACK_BASE.saturating_sub(config::STACK_SIZE as u32) {
-            "stack"
-        } else if mem_start >= config::DATA_BASE {
-            "data"
-        } else if mem_start >= config::TEXT_BASE {
-            "text"
-        } else {
-            "unmapped"
+        // device registers are not read here, since reads can have side effects
+        let section = match (app.processor.bus().range_at(mem_start), app.processor.memory().region_at(mem_start)) {
+            (Some(device), _) => format!("device {}", device.name),
+            (None, Some(region)) => format!("{} {}", region.name, region.permissions),
+            (None, None) => "unmapped".to_string(),
         };
 
         let mem_style = if app.active_pane == Pane::Memory { Style::default().fg(Color::Yellow) } else { Style::default() };
@@ -385,10 +551,20 @@ mod ui {
             Block::default()
                 .borders(Borders::ALL)
                 .border_style(mem_style)
-                .title(format!("Memory (.{}) (0x{:08x})", section, mem_start)),
+                .title(format!("Memory ({}) (0x{:08x})", section, mem_start)),
         );
         f.render_widget(mem_p, middle_chunks[2]);
 
+        // Bottom section
+        let bottom_chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([
+                Constraint::Percentage(34), // Logs
+                Constraint::Percentage(33), // Console
+                Constraint::Percentage(33), // UART
+            ])
+            .split(chunks[2]);
+
         // Logs
         let logs_style = if app.active_pane == Pane::Logs { Style::default().fg(Color::Yellow) } else { Style::default() };
         let logs_text = app.logs.join("\n");
@@ -398,7 +574,43 @@ mod ui {
                 .border_style(logs_style)
                 .title("Execution Logs"),
         );
-        f.render_widget(logs, chunks[2]);
+        f.render_widget(logs, bottom_chunks[0]);
+
+        // Console
+        let console_style = if app.active_pane == Pane::Console { Style::default().fg(Color::Yellow) } else { Style::default() };
+        let mut console_text = app.console.borrow().output_text();
+        if !console_text.is_empty() && !console_text.ends_with('\n') {
+            console_text.push('\n');
+        }
+        console_text.push_str(&format!("> {}", app.console_input));
+        // Keep the prompt line visible by scrolling to the bottom
+        let console_lines = console_text.lines().count() as u16;
+        let console_scroll = console_lines.saturating_sub(bottom_chunks[1].height.saturating_sub(2));
+        let console_title = if app.waiting_for_input { "Console (waiting for input)" } else { "Console" };
+        let console = Paragraph::new(console_text)
+            .scroll((console_scroll, 0))
+            .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(console_style)
+                .title(console_title),
+        );
+        f.render_widget(console, bottom_chunks[1]);
+
+        // UART
+        let uart_style = if app.active_pane == Pane::Uart { Style::default().fg(Color::Yellow) } else { Style::default() };
+        let uart_text = app.uart.borrow().output_text().replace('\r', "");
+        let uart_lines = uart_text.lines().count() as u16;
+        let uart_scroll = uart_lines.saturating_sub(bottom_chunks[2].height.saturating_sub(2));
+        let uart = Paragraph::new(uart_text)
+            .scroll((uart_scroll, 0))
+            .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(uart_style)
+                .title(format!("UART (0x{:08x})", uart::BASE)),
+        );
+        f.render_widget(uart, bottom_chunks[2]);
     }
 }
 
@@ -408,15 +620,121 @@ mod tests {
 
     #[test]
     fn test_app_load_file() {
-        let app = App::new(Some("Cargo.toml".to_string()));
+        let app = App::new(Some("Cargo.toml".to_string()), MemoryMap::default());
         assert!(!app.editor.lines().is_empty());
         assert!(app.logs[0].contains("Loaded file: Cargo.toml"));
     }
 
     #[test]
     fn test_app_load_non_existent_file() {
-        let app = App::new(Some("non_existent_file.asm".to_string()));
+        let app = App::new(Some("non_existent_file.asm".to_string()), MemoryMap::default());
         assert_eq!(app.editor.lines().len(), 1); // Default empty line
         assert!(app.logs[0].contains("Error loading file"));
     }
+
+    fn app_with_source(source: &str) -> App<'static> {
+        let mut app = App::new(None, MemoryMap::default());
+        app.editor = TextArea::new(source.lines().map(|s| s.to_string()).collect());
+        compile_and_load(&mut app).unwrap();
+        app
+    }
+
+    #[test]
+    fn test_export_elf_next_to_source() {
+        let dir = std::env::temp_dir().join(format!("ruscv-export-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("prog.s");
+        std::fs::write(&source, "main: addi a0, zero, 1\n").unwrap();
+
+        let mut app = App::new(Some(source.display().to_string()), MemoryMap::default());
+        let path = export_elf(&mut app).unwrap();
+        assert!(path.ends_with("prog.elf"));
+
+        // opening the exported file loads the same program
+        let mut reopened = App::new(Some(path), MemoryMap::default());
+        assert!(reopened.elf_program.is_some());
+        compile_and_load(&mut reopened).unwrap();
+        assert_eq!(reopened.processor.symbol_at(reopened.memory_map.text_base()), Some("main"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_listing_next_to_source() {
+        let dir = std::env::temp_dir().join(format!("ruscv-listing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("prog.s");
+        std::fs::write(&source, "main: li a0, 1\n").unwrap();
+
+        let mut app = App::new(Some(source.display().to_string()), MemoryMap::default());
+        let paths = export_listing(&mut app).unwrap();
+        assert_eq!(paths, [dir.join("prog.lst").display().to_string(), dir.join("prog.map").display().to_string()]);
+        let listing = std::fs::read_to_string(dir.join("prog.lst")).unwrap();
+        assert!(listing.contains("00010000  13 05 10 00  addi x10, x0, 1"), "{}", listing);
+        let map = std::fs::read_to_string(dir.join("prog.map")).unwrap();
+        assert!(map.contains("00010000  00000000  .text             local   notype    main"), "{}", map);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_program_output_goes_to_console() {
+        let mut app = app_with_source("
+            .data
+            msg: .asciz \"Hi\"
+            .text
+                lui a0, %hi(msg)
+                addi a0, a0, %lo(msg)
+                li a7, 4
+                ecall
+                li a0, 3
+                li a7, 93
+                ecall
+        ");
+        run_program(&mut app);
+        assert_eq!(app.console.borrow().output_text(), "Hi");
+        assert_eq!(app.mode, RunMode::Editing);
+        assert!(app.logs.last().unwrap().contains("exited with code 3"));
+    }
+
+    #[test]
+    fn test_run_blocks_until_console_input() {
+        let mut app = app_with_source("
+                li a7, 5
+                ecall
+                li a7, 1
+                ecall
+                li a7, 10
+                ecall
+        ");
+        run_program(&mut app);
+        assert!(app.waiting_for_input);
+        assert_eq!(app.active_pane, Pane::Console);
+
+        app.console.borrow_mut().push_input("21\n");
+        app.waiting_for_input = false;
+        run_program(&mut app);
+        assert_eq!(app.console.borrow().output_text(), "21");
+        assert_eq!(app.mode, RunMode::Editing);
+    }
+
+    #[test]
+    fn test_uart_echoes_input_while_running() {
+        let mut app = app_with_source("
+                lui t0, 0x10000
+            wait:
+                lbu t1, 5(t0)
+                andi t1, t1, 1
+                beqz t1, wait
+                lbu t1, 0(t0)
+                sb t1, 0(t0)
+                j wait
+        ");
+        run_program(&mut app);
+        assert_eq!(app.mode, RunMode::Running);
+        assert!(app.uart.borrow().output().is_empty());
+
+        app.uart.borrow_mut().push_input("ok\r");
+        run_program(&mut app);
+        assert_eq!(app.uart.borrow().output_text(), "ok\r");
+        assert_eq!(app.console.borrow().output_text(), "");
+    }
 }
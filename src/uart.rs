@@ -0,0 +1,243 @@
+use crate::bus::Device;
+use crate::csr;
+use crate::syscalls::Console;
+
+/// A 16550-compatible UART at the address QEMU's `virt` machine uses, with
+/// byte-wide registers (no register shift).
+pub const BASE: u32 = 0x1000_0000;
+pub const SIZE: u32 = 0x100;
+
+// Register offsets from BASE. RBR/THR and IER double as the divisor latch while LCR.DLAB is set.
+pub const RBR: u32 = 0; // read
+pub const THR: u32 = 0; // write
+pub const IER: u32 = 1;
+pub const IIR: u32 = 2; // read
+pub const FCR: u32 = 2; // write
+pub const LCR: u32 = 3;
+pub const MCR: u32 = 4;
+pub const LSR: u32 = 5;
+pub const MSR: u32 = 6;
+pub const SCR: u32 = 7;
+
+// IER bits
+pub const IER_RX_AVAILABLE: u8 = 1 << 0;
+pub const IER_THR_EMPTY: u8 = 1 << 1;
+
+// LSR bits
+pub const LSR_DATA_READY: u8 = 1 << 0;
+pub const LSR_THR_EMPTY: u8 = 1 << 5;
+pub const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+// IIR values, highest priority first
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_NONE: u8 = 0x01;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+// Carrier detect, data set ready and clear to send: the other end is always there
+const MSR_CONNECTED: u8 = 0xB0;
+
+/// Transmitted bytes are written to the console as soon as THR is written,
+/// so the transmitter is always empty. Received bytes are taken from the
+/// console one at a time into RBR.
+pub struct Uart {
+    console: Box<dyn Console>,
+    rbr: Option<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    fifo_enabled: bool,
+    thr_empty_pending: bool, // cleared by reading IIR or writing THR
+}
+
+impl Uart {
+    pub fn new(console: impl Console + 'static) -> Self {
+        Self {
+            console: Box::new(console),
+            rbr: None,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            fifo_enabled: false,
+            thr_empty_pending: false,
+        }
+    }
+
+    fn receive(&mut self) {
+        if self.rbr.is_none() {
+            self.rbr = self.console.read_byte();
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn rx_interrupt(&self) -> bool {
+        self.ier & IER_RX_AVAILABLE != 0 && self.rbr.is_some()
+    }
+
+    fn thr_interrupt(&self) -> bool {
+        self.ier & IER_THR_EMPTY != 0 && self.thr_empty_pending
+    }
+
+    fn read_register(&mut self, offset: u32) -> Option<u8> {
+        let value = match offset {
+            RBR if self.dlab() => self.divisor as u8,
+            RBR => {
+                self.receive();
+                self.rbr.take().unwrap_or(0)
+            }
+            IER if self.dlab() => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR => {
+                let fifo = if self.fifo_enabled { IIR_FIFO_ENABLED } else { 0 };
+                let id = if self.rx_interrupt() {
+                    IIR_RX_AVAILABLE
+                } else if self.thr_interrupt() {
+                    self.thr_empty_pending = false;
+                    IIR_THR_EMPTY
+                } else {
+                    IIR_NONE
+                };
+                fifo | id
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.receive();
+                let ready = if self.rbr.is_some() { LSR_DATA_READY } else { 0 };
+                ready | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+            }
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn write_register(&mut self, offset: u32, value: u8) -> Option<()> {
+        match offset {
+            THR if self.dlab() => self.divisor = (self.divisor & 0xFF00) | value as u16,
+            THR => {
+                self.console.write(&[value]);
+                self.thr_empty_pending = true;
+            }
+            IER if self.dlab() => self.divisor = (self.divisor & 0x00FF) | (value as u16) << 8,
+            IER => {
+                // Enabling the THR empty interrupt raises it at once, since THR always is
+                if value & IER_THR_EMPTY != 0 && self.ier & IER_THR_EMPTY == 0 {
+                    self.thr_empty_pending = true;
+                }
+                self.ier = value & 0x0F;
+            }
+            FCR => {
+                self.fifo_enabled = value & 1 != 0;
+                if value & 0b10 != 0 {
+                    self.rbr = None; // clear the receive FIFO
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1F,
+            LSR | MSR => {} // read only
+            SCR => self.scr = value,
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+// Accesses wider than a byte are accepted and use the low byte, so word
+// loads and stores work as well as lbu and sb
+impl Device for Uart {
+    fn read(&mut self, offset: u32, _size: u32) -> Option<u32> {
+        self.read_register(offset).map(u32::from)
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32) -> Option<()> {
+        self.write_register(offset, value as u8)
+    }
+
+    // Input is only taken from the console ahead of a read when it can raise
+    // an interrupt, so a console shared with ECALL does not lose bytes to an unused UART
+    fn tick(&mut self) {
+        if self.ier & IER_RX_AVAILABLE != 0 {
+            self.receive();
+        }
+    }
+
+    fn interrupts(&self) -> u32 {
+        if self.rx_interrupt() || self.thr_interrupt() { csr::MEI } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syscalls::BufferedConsole;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn uart() -> (Uart, Rc<RefCell<BufferedConsole>>) {
+        let console = Rc::new(RefCell::new(BufferedConsole::new()));
+        (Uart::new(console.clone()), console)
+    }
+
+    #[test]
+    fn test_transmit_and_receive() {
+        let (mut uart, console) = uart();
+        assert_eq!(uart.read(LSR, 1), Some((LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY) as u32));
+        uart.write(THR, 1, b'O' as u32).unwrap();
+        uart.write(THR, 4, b'K' as u32).unwrap();
+        assert_eq!(console.borrow().output_text(), "OK");
+
+        console.borrow_mut().push_input("ab");
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_DATA_READY, LSR_DATA_READY);
+        assert_eq!(uart.read(RBR, 1), Some(b'a' as u32));
+        assert_eq!(uart.read(RBR, 1), Some(b'b' as u32));
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_DATA_READY, 0);
+        assert_eq!(uart.read(0x8, 1), None);
+    }
+
+    #[test]
+    fn test_interrupts_follow_ier() {
+        let (mut uart, console) = uart();
+        console.borrow_mut().push_input("x");
+        uart.tick();
+        assert_eq!(uart.interrupts(), 0);
+        assert_eq!(uart.read(IIR, 1), Some(IIR_NONE as u32));
+
+        uart.write(IER, 1, IER_RX_AVAILABLE as u32).unwrap();
+        uart.tick();
+        assert_eq!(uart.interrupts(), csr::MEI);
+        assert_eq!(uart.read(IIR, 1), Some(IIR_RX_AVAILABLE as u32));
+        uart.read(RBR, 1).unwrap();
+        assert_eq!(uart.interrupts(), 0);
+
+        // THR empty is raised when enabled and after each write, and cleared by reading IIR
+        uart.write(IER, 1, (IER_RX_AVAILABLE | IER_THR_EMPTY) as u32).unwrap();
+        assert_eq!(uart.interrupts(), csr::MEI);
+        assert_eq!(uart.read(IIR, 1), Some(IIR_THR_EMPTY as u32));
+        assert_eq!(uart.interrupts(), 0);
+        uart.write(THR, 1, b'!' as u32).unwrap();
+        assert_eq!(uart.interrupts(), csr::MEI);
+    }
+
+    #[test]
+    fn test_divisor_latch() {
+        let (mut uart, console) = uart();
+        uart.write(LCR, 1, (LCR_DLAB | 0x03) as u32).unwrap();
+        uart.write(THR, 1, 0x01).unwrap();
+        uart.write(IER, 1, 0x00).unwrap();
+        uart.write(LCR, 1, 0x03).unwrap();
+        assert_eq!(uart.divisor, 1);
+        assert_eq!(uart.read(IER, 1), Some(0));
+        assert!(console.borrow().output().is_empty());
+    }
+}